@@ -1,20 +1,10 @@
 #![no_main]
 #![no_std]
-#![feature(lang_items)]
-#![allow(dead_code)]
 
-use core::panic::PanicInfo;
+extern crate libblanc;
 
-
-
-#[no_mangle] // don't mangle the name of this function
-pub extern "C" fn _start() -> ! {
-    loop {}
-}
-
-
-
-#[panic_handler]
-fn panic(_panic: &PanicInfo<'_>) -> ! {
+/// Spins forever, something to stop, continue and kill from the shell
+#[no_mangle]
+pub extern "C" fn main(_argc: isize, _argv: *const *const u8) -> isize {
     loop {}
 }
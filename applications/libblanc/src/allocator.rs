@@ -0,0 +1,207 @@
+//! The global allocator of the runtime, a first fit free list like the one of the
+//! kernel heap that grows whenever no free region is large enough. The list is kept in
+//! address order so freed regions merge with their neighbours
+//!
+//! The heap grows by moving the end of the process heap with `sbrk`. Once the kernel
+//! refuses to move it further allocations fail and the program panics
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+use spin::Mutex;
+
+use crate::syscall;
+
+/// Smallest amount the heap grows by, larger allocations grow it by their size
+const GROW_SIZE: usize = 16 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: Locked<Heap> = Locked::new(Heap::new());
+
+/// A wrapper around spin::Mutex to permit trait implementations
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// Align the given address `addr` upwards to alignment `align`, which must be a power
+/// of two
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+pub struct Heap {
+    head: ListNode,
+}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Self {
+            head: ListNode::new(0),
+        }
+    }
+
+    /// Put the region at `addr` into the list, which is kept in address order, and merge
+    /// it with the free regions right in front of and behind it
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // The last region in front of `addr`, or the head if there is none
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        let node = &mut *node_ptr;
+
+        if let Some(next) = node.next.take() {
+            if next.start_addr() == node.end_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+
+        // The head has no size, so it never ends where a region starts
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next.take();
+        } else {
+            current.next = Some(node);
+        }
+    }
+
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return ret;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// Add at least `size` bytes of new memory aligned to `align` as a free region,
+    /// returns false once there is no memory left or the size does not fit a `usize`
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        // Room to align the allocation and for a node of what is left after it, rounded
+        // up to the alignment of a node
+        let node_mask = mem::align_of::<ListNode>() - 1;
+        let size = match size
+            .checked_add(align)
+            .and_then(|size| size.checked_add(mem::size_of::<ListNode>() + node_mask))
+        {
+            Some(size) => (size & !node_mask).max(GROW_SIZE),
+            None => return false,
+        };
+        match morecore(size) {
+            Some(start) => {
+                self.add_free_region(start, size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+/// Move the end of the process heap up by `size` bytes, returns the start of the new
+/// memory
+fn morecore(size: usize) -> Option<usize> {
+    syscall::sbrk(isize::try_from(size).ok()?).ok()
+}
+
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = Heap::size_align(layout);
+        let mut allocator = self.lock();
+
+        loop {
+            if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+                let alloc_end = alloc_start.checked_add(size).expect("overflow");
+                let excess_size = region.end_addr() - alloc_end;
+                if excess_size > 0 {
+                    allocator.add_free_region(alloc_end, excess_size);
+                }
+                return alloc_start as *mut u8;
+            }
+            if !allocator.grow(size, align) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Heap::size_align(layout);
+
+        self.lock().add_free_region(ptr as usize, size)
+    }
+}
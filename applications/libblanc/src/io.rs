@@ -0,0 +1,41 @@
+//! Printing to the terminal, which is open as descriptors 0 to 2
+use core::fmt;
+
+use crate::syscall;
+
+/// Descriptor `print!` writes to
+pub const STDOUT: u64 = 1;
+
+/// Writes formatted text to [STDOUT]
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match syscall::write(STDOUT, bytes) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(written) => bytes = &bytes[written..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    // There is nowhere to report a failed write to
+    let _ = Stdout.write_fmt(args);
+}
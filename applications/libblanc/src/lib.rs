@@ -0,0 +1,24 @@
+#![no_std]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(alloc_error_handler)]
+
+//! Runtime shared by the programs in `applications`
+//!
+//! A program links against this crate and defines
+//! `#[no_mangle] pub extern "C" fn main(argc: isize, argv: *const *const u8) -> isize`,
+//! the runtime provides `_start`, exits with the status `main` returns, reports panics
+//! and provides a global allocator so the program can use `alloc`
+//!
+//! [syscall] wraps every syscall of the kernel, [print!] and [println!] write to the
+//! terminal
+
+extern crate alloc;
+
+pub mod allocator;
+pub mod io;
+pub mod rt;
+pub mod signal;
+pub mod syscall;
+
+pub use rt::args;
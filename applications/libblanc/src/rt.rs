@@ -0,0 +1,60 @@
+//! Entry point of a program and what it ends with
+//!
+//! `_start` in `start.s` hands the initial stack to [__libblanc_start], which keeps
+//! argc and argv for [args] and calls the `main` of the program. The status `main`
+//! returns is the exit code of the process
+use core::alloc::Layout;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{println, syscall};
+
+global_asm!(include_str!("start.s"));
+
+extern "C" {
+    /// Defined by the program with `#[no_mangle]`
+    fn main(argc: isize, argv: *const *const u8) -> isize;
+}
+
+/// Exit code of a program that panicked
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicUsize = AtomicUsize::new(0);
+
+/// Called from `_start` with the stack pointer the program was started with, which
+/// points at argc followed by the argv pointers
+#[no_mangle]
+unsafe extern "C" fn __libblanc_start(stack: *const u64) -> ! {
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *const *const u8;
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as usize, Ordering::Relaxed);
+
+    let status = main(argc as isize, argv);
+    syscall::exit(status as i32)
+}
+
+/// The arguments the program was started with, the first one is its name. Arguments
+/// that are not valid UTF-8 are empty
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argc = ARGC.load(Ordering::Relaxed);
+    let argv = ARGV.load(Ordering::Relaxed) as *const *const u8;
+
+    (0..argc).map(move |i| unsafe {
+        let arg = *argv.add(i);
+        let length = (0..).find(|&j| *arg.add(j) == 0).unwrap();
+        core::str::from_utf8(core::slice::from_raw_parts(arg, length)).unwrap_or("")
+    })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}", info);
+    syscall::exit(PANIC_EXIT_CODE)
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
@@ -0,0 +1,137 @@
+//! Signal numbers and the actions a process can take for them
+//!
+//! Handlers installed with [SigAction::handler] return through a restorer of the
+//! runtime that makes the `sigreturn` syscall, so they are plain functions taking the
+//! signal number
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+
+/// Take the default action for the signal
+pub const SIG_DFL: u64 = 0;
+/// Discard the signal
+pub const SIG_IGN: u64 = 1;
+
+/// The restorer field of the action is set
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// The signal is not blocked while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// The action is reset to the default once the handler is called
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `sigprocmask` adds the set to the blocked signals
+pub const SIG_BLOCK: u64 = 0;
+/// `sigprocmask` removes the set from the blocked signals
+pub const SIG_UNBLOCK: u64 = 1;
+/// `sigprocmask` replaces the blocked signals with the set
+pub const SIG_SETMASK: u64 = 2;
+
+extern "C" {
+    /// Makes the `sigreturn` syscall, defined in `start.s`
+    fn __libblanc_sigreturn();
+}
+
+/// A set of signals, signal `n` is bit `n - 1`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SignalSet(u64);
+
+impl SignalSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, signal: u32) -> bool {
+        self.0 & bit(signal) != 0
+    }
+
+    pub fn insert(&mut self, signal: u32) {
+        self.0 |= bit(signal);
+    }
+
+    pub fn remove(&mut self, signal: u32) {
+        self.0 &= !bit(signal);
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+const fn bit(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+/// Layout of the `sigaction` structure the kernel expects
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct SigAction {
+    /// [SIG_DFL], [SIG_IGN] or the address of the handler
+    pub handler: u64,
+    pub flags: u64,
+    /// Address the handler returns to, it has to call `sigreturn`
+    pub restorer: u64,
+    /// Signals blocked in addition while the handler runs
+    pub mask: SignalSet,
+}
+
+impl SigAction {
+    /// Take the default action for the signal
+    pub const fn default_action() -> Self {
+        Self {
+            handler: SIG_DFL,
+            flags: 0,
+            restorer: 0,
+            mask: SignalSet::empty(),
+        }
+    }
+
+    /// Discard the signal
+    pub const fn ignore() -> Self {
+        Self {
+            handler: SIG_IGN,
+            flags: 0,
+            restorer: 0,
+            mask: SignalSet::empty(),
+        }
+    }
+
+    /// Call `handler` with the signal number, blocking the signals in `mask` as well
+    /// while it runs
+    pub fn handler(handler: extern "C" fn(u32), mask: SignalSet) -> Self {
+        Self {
+            handler: handler as usize as u64,
+            flags: SA_RESTORER,
+            restorer: __libblanc_sigreturn as usize as u64,
+            mask,
+        }
+    }
+}
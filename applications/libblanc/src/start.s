@@ -0,0 +1,19 @@
+    .text
+    .code64
+
+/* Entered with the stack pointer at argc, followed by argv, envp and the
+   auxiliary vector as laid out by the kernel */
+    .global _start
+_start:
+    xor rbp, rbp
+    mov rdi, rsp
+    call __libblanc_start
+    ud2
+
+/* Signal handlers return here, the stack pointer is left where sigreturn
+   expects the signal frame */
+    .global __libblanc_sigreturn
+__libblanc_sigreturn:
+    mov rax, 11
+    int 0x80
+    ud2
@@ -0,0 +1,580 @@
+//! Typed wrappers for the syscalls of the kernel
+//!
+//! A syscall is made with `int 0x80`, the number goes in rax and up to three
+//! parameters in rdi, rsi and rdx. The kernel leaves every other register as it was and
+//! returns in rax, errors as the negated error number which the wrappers turn into an
+//! [Errno]
+use crate::signal::{SigAction, SignalSet};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+// Syscall numbers
+pub const PRINT: u64 = 0;
+pub const EXIT: u64 = 1;
+pub const EXEC: u64 = 2;
+pub const SPAWN: u64 = 3;
+pub const FORK: u64 = 4;
+pub const THREAD_CREATE: u64 = 5;
+pub const THREAD_EXIT: u64 = 6;
+pub const THREAD_JOIN: u64 = 7;
+pub const KILL: u64 = 8;
+pub const SIGACTION: u64 = 9;
+pub const SIGPROCMASK: u64 = 10;
+pub const SIGRETURN: u64 = 11;
+pub const SETPGID: u64 = 12;
+pub const GETPGID: u64 = 13;
+pub const SETSID: u64 = 14;
+pub const TCSETPGRP: u64 = 15;
+pub const TCGETPGRP: u64 = 16;
+pub const WAITPID: u64 = 17;
+pub const READ: u64 = 18;
+pub const TASK_INFO: u64 = 19;
+pub const LIST_TASKS: u64 = 20;
+pub const BRK: u64 = 21;
+pub const SBRK: u64 = 22;
+pub const MMAP: u64 = 23;
+pub const MUNMAP: u64 = 24;
+pub const MPROTECT: u64 = 25;
+pub const SHM_OPEN: u64 = 26;
+pub const SHM_MAP: u64 = 27;
+pub const SHM_UNMAP: u64 = 28;
+pub const SHM_CLOSE: u64 = 29;
+pub const MEMINFO: u64 = 30;
+pub const GETRLIMIT: u64 = 31;
+pub const SETRLIMIT: u64 = 32;
+
+/// ID of a process, a process group or a session
+pub type Pid = u64;
+/// ID of a thread
+pub type Tid = u64;
+
+/// `waitpid` returns `None` instead of blocking when no child has changed its state
+pub const WNOHANG: u64 = 1;
+/// `waitpid` also reports children that have been stopped
+pub const WUNTRACED: u64 = 2;
+
+/// The pages can not be accessed
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Changes to the mapping are seen by every process that maps it, forked children too
+pub const MAP_SHARED: u64 = 0x01;
+/// Changes to the mapping are private, a forked child gets a copy on write
+pub const MAP_PRIVATE: u64 = 0x02;
+/// Place the mapping exactly at the address, replacing the mappings there
+pub const MAP_FIXED: u64 = 0x10;
+/// The mapping is not backed by a file, the kernel only supports anonymous mappings
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// [shm_open] creates the object if there is none with the name
+pub const SHM_CREATE: u64 = 1;
+/// [shm_open] fails if an object with the name exists, only with [SHM_CREATE]
+pub const SHM_EXCLUSIVE: u64 = 2;
+/// Open the object for writing, without it mappings can only be read
+pub const SHM_WRITE: u64 = 4;
+
+/// Seconds of CPU time used by all threads, SIGXCPU is sent past the soft limit
+pub const RLIMIT_CPU: u32 = 0;
+/// Bytes of the stack of every thread started afterwards
+pub const RLIMIT_STACK: u32 = 3;
+/// Bytes of memory mapped into the process
+pub const RLIMIT_RSS: u32 = 5;
+/// One more than the highest file descriptor that can be opened
+pub const RLIMIT_NOFILE: u32 = 7;
+/// Bytes of address space used
+pub const RLIMIT_AS: u32 = 9;
+
+/// No limit
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Longest task name in a [TaskStat], including the null terminator
+pub const TASK_NAME_LENGTH: usize = 32;
+
+/// An error number returned by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    /// Operation not permitted
+    pub const EPERM: Errno = Errno(1);
+    /// No such file or directory
+    pub const ENOENT: Errno = Errno(2);
+    /// No such process
+    pub const ESRCH: Errno = Errno(3);
+    /// Interrupted by a signal
+    pub const EINTR: Errno = Errno(4);
+    /// Input/output error
+    pub const EIO: Errno = Errno(5);
+    /// Argument list too long
+    pub const E2BIG: Errno = Errno(7);
+    /// Exec format error
+    pub const ENOEXEC: Errno = Errno(8);
+    /// Bad file descriptor
+    pub const EBADF: Errno = Errno(9);
+    /// No child processes
+    pub const ECHILD: Errno = Errno(10);
+    /// Resource temporarily unavailable
+    pub const EAGAIN: Errno = Errno(11);
+    /// Cannot allocate memory
+    pub const ENOMEM: Errno = Errno(12);
+    /// Permission denied
+    pub const EACCES: Errno = Errno(13);
+    /// Bad address
+    pub const EFAULT: Errno = Errno(14);
+    /// File exists
+    pub const EEXIST: Errno = Errno(17);
+    /// Invalid argument
+    pub const EINVAL: Errno = Errno(22);
+    /// The file is not a terminal
+    pub const ENOTTY: Errno = Errno(25);
+    /// Resource deadlock would occur
+    pub const EDEADLK: Errno = Errno(35);
+    /// Function not implemented
+    pub const ENOSYS: Errno = Errno(38);
+}
+
+/// Highest error number, return values in the last page of the address space are errors
+const MAX_ERRNO: u64 = 4095;
+
+/// Make the syscall `number` with three parameters and return rax as it is
+///
+/// # Safety
+/// The parameters must be valid for the syscall, pointers must point to memory of the
+/// size and layout the kernel reads or writes
+#[inline(always)]
+pub unsafe fn syscall(number: u64, param1: u64, param2: u64, param3: u64) -> u64 {
+    let result;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number => result,
+        in("rdi") param1,
+        in("rsi") param2,
+        in("rdx") param3,
+        options(nostack),
+    );
+    result
+}
+
+/// Make the syscall `number` with a fourth parameter passed in r10
+///
+/// # Safety
+/// See [syscall]
+#[inline(always)]
+pub unsafe fn syscall4(number: u64, param1: u64, param2: u64, param3: u64, param4: u64) -> u64 {
+    let result;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number => result,
+        in("rdi") param1,
+        in("rsi") param2,
+        in("rdx") param3,
+        in("r10") param4,
+        options(nostack),
+    );
+    result
+}
+
+/// Split a value returned in rax into a result
+fn check(value: u64) -> Result<u64, Errno> {
+    if value >= MAX_ERRNO.wrapping_neg() {
+        Err(Errno(value.wrapping_neg()))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Copy `string` with a null terminator appended
+fn c_string(string: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(string.len() + 1);
+    bytes.extend_from_slice(string.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// A null terminated array of pointers to the null terminated `strings`, which have to
+/// outlive it
+fn c_string_array(strings: &[Vec<u8>]) -> Vec<u64> {
+    strings
+        .iter()
+        .map(|string| string.as_ptr() as u64)
+        .chain(core::iter::once(0))
+        .collect()
+}
+
+/// Write `buf` to the file `fd`, returns the number of bytes written
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    let written = unsafe { syscall(PRINT, fd, buf.as_ptr() as u64, buf.len() as u64) };
+    check(written).map(|written| written as usize)
+}
+
+/// Read up to `buf.len()` bytes of the next line typed at the terminal on descriptor 0,
+/// blocks until a line is typed. Returns the number of bytes read
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let read = unsafe { syscall(READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    check(read).map(|read| read as usize)
+}
+
+/// End the process with the exit code `status`
+pub fn exit(status: i32) -> ! {
+    unsafe { syscall(EXIT, status as u64, 0, 0) };
+    unreachable!("exit returned")
+}
+
+/// Replace the program of the process with the executable at `path`, only returns if
+/// that fails
+pub fn exec(path: &str, args: &[&str], env: &[&str]) -> Errno {
+    let path = c_string(path);
+    let args: Vec<_> = args.iter().map(|arg| c_string(arg)).collect();
+    let env: Vec<_> = env.iter().map(|var| c_string(var)).collect();
+    let (argv, envp) = (c_string_array(&args), c_string_array(&env));
+
+    let result = unsafe {
+        syscall(
+            EXEC,
+            path.as_ptr() as u64,
+            argv.as_ptr() as u64,
+            envp.as_ptr() as u64,
+        )
+    };
+    match check(result) {
+        Err(errno) => errno,
+        Ok(_) => unreachable!("exec returned"),
+    }
+}
+
+/// Start the executable at `path` as a child process, returns its ID
+pub fn spawn(path: &str, args: &[&str], env: &[&str]) -> Result<Pid, Errno> {
+    let path = c_string(path);
+    let args: Vec<_> = args.iter().map(|arg| c_string(arg)).collect();
+    let env: Vec<_> = env.iter().map(|var| c_string(var)).collect();
+    let (argv, envp) = (c_string_array(&args), c_string_array(&env));
+
+    check(unsafe {
+        syscall(
+            SPAWN,
+            path.as_ptr() as u64,
+            argv.as_ptr() as u64,
+            envp.as_ptr() as u64,
+        )
+    })
+}
+
+/// Duplicate the process, the parent gets the ID of the child and the child 0
+pub fn fork() -> Result<Pid, Errno> {
+    check(unsafe { syscall(FORK, 0, 0, 0) })
+}
+
+/// Start a thread that calls `entry` with `arg`, returns its ID. The entry function
+/// must end the thread with [thread_exit], it has nothing to return to
+pub fn thread_create(entry: extern "C" fn(u64) -> !, arg: u64) -> Result<Tid, Errno> {
+    check(unsafe { syscall(THREAD_CREATE, entry as usize as u64, arg, 0) })
+}
+
+/// End the calling thread, `value` is handed to the thread that joins it
+pub fn thread_exit(value: u64) -> ! {
+    unsafe { syscall(THREAD_EXIT, value, 0, 0) };
+    unreachable!("thread_exit returned")
+}
+
+/// Wait for the thread `thread` of the process to exit and return its exit value
+pub fn thread_join(thread: Tid) -> Result<u64, Errno> {
+    check(unsafe { syscall(THREAD_JOIN, thread, 0, 0) })
+}
+
+/// Send `signal` to the process `pid`, to the callers group for a `pid` of 0 or to the
+/// group -`pid` for a negative one
+pub fn kill(pid: i64, signal: u32) -> Result<(), Errno> {
+    check(unsafe { syscall(KILL, pid as u64, u64::from(signal), 0) }).map(drop)
+}
+
+/// Handle `signal` as `action` says, or leave it as it is for `None`. Returns the
+/// previous action
+pub fn sigaction(signal: u32, action: Option<&SigAction>) -> Result<SigAction, Errno> {
+    let action = action.map_or(0, |action| action as *const SigAction as u64);
+    let mut old = SigAction::default();
+    check(unsafe {
+        syscall(
+            SIGACTION,
+            u64::from(signal),
+            action,
+            &mut old as *mut SigAction as u64,
+        )
+    })?;
+    Ok(old)
+}
+
+/// Change the blocked signals by `set` as selected by `how`, one of
+/// [SIG_BLOCK](crate::signal::SIG_BLOCK), [SIG_UNBLOCK](crate::signal::SIG_UNBLOCK) or
+/// [SIG_SETMASK](crate::signal::SIG_SETMASK). Returns the previously blocked signals
+pub fn sigprocmask(how: u64, set: Option<SignalSet>) -> Result<SignalSet, Errno> {
+    let set = set.as_ref().map_or(0, |set| set as *const SignalSet as u64);
+    let mut old = SignalSet::empty();
+    check(unsafe { syscall(SIGPROCMASK, how, set, &mut old as *mut SignalSet as u64) })?;
+    Ok(old)
+}
+
+/// Move the process `pid` into the group `pgid`, 0 is the caller and a new group
+/// named after the process respectively
+pub fn setpgid(pid: Pid, pgid: Pid) -> Result<(), Errno> {
+    check(unsafe { syscall(SETPGID, pid, pgid, 0) }).map(drop)
+}
+
+/// The process group of the process `pid`, 0 is the caller
+pub fn getpgid(pid: Pid) -> Result<Pid, Errno> {
+    check(unsafe { syscall(GETPGID, pid, 0, 0) })
+}
+
+/// Start a new session led by the caller, returns its ID
+pub fn setsid() -> Result<Pid, Errno> {
+    check(unsafe { syscall(SETSID, 0, 0, 0) })
+}
+
+/// Move the group `pgid` into the foreground of the terminal open as `fd`
+pub fn tcsetpgrp(fd: u64, pgid: Pid) -> Result<(), Errno> {
+    check(unsafe { syscall(TCSETPGRP, fd, pgid, 0) }).map(drop)
+}
+
+/// The foreground group of the terminal open as `fd`
+pub fn tcgetpgrp(fd: u64) -> Result<Pid, Errno> {
+    check(unsafe { syscall(TCGETPGRP, fd, 0, 0) })
+}
+
+/// How a child changed, as reported by [waitpid]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStatus(pub u32);
+
+impl WaitStatus {
+    /// The child exited by itself
+    pub fn exited(self) -> bool {
+        self.0 & 0x7f == 0
+    }
+
+    /// Exit code of a child that [exited](WaitStatus::exited)
+    pub fn exit_code(self) -> u32 {
+        (self.0 >> 8) & 0xff
+    }
+
+    /// The child was ended by a signal
+    pub fn signaled(self) -> bool {
+        !self.exited() && !self.stopped()
+    }
+
+    /// The signal that ended a [signaled](WaitStatus::signaled) child
+    pub fn term_signal(self) -> u32 {
+        self.0 & 0x7f
+    }
+
+    /// The child has been stopped
+    pub fn stopped(self) -> bool {
+        self.0 & 0xff == 0x7f
+    }
+
+    /// The signal that stopped a [stopped](WaitStatus::stopped) child
+    pub fn stop_signal(self) -> u32 {
+        (self.0 >> 8) & 0xff
+    }
+}
+
+/// Wait for a child selected by `pid` to exit, or to stop with [WUNTRACED]. -1 is any
+/// child, 0 any child in the callers group and -pgid any child in the group pgid.
+/// Returns the child and how it changed, or `None` with [WNOHANG] if no child has
+pub fn waitpid(pid: i64, options: u64) -> Result<Option<(Pid, WaitStatus)>, Errno> {
+    let mut status = 0u32;
+    let child =
+        check(unsafe { syscall(WAITPID, pid as u64, &mut status as *mut u32 as u64, options) })?;
+    Ok(match child {
+        0 => None,
+        child => Some((child, WaitStatus(status))),
+    })
+}
+
+/// What the kernel knows about a task, IDs of 0 stand for none
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TaskStat {
+    pub task_id: u64,
+    pub process_id: u64,
+    pub parent: u64,
+    pub process_group: u64,
+    /// 0 new, 1 ready, 2 running, 3 blocked, 4 stopped, 5 finished
+    pub state: u64,
+    /// 0 or 3
+    pub ring: u64,
+    pub cpu_ticks: u64,
+    pub memory: u64,
+    pub start_tick: u64,
+    /// Null terminated, cut off if it is too long
+    pub name: [u8; TASK_NAME_LENGTH],
+}
+
+impl TaskStat {
+    pub const fn empty() -> Self {
+        Self {
+            task_id: 0,
+            process_id: 0,
+            parent: 0,
+            process_group: 0,
+            state: 0,
+            ring: 0,
+            cpu_ticks: 0,
+            memory: 0,
+            start_tick: 0,
+            name: [0; TASK_NAME_LENGTH],
+        }
+    }
+
+    /// The name up to its null terminator
+    pub fn name(&self) -> &str {
+        let length = self.name.iter().position(|&byte| byte == 0);
+        let name = &self.name[..length.unwrap_or(TASK_NAME_LENGTH)];
+        core::str::from_utf8(name).unwrap_or("?")
+    }
+}
+
+/// What the kernel knows about the task `task`, 0 is the calling thread
+pub fn task_info(task: Tid) -> Result<TaskStat, Errno> {
+    let mut stat = TaskStat::empty();
+    check(unsafe { syscall(TASK_INFO, task, &mut stat as *mut TaskStat as u64, 0) })?;
+    Ok(stat)
+}
+
+/// Fill `stats` with the tasks of the kernel ordered by ID. Returns the number of
+/// tasks, which can be more than fit into `stats`, and the current timer tick
+pub fn list_tasks(stats: &mut [TaskStat]) -> Result<(usize, u64), Errno> {
+    let mut now = 0u64;
+    let count = check(unsafe {
+        syscall(
+            LIST_TASKS,
+            stats.as_mut_ptr() as u64,
+            stats.len() as u64,
+            &mut now as *mut u64 as u64,
+        )
+    })?;
+    Ok((count as usize, now))
+}
+
+/// Move the end of the heap to `addr` and return the new end, which is the old one if
+/// the heap can not end there. An `addr` of 0 returns the current end
+pub fn brk(addr: usize) -> usize {
+    unsafe { syscall(BRK, addr as u64, 0, 0) as usize }
+}
+
+/// Grow or shrink the heap by `increment` bytes, returns the previous end of the heap
+pub fn sbrk(increment: isize) -> Result<usize, Errno> {
+    check(unsafe { syscall(SBRK, increment as u64, 0, 0) }).map(|end| end as usize)
+}
+
+/// Map `length` bytes of zeroed memory with the PROT_* flags `protection` and the
+/// MAP_* flags `flags`, which have to include [MAP_ANONYMOUS]. `addr` is a hint unless
+/// [MAP_FIXED] is given. Returns the start of the mapping
+pub fn mmap(addr: usize, length: usize, protection: u64, flags: u64) -> Result<*mut u8, Errno> {
+    check(unsafe { syscall4(MMAP, addr as u64, length as u64, protection, flags) })
+        .map(|start| start as *mut u8)
+}
+
+/// Unmap `length` bytes of mappings from `addr`
+///
+/// # Safety
+/// Nothing may use the memory anymore
+pub unsafe fn munmap(addr: *mut u8, length: usize) -> Result<(), Errno> {
+    check(syscall(MUNMAP, addr as u64, length as u64, 0)).map(drop)
+}
+
+/// Change the protection of `length` bytes of mappings from `addr` to the PROT_* flags
+/// `protection`
+pub fn mprotect(addr: *mut u8, length: usize, protection: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(MPROTECT, addr as u64, length as u64, protection) }).map(drop)
+}
+
+/// Open the shared memory object called `name` with the SHM_* flags `flags`, creating
+/// it with `size` bytes rounded up to whole pages if there is none and [SHM_CREATE] is
+/// given. Returns a handle to it
+pub fn shm_open(name: &str, size: usize, flags: u64) -> Result<u64, Errno> {
+    let name = c_string(name);
+    check(unsafe { syscall(SHM_OPEN, name.as_ptr() as u64, size as u64, flags) })
+}
+
+/// Map the whole shared memory object of `handle` with the PROT_* flags `protection`,
+/// at `addr` if it is free. Returns the start of the mapping
+pub fn shm_map(handle: u64, addr: usize, protection: u64) -> Result<*mut u8, Errno> {
+    check(unsafe { syscall(SHM_MAP, handle, addr as u64, protection) })
+        .map(|start| start as *mut u8)
+}
+
+/// Unmap the mapping of the shared memory object of `handle` at `addr`
+///
+/// # Safety
+/// Nothing may use the memory anymore
+pub unsafe fn shm_unmap(handle: u64, addr: *mut u8) -> Result<(), Errno> {
+    check(syscall(SHM_UNMAP, handle, addr as u64, 0)).map(drop)
+}
+
+/// Close `handle` to a shared memory object, its mappings stay
+pub fn shm_close(handle: u64) -> Result<(), Errno> {
+    check(unsafe { syscall(SHM_CLOSE, handle, 0, 0) }).map(drop)
+}
+
+/// The memory counters of the kernel at one point in time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemInfo {
+    /// Usable frames of RAM
+    pub total_frames: u64,
+    pub free_frames: u64,
+    /// Frames in use, including the page table frames
+    pub used_frames: u64,
+    pub page_table_frames: u64,
+    /// Bytes of the kernel heap that are mapped
+    pub heap_size: u64,
+    /// Bytes allocated from the kernel heap and not freed yet
+    pub heap_used: u64,
+    /// Most bytes allocated from the kernel heap at once since boot
+    pub heap_peak: u64,
+}
+
+/// The memory counters of the kernel and the number of pages mapped for the task
+/// `task`, 0 is the calling thread
+pub fn meminfo(task: Tid) -> Result<(MemInfo, u64), Errno> {
+    let mut info = MemInfo::default();
+    let pages = check(unsafe { syscall(MEMINFO, &mut info as *mut MemInfo as u64, task, 0) })?;
+    Ok((info, pages))
+}
+
+/// A soft limit that is enforced and a hard limit it can be raised up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RLimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+/// The limits of the RLIMIT_* `resource` of the process
+pub fn getrlimit(resource: u32) -> Result<RLimit, Errno> {
+    let mut limit = RLimit { cur: 0, max: 0 };
+    check(unsafe {
+        syscall(
+            GETRLIMIT,
+            u64::from(resource),
+            &mut limit as *mut RLimit as u64,
+            0,
+        )
+    })?;
+    Ok(limit)
+}
+
+/// Change the limits of the RLIMIT_* `resource` of the process to `limit`, only a
+/// process running as root can raise its hard limit
+pub fn setrlimit(resource: u32, limit: &RLimit) -> Result<(), Errno> {
+    check(unsafe {
+        syscall(
+            SETRLIMIT,
+            u64::from(resource),
+            limit as *const RLimit as u64,
+            0,
+        )
+    })
+    .map(drop)
+}
@@ -1,24 +1,227 @@
 #![no_main]
 #![no_std]
-#![feature(lang_items)]
-#![feature(global_asm)]
-#![allow(dead_code)]
 
-use core::panic::PanicInfo;
+//! A small job control shell. Every command line starts one program in a process
+//! group of its own, which is moved into the foreground of the terminal unless the
+//! line ends with `&`. Ctrl+Z stops the foreground job and hands the terminal back
+//! to the shell, the builtins `jobs`, `fg` and `bg` list, continue in the foreground
+//! and continue in the background the jobs the shell knows about
 
-global_asm!(include_str!("syscall_interrupts.s"));
+extern crate alloc;
 
+use alloc::{format, string::String, vec::Vec};
 
-#[no_mangle] // don't mangle the name of this function
-pub extern "C" fn _start() -> ! {
-    loop {}
+use libblanc::signal::{SigAction, SIGCONT, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU};
+use libblanc::syscall::{self, Errno, Pid, WaitStatus, WNOHANG, WUNTRACED};
+use libblanc::{print, println};
+
+const MAX_JOBS: usize = 8;
+const LINE_LENGTH: usize = 128;
+
+struct Job {
+    /// The job is a single process leading its own group, so this is its group too
+    pid: Pid,
+    stopped: bool,
+    name: String,
+}
+
+struct Shell {
+    jobs: [Option<Job>; MAX_JOBS],
+    /// Group of the shell, it takes the terminal back when a foreground job is done
+    group: Pid,
 }
 
-extern "C" {
-    fn syscall(call_num : u64, param1 : u64, param2 : u64, param3: u64) -> u64;
+#[no_mangle]
+pub extern "C" fn main(_argc: isize, _argv: *const *const u8) -> isize {
+    // Signals typed at the terminal are meant for the jobs, not the shell
+    for signal in [SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU] {
+        syscall::sigaction(signal, Some(&SigAction::ignore())).unwrap();
+    }
+
+    let mut shell = Shell {
+        jobs: Default::default(),
+        group: syscall::getpgid(0).unwrap(),
+    };
+
+    let mut line = [0u8; LINE_LENGTH];
+    loop {
+        shell.report_done();
+        print!("$ ");
+
+        let length = match syscall::read(0, &mut line) {
+            Ok(length) => length,
+            Err(_) => continue,
+        };
+        match core::str::from_utf8(&line[..length]) {
+            Ok(line) => shell.run_line(line),
+            Err(_) => println!("invalid input"),
+        }
+    }
+}
+
+impl Shell {
+    fn run_line(&mut self, line: &str) {
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+
+        let background = words.last() == Some(&"&");
+        if background {
+            words.pop();
+        }
+        if words.is_empty() {
+            return;
+        }
+
+        match words[0] {
+            "jobs" => self.list_jobs(),
+            "fg" => match self.select_job(&words[1..]) {
+                Some(index) => self.continue_job(index, true),
+                None => println!("fg: no such job"),
+            },
+            "bg" => match self.select_job(&words[1..]) {
+                Some(index) => self.continue_job(index, false),
+                None => println!("bg: no such job"),
+            },
+            _ => self.start_job(&words, background),
+        }
+    }
+
+    /// Start the program named by `words[0]` with `words` as its arguments
+    fn start_job(&mut self, words: &[&str], background: bool) {
+        let index = match self.jobs.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                println!("too many jobs");
+                return;
+            }
+        };
+
+        let path = if words[0].starts_with('/') {
+            String::from(words[0])
+        } else {
+            format!("/bin/{}", words[0])
+        };
+        let pid = match syscall::spawn(&path, words, &[]) {
+            Ok(pid) => pid,
+            Err(_) => {
+                println!("{}: command not found", words[0]);
+                return;
+            }
+        };
+        // The child may have exited already, then there is no group to move
+        let _ = syscall::setpgid(pid, pid);
+
+        self.jobs[index] = Some(Job {
+            pid,
+            stopped: false,
+            name: String::from(words[0]),
+        });
+
+        if background {
+            print_job(index, "", self.jobs[index].as_ref().unwrap());
+        } else {
+            self.wait_foreground(index);
+        }
+    }
+
+    /// Send SIGCONT to a job, in the foreground it gets the terminal and is waited for
+    fn continue_job(&mut self, index: usize, foreground: bool) {
+        let job = match self.jobs[index].as_mut() {
+            Some(job) => job,
+            None => return,
+        };
+        job.stopped = false;
+        let pid = job.pid;
+
+        if foreground {
+            let _ = syscall::tcsetpgrp(0, pid);
+            let _ = syscall::kill(-(pid as i64), SIGCONT);
+            self.wait_foreground(index);
+        } else {
+            let _ = syscall::kill(-(pid as i64), SIGCONT);
+            print_job(index, "", self.jobs[index].as_ref().unwrap());
+        }
+    }
+
+    /// Give the terminal to a job and wait until it exits or stops
+    fn wait_foreground(&mut self, index: usize) {
+        let pid = self.jobs[index].as_ref().unwrap().pid;
+        let _ = syscall::tcsetpgrp(0, pid);
+
+        loop {
+            match syscall::waitpid(pid as i64, WUNTRACED) {
+                Err(Errno::EINTR) => continue,
+                Ok(Some((_, status))) if status.stopped() => {
+                    let job = self.jobs[index].as_mut().unwrap();
+                    job.stopped = true;
+                    print_job(index, "Stopped", job);
+                }
+                _ => self.jobs[index] = None,
+            }
+            break;
+        }
+
+        let _ = syscall::tcsetpgrp(0, self.group);
+    }
+
+    /// Collect the background jobs that changed without blocking and tell about the
+    /// ones that are done
+    fn report_done(&mut self) {
+        while let Ok(Some((pid, status))) = syscall::waitpid(-1, WNOHANG | WUNTRACED) {
+            let index = match self.find_job(pid) {
+                Some(index) => index,
+                None => continue,
+            };
+            self.update_job(index, status);
+        }
+    }
+
+    fn update_job(&mut self, index: usize, status: WaitStatus) {
+        if status.stopped() {
+            let job = self.jobs[index].as_mut().unwrap();
+            job.stopped = true;
+            print_job(index, "Stopped", job);
+        } else {
+            print_job(index, "Done", self.jobs[index].as_ref().unwrap());
+            self.jobs[index] = None;
+        }
+    }
+
+    fn list_jobs(&mut self) {
+        self.report_done();
+        for (index, job) in self.jobs.iter().enumerate() {
+            if let Some(job) = job {
+                let state = if job.stopped { "Stopped" } else { "Running" };
+                print_job(index, state, job);
+            }
+        }
+    }
+
+    /// The job named by `%n` or `n`, or the most recent one without an argument
+    fn select_job(&self, args: &[&str]) -> Option<usize> {
+        match args.first() {
+            None => (0..MAX_JOBS)
+                .rev()
+                .find(|&index| self.jobs[index].is_some()),
+            Some(arg) => {
+                let number: usize = arg.strip_prefix('%').unwrap_or(*arg).parse().ok()?;
+                let index = number.checked_sub(1)?;
+                self.jobs.get(index)?.as_ref().map(|_| index)
+            }
+        }
+    }
+
+    fn find_job(&self, pid: Pid) -> Option<usize> {
+        self.jobs
+            .iter()
+            .position(|job| job.as_ref().map_or(false, |job| job.pid == pid))
+    }
 }
 
-#[panic_handler]
-fn panic(_panic: &PanicInfo<'_>) -> ! {
-    loop {}
-}
\ No newline at end of file
+/// Print a job as `[n] state name`, a job started in the background shows its pid
+fn print_job(index: usize, state: &str, job: &Job) {
+    if state.is_empty() {
+        println!("[{}] {} {}", index + 1, job.pid, job.name);
+    } else {
+        println!("[{}] {} {}", index + 1, state, job.name);
+    }
+}
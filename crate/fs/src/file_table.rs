@@ -42,18 +42,45 @@ impl FileHandle {
 
 type LockedFileHandle = RwLock<Vec<Option<Arc<FileHandle>>>>;
 
-pub struct FileTable(LockedFileHandle);
+/// Number of file descriptors a table can hand out until its limit is changed
+pub const DEFAULT_LIMIT: usize = 256;
+
+pub struct FileTable {
+    files: LockedFileHandle,
+    /// File descriptors are below this value
+    limit: AtomicUsize,
+}
 
 impl FileTable {
     pub fn new() -> Self {
-        let mut table = Vec::new();
-        table.resize(256, None);
+        Self::with_limit(DEFAULT_LIMIT)
+    }
+
+    /// Create an empty table handing out file descriptors below `limit`
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            files: RwLock::new(Vec::new()),
+            limit: AtomicUsize::new(limit),
+        }
+    }
 
-        Self(RwLock::new(table))
+    /// Change the value all new file descriptors are below, files that are already
+    /// open keep their descriptors
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::SeqCst);
+    }
+
+    /// Create a copy of the table for a forked task, both tables refer to the
+    /// same open file handles so they also share the file offsets
+    pub fn fork(&self) -> Self {
+        Self {
+            files: RwLock::new(self.files.read().clone()),
+            limit: AtomicUsize::new(self.limit.load(Ordering::SeqCst)),
+        }
     }
 
     pub fn get_handle(&self, fd: usize) -> Option<Arc<FileHandle>> {
-        let files = self.0.read();
+        let files = self.files.read();
         match &files.get(fd) {
             Some(Some(handle)) => Some(handle.clone()),
             _ => None,
@@ -61,17 +88,22 @@ impl FileTable {
     }
 
     pub fn open_file(&self, node : Arc<dyn INode>, flags: OFlags) -> Result<usize, FileSystemError> {
-        let mut files = self.0.write();
-
-        if let Some((i, f)) = files.iter_mut().enumerate().find(|e| e.1.is_none()) {
-            let handle = Arc::new(FileHandle::new(i, node, flags));
-
-            handle.inode.open(i.to_string().as_str(), flags)?;
-            *f = Some(handle);
-            return Ok(i)
-        } 
-
-        Err(FileSystemError::Busy)
+        let mut files = self.files.write();
+        let limit = self.limit.load(Ordering::SeqCst);
+
+        let i = match files.iter().take(limit).position(Option::is_none) {
+            Some(i) => i,
+            None if files.len() < limit => {
+                files.push(None);
+                files.len() - 1
+            }
+            None => return Err(FileSystemError::Busy),
+        };
+
+        let handle = Arc::new(FileHandle::new(i, node, flags));
+        handle.inode.open(i.to_string().as_str(), flags)?;
+        files[i] = Some(handle);
+        Ok(i)
     }
 }
 
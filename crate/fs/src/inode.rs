@@ -38,6 +38,9 @@ pub trait INode: Send + Sync {
     /// Finds a directory in the filesystem
     fn find_dir(&self, name :&str) -> Result<Arc<dyn INode>, FileSystemError>;
 
+    /// Finds any entry (file or directory) inside this directory
+    fn find(&self, name : &str) -> Result<Arc<dyn INode>, FileSystemError>;
+
     /// Return the filesystem type that this inode belongs too
     fn filesystem(&self) -> Weak<dyn FileSystem>; 
 
@@ -2,6 +2,57 @@
 #![feature(once_cell)]
 #![feature(map_try_insert)]
 
+extern crate alloc;
+
 pub mod inode;
 pub mod ramdisk;
-pub mod file_table;
\ No newline at end of file
+pub mod file_table;
+
+use alloc::{sync::Arc, vec::Vec};
+use inode::{FileSystemError, FileTypeFlags, INode, OFlags};
+use ramdisk::RAMFS;
+
+/// Resolve an absolute path, such as `/lib/libblanc.so`, starting at the root
+/// of the ram filesystem
+pub fn lookup(path: &str) -> Result<Arc<dyn INode>, FileSystemError> {
+    let mut node: Arc<dyn INode> = RAMFS.root_inode.clone();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        node = node.find(component)?;
+    }
+    Ok(node)
+}
+
+/// Read the entire contents of the file found at `path`
+pub fn read_file(path: &str) -> Result<Vec<u8>, FileSystemError> {
+    let node = lookup(path)?;
+    let metadata = node.metadata()?;
+    if *metadata.file_type() != FileTypeFlags::FILE {
+        return Err(FileSystemError::EntryNotFound);
+    }
+
+    let mut buffer = Vec::new();
+    buffer.resize(*metadata.size(), 0);
+    node.pread(0, buffer.len(), &mut buffer);
+    Ok(buffer)
+}
+
+/// Write `contents` to the start of the file at `path`, the file and any missing
+/// parent directories are created along the way
+pub fn write_file(path: &str, contents: &[u8]) -> Result<(), FileSystemError> {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    let mut dir: Arc<dyn INode> = RAMFS.root_inode.clone();
+    for component in parent.split('/').filter(|c| !c.is_empty()) {
+        dir = match dir.find_dir(component) {
+            Ok(child) => child,
+            Err(_) => {
+                dir.mkdir(component)?;
+                dir.find_dir(component)?
+            }
+        };
+    }
+
+    dir.open(name, OFlags::O_CREAT | OFlags::O_WRONLY)?;
+    dir.find(name)?.pwrite(0, contents.len(), contents);
+    Ok(())
+}
@@ -104,8 +104,10 @@ impl INode for LockedRamINode {
                 let content = mut_cont.lock();
                 
                 for index in 0..count as usize {
-                    let y = content.get(offset as usize).unwrap();
-                    buffer[index as usize] = *y; 
+                    match content.get(offset + index) {
+                        Some(y) => buffer[index] = *y,
+                        None => return,
+                    }
                 }
             },
             FileContents::None => unreachable!("File Type is File but doesn't have content"),
@@ -125,10 +127,13 @@ impl INode for LockedRamINode {
         match &this.contents {
             FileContents::Content(mut_cont) => {
                 let mut content = mut_cont.lock();
+
+                if content.len() < offset + count {
+                    content.resize(offset + count, 0);
+                }
                 
                 for index in 0..count as usize {
-                    let y = content.get_mut(offset as usize).unwrap();
-                    *y = buffer[index as usize];
+                    content[offset + index] = buffer[index];
                 }
             }
             FileContents::None => todo!(),
@@ -191,6 +196,13 @@ impl INode for LockedRamINode {
         Ok(child.clone())
     }
 
+    /// Find and return any child entry inside this directory
+    fn find(&self, name : &str) -> Result<Arc<dyn INode>, FileSystemError> {
+        let this = self.0.read();
+        let child = this.children.get(name).ok_or(FileSystemError::EntryNotFound)?;
+        Ok(child.clone())
+    }
+
     fn filesystem(&self) -> Weak<dyn FileSystem> {
         self.0.read().filesystem.clone()
     }
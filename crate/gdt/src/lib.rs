@@ -10,6 +10,11 @@ use lazy_static::lazy_static;
 /// Index in the interrupt stack table in the TSS for a double fault
 pub const DOUBLE_FAULT_INDEX: u16 = 0;
 
+/// Index in the interrupt stack table in the TSS for the syscall and timer
+/// interrupts, both save and replace the interrupted tasks registers so they
+/// always run on a kernel stack no matter which task was interrupted
+pub const CONTEXT_SWITCH_INDEX: u16 = 1;
+
 lazy_static! {
     /// Global Static Reference to the kernels task state segment
     ///
@@ -31,6 +36,23 @@ lazy_static! {
             // Assign the table entry to point to the end of the stack
             stack_start + STACK_SIZE
         };
+        tss.interrupt_stack_table[CONTEXT_SWITCH_INDEX as usize] = {
+            // Stack size = 32kb
+            const STACK_SIZE: usize = 4096 * 8;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
+        // Stack loaded when an interrupt moves from ring 3 to ring 0
+        tss.privilege_stack_table[0] = {
+            // Stack size = 32kb
+            const STACK_SIZE: usize = 4096 * 8;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
         Spinlock::new(tss)
     });
 
@@ -0,0 +1,89 @@
+    .text
+    .code64
+
+/* Push the general purpose registers below the interrupt frame so the
+   stack matches the layout of `task::task::Context` */
+.macro SAVE_CONTEXT
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+.endm
+
+.macro RESTORE_CONTEXT
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+.endm
+
+    .global syscall_entry
+syscall_entry:
+    SAVE_CONTEXT
+    mov rdi, rsp
+    cld
+    call syscall_dispatch
+    RESTORE_CONTEXT
+    iretq
+
+    .global timer_entry
+timer_entry:
+    SAVE_CONTEXT
+    mov rdi, rsp
+    cld
+    call timer_dispatch
+    RESTORE_CONTEXT
+    iretq
+
+/* Faults that can be raised by user code. The CPU pushes an error code below the
+   interrupt frame for some of them, a zero is pushed for the others. The error code
+   is taken out from between the saved registers and the frame so they match the
+   layout of `task::task::Context`, it is passed to `fault_dispatch` with the vector */
+.macro FAULT_ENTRY name, vector, has_error_code
+    .global \name
+\name:
+.if \has_error_code == 0
+    push 0
+.endif
+    SAVE_CONTEXT
+    mov rsi, \vector
+    mov rdx, [rsp + 15 * 8]
+    mov rcx, 15
+1:
+    mov rax, [rsp + rcx * 8 - 8]
+    mov [rsp + rcx * 8], rax
+    loop 1b
+    add rsp, 8
+    mov rdi, rsp
+    cld
+    call fault_dispatch
+    RESTORE_CONTEXT
+    iretq
+.endm
+
+    FAULT_ENTRY divide_error_entry, 0, 0
+    FAULT_ENTRY invalid_opcode_entry, 6, 0
+    FAULT_ENTRY general_protection_entry, 13, 1
+    FAULT_ENTRY page_fault_entry, 14, 1
@@ -10,18 +10,24 @@ pub fn init_idt() {
     IDT.load();
 }
 
-use coop::keyboard;
 use coop::mouse;
 use lazy_static::lazy_static;
-use memory::active_level_4_table;
-use memory::swap_to_kernel_table;
+use memory::{allocator, phys, swap_to_kernel_table};
 use os_units::NumOfPages;
 use printer::{print, println};
 use serial::serial_println;
 use task::scheduler::Scheduler;
+use task::signal::{self, SIGFPE, SIGILL, SIGSEGV};
+use task::task::Context;
+use task::table;
+use task::terminal;
 use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
+pub mod monitor;
+pub mod oom;
 pub mod syscall;
+mod user;
 
 lazy_static! {
     ///Static Interrupt Descriptor Table with all of the registered interrupt types and their handler functions
@@ -35,7 +41,6 @@ lazy_static! {
         idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         idt.alignment_check.set_handler_fn(alignment_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_op_handler);
         idt.invalid_tss.set_handler_fn(invalid_tss_handler);
         idt.stack_segment_fault.set_handler_fn(stack_segment_handler);
         idt.security_exception.set_handler_fn(security_exception_handler);
@@ -43,14 +48,27 @@ lazy_static! {
             idt[i as usize].set_handler_fn(tmp_handler);
         }
 
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(timer_entry as usize as u64))
+                .set_stack_index(gdt::CONTEXT_SWITCH_INDEX);
+        }
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::PrimATA.as_usize()].set_handler_fn(ata_interrupt_handler);
         idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
-        idt[0x80].set_handler_fn(syscall);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_handler);
+        unsafe {
+            idt[0x80]
+                .set_handler_addr(VirtAddr::new(syscall_entry as usize as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3)
+                .set_stack_index(gdt::CONTEXT_SWITCH_INDEX);
+        }
+        unsafe {
+            idt.divide_error.set_handler_addr(VirtAddr::new(divide_error_entry as usize as u64));
+            idt.invalid_opcode.set_handler_addr(VirtAddr::new(invalid_opcode_entry as usize as u64));
+            idt.general_protection_fault
+                .set_handler_addr(VirtAddr::new(general_protection_entry as usize as u64));
+            idt.page_fault.set_handler_addr(VirtAddr::new(page_fault_entry as usize as u64));
+        }
         idt.virtualization.set_handler_fn(virtualization_handler);
 
 
@@ -58,25 +76,21 @@ lazy_static! {
     };
 }
 
-extern "x86-interrupt" fn syscall(_: InterruptStackFrame) {
-    //     unsafe { syscall(4, 5, 6 ,7)};
+global_asm!(include_str!("context_switch.s"));
 
-    let call_num: u64;
-    let param1: u64;
-    let param2: u64;
-    let param3: u64;
-    unsafe {
-        asm!("mov {}, rax", out(reg) call_num);
-        asm!("mov {}, rdi", out(reg) param1);
-        asm!("mov {}, rsi", out(reg) param2);
-        asm!("mov {}, rdx", out(reg) param3);
-    }
-    if (call_num as usize) < SYSTEM_CALLS.len() {
-        SYSTEM_CALLS[call_num as usize](param1, param2, param3);
-    }
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(0x80);
-    }
+extern "C" {
+    /// Saves the interrupted registers as a [Context] and calls [syscall_dispatch]
+    fn syscall_entry();
+
+    /// Saves the interrupted registers as a [Context] and calls [timer_dispatch]
+    fn timer_entry();
+
+    /// Fault stubs, each saves the interrupted registers as a [Context] and calls
+    /// [fault_dispatch] with its vector and error code
+    fn divide_error_entry();
+    fn invalid_opcode_entry();
+    fn general_protection_entry();
+    fn page_fault_entry();
 }
 
 use pic8259::ChainedPics;
@@ -96,21 +110,6 @@ pub static READY: spin::Mutex<bool> = spin::Mutex::new(false);
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::SelectorErrorCode;
 
-extern "x86-interrupt" fn general_protection_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    panic!(
-        "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nERROR CODE : {:#?}",
-        stack_frame,
-        SelectorErrorCode::new(error_code)
-    );
-}
-
-extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
-}
-
 extern "x86-interrupt" fn security_exception_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
@@ -138,10 +137,6 @@ extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame,
     );
 }
 
-extern "x86-interrupt" fn invalid_op_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: INVALID OPCODE\n{:#?}\n", stack_frame);
-}
-
 extern "x86-interrupt" fn alignment_handler(stack_frame: InterruptStackFrame, error_code: u64) {
     panic!(
         "EXCEPTION: OUT OF ALIGNMENT\n{:#?}\nERROR CODE : {:#?}",
@@ -205,12 +200,12 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
-///Reads the key code from 0x60 port and adds that to the keyboard task handler
+///Reads the key code from 0x60 port and hands it to the terminal
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    keyboard::add_scancode(scancode);
+    terminal::receive_scancode(scancode);
 
     unsafe {
         PICS.lock()
@@ -220,25 +215,75 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::Mapper;
 use x86_64::structures::paging::Page;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::structures::paging::RecursivePageTable;
 use x86_64::structures::paging::Size4KiB;
 
-use crate::syscall::SYSTEM_CALLS;
+/// Vectors of the faults that are routed through the `FAULT_ENTRY` stubs
+const DIVIDE_ERROR: u64 = 0;
+const INVALID_OPCODE: u64 = 6;
+const GENERAL_PROTECTION: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+
+///Called from the fault stubs with the registers of the faulting code
+///
+///Page faults the kernel can resolve return to the faulting instruction. Any other
+///fault in ring 3 sends the matching signal to the faulting thread, in ring 0 it panics
+///
+///A page fault in ring 3 there is no memory left for kills the largest process, see
+///[oom], and gives up the CPU before the faulting instruction is tried again
+#[no_mangle]
+extern "C" fn fault_dispatch(context: &mut Context, vector: u64, error_code: u64) {
+    if vector == PAGE_FAULT {
+        match handle_page_fault(PageFaultErrorCode::from_bits_truncate(error_code)) {
+            Ok(true) => return,
+            Err(_) if context.is_user() => {
+                oom::kill_largest(&mut Scheduler::get_scheduler());
+                Scheduler::schedule(context);
+                signal::deliver(context);
+                return;
+            }
+            _ => {}
+        }
+    }
 
-///Page fault handler prints out the respective errors and stack frame and halts cpu execution
-extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
-) {
+    let (name, signal) = match vector {
+        DIVIDE_ERROR => ("DIVIDE ERROR", SIGFPE),
+        INVALID_OPCODE => ("INVALID OPCODE", SIGILL),
+        GENERAL_PROTECTION => ("GENERAL PROTECTION FAULT", SIGSEGV),
+        _ => ("PAGE FAULT", SIGSEGV),
+    };
+
+    if !context.is_user() {
+        panic!(
+            "EXCEPTION: {}\nAccessed Address: {:?}\n{:#x?}\nERROR CODE : {:#x}",
+            name,
+            Cr2::read(),
+            context,
+            error_code
+        );
+    }
+
+    Scheduler::get_scheduler().force_signal(signal);
+    signal::deliver(context);
+}
+
+///Resolves page faults on copy on write pages and maps pages the kernel writes
+///to or fetches from lazily, returns false if the fault could not be resolved and
+///an error if there was no memory left to resolve it
+fn handle_page_fault(error_code: PageFaultErrorCode) -> Result<bool, MapToError<Size4KiB>> {
     let acc_addr = Cr2::read();
-    // println!("EXCEPTION: PAGE FAULT");
-    // println!("Accessed Address: {:?}", acc_addr);
-    // println!("Error Code: {:?}", _error_code);
-    // println!("{:#?}", _stack_frame);
-    match _error_code {
+
+    // Writes to pages shared after a fork get their own copy of the frame
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(cow_fault) && memory::virt::handle_copy_on_write(acc_addr)? {
+        return Ok(true);
+    }
+
+    match error_code {
         PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::CAUSED_BY_WRITE => {
             let mut rpt = RecursivePageTable::new(memory::active_level_4_table()).unwrap();
             let page = Page::<Size4KiB>::containing_address(acc_addr);
@@ -247,42 +292,44 @@ extern "x86-interrupt" fn page_fault_handler(
                 match rpt.update_flags(page, flags) {
                     Ok(_) => (),
                     Err(x86_64::structures::paging::mapper::FlagUpdateError::PageNotMapped) => {
-                        memory::virt::allocate_pages(acc_addr, NumOfPages::<Size4KiB>::new(1))
+                        memory::virt::allocate_pages(acc_addr, NumOfPages::<Size4KiB>::new(1))?
                     }
                     Err(e) => panic!("{:#?}", e),
                 }
                 PICS.lock().notify_end_of_interrupt(0xE);
             }
+            Ok(true)
         }
 
-        _ => loop {
-            x86_64::instructions::hlt();
-        },
+        _ => Ok(false),
     }
 }
 
 ///Used for task time slices
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    println!("*");
+///
+///Called from `timer_entry` with the registers of the interrupted code, the
+///scheduler replaces them with the registers of the next task to run
+///
+///A kernel thread interrupted while it holds the heap or the frame allocator keeps
+///running, the scheduler needs both and the lock would never be released
+#[no_mangle]
+extern "C" fn timer_dispatch(context: &mut Context) {
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    table::tick();
+    monitor::poll();
 
+    if allocator::is_locked() || phys::is_locked() {
+        return;
+    }
     if *READY.lock() {
-        x86_64::instructions::interrupts::disable();
-        let mut rpt = RecursivePageTable::new(active_level_4_table()).unwrap();
-        for (i, entry) in rpt.level_4_table().iter().enumerate() {
-            if !entry.is_unused() {
-                println!("{} {:#?}", i, entry);
-            }
-        }
-
-        println!("Disabled int {:p}", &IDT);
-        Scheduler::run();
+        terminal::dispatch();
+        Scheduler::get_scheduler().charge_tick();
+        Scheduler::schedule(context);
+        signal::deliver(context);
     }
-
-    // println!("ending timer interrupt");
 }
 
 ///Double fault interrupt panics and prints the stack frame
@@ -0,0 +1,69 @@
+//! Single letter commands read from the serial interface on every timer tick, to look
+//! into a running kernel from the host, for example to spot leaks in a CI boot
+//!
+//! `m` prints the memory counters, the pages mapped for every task and the frames owned
+//! by its process. The `heap-debug` feature adds the heap commands of
+//! [debug](memory::allocator::debug)
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use memory::{allocator, meminfo};
+use serial::serial_println;
+use task::table;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// A command that could not run yet because the interrupted code held a lock it needs,
+/// 0 for none
+static PENDING: AtomicU8 = AtomicU8::new(0);
+
+/// Read a command from the serial interface and run it, called from the timer
+pub fn poll() {
+    let command = serial::try_receive();
+    allocator::poll(command);
+
+    let command = command.unwrap_or_else(|| PENDING.swap(0, Ordering::AcqRel));
+    if command == b'm' && !print_memory() {
+        PENDING.store(command, Ordering::Release);
+    }
+}
+
+/// Print the memory counters, the pages mapped for each task and the frames owned by its
+/// process, returns false without printing anything while the heap or the frame
+/// allocator is in use. Interrupts are off, so neither can be taken by anyone else until
+/// this returns
+fn print_memory() -> bool {
+    if allocator::is_locked() {
+        return false;
+    }
+    let info = match meminfo::try_meminfo() {
+        Some(info) => info,
+        None => return false,
+    };
+    let tasks = table::list();
+    let mut owners: Vec<u32> = tasks
+        .iter()
+        .map(|task| task.process_id.get_id() as u32)
+        .collect();
+    owners.sort_unstable();
+    owners.dedup();
+    let owned = match meminfo::try_owned_frames(&owners) {
+        Some(owned) => owned,
+        None => return false,
+    };
+
+    serial_println!("{}", info);
+    for task in tasks {
+        let process = task.process_id.get_id() as u32;
+        let frames = owners.binary_search(&process).map_or(0, |i| owned[i]);
+        serial_println!(
+            "{} {}: {} pages, {} frames owned by its process",
+            task.task_id.get_id(),
+            task.name,
+            task.memory as u64 / Size4KiB::SIZE,
+            frames
+        );
+    }
+    true
+}
@@ -0,0 +1,52 @@
+//! What happens when memory runs out
+//!
+//! Memory of processes is never taken from the last
+//! [EMERGENCY_FRAMES](memory::phys::EMERGENCY_FRAMES) frames, so the kernel can keep
+//! allocating after processes can not. A syscall that runs out returns ENOMEM, a page
+//! fault has no caller to hand an error to. It kills the user process with the most
+//! memory mapped instead, which is logged on the serial interface, and the faulting
+//! access is tried again after the other tasks had their turn
+use memory::phys::FRAME_ALLOCATOR;
+use serial::serial_println;
+use spin::Mutex;
+use task::process::Process;
+use task::scheduler::Scheduler;
+use task::signal::SIGKILL;
+
+extern crate alloc;
+use alloc::sync::{Arc, Weak};
+
+/// The process killed last, its memory is only freed once its threads are reaped
+static VICTIM: Mutex<Option<Weak<Process>>> = Mutex::new(None);
+
+/// Kill the user process with the most memory mapped, returns false if there is none.
+/// Nothing is killed while the process killed before still holds its memory, its
+/// threads may simply not have been reaped yet
+pub fn kill_largest(scheduler: &mut Scheduler) -> bool {
+    let mut victim = VICTIM.lock();
+    if victim.as_ref().and_then(Weak::upgrade).is_some() {
+        return false;
+    }
+
+    let largest = Process::all()
+        .into_iter()
+        .map(|process| (process.memory_footprint(), process))
+        .max_by_key(|(footprint, _)| *footprint);
+    let (footprint, process) = match largest {
+        Some(largest) => largest,
+        None => return false,
+    };
+
+    let free = FRAME_ALLOCATOR.wait().unwrap().inner.lock().free_frames();
+    serial_println!(
+        "oom: killed process {} ({}) with {} KiB mapped, {} frames free",
+        process.process_id().get_id(),
+        process.name,
+        footprint / 1024,
+        free
+    );
+    *victim = Some(Arc::downgrade(&process));
+    scheduler
+        .signal_process(process.process_id(), SIGKILL)
+        .is_ok()
+}
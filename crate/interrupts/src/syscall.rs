@@ -1,6 +1,29 @@
-use core::slice;
+use crate::user;
+use core::{convert::TryFrom, mem, slice};
 
+use memory::meminfo::{self, MemInfo};
 use printer::{print, println};
+use task::dynamic::LinkError;
+use task::exec::ExecError;
+use task::job::{self, JobError, WNOHANG};
+use task::process::Process;
+use task::region::{self, RegionError};
+use task::rlimit::{LimitError, RLimit};
+use task::scheduler::{JoinError, Scheduler, SignalError};
+use task::shm::{self, ShmError};
+use task::signal::{
+    self, SigAction, SignalSet, SIGSEGV, SIGTTIN, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, UNBLOCKABLE,
+};
+use task::table::{self, TaskStat};
+use task::task::{Context, TaskID, TaskState, WaitReason};
+use task::terminal::{self, ReadResult};
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    VirtAddr,
+};
+
+extern crate alloc;
+use alloc::{string::String, sync::Arc, vec::Vec};
 
 global_asm!(include_str!("syscall_interrupts.s"));
 
@@ -8,45 +31,755 @@ extern "C" {
     pub fn syscall(call_num: u64, param1: u64, param2: u64, param3: u64) -> u64;
 }
 
-pub(crate) static SYSTEM_CALLS: [fn(param1: u64, param2: u64, param3: u64); 2] = [
+/// A syscall handler, called with the saved registers of the caller and the
+/// parameters passed in rdi, rsi and rdx
+type SystemCall = fn(&mut Context, u64, u64, u64) -> Result<u64, SyscallError>;
+
+pub(crate) static SYSTEM_CALLS: [SystemCall; 33] = [
     // Syscall 0
-    print, // Syscall 1
-    exit,
+    print,         // Syscall 1
+    exit,          // Syscall 2
+    exec,          // Syscall 3
+    spawn,         // Syscall 4
+    fork,          // Syscall 5
+    thread_create, // Syscall 6
+    thread_exit,   // Syscall 7
+    thread_join,   // Syscall 8
+    kill,          // Syscall 9
+    sigaction,     // Syscall 10
+    sigprocmask,   // Syscall 11
+    sigreturn,     // Syscall 12
+    setpgid,       // Syscall 13
+    getpgid,       // Syscall 14
+    setsid,        // Syscall 15
+    tcsetpgrp,     // Syscall 16
+    tcgetpgrp,     // Syscall 17
+    waitpid,       // Syscall 18
+    read,          // Syscall 19
+    task_info,     // Syscall 20
+    list_tasks,    // Syscall 21
+    brk,           // Syscall 22
+    sbrk,          // Syscall 23
+    mmap,          // Syscall 24
+    munmap,        // Syscall 25
+    mprotect,      // Syscall 26
+    shm_open,      // Syscall 27
+    shm_map,       // Syscall 28
+    shm_unmap,     // Syscall 29
+    shm_close,     // Syscall 30
+    meminfo,       // Syscall 31
+    getrlimit,     // Syscall 32
+    setrlimit,     // Syscall 33
 ];
 
-fn print(_file_descriptor: u64, affective_address: u64, bytes: u64) {
-    // TODO IMPLEMENT FD AND ERROR CHECKING
-    unsafe {
-        let slice = slice::from_raw_parts(affective_address as *const _, bytes as usize);
-        match core::str::from_utf8(slice) {
-            Ok(str) => print!("{}", str),
-            Err(_) => {
-                let number = affective_address as *const u64;
+/// Longest string that is copied in from user space
+const MAX_STRING_LENGTH: usize = 4096;
+
+/// Most entries that are copied in from an argv or envp array
+const MAX_ARRAY_LENGTH: usize = 256;
+
+/// Errors handed back to the caller of a syscall, rax holds the negated error number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// Operation not permitted
+    NotPermitted = 1,
+    /// No such file or directory
+    NotFound = 2,
+    /// No such process
+    NoProcess = 3,
+    /// Input/output error
+    Io = 5,
+    /// Argument list too long
+    TooBig = 7,
+    /// Exec format error
+    BadExecutable = 8,
+    /// Bad file descriptor
+    BadFile = 9,
+    /// No child processes
+    NoChild = 10,
+    /// Resource temporarily unavailable
+    Again = 11,
+    /// Cannot allocate memory
+    NoMemory = 12,
+    /// Permission denied
+    AccessDenied = 13,
+    /// Bad address
+    Fault = 14,
+    /// File exists
+    Exists = 17,
+    /// Invalid argument
+    Invalid = 22,
+    /// Inappropriate ioctl for device, the file is not a terminal
+    NotTerminal = 25,
+    /// Resource deadlock would occur
+    Deadlock = 35,
+    /// Function not implemented
+    NoSys = 38,
+}
+
+impl SyscallError {
+    /// The value placed in rax for the caller
+    pub fn as_return(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+impl From<ExecError> for SyscallError {
+    fn from(err: ExecError) -> Self {
+        match err {
+            ExecError::FileSystem(_) | ExecError::Link(LinkError::LibraryNotFound(_)) => {
+                SyscallError::NotFound
+            }
+            ExecError::Link(LinkError::NoMemory) => SyscallError::NoMemory,
+            ExecError::Link(LinkError::TooBig) => SyscallError::TooBig,
+            ExecError::Link(_) => SyscallError::BadExecutable,
+            ExecError::QueueFull => SyscallError::Again,
+        }
+    }
+}
+
+impl From<SignalError> for SyscallError {
+    fn from(err: SignalError) -> Self {
+        match err {
+            SignalError::NoSuchProcess => SyscallError::NoProcess,
+            SignalError::InvalidSignal => SyscallError::Invalid,
+        }
+    }
+}
+
+impl From<JobError> for SyscallError {
+    fn from(err: JobError) -> Self {
+        match err {
+            JobError::NoSuchProcess => SyscallError::NoProcess,
+            JobError::NotPermitted => SyscallError::NotPermitted,
+            JobError::NoChild => SyscallError::NoChild,
+            JobError::NotTerminal => SyscallError::NotTerminal,
+        }
+    }
+}
+
+impl From<JoinError> for SyscallError {
+    fn from(err: JoinError) -> Self {
+        match err {
+            JoinError::NoSuchThread => SyscallError::NoProcess,
+            JoinError::Deadlock => SyscallError::Deadlock,
+        }
+    }
+}
+
+impl From<RegionError> for SyscallError {
+    fn from(err: RegionError) -> Self {
+        match err {
+            RegionError::Invalid => SyscallError::Invalid,
+            RegionError::NoMemory => SyscallError::NoMemory,
+            RegionError::AccessDenied => SyscallError::AccessDenied,
+        }
+    }
+}
+
+impl From<LimitError> for SyscallError {
+    fn from(err: LimitError) -> Self {
+        match err {
+            LimitError::Invalid => SyscallError::Invalid,
+            LimitError::NotPermitted => SyscallError::NotPermitted,
+        }
+    }
+}
+
+impl From<ShmError> for SyscallError {
+    fn from(err: ShmError) -> Self {
+        match err {
+            ShmError::Invalid => SyscallError::Invalid,
+            ShmError::NotFound => SyscallError::NotFound,
+            ShmError::Exists => SyscallError::Exists,
+            ShmError::BadHandle => SyscallError::BadFile,
+            ShmError::AccessDenied => SyscallError::AccessDenied,
+            ShmError::NoMemory => SyscallError::NoMemory,
+        }
+    }
+}
+
+/// Called from `syscall_entry` with the saved registers of the caller. rax holds the
+/// syscall number and is overwritten with the return value
+///
+/// If the syscall left the running task in any state other than running (it exited or
+/// has to wait) the scheduler switches to the next task before returning. Pending
+/// signals are delivered to whichever task is returned to
+#[no_mangle]
+extern "C" fn syscall_dispatch(context: &mut Context) {
+    let (param1, param2, param3) = (context.rdi, context.rsi, context.rdx);
+
+    let result = match SYSTEM_CALLS.get(context.rax as usize) {
+        Some(system_call) => system_call(context, param1, param2, param3),
+        None => Err(SyscallError::NoSys),
+    };
+
+    context.rax = match result {
+        Ok(value) => value,
+        Err(err) => err.as_return(),
+    };
+
+    let gave_up_cpu = Scheduler::get_scheduler()
+        .running_task()
+        .map_or(false, |task| task.state() != TaskState::Running);
+
+    if gave_up_cpu {
+        Scheduler::schedule(context);
+    }
+
+    signal::deliver(context);
+}
+
+fn print(
+    _: &mut Context,
+    _file_descriptor: u64,
+    affective_address: u64,
+    bytes: u64,
+) -> Result<u64, SyscallError> {
+    // TODO IMPLEMENT FD
+    let buffer = user::copy_slice_from_user::<u8>(affective_address, bytes as usize)?;
+    match core::str::from_utf8(&buffer) {
+        Ok(str) => print!("{}", str),
+        Err(_) => {
+            let number = user::copy_from_user::<u64>(affective_address)?;
+
+            print!("{}", number)
+        }
+    }
+    Ok(bytes)
+}
+
+/// End the process of the running task with the exit code `status`, the running task is
+/// marked finished so the dispatcher invokes the scheduler which drops it and every
+/// other thread of the process
+fn exit(_: &mut Context, status: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    let process = scheduler
+        .running_task()
+        .ok_or(SyscallError::NoProcess)?
+        .process()
+        .clone();
+
+    scheduler.end_process(&process, job::exit_status(status));
+    Ok(0)
+}
+
+/// Replace the program of the running task with the executable found at the
+/// null terminated `path`. `argv` and `envp` are null terminated arrays of
+/// null terminated strings, either may be null
+fn exec(context: &mut Context, path: u64, argv: u64, envp: u64) -> Result<u64, SyscallError> {
+    let path = read_c_str(path)?;
+    let args = read_c_str_array(argv)?;
+    let env = read_c_str_array(envp)?;
+
+    let mut scheduler = Scheduler::get_scheduler();
+    let running_task = scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+
+    task::exec::exec(running_task, context, &path, args, env)?;
+    Ok(0)
+}
+
+/// Start the executable found at `path` as a child process of the running task, takes
+/// the same parameters as [exec] and returns the ID of the new task
+fn spawn(_: &mut Context, path: u64, argv: u64, envp: u64) -> Result<u64, SyscallError> {
+    let path = read_c_str(path)?;
+    let args = read_c_str_array(argv)?;
+    let env = read_c_str_array(envp)?;
+
+    let parent = Scheduler::get_scheduler()
+        .running_task()
+        .map(|task| task.process().clone());
+    let task_id = task::exec::spawn(&path, args, env, parent.as_deref())?;
+    Ok(task_id.get_id() as u64)
+}
+
+/// Duplicate the running task, the parent gets the ID of the child and the child
+/// returns from the same syscall with 0
+fn fork(context: &mut Context, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let child = {
+        let mut scheduler = Scheduler::get_scheduler();
+        let running_task = scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+        running_task
+            .fork(context)
+            .map_err(|_| SyscallError::NoMemory)?
+    };
+    let task_id = child.task_id();
+    let process = child.process().clone();
+
+    if Scheduler::add_task(child).is_err() {
+        running_process(&mut Scheduler::get_scheduler())?.disown(&process);
+        return Err(SyscallError::Again);
+    }
+    Ok(task_id.get_id() as u64)
+}
+
+/// Start a new thread in the process of the running task that calls `entry` with
+/// `arg` as its first parameter, returns the ID of the new thread
+fn thread_create(_: &mut Context, entry: u64, arg: u64, _: u64) -> Result<u64, SyscallError> {
+    let entry = VirtAddr::try_new(entry).map_err(|_| SyscallError::Fault)?;
+
+    let thread = {
+        let mut scheduler = Scheduler::get_scheduler();
+        let running_task = scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+        running_task
+            .thread(entry, arg)
+            .map_err(|_| SyscallError::NoMemory)?
+    };
+    let task_id = thread.task_id();
+
+    Scheduler::add_task(thread).map_err(|_| SyscallError::Again)?;
+    Ok(task_id.get_id() as u64)
+}
+
+/// End the running thread, `value` is handed to the thread that joins it. The process
+/// exits when its last thread does
+fn thread_exit(_: &mut Context, value: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+
+    scheduler.exit_thread(value);
+    Ok(0)
+}
+
+/// Wait for a thread of the same process to exit and return the value it exited with
+fn thread_join(context: &mut Context, thread: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+
+    match scheduler.join(TaskID::new(thread as usize))? {
+        Some(value) => Ok(value),
+        // The task is blocked, rax is set when the thread exits. Returning the
+        // current value leaves the saved registers as they are until then
+        None => Ok(context.rax),
+    }
+}
+
+/// Send `signal` to the process with the ID `pid`, to every process in the callers
+/// group for a `pid` of 0 or to every process in the group -`pid` for a negative one
+fn kill(_: &mut Context, pid: u64, signal: u64, _: u64) -> Result<u64, SyscallError> {
+    let signal = u32::try_from(signal).map_err(|_| SyscallError::Invalid)?;
+    let mut scheduler = Scheduler::get_scheduler();
+
+    match pid as i64 {
+        0 => {
+            let group = running_process(&mut scheduler)?.process_group();
+            scheduler.signal_group(group, signal)?;
+        }
+        // Signalling every process is not supported
+        -1 => return Err(SyscallError::Invalid),
+        pid if pid < 0 => scheduler.signal_group(TaskID::new((-pid) as usize), signal)?,
+        pid => scheduler.signal_process(TaskID::new(pid as usize), signal)?,
+    }
+    Ok(0)
+}
+
+/// Change how the process handles `signal` to the [SigAction] at `action` and write the
+/// previous one to `old_action`, either pointer may be null
+fn sigaction(
+    _: &mut Context,
+    signal: u64,
+    action: u64,
+    old_action: u64,
+) -> Result<u64, SyscallError> {
+    let signal = u32::try_from(signal).map_err(|_| SyscallError::Invalid)?;
+    if !signal::is_valid(signal) || (action != 0 && UNBLOCKABLE.contains(signal)) {
+        return Err(SyscallError::Invalid);
+    }
+
+    let mut scheduler = Scheduler::get_scheduler();
+    let process = scheduler
+        .running_task()
+        .ok_or(SyscallError::NoProcess)?
+        .process();
+
+    let old = match action {
+        0 => process.action(signal),
+        addr => {
+            let mut action = user::copy_from_user::<SigAction>(addr)?;
+            action.mask = action.mask.difference(UNBLOCKABLE);
+            process.set_action(signal, action)
+        }
+    };
+
+    if old_action != 0 {
+        user::copy_to_user(old_action, &old)?;
+    }
+    Ok(0)
+}
+
+/// Change the signals the running thread blocks by the [SignalSet] at `set` as selected
+/// by `how` and write the previous set to `old_set`, either pointer may be null
+fn sigprocmask(_: &mut Context, how: u64, set: u64, old_set: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    let running_task = scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+    let old = running_task.blocked_signals();
+
+    if set != 0 {
+        let set = user::copy_from_user::<SignalSet>(set)?;
+        let blocked = match how {
+            SIG_BLOCK => old.union(set),
+            SIG_UNBLOCK => old.difference(set),
+            SIG_SETMASK => set,
+            _ => return Err(SyscallError::Invalid),
+        };
+        running_task.set_blocked_signals(blocked);
+    }
+
+    if old_set != 0 {
+        user::copy_to_user(old_set, &old)?;
+    }
+    Ok(0)
+}
+
+/// Return from a signal handler to the registers saved in the signal frame on the
+/// stack, rax is part of them so the restored value is returned. A thread without a
+/// valid frame gets a SIGSEGV
+fn sigreturn(context: &mut Context, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+
+    match unsafe { signal::restore_frame(context) } {
+        Some((rax, blocked)) => {
+            scheduler
+                .running_task()
+                .unwrap()
+                .set_blocked_signals(blocked);
+            Ok(rax)
+        }
+        None => {
+            scheduler.force_signal(SIGSEGV);
+            Err(SyscallError::Fault)
+        }
+    }
+}
+
+/// Move the process `pid` into the process group `pgid`, a `pid` of 0 is the caller and
+/// a `pgid` of 0 makes the process the leader of a new group. See [job::set_process_group]
+fn setpgid(_: &mut Context, pid: u64, pgid: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    let caller = running_process(&mut scheduler)?;
+
+    let pid = match pid {
+        0 => caller.process_id(),
+        pid => TaskID::new(pid as usize),
+    };
+    let pgid = match pgid {
+        0 => pid,
+        pgid => TaskID::new(pgid as usize),
+    };
+
+    job::set_process_group(&caller, pid, pgid)?;
+    Ok(0)
+}
+
+/// Return the process group of the process `pid`, a `pid` of 0 is the caller
+fn getpgid(_: &mut Context, pid: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    let process = match pid {
+        0 => running_process(&mut scheduler)?,
+        pid => Process::find(TaskID::new(pid as usize)).ok_or(SyscallError::NoProcess)?,
+    };
 
-                print!("{}", *number)
+    Ok(process.process_group().get_id() as u64)
+}
+
+/// Start a new session led by the caller and return its ID, see [job::create_session]
+fn setsid(_: &mut Context, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let mut scheduler = Scheduler::get_scheduler();
+    let caller = running_process(&mut scheduler)?;
+
+    let session = job::create_session(&caller)?;
+    Ok(session.get_id() as u64)
+}
+
+/// Move the process group `pgid` into the foreground of the terminal open as `fd`
+fn tcsetpgrp(_: &mut Context, fd: u64, pgid: u64, _: u64) -> Result<u64, SyscallError> {
+    check_terminal(fd)?;
+    let mut scheduler = Scheduler::get_scheduler();
+    let caller = running_process(&mut scheduler)?;
+
+    terminal::set_foreground_group(&caller, TaskID::new(pgid as usize))?;
+    Ok(0)
+}
+
+/// Return the foreground process group of the terminal open as `fd`
+fn tcgetpgrp(_: &mut Context, fd: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    check_terminal(fd)?;
+    let mut scheduler = Scheduler::get_scheduler();
+    let caller = running_process(&mut scheduler)?;
+
+    Ok(terminal::foreground_group(&caller)?.get_id() as u64)
+}
+
+/// Wait for a child selected by `pid` to exit, or to stop if `options` holds WUNTRACED,
+/// and write its wait status to `status` unless it is null. Returns the ID of the child,
+/// or 0 with WNOHANG if no child has changed. See [Scheduler::wait_child]
+fn waitpid(
+    context: &mut Context,
+    pid: u64,
+    status: u64,
+    options: u64,
+) -> Result<u64, SyscallError> {
+    // The child is reaped by the wait, so a bad status pointer has to fail before it
+    if status != 0 {
+        user::check(status, mem::size_of::<u32>(), true)?;
+    }
+    let mut scheduler = Scheduler::get_scheduler();
+
+    match scheduler.wait_child(pid as i64, options)? {
+        Some((child, wait_status)) => {
+            if status != 0 {
+                user::copy_to_user(status, &wait_status)?;
             }
+            Ok(child.get_id() as u64)
         }
+        None if options & WNOHANG != 0 => Ok(0),
+        // Blocked, the syscall is made again once a child changes
+        None => Ok(context.rax),
     }
 }
 
-/// Get the current running task from the mutex scheduler,
-/// changed the tasks state to finished then use a timer interrupt
-/// to invoke the scheduler, the scheduler will then take the current
-/// task off of a ready queue so it won't execute again
-fn exit(_: u64, _: u64, _: u64) {
-    use crate::InterruptIndex;
-    use task::scheduler::Scheduler;
-    use task::task::{Task, TaskState};
+/// Read up to `count` bytes of the next line typed at the terminal into `buf`, the only
+/// file that can be read is the terminal on descriptor 0
+///
+/// Blocks until a line is typed. A reader in a background group is stopped with SIGTTIN
+/// and reads again once it is continued, unless it ignores or blocks SIGTTIN. A reader
+/// outside the session of the terminal fails with EIO
+fn read(context: &mut Context, fd: u64, buf: u64, count: u64) -> Result<u64, SyscallError> {
+    if fd != 0 {
+        return Err(SyscallError::BadFile);
+    }
+    // Input is taken off the terminal as it is read, so the buffer is checked first
+    user::check(buf, count as usize, true)?;
+    let buf = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count as usize) };
 
     let mut scheduler = Scheduler::get_scheduler();
-    let running_task = scheduler.running_task_mut();
+    let running_task = scheduler.running_task().ok_or(SyscallError::NoProcess)?;
+    let process = running_task.process().clone();
+
+    match terminal::read(&process, buf) {
+        ReadResult::Data(length) => Ok(length as u64),
+        ReadResult::Empty => {
+            running_task.block(WaitReason::TerminalInput);
+            Ok(context.rax)
+        }
+        ReadResult::Background => {
+            if running_task.blocked_signals().contains(SIGTTIN)
+                || process.action(SIGTTIN).handler == signal::SIG_IGN
+            {
+                return Err(SyscallError::Io);
+            }
+            scheduler.signal_group(process.process_group(), SIGTTIN)?;
+            context.repeat_syscall();
+            Ok(context.rax)
+        }
+        ReadResult::NotInSession => Err(SyscallError::Io),
+    }
+}
+
+/// Write what the task table knows about the task `task_id` to the [TaskStat] at `stat`,
+/// a `task_id` of 0 is the calling thread
+fn task_info(_: &mut Context, task_id: u64, stat: u64, _: u64) -> Result<u64, SyscallError> {
+    let task_id = match task_id {
+        0 => Scheduler::get_scheduler()
+            .running_task()
+            .ok_or(SyscallError::NoProcess)?
+            .task_id(),
+        task_id => TaskID::new(task_id as usize),
+    };
+
+    let info = table::info(task_id).ok_or(SyscallError::NoProcess)?;
+    user::copy_to_user(stat, &TaskStat::from(&info))?;
+    Ok(0)
+}
+
+/// Write a [TaskStat] for each task in the task table to the array at `stats` with room
+/// for `capacity` of them and the current timer tick to `now` unless it is null. Returns
+/// the number of tasks, which can be more than were written
+fn list_tasks(_: &mut Context, stats: u64, capacity: u64, now: u64) -> Result<u64, SyscallError> {
+    let capacity = capacity as usize;
+    if capacity != 0 {
+        let bytes = capacity
+            .checked_mul(mem::size_of::<TaskStat>())
+            .ok_or(SyscallError::Fault)?;
+        user::check(stats, bytes, true)?;
+    }
+
+    let tasks = table::list();
+    let written: Vec<TaskStat> = tasks.iter().take(capacity).map(TaskStat::from).collect();
+    if !written.is_empty() {
+        user::copy_slice_to_user(stats, &written)?;
+    }
+
+    if now != 0 {
+        user::copy_to_user(now, &table::ticks())?;
+    }
+    Ok(tasks.len() as u64)
+}
 
-    running_task.set_state(TaskState::Finished);
+/// Write the memory counters as a [MemInfo] to `info` unless it is null and return the
+/// number of pages mapped for the task `task_id`, or the running task if it is 0
+fn meminfo(_: &mut Context, info: u64, task_id: u64, _: u64) -> Result<u64, SyscallError> {
+    let task_id = match task_id {
+        0 => Scheduler::get_scheduler()
+            .running_task()
+            .ok_or(SyscallError::NoProcess)?
+            .task_id(),
+        task_id => TaskID::new(task_id as usize),
+    };
+    let task = table::info(task_id).ok_or(SyscallError::NoProcess)?;
 
-    unsafe {
-        asm!("int 32");
+    if info != 0 {
+        user::copy_to_user::<MemInfo>(info, &meminfo::meminfo())?;
     }
-    // We somehow need to get the current task and change its state to finished then invoke the scheduler
-    // Should we store the current task as a mutable global? maybe
-    // then invoke the scheduler from here
+    Ok(task.memory as u64 / Size4KiB::SIZE)
+}
+
+/// Move the end of the heap of the process to `addr` and return the new end, or the
+/// current end if the heap can not end there. An `addr` of 0 returns the current end
+fn brk(_: &mut Context, addr: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    Ok(region::brk(&process, addr))
+}
+
+/// Grow or shrink the heap of the process by `increment` bytes, a two's complement
+/// value, and return the previous end of the heap
+fn sbrk(_: &mut Context, increment: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    Ok(region::sbrk(&process, increment as i64)?)
+}
+
+/// Map `length` bytes of zeroed anonymous memory with the PROT_* flags `protection`
+/// and return its address. The MAP_* flags are the fourth parameter, passed in r10
+/// like on Linux. See [region::mmap]
+fn mmap(
+    context: &mut Context,
+    addr: u64,
+    length: u64,
+    protection: u64,
+) -> Result<u64, SyscallError> {
+    let flags = context.r10;
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    Ok(region::mmap(&process, addr, length, protection, flags)?.as_u64())
+}
+
+/// Unmap `length` bytes of mappings from `addr`, see [region::munmap]
+fn munmap(_: &mut Context, addr: u64, length: u64, _: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    region::munmap(&process, addr, length)?;
+    Ok(0)
+}
+
+/// Change the protection of `length` bytes of mappings from `addr` to the PROT_* flags
+/// `protection`, see [region::mprotect]
+fn mprotect(_: &mut Context, addr: u64, length: u64, protection: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    region::mprotect(&process, addr, length, protection)?;
+    Ok(0)
+}
+
+/// Open the shared memory object called by the null terminated string at `name` with
+/// the SHM_* flags `flags`, creating it with `size` bytes if there is none and
+/// SHM_CREATE is given. Returns a handle, see [shm::open]
+fn shm_open(_: &mut Context, name: u64, size: u64, flags: u64) -> Result<u64, SyscallError> {
+    let name = read_c_str(name)?;
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    Ok(shm::open(&process, &name, size, flags)?)
+}
+
+/// Map the whole shared memory object of `handle` with the PROT_* flags `protection`,
+/// at `addr` if it is free, and return its address. See [shm::map]
+fn shm_map(_: &mut Context, handle: u64, addr: u64, protection: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    Ok(shm::map(&process, handle, addr, protection)?.as_u64())
+}
+
+/// Unmap the mapping of the shared memory object of `handle` at `addr`
+fn shm_unmap(_: &mut Context, handle: u64, addr: u64, _: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    shm::unmap(&process, handle, addr)?;
+    Ok(0)
+}
+
+/// Close `handle` to a shared memory object, its mappings stay
+fn shm_close(_: &mut Context, handle: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    shm::close(&process, handle)?;
+    Ok(0)
+}
+
+/// Write the soft and hard limit of the RLIMIT_* `resource` of the process as an
+/// [RLimit] to `limit`
+fn getrlimit(_: &mut Context, resource: u64, limit: u64, _: u64) -> Result<u64, SyscallError> {
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    let resource = u32::try_from(resource).map_err(|_| SyscallError::Invalid)?;
+    user::copy_to_user(limit, &process.limits().get(resource)?)?;
+    Ok(0)
+}
+
+/// Change the limits of the RLIMIT_* `resource` of the process to the [RLimit] at
+/// `limit`, only a process running as root can raise its hard limit
+fn setrlimit(_: &mut Context, resource: u64, limit: u64, _: u64) -> Result<u64, SyscallError> {
+    let limit = user::copy_from_user::<RLimit>(limit)?;
+    let process = running_process(&mut Scheduler::get_scheduler())?;
+    let resource = u32::try_from(resource).map_err(|_| SyscallError::Invalid)?;
+    process.set_limit(resource, limit)?;
+    Ok(0)
+}
+
+/// Only the terminal is open, on the standard descriptors 0 to 2
+fn check_terminal(fd: u64) -> Result<(), SyscallError> {
+    match fd {
+        0..=2 => Ok(()),
+        _ => Err(SyscallError::BadFile),
+    }
+}
+
+/// The process of the running task
+fn running_process(scheduler: &mut Scheduler) -> Result<Arc<Process>, SyscallError> {
+    scheduler
+        .running_task()
+        .map(|task| task.process().clone())
+        .ok_or(SyscallError::NoProcess)
+}
+
+/// Copy a null terminated string out of the address space of the caller
+fn read_c_str(addr: u64) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    let mut next = addr;
+    while bytes.len() < MAX_STRING_LENGTH {
+        // Only read up to the end of the page, the string may end before an unmapped one
+        let length = (Size4KiB::SIZE - next % Size4KiB::SIZE) as usize;
+        let length = length.min(MAX_STRING_LENGTH - bytes.len());
+        let chunk = user::copy_slice_from_user::<u8>(next, length)?;
+        if let Some(end) = chunk.iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return String::from_utf8(bytes).map_err(|_| SyscallError::Invalid);
+        }
+        bytes.extend(chunk);
+        next += length as u64;
+    }
+
+    Err(SyscallError::TooBig)
+}
+
+/// Copy a null terminated array of null terminated strings out of the address
+/// space of the caller, a null array is read as an empty one
+fn read_c_str_array(addr: u64) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+
+    for i in 0..MAX_ARRAY_LENGTH {
+        let entry = addr
+            .checked_add((i * mem::size_of::<u64>()) as u64)
+            .ok_or(SyscallError::Fault)?;
+        match user::copy_from_user::<u64>(entry)? {
+            0 => return Ok(strings),
+            string => strings.push(read_c_str(string)?),
+        }
+    }
+
+    Err(SyscallError::TooBig)
 }
@@ -0,0 +1,98 @@
+//! Copies between the kernel and the address space of the running task
+//!
+//! Every pointer a task passes to a syscall is read and written through these. A range
+//! that is not in the level 4 entries of the task or not on pages mapped accessible to
+//! it fails with [SyscallError::Fault] instead of faulting in the kernel
+use crate::syscall::SyscallError;
+use core::{mem, ptr};
+use memory::virt;
+use task::task::USER_P4_INDICES;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Bytes mapped by one level 4 entry
+const P4_ENTRY_SIZE: u64 = 1 << 39;
+
+/// The first address of the task and the address after its last one. Both halves of the
+/// range are canonical as it ends at the top of the lower half
+const USER_START: u64 = USER_P4_INDICES.start as u64 * P4_ENTRY_SIZE;
+const USER_END: u64 = USER_P4_INDICES.end as u64 * P4_ENTRY_SIZE;
+
+/// Read a `T` from `addr`, which does not have to be aligned
+pub fn copy_from_user<T: Copy>(addr: u64) -> Result<T, SyscallError> {
+    check(addr, mem::size_of::<T>(), false)?;
+    // SAFETY: Every byte of the value is on a page mapped for the task
+    Ok(unsafe { ptr::read_unaligned(addr as *const T) })
+}
+
+/// Write `value` to `addr`, which does not have to be aligned
+pub fn copy_to_user<T: Copy>(addr: u64, value: &T) -> Result<(), SyscallError> {
+    check(addr, mem::size_of::<T>(), true)?;
+    // SAFETY: Every byte of the value is on a page mapped writable for the task
+    unsafe { ptr::write_unaligned(addr as *mut T, *value) };
+    Ok(())
+}
+
+/// Read `len` elements starting at `addr`
+pub fn copy_slice_from_user<T: Copy>(addr: u64, len: usize) -> Result<Vec<T>, SyscallError> {
+    let bytes = mem::size_of::<T>()
+        .checked_mul(len)
+        .ok_or(SyscallError::Fault)?;
+    check(addr, bytes, false)?;
+
+    let mut values = Vec::with_capacity(len);
+    // SAFETY: The source is on pages mapped for the task and the vector has room for
+    // `len` elements, the copy is bytewise so `addr` does not have to be aligned
+    unsafe {
+        ptr::copy_nonoverlapping(addr as *const u8, values.as_mut_ptr() as *mut u8, bytes);
+        values.set_len(len);
+    }
+    Ok(values)
+}
+
+/// Write every element of `values` starting at `addr`
+pub fn copy_slice_to_user<T: Copy>(addr: u64, values: &[T]) -> Result<(), SyscallError> {
+    let bytes = mem::size_of_val(values);
+    check(addr, bytes, true)?;
+    // SAFETY: The destination is on pages mapped writable for the task
+    unsafe { ptr::copy_nonoverlapping(values.as_ptr() as *const u8, addr as *mut u8, bytes) };
+    Ok(())
+}
+
+/// Make sure the `len` bytes at `addr` are in the level 4 entries of the task and on
+/// pages it may access, and may write if `write` is set. Pages shared after a fork get
+/// their own frame first so the kernel does not write to the frame of another process
+///
+/// Syscalls that consume something before they copy it out check the destination
+/// with this first, so a bad pointer does not lose it
+pub fn check(addr: u64, len: usize, write: bool) -> Result<(), SyscallError> {
+    let end = addr.checked_add(len as u64).ok_or(SyscallError::Fault)?;
+    if addr < USER_START || end > USER_END {
+        return Err(SyscallError::Fault);
+    }
+    if len == 0 {
+        return Ok(());
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        let addr = page.start_address();
+        let flags = virt::page_flags(addr).ok_or(SyscallError::Fault)?;
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return Err(SyscallError::Fault);
+        }
+        if write
+            && !flags.contains(PageTableFlags::WRITABLE)
+            && !virt::handle_copy_on_write(addr).map_err(|_| SyscallError::NoMemory)?
+        {
+            return Err(SyscallError::Fault);
+        }
+    }
+    Ok(())
+}
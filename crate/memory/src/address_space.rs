@@ -0,0 +1,606 @@
+//! Address spaces and the page tables behind them
+//!
+//! Every address space maps its level 4 table recursively at the index the bootloader
+//! picked for the kernel, [RECURSIVE_INDEX](crate::RECURSIVE_INDEX), so switching
+//! between them is nothing more than loading CR3. The level 4 entries 0, 256 and 507
+//! up to 511 are shared with the kernel address space, everything below the other
+//! entries is unmapped and its page tables are freed when the address space is dropped
+//!
+//! An address space that is not active is edited through a window. Its level 4 table
+//! is put into the entry [WINDOW_INDEX] of the active table while the change is made,
+//! which makes its tables reachable through its own recursive entry the same way the
+//! tables of the active address space are
+//!
+//! Mappings may use 2 MiB and 1 GiB pages. A huge page is split into pages one size
+//! smaller whenever only a part of it changes, functions working on single 4 KiB pages
+//! only see the pages of huge ones after they have been split
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::{interrupts, tlb::flush_all},
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, Page, PageSize, PageTable, PageTableFlags, PageTableIndex, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    kpbox::KpBox,
+    phys::{
+        order_of, put_frame, put_frames, FrameFlags, PageTableFrames, FRAME_ALLOCATOR, NO_OWNER,
+    },
+    tlb, RECURSIVE_INDEX,
+};
+
+/// Level 4 entry of the active address space an inactive one is mapped into while it
+/// is edited, the entry is unused in every address space otherwise
+pub const WINDOW_INDEX: u16 = 384;
+
+static KERNEL_ADDRESS_SPACE: Once<AddressSpace> = Once::new();
+
+/// Only one inactive address space can be edited through the window at a time
+static WINDOW: Mutex<()> = Mutex::new(());
+
+/// Owner of the active address space, see [active_owner]
+static ACTIVE_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+
+/// Id of the process the active address space belongs to, [NO_OWNER] for the kernel
+/// address space and address spaces without an owner
+pub fn active_owner() -> u32 {
+    ACTIVE_OWNER.load(Ordering::Relaxed)
+}
+
+/// A set of page tables rooted in one level 4 table
+///
+/// The tables can be changed through a shared reference, callers editing the same
+/// address space from several threads have to serialize their changes themselves
+pub struct AddressSpace {
+    frame: PhysFrame,
+    /// Owns the level 4 table, None for the kernel address space the bootloader built
+    _table: Option<KpBox<PageTable>>,
+    /// Id of the process the address space belongs to, frames allocated for its memory
+    /// are marked with it
+    owner: u32,
+}
+
+impl AddressSpace {
+    /// Remember the address space active at boot as the kernel address space
+    pub(crate) fn init_kernel() {
+        KERNEL_ADDRESS_SPACE.call_once(|| AddressSpace {
+            frame: Cr3::read().0,
+            _table: None,
+            owner: NO_OWNER,
+        });
+    }
+
+    /// The address space the bootloader handed over, it only holds the kernel
+    pub fn kernel() -> &'static AddressSpace {
+        KERNEL_ADDRESS_SPACE.wait().unwrap()
+    }
+
+    /// An address space that only shares the kernel entries
+    pub fn new() -> Self {
+        Self::with_owner(NO_OWNER)
+    }
+
+    /// [new](AddressSpace::new) for the process with the id `owner`
+    pub fn with_owner(owner: u32) -> Self {
+        let mut table = KpBox::<PageTable>::default();
+        // Shared entries are the same in every address space
+        let active = Tables::active().p4();
+        table[0] = active[0].clone();
+        table[256] = active[256].clone();
+        for i in 507..512 {
+            table[i] = active[i].clone();
+        }
+
+        let frame = PhysFrame::containing_address(table.phys_addr());
+        FRAME_ALLOCATOR
+            .wait()
+            .unwrap()
+            .inner
+            .lock()
+            .mark(frame, 0, FrameFlags::PAGE_TABLE);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        table[recursive_index()].set_frame(frame, flags);
+
+        Self {
+            frame,
+            _table: Some(table),
+            owner,
+        }
+    }
+
+    /// Frame of the level 4 table, the value CR3 holds while the address space is active
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.frame
+    }
+
+    /// Load the address space into CR3
+    pub fn activate(&self) {
+        ACTIVE_OWNER.store(self.owner, Ordering::Relaxed);
+        if !self.is_active() {
+            unsafe { Cr3::write(self.frame, Cr3::read().1) };
+        }
+    }
+
+    /// Run `f` with this address space active, for work that touches the memory it maps
+    /// and not only its tables. The address space that was active before is restored
+    /// afterwards
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let (previous, flags) = Cr3::read();
+        let previous_owner = ACTIVE_OWNER.load(Ordering::Relaxed);
+        self.activate();
+        let result = f();
+        if previous != self.frame {
+            unsafe { Cr3::write(previous, flags) };
+        }
+        ACTIVE_OWNER.store(previous_owner, Ordering::Relaxed);
+        result
+    }
+
+    /// Map `page` to `frame` with `flags`, page tables missing on the way are created
+    pub fn map(
+        &self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        self.with_tables(|tables| {
+            let entry = tables.create(page)?;
+            if !entry.is_unused() {
+                return Err(MapToError::PageAlreadyMapped(entry.frame().unwrap()));
+            }
+            entry.set_frame(frame, flags);
+            Ok(())
+        })?;
+        self.shootdown(page);
+        Ok(())
+    }
+
+    /// Remove the mapping of `page`, returns the frame it was mapped to
+    pub fn unmap(&self, page: Page) -> Result<PhysFrame, UnmapError> {
+        let frame = self.with_tables(|tables| {
+            let entry = tables.entry(page).ok_or(UnmapError::PageNotMapped)?;
+            let frame = entry
+                .frame()
+                .map_err(|_| UnmapError::InvalidFrameAddress(entry.addr()))?;
+            entry.set_unused();
+            Ok(frame)
+        })?;
+        self.shootdown(page);
+        Ok(frame)
+    }
+
+    /// Give the mapping of `page` the flags `flags`
+    pub fn protect(&self, page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+        self.with_tables(|tables| {
+            let entry = tables.entry(page).ok_or(FlagUpdateError::PageNotMapped)?;
+            entry.set_flags(flags);
+            Ok(())
+        })?;
+        self.shootdown(page);
+        Ok(())
+    }
+
+    /// The physical address `addr` is mapped to
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        self.with_tables(|tables| {
+            let (start, size) = match tables.huge_entry(page) {
+                Some((entry, size)) => (entry.addr(), size),
+                None => (tables.entry(page)?.addr(), Size4KiB::SIZE),
+            };
+            Some(start + addr.as_u64() % size)
+        })
+    }
+
+    /// Bytes mapped below the level 4 entries in `p4_range`, huge pages included
+    pub fn mapped_bytes(&self, p4_range: Range<u16>) -> u64 {
+        self.with_tables(|tables| tables.mapped_bytes(p4_range))
+    }
+
+    /// Split every huge page mapped below the level 4 entries in `p4_range` into 4 KiB
+    /// pages, for work that has to see every page on its own like a fork
+    pub fn split_huge_pages(&self, p4_range: Range<u16>) -> Result<(), MapToError<Size4KiB>> {
+        self.with_tables(|tables| tables.split_all(p4_range))
+    }
+
+    /// Walk every 4 KiB page mapped below the level 4 entries in `p4_range`, `f` is
+    /// called with the page and its level 1 entry which may be modified. Huge pages are
+    /// skipped, see [split_huge_pages](AddressSpace::split_huge_pages). The caller is
+    /// responsible for flushing the TLB after changing entries of the active address
+    /// space
+    pub fn for_each_mapping(&self, p4_range: Range<u16>, f: impl FnMut(Page, &mut PageTableEntry)) {
+        self.with_tables(|tables| tables.for_each_mapping(p4_range, f))
+    }
+
+    /// Run `f` on the tables of this address space, through the window if it is not
+    /// the active one
+    fn with_tables<R>(&self, f: impl FnOnce(Tables) -> R) -> R {
+        if self.is_active() {
+            return f(Tables::active());
+        }
+
+        interrupts::without_interrupts(|| {
+            let _window = WINDOW.lock();
+            let index = PageTableIndex::new(WINDOW_INDEX);
+            let active = Tables::active().p4();
+
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            active[index].set_frame(self.frame, flags);
+            flush_all();
+
+            let result = f(Tables {
+                root: index,
+                recursive: recursive_index(),
+            });
+
+            active[index].set_unused();
+            flush_all();
+            result
+        })
+    }
+
+    /// Changes to an inactive address space are picked up when it is loaded
+    fn shootdown(&self, page: Page) {
+        if self.is_active() {
+            tlb::shootdown_page(page);
+        }
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Unmap everything below the level 4 entries that are not shared with the kernel,
+    /// dropping the references to the frames mapped, and free the page tables
+    fn drop(&mut self) {
+        let recursive = u16::from(recursive_index());
+        let owned = (1..507).filter(|&i| i != 256 && i != WINDOW_INDEX && i != recursive);
+        self.with_tables(|tables| {
+            for i in owned {
+                tables.clear(i..i + 1);
+            }
+        });
+        if self.is_active() {
+            flush_all();
+        }
+    }
+}
+
+/// Index of the recursive entry, the same in every address space
+fn recursive_index() -> PageTableIndex {
+    PageTableIndex::new(*RECURSIVE_INDEX.wait().unwrap())
+}
+
+/// The page tables of an address space reached through the level 4 entry `root` of the
+/// active table and the recursive entry of the address space
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Tables {
+    root: PageTableIndex,
+    recursive: PageTableIndex,
+}
+
+impl Tables {
+    /// Tables of the active address space
+    pub(crate) fn active() -> Self {
+        let r = recursive_index();
+        Self {
+            root: r,
+            recursive: r,
+        }
+    }
+
+    /// The page a table is reached at, the indices select it the same way they would
+    /// select a page below the root entry
+    fn page(&self, p3: PageTableIndex, p2: PageTableIndex, p1: PageTableIndex) -> Page {
+        Page::from_page_table_indices(self.root, p3, p2, p1)
+    }
+
+    fn table(
+        &self,
+        p3: PageTableIndex,
+        p2: PageTableIndex,
+        p1: PageTableIndex,
+    ) -> &'static mut PageTable {
+        let page = self.page(p3, p2, p1);
+        unsafe { &mut *page.start_address().as_mut_ptr::<PageTable>() }
+    }
+
+    fn p4(&self) -> &'static mut PageTable {
+        let r = self.recursive;
+        self.table(r, r, r)
+    }
+
+    fn p3(&self, i: PageTableIndex) -> &'static mut PageTable {
+        let r = self.recursive;
+        self.table(r, r, i)
+    }
+
+    fn p2(&self, i: PageTableIndex, j: PageTableIndex) -> &'static mut PageTable {
+        self.table(self.recursive, i, j)
+    }
+
+    fn p1(
+        &self,
+        i: PageTableIndex,
+        j: PageTableIndex,
+        k: PageTableIndex,
+    ) -> &'static mut PageTable {
+        self.table(i, j, k)
+    }
+
+    /// The level 1 entry of `page` if it is in use, None if any of the tables above it
+    /// are missing
+    pub(crate) fn entry(&self, page: Page) -> Option<&'static mut PageTableEntry> {
+        let (i, j, k) = (page.p4_index(), page.p3_index(), page.p2_index());
+        if !is_table(&self.p4()[i]) || !is_table(&self.p3(i)[j]) || !is_table(&self.p2(i, j)[k]) {
+            return None;
+        }
+
+        let entry = &mut self.p1(i, j, k)[page.p1_index()];
+        (!entry.is_unused()).then(|| entry)
+    }
+
+    /// The level 3 or level 2 entry of the huge page `page` is in and the size of that
+    /// huge page, None if `page` is not in a huge page
+    pub(crate) fn huge_entry(&self, page: Page) -> Option<(&'static mut PageTableEntry, u64)> {
+        let (i, j, k) = (page.p4_index(), page.p3_index(), page.p2_index());
+        if !is_table(&self.p4()[i]) {
+            return None;
+        }
+
+        let p3_entry = &mut self.p3(i)[j];
+        if is_huge(p3_entry) {
+            return Some((p3_entry, Size1GiB::SIZE));
+        } else if !is_table(p3_entry) {
+            return None;
+        }
+
+        let p2_entry = &mut self.p2(i, j)[k];
+        is_huge(p2_entry).then(|| (p2_entry, Size2MiB::SIZE))
+    }
+
+    /// Split the huge page `page` is in until `page` is mapped by a 4 KiB page, the
+    /// pages of the huge page keep its frames and flags. Nothing happens if `page` is
+    /// not in a huge page
+    pub(crate) fn split(&self, page: Page) -> Result<(), MapToError<Size4KiB>> {
+        let (i, j, k) = (page.p4_index(), page.p3_index(), page.p2_index());
+        let r = self.recursive;
+        if !is_table(&self.p4()[i]) {
+            return Ok(());
+        }
+
+        if is_huge(&self.p3(i)[j]) {
+            split_entry(&mut self.p3(i)[j], self.page(r, i, j), Size2MiB::SIZE)?;
+        }
+        if is_table(&self.p3(i)[j]) && is_huge(&self.p2(i, j)[k]) {
+            split_entry(&mut self.p2(i, j)[k], self.page(i, j, k), Size4KiB::SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// The level 1 entry of `page`, used or not, creating the tables above it
+    fn create(&self, page: Page) -> Result<&'static mut PageTableEntry, MapToError<Size4KiB>> {
+        let (i, j, k) = (page.p4_index(), page.p3_index(), page.p2_index());
+        let r = self.recursive;
+
+        next_table(&mut self.p4()[i], self.page(r, r, i))?;
+        next_table(&mut self.p3(i)[j], self.page(r, i, j))?;
+        next_table(&mut self.p2(i, j)[k], self.page(i, j, k))?;
+        Ok(&mut self.p1(i, j, k)[page.p1_index()])
+    }
+
+    pub(crate) fn for_each_mapping(
+        &self,
+        p4_range: Range<u16>,
+        mut f: impl FnMut(Page, &mut PageTableEntry),
+    ) {
+        let p4 = self.p4();
+        for i in p4_range.map(PageTableIndex::new) {
+            if !is_table(&p4[i]) {
+                continue;
+            }
+            let p3 = self.p3(i);
+            for j in (0..512).map(PageTableIndex::new) {
+                if !is_table(&p3[j]) {
+                    continue;
+                }
+                let p2 = self.p2(i, j);
+                for k in (0..512).map(PageTableIndex::new) {
+                    if !is_table(&p2[k]) {
+                        continue;
+                    }
+                    let p1 = self.p1(i, j, k);
+                    for l in (0..512).map(PageTableIndex::new) {
+                        if !p1[l].is_unused() {
+                            f(Page::from_page_table_indices(i, j, k, l), &mut p1[l]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn mapped_bytes(&self, p4_range: Range<u16>) -> u64 {
+        let mut bytes = 0;
+        self.for_each_huge_entry(p4_range.clone(), |_, _, size| bytes += size);
+        self.for_each_mapping(p4_range, |_, _| bytes += Size4KiB::SIZE);
+        bytes
+    }
+
+    /// Unmap every page below the level 4 entries in `p4_range`, dropping the references
+    /// to their frames, and free the page tables below the entries
+    fn clear(&self, p4_range: Range<u16>) {
+        self.for_each_huge_entry(p4_range.clone(), |_, entry, size| {
+            let frame = PhysFrame::containing_address(entry.addr());
+            entry.set_unused();
+            unsafe { put_frames(frame, order_of(size, size)) };
+        });
+        self.for_each_mapping(p4_range.clone(), |_, entry| {
+            let frame = PhysFrame::containing_address(entry.addr());
+            entry.set_unused();
+            unsafe { put_frame(frame) };
+        });
+
+        // A table is only reachable while the entry above it is in use, so the tables
+        // below an entry are freed first
+        let p4 = self.p4();
+        for i in p4_range.map(PageTableIndex::new) {
+            if !is_table(&p4[i]) {
+                continue;
+            }
+            let p3 = self.p3(i);
+            for j in (0..512).map(PageTableIndex::new) {
+                if !is_table(&p3[j]) {
+                    continue;
+                }
+                let p2 = self.p2(i, j);
+                for k in (0..512).map(PageTableIndex::new) {
+                    if is_table(&p2[k]) {
+                        free_table(&mut p2[k]);
+                    }
+                }
+                free_table(&mut p3[j]);
+            }
+            free_table(&mut p4[i]);
+        }
+    }
+
+    fn split_all(&self, p4_range: Range<u16>) -> Result<(), MapToError<Size4KiB>> {
+        let mut result = Ok(());
+        // 1 GiB pages become 2 MiB pages first, which the second walk splits again
+        for _ in 0..2 {
+            let mut huge_pages = Vec::new();
+            self.for_each_huge_entry(p4_range.clone(), |page, _, _| huge_pages.push(page));
+            for page in huge_pages {
+                result = result.and(self.split(page));
+            }
+        }
+        result
+    }
+
+    /// Walk the level 3 and level 2 entries below `p4_range` that map a huge page, `f`
+    /// is called with the first page of the huge page, its entry and its size
+    fn for_each_huge_entry(
+        &self,
+        p4_range: Range<u16>,
+        mut f: impl FnMut(Page, &mut PageTableEntry, u64),
+    ) {
+        let p4 = self.p4();
+        let zero = PageTableIndex::new(0);
+        for i in p4_range.map(PageTableIndex::new) {
+            if !is_table(&p4[i]) {
+                continue;
+            }
+            let p3 = self.p3(i);
+            for j in (0..512).map(PageTableIndex::new) {
+                if is_huge(&p3[j]) {
+                    let page = Page::from_page_table_indices(i, j, zero, zero);
+                    f(page, &mut p3[j], Size1GiB::SIZE);
+                    continue;
+                } else if !is_table(&p3[j]) {
+                    continue;
+                }
+                let p2 = self.p2(i, j);
+                for k in (0..512).map(PageTableIndex::new) {
+                    if is_huge(&p2[k]) {
+                        let page = Page::from_page_table_indices(i, j, k, zero);
+                        f(page, &mut p2[k], Size2MiB::SIZE);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Remove the table `entry` points to and free its frame
+fn free_table(entry: &mut PageTableEntry) {
+    let frame = PhysFrame::containing_address(entry.addr());
+    entry.set_unused();
+    unsafe { put_frame(frame) };
+}
+
+/// Whether `entry` points to a page table rather than being unused or a huge page
+fn is_table(entry: &PageTableEntry) -> bool {
+    !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE)
+}
+
+/// Whether `entry` maps a huge page, only meaningful for level 3 and level 2 entries
+fn is_huge(entry: &PageTableEntry) -> bool {
+    !entry.is_unused() && entry.flags().contains(PageTableFlags::HUGE_PAGE)
+}
+
+/// Replace the huge page `entry` maps by a new table of 512 pages of `size` bytes
+/// mapping the same frames with the same flags. `table` is the page the new table is
+/// reached at through the recursive entry
+///
+/// The huge page is not mapped while the table is filled, the memory it maps must not
+/// be in use by the code splitting it
+fn split_entry(
+    entry: &mut PageTableEntry,
+    table: Page,
+    size: u64,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = PageTableFrames
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let start = entry.addr();
+    let mut flags = entry.flags();
+    // Only level 3 and level 2 entries have the huge page bit
+    if size == Size4KiB::SIZE {
+        flags -= PageTableFlags::HUGE_PAGE;
+    }
+    let table_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    interrupts::without_interrupts(|| {
+        entry.set_frame(frame, table_flags);
+        tlb::shootdown_page(table);
+
+        let table = unsafe { &mut *table.start_address().as_mut_ptr::<PageTable>() };
+        for (n, page) in table.iter_mut().enumerate() {
+            page.set_addr(start + n as u64 * size, flags);
+        }
+        // Translations of the huge page may still be cached
+        flush_all();
+    });
+    Ok(())
+}
+
+/// Make `entry` point to a page table, a new zeroed one if it is unused. `table` is
+/// the page the table it points to is reached at through the recursive entry
+fn next_table(entry: &mut PageTableEntry, table: Page) -> Result<(), MapToError<Size4KiB>> {
+    let table_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    if entry.is_unused() {
+        let frame = PageTableFrames
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        entry.set_frame(frame, table_flags);
+        tlb::shootdown_page(table);
+        unsafe { (*table.start_address().as_mut_ptr::<PageTable>()).zero() };
+    } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(MapToError::ParentEntryHugePage);
+    } else if !entry.flags().contains(table_flags) {
+        entry.set_flags(entry.flags() | table_flags);
+    }
+    Ok(())
+}
@@ -0,0 +1,299 @@
+//! Debugging mode of the kernel heap, selected with the `heap-debug` feature
+//!
+//! Every allocation is surrounded by red zones of [RED_ZONE] guard bytes that are checked
+//! when it is freed, and freed memory is filled with [POISON] so a use after free reads
+//! an obvious pattern. Live allocations are kept in a list with their size and the
+//! address they were allocated from. [poll] walks the list from the timer to catch
+//! corruption of allocations that are never freed, and runs single letter commands
+//! read from the serial interface: `c` checks the heap right away and `l` lists every
+//! live allocation to find leaks
+//!
+//! Caller addresses are taken from the frame pointer chain, they are only meaningful in
+//! a kernel built with `RUSTFLAGS=-Cforce-frame-pointers=yes`
+use super::{align_up, HeapAllocator, Locked};
+use crate::virt;
+use alloc::alloc::Layout;
+use core::{fmt, mem, ptr};
+use serial::serial_println;
+use x86_64::VirtAddr;
+
+/// Guard bytes before and after every allocation
+pub const RED_ZONE: usize = 16;
+
+/// Value of the guard bytes
+pub const GUARD: u8 = 0xFD;
+
+/// Value freed memory is filled with
+pub const POISON: u8 = 0xDD;
+
+/// Marks the header of a live allocation
+const MAGIC: u64 = 0x4845_4150_4C49_5645;
+
+/// Frames between [DebugAllocator::allocate] and the code that allocated
+const CALLER_DEPTH: usize = 4;
+
+/// Timer ticks between two checks of the heap
+const CHECK_TICKS: u64 = 100;
+
+/// Kept in front of the red zone before every allocation
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    caller: usize,
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+/// A corrupted allocation found by [DebugAllocator::check]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    pub addr: usize,
+    pub size: usize,
+    pub caller: usize,
+    pub what: &'static str,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of the {} byte allocation at {:#x} from {:#x}",
+            self.what, self.size, self.addr, self.caller
+        )
+    }
+}
+
+/// Wraps the heap allocator `A` with red zones, poisoning and a list of live allocations
+pub struct DebugAllocator<A> {
+    inner: A,
+    head: *mut Header,
+    live: usize,
+    ticks: u64,
+}
+
+// The headers are only reached through the allocator, which is behind a lock
+unsafe impl<A: Send> Send for DebugAllocator<A> {}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            head: ptr::null_mut(),
+            live: 0,
+            ticks: 0,
+        }
+    }
+
+    /// Number of allocations that have not been freed
+    pub fn live_allocations(&self) -> usize {
+        self.live
+    }
+
+    /// Verify the header and both red zones of every live allocation, returns the first
+    /// corrupted one
+    pub fn check(&self) -> Result<(), Corruption> {
+        let mut header = self.head;
+        while !header.is_null() {
+            unsafe {
+                check_allocation(header)?;
+                header = (*header).next;
+            }
+        }
+        Ok(())
+    }
+
+    /// Print every live allocation over serial
+    pub fn dump(&self) {
+        let mut header = self.head;
+        let mut bytes = 0;
+        while !header.is_null() {
+            unsafe {
+                serial_println!(
+                    "{:#x}: {} bytes from {:#x}",
+                    allocation_of(header) as usize,
+                    (*header).size,
+                    (*header).caller
+                );
+                bytes += (*header).size;
+                header = (*header).next;
+            }
+        }
+        serial_println!("{} live allocations, {} bytes", self.live, bytes);
+    }
+
+    fn push(&mut self, header: *mut Header) {
+        unsafe {
+            (*header).prev = ptr::null_mut();
+            (*header).next = self.head;
+            if !self.head.is_null() {
+                (*self.head).prev = header;
+            }
+        }
+        self.head = header;
+        self.live += 1;
+    }
+
+    fn remove(&mut self, header: *mut Header) {
+        unsafe {
+            let Header { prev, next, .. } = *header;
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        self.live -= 1;
+    }
+}
+
+impl<A: HeapAllocator> HeapAllocator for DebugAllocator<A> {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (inner, prefix) = match inner_layout(layout) {
+            Some(inner) => inner,
+            None => return ptr::null_mut(),
+        };
+        let base = self.inner.allocate(inner);
+        if base.is_null() {
+            return base;
+        }
+
+        unsafe {
+            let allocation = base.add(prefix);
+            let header = header_of(allocation);
+            header.write(Header {
+                magic: MAGIC,
+                size: layout.size(),
+                caller: caller(),
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            });
+            allocation.sub(RED_ZONE).write_bytes(GUARD, RED_ZONE);
+            allocation.add(layout.size()).write_bytes(GUARD, RED_ZONE);
+            self.push(header);
+            allocation
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let header = header_of(ptr);
+        if (*header).magic != MAGIC || (*header).size != layout.size() {
+            panic!(
+                "heap: free of {:#x}, which is not a live allocation",
+                ptr as usize
+            );
+        }
+        if let Err(corruption) = check_allocation(header) {
+            panic!("heap: {}", corruption);
+        }
+
+        self.remove(header);
+        let (inner, prefix) = inner_layout(layout).unwrap();
+        let base = ptr.sub(prefix);
+        base.write_bytes(POISON, inner.size());
+        self.inner.deallocate(base, inner);
+    }
+
+    unsafe fn add_memory(&mut self, start: usize, size: usize) {
+        self.inner.add_memory(start, size)
+    }
+
+    fn release_end(&mut self, min: usize, end: usize) -> usize {
+        self.inner.release_end(min, end)
+    }
+
+    fn required_layout(&self, layout: Layout) -> Layout {
+        inner_layout(layout).map_or(layout, |(inner, _)| self.inner.required_layout(inner))
+    }
+}
+
+/// Layout of the memory taken from the inner allocator for `layout` and the offset of
+/// the allocation into it. The header and front red zone take a multiple of the
+/// alignment, so the allocation stays aligned
+fn inner_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let prefix = align_up(mem::size_of::<Header>() + RED_ZONE, align);
+    let size = prefix.checked_add(layout.size())?.checked_add(RED_ZONE)?;
+    Some((Layout::from_size_align(size, align).ok()?, prefix))
+}
+
+fn header_of(allocation: *mut u8) -> *mut Header {
+    allocation.wrapping_sub(RED_ZONE + mem::size_of::<Header>()) as *mut Header
+}
+
+fn allocation_of(header: *mut Header) -> *mut u8 {
+    (header as *mut u8).wrapping_add(mem::size_of::<Header>() + RED_ZONE)
+}
+
+/// # Safety
+/// `header` has to be the header of a live allocation
+unsafe fn check_allocation(header: *mut Header) -> Result<(), Corruption> {
+    let allocation = allocation_of(header);
+    let corruption = |what| Corruption {
+        addr: allocation as usize,
+        size: (*header).size,
+        caller: (*header).caller,
+        what,
+    };
+
+    if (*header).magic != MAGIC {
+        return Err(corruption("Overwritten header"));
+    }
+    let front = core::slice::from_raw_parts(allocation.sub(RED_ZONE), RED_ZONE);
+    if front.iter().any(|&byte| byte != GUARD) {
+        return Err(corruption("Underflow"));
+    }
+    let back = core::slice::from_raw_parts(allocation.add((*header).size), RED_ZONE);
+    if back.iter().any(|&byte| byte != GUARD) {
+        return Err(corruption("Overflow"));
+    }
+    Ok(())
+}
+
+/// Return address [CALLER_DEPTH] frames up the frame pointer chain, 0 where the chain
+/// ends or leaves mapped memory
+fn caller() -> usize {
+    let mut frame: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame) };
+    for _ in 0..CALLER_DEPTH {
+        if !readable(frame) {
+            return 0;
+        }
+        frame = unsafe { *(frame as *const usize) };
+    }
+    if readable(frame + 8) {
+        unsafe { *((frame + 8) as *const usize) }
+    } else {
+        0
+    }
+}
+
+fn readable(addr: usize) -> bool {
+    addr != 0
+        && addr % mem::align_of::<usize>() == 0
+        && VirtAddr::try_new(addr as u64)
+            .map_or(false, |addr| virt::mapped_page_size(addr).is_some())
+}
+
+/// Check the heap every [CHECK_TICKS] calls and run `command` if it is one of the heap
+/// commands, called from the timer. Nothing is done while the heap is in use by the
+/// interrupted code
+pub fn poll<A: HeapAllocator>(allocator: &Locked<DebugAllocator<A>>, command: Option<u8>) {
+    let mut allocator = match allocator.try_lock() {
+        Some(allocator) => allocator,
+        None => return,
+    };
+    allocator.ticks += 1;
+
+    if allocator.ticks % CHECK_TICKS == 0 || command == Some(b'c') {
+        if let Err(corruption) = allocator.check() {
+            panic!("heap: {}", corruption);
+        }
+    }
+    if command == Some(b'l') {
+        allocator.dump();
+    }
+}
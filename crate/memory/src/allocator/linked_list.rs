@@ -1,6 +1,8 @@
 //! This is the linked list allocator implementation for the systems heap allocation
-use super::align_up;
-use core::mem;
+use super::{align_up, HeapAllocator};
+use alloc::alloc::Layout;
+use core::{mem, ptr};
+use x86_64::structures::paging::{PageSize, Size4KiB};
 
 // TODO DOCUMENT EVERYTHING!
 struct ListNode {
@@ -38,14 +40,44 @@ impl LinkedListAllocator {
         self.add_free_region(heap_start, heap_size);
     }
 
+    /// Put the region at `addr` into the list, which is kept in address order, and merge
+    /// it with the free regions right in front of and behind it
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
+
+        // The last region in front of `addr`, or the head if there is none
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let node_ptr = addr as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        let node = &mut *node_ptr;
+
+        if let Some(next) = node.next.take() {
+            if next.start_addr() == node.end_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+
+        // The head has no size, so it never ends where a region starts
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next.take();
+        } else {
+            current.next = Some(node);
+        }
     }
 
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
@@ -63,6 +95,22 @@ impl LinkedListAllocator {
         None
     }
 
+    /// Take the free region that ends at `end` out of the list
+    fn take_region_ending_at(&mut self, end: usize) -> Option<&'static mut ListNode> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if region.end_addr() == end {
+                let next = region.next.take();
+                let ret = current.next.take();
+                current.next = next;
+                return ret;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
         let alloc_start = align_up(region.start_addr(), align);
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
@@ -89,20 +137,23 @@ impl LinkedListAllocator {
     }
 }
 
-use super::Locked;
-use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr;
-
-unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
+impl HeapAllocator for LinkedListAllocator {
+    /// First fit allocation of `layout`
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+            let padding = alloc_start - region.start_addr();
+            unsafe {
+                if excess_size > 0 {
+                    self.add_free_region(alloc_end, excess_size);
+                }
+                // Room left in front of an aligned allocation
+                if padding >= mem::size_of::<ListNode>() {
+                    self.add_free_region(region.start_addr(), padding);
+                }
             }
             alloc_start as *mut u8
         } else {
@@ -110,9 +161,31 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
 
-        self.lock().add_free_region(ptr as usize, size)
+    unsafe fn add_memory(&mut self, start: usize, size: usize) {
+        self.add_free_region(start, size)
+    }
+
+    fn release_end(&mut self, min: usize, end: usize) -> usize {
+        // Adjacent free regions are merged, at most one reaches up to `end`
+        let free_start = self
+            .take_region_ending_at(end)
+            .map_or(end, |region| region.start_addr());
+
+        let page_size = Size4KiB::SIZE as usize;
+        let mut cut = align_up(free_start.max(min), page_size);
+        // What stays has to hold a node
+        if cut > free_start && cut - free_start < mem::size_of::<ListNode>() {
+            cut += page_size;
+        }
+        let cut = cut.min(end);
+        if cut > free_start {
+            unsafe { self.add_free_region(free_start, cut - free_start) };
+        }
+        cut
     }
 }
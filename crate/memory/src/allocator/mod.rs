@@ -1,57 +1,221 @@
+//! The kernel heap
+//!
+//! The heap starts out with [HEAP_SIZE] bytes mapped at [HEAP_START] and grows on demand
+//! whenever the allocator runs out of memory, by mapping new frames after its end until
+//! it reaches the limit set with [set_heap_limit]. Free memory at the end of the heap can
+//! be given back to the frame allocator with [trim_heap]
 pub const HEAP_START: usize = 0xFFFF_FF00_004A_0000;
 
 pub const HEAP_SIZE: usize = 200 * 1024; // 200 KiB
 
+/// Default for the size the heap may grow to
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+
+/// Smallest amount the heap grows by, larger allocations grow it by their size
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+
 extern crate alloc;
 
 pub mod linked_list;
 
-use crate::{phys::FRAME_ALLOCATOR, KERNEL_PAGE_TABLE};
-use linked_list::LinkedListAllocator;
+pub mod slab;
+
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+
+use crate::virt;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The allocator managing the heap, the slab allocator unless the `linked-list-heap`
+/// feature selects the first fit allocator to compare them
+#[cfg(not(feature = "linked-list-heap"))]
+pub type Heap = slab::SlabAllocator;
+
+#[cfg(feature = "linked-list-heap")]
+pub type Heap = linked_list::LinkedListAllocator;
 
+/// The global allocator, the `heap-debug` feature wraps the heap allocator with
+/// [DebugAllocator](debug::DebugAllocator)
+#[cfg(not(feature = "heap-debug"))]
 #[global_allocator]
-pub static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+pub static ALLOCATOR: Locked<Heap> = Locked::new(Heap::new());
+
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+pub static ALLOCATOR: Locked<debug::DebugAllocator<Heap>> =
+    Locked::new(debug::DebugAllocator::new(Heap::new()));
 
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
+/// End of the mapped part of the heap, only changed with the allocator locked
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+
+/// Size the heap may grow to
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Bytes of the allocations that have not been freed
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Most bytes [HEAP_USED] has been at
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// An allocator the heap is managed by, it is given more memory when it runs out
+pub trait HeapAllocator {
+    /// Allocate memory for `layout`, a null pointer if no free memory is large enough
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` has to be allocated by this allocator with the same `layout`
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// Hand the allocator `size` more bytes from `start`
+    ///
+    /// # Safety
+    /// The memory has to be mapped and unused
+    unsafe fn add_memory(&mut self, start: usize, size: usize);
+
+    /// Take the free memory at the end of the allocators memory at `end` out of the
+    /// allocator from the first page boundary at or above `min`. Returns where the memory
+    /// of the allocator ends afterwards
+    fn release_end(&mut self, min: usize, end: usize) -> usize;
+
+    /// Layout of the memory an allocation of `layout` takes from the heap, the heap grows
+    /// by at least that much when the allocation does not fit
+    fn required_layout(&self, layout: Layout) -> Layout {
+        layout
+    }
+}
+
+/// Map the heap and hand it to the allocator, the heap is mapped with huge pages where
+/// its placement allows
 pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_page_end = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_page_end)
+        Page::range(heap_start_page, heap_page_end + 1)
     };
 
-    for page in page_range {
-        let frame = FRAME_ALLOCATOR
-            .wait()
-            .unwrap()
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-        unsafe {
-            KERNEL_PAGE_TABLE
-                .wait()
-                .unwrap()
-                .lock()
-                .map_to(page, frame, flags, FRAME_ALLOCATOR.wait().as_mut().unwrap())?
-                .flush()
-        };
-    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    virt::map_zeroed(page_range, flags)?;
 
+    let mut allocator = ALLOCATOR.lock();
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::Release);
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        allocator.add_memory(HEAP_START, HEAP_SIZE);
     }
     Ok(())
 }
 
+/// Bytes of the heap that are mapped
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::Acquire) - HEAP_START
+}
+
+/// Bytes allocated from the heap and not freed yet, without what the allocator itself
+/// takes for alignment and bookkeeping
+pub fn heap_used() -> usize {
+    HEAP_USED.load(Ordering::Acquire)
+}
+
+/// Most bytes that have been allocated from the heap at once since boot
+pub fn heap_peak() -> usize {
+    HEAP_PEAK.load(Ordering::Acquire)
+}
+
+/// Let the heap grow to `bytes`, a limit below the current size keeps the heap from
+/// growing any further
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes, Ordering::Release);
+}
+
+/// Map more memory after the end of the heap, enough for an allocation of `layout`.
+/// Returns the new memory, None once the heap would grow past its limit or no frames
+/// are left
+fn grow_heap(layout: Layout) -> Option<(usize, usize)> {
+    let page_size = Size4KiB::SIZE as usize;
+    let start = HEAP_END.load(Ordering::Acquire);
+    // Room to align the allocation and for a free region after it
+    let needed = layout.size() + layout.align() + 2 * mem::size_of::<usize>();
+    let size = align_up(needed.max(HEAP_GROW_SIZE), page_size);
+    if start - HEAP_START + size > HEAP_LIMIT.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let first = Page::containing_address(VirtAddr::new(start as u64));
+    let pages = Page::range(first, first + (size / page_size) as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    virt::map_zeroed(pages, flags).ok()?;
+
+    HEAP_END.store(start + size, Ordering::Release);
+    Some((start, size))
+}
+
+/// Give the free pages at the end of the heap back to the frame allocator, the heap
+/// never shrinks below [HEAP_SIZE]. Returns the number of bytes given back
+pub fn trim_heap() -> usize {
+    let mut allocator = ALLOCATOR.lock();
+    let end = HEAP_END.load(Ordering::Acquire);
+    let new_end = allocator.release_end(HEAP_START + HEAP_SIZE, end);
+    if new_end < end {
+        let first = Page::containing_address(VirtAddr::new(new_end as u64));
+        let last = Page::containing_address(VirtAddr::new(end as u64));
+        if virt::unmap_range(Page::range(first, last)).is_err() {
+            // Nothing was unmapped, the heap keeps the pages
+            unsafe { allocator.add_memory(new_end, end - new_end) };
+            return 0;
+        }
+        HEAP_END.store(new_end, Ordering::Release);
+    }
+    end - new_end
+}
+
+/// Run the periodic checks of the heap debugging mode and the `command` read from
+/// the serial interface, called on every timer tick. Does nothing without the
+/// `heap-debug` feature
+pub fn poll(command: Option<u8>) {
+    #[cfg(feature = "heap-debug")]
+    debug::poll(&ALLOCATOR, command);
+    #[cfg(not(feature = "heap-debug"))]
+    let _ = command;
+}
+
+/// Whether the heap is locked, by code an interrupt handler may have interrupted
+pub fn is_locked() -> bool {
+    ALLOCATOR.try_lock().is_none()
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        loop {
+            let ptr = allocator.allocate(layout);
+            if !ptr.is_null() {
+                let used = HEAP_USED.fetch_add(layout.size(), Ordering::AcqRel) + layout.size();
+                HEAP_PEAK.fetch_max(used, Ordering::AcqRel);
+                return ptr;
+            }
+            match grow_heap(allocator.required_layout(layout)) {
+                Some((start, size)) => allocator.add_memory(start, size),
+                None => return ptr::null_mut(),
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout);
+        HEAP_USED.fetch_sub(layout.size(), Ordering::AcqRel);
+    }
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -66,6 +230,10 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
 }
 
 fn align_up(addr: usize, align: usize) -> usize {
@@ -0,0 +1,117 @@
+//! Slab allocator for the systems heap allocation
+//!
+//! Allocations up to [MAX_BLOCK_SIZE] bytes are rounded up to a power of two size class
+//! and served from the free list of their class. An empty list is refilled with a slab,
+//! a page of the heap cut into blocks of the class. Freed blocks go back to the list of
+//! their class, so blocks of a class are reused instead of fragmenting the heap. Larger
+//! allocations and the slabs themselves come from a [LinkedListAllocator]
+use super::{linked_list::LinkedListAllocator, HeapAllocator};
+use alloc::alloc::Layout;
+use core::{mem, ptr};
+
+/// Sizes of the blocks of each class, each size is also the alignment of its blocks
+const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Allocations larger than this take the large object path
+pub const MAX_BLOCK_SIZE: usize = BLOCK_SIZES[BLOCK_SIZES.len() - 1];
+
+/// Bytes taken from the large object allocator to refill an empty class
+const SLAB_SIZE: usize = 4096;
+
+/// A free block, stored in the block itself
+struct BlockNode {
+    next: Option<&'static mut BlockNode>,
+}
+
+pub struct SlabAllocator {
+    /// Free blocks of each class in the order of [BLOCK_SIZES]
+    lists: [Option<&'static mut BlockNode>; BLOCK_SIZES.len()],
+    /// Slabs and allocations too large for a class
+    large: LinkedListAllocator,
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut BlockNode> = None;
+        Self {
+            lists: [EMPTY; BLOCK_SIZES.len()],
+            large: LinkedListAllocator::new(),
+        }
+    }
+
+    /// Number of free blocks in the class `size` bytes belong to, None for sizes of the
+    /// large object path
+    pub fn free_blocks(&self, size: usize) -> Option<usize> {
+        let mut node = self.lists[class_of(size, 1)?].as_deref();
+        let mut count = 0;
+        while let Some(block) = node {
+            count += 1;
+            node = block.next.as_deref();
+        }
+        Some(count)
+    }
+
+    /// Cut a new slab into blocks of `class`, false once the heap is full
+    fn refill(&mut self, class: usize) -> bool {
+        let size = BLOCK_SIZES[class];
+        let layout = Layout::from_size_align(SLAB_SIZE, size).unwrap();
+        let slab = self.large.allocate(layout);
+        if slab.is_null() {
+            return false;
+        }
+
+        for offset in (0..SLAB_SIZE).step_by(size).rev() {
+            unsafe { self.push(class, slab.add(offset)) };
+        }
+        true
+    }
+
+    unsafe fn push(&mut self, class: usize, ptr: *mut u8) {
+        let node = BlockNode {
+            next: self.lists[class].take(),
+        };
+        let node_ptr = ptr as *mut BlockNode;
+        node_ptr.write(node);
+        self.lists[class] = Some(&mut *node_ptr);
+    }
+}
+
+/// Index of the smallest class whose blocks hold `size` bytes aligned to `align`
+fn class_of(size: usize, align: usize) -> Option<usize> {
+    let size = size.max(align).max(mem::size_of::<BlockNode>());
+    BLOCK_SIZES
+        .iter()
+        .position(|&block_size| block_size >= size)
+}
+
+impl HeapAllocator for SlabAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let class = match class_of(layout.size(), layout.align()) {
+            Some(class) => class,
+            None => return self.large.allocate(layout),
+        };
+
+        if self.lists[class].is_none() && !self.refill(class) {
+            return ptr::null_mut();
+        }
+        let block = self.lists[class].take().unwrap();
+        self.lists[class] = block.next.take();
+        block as *mut BlockNode as *mut u8
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match class_of(layout.size(), layout.align()) {
+            Some(class) => self.push(class, ptr),
+            None => self.large.deallocate(ptr, layout),
+        }
+    }
+
+    unsafe fn add_memory(&mut self, start: usize, size: usize) {
+        self.large.add_memory(start, size)
+    }
+
+    /// Slabs are never given back, only free memory of the large object path is
+    fn release_end(&mut self, min: usize, end: usize) -> usize {
+        self.large.release_end(min, end)
+    }
+}
@@ -0,0 +1,102 @@
+//! Memory shared with devices that read and write it by its physical address, like the
+//! descriptor rings of disk and network controllers
+//!
+//! A [DmaBuffer] lies on frames that follow each other in physical memory below the
+//! limit of the device, so the device sees it as one block from [DmaBuffer::phys_addr].
+//! It is mapped [uncached](CacheMode::Uncached): every access of the kernel goes to
+//! memory in program order, and what the device writes is read without flushing any
+//! cache, even for devices that do not snoop the caches of the processor
+use crate::{kpbox::KpBox, pat::CacheMode};
+use core::{
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    ptr,
+};
+use os_units::Bytes;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A `T` in memory shared with a device
+pub struct DmaBuffer<T: ?Sized> {
+    memory: KpBox<T>,
+    phys: PhysAddr,
+}
+
+impl<T> DmaBuffer<T> {
+    /// Place `x` in a buffer that ends at or below `phys_limit`, `None` if there are no
+    /// contiguous frames left below it
+    pub fn new(x: T, phys_limit: PhysAddr) -> Option<Self> {
+        let bytes = Bytes::new(mem::size_of::<T>());
+        let (memory, phys) = KpBox::from_contiguous(bytes, 1, phys_limit, CacheMode::Uncached)?;
+        // SAFETY: The memory is mapped, page aligned and holds no value yet
+        unsafe { ptr::write(memory.virt_addr().as_mut_ptr(), x) };
+        Some(Self { memory, phys })
+    }
+}
+
+impl<T: Clone> DmaBuffer<[T]> {
+    /// A buffer of `num_of_elements` copies of `x` that ends at or below `phys_limit`,
+    /// `None` if there are no contiguous frames left below it or the elements do not fit
+    /// into the address space. The buffer may be empty
+    pub fn new_slice(x: T, num_of_elements: usize, phys_limit: PhysAddr) -> Option<Self> {
+        let bytes = Bytes::new(mem::size_of::<T>().checked_mul(num_of_elements)?);
+        let (memory, phys) =
+            KpBox::from_contiguous(bytes, num_of_elements, phys_limit, CacheMode::Uncached)?;
+        let first = memory.virt_addr().as_mut_ptr::<T>();
+        for i in 0..num_of_elements {
+            // SAFETY: The element is inside the mapped memory and aligned like the first one
+            unsafe { ptr::write(first.add(i), x.clone()) };
+        }
+        Some(Self { memory, phys })
+    }
+}
+
+impl<T: ?Sized> DmaBuffer<T> {
+    /// The address the device reaches the buffer at
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.memory.virt_addr()
+    }
+
+    pub fn bytes(&self) -> Bytes {
+        self.memory.bytes()
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.memory
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.memory
+    }
+}
+
+impl<T: Clone> Deref for DmaBuffer<[T]> {
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        &self.memory
+    }
+}
+
+impl<T: Clone> DerefMut for DmaBuffer<[T]> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.memory
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("virt", &self.virt_addr())
+            .field("phys", &self.phys)
+            .field("bytes", &self.bytes().as_usize())
+            .finish()
+    }
+}
@@ -1,11 +1,15 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 #![allow(clippy::type_repetition_in_bounds)]
-use crate::{active_level_4_table, virt::allocate_new};
+use crate::{
+    active_level_4_table,
+    pat::CacheMode,
+    virt::{allocate_contiguous_new, allocate_new, deallocate_new},
+};
 use core::{
     convert::TryFrom,
     fmt,
     marker::PhantomData,
-    mem,
+    mem::{self, MaybeUninit},
     ops::{Deref, DerefMut},
     ptr, slice,
 };
@@ -18,9 +22,18 @@ use x86_64::{
 pub struct KpBox<T: ?Sized> {
     virt: VirtAddr,
     bytes: Bytes,
+    /// Number of elements of a slice, 1 for a single value. Kept apart from `bytes` as
+    /// a slice of zero sized elements has no bytes
+    len: usize,
     _marker: PhantomData<T>,
 }
 impl<T> KpBox<T> {
+    /// Allocate a box whose memory is all zero bytes, like a `T` of only zeros if that
+    /// is a valid `T`
+    pub fn new_zeroed() -> KpBox<MaybeUninit<T>> {
+        KpBox::from_bytes(Bytes::new(mem::size_of::<T>()), 1)
+    }
+
     fn write_initial_value(&mut self, x: T) {
         // SAFETY: This operation is safe because the memory `self.virt.as_mut_ptr` points is
         // allocated, and is page-aligned.
@@ -29,6 +42,23 @@ impl<T> KpBox<T> {
         }
     }
 }
+impl<T> KpBox<MaybeUninit<T>> {
+    /// # Safety
+    ///
+    /// The memory of the box must hold a valid `T`, like a zeroed box of a type for which
+    /// all zeros is a valid value.
+    #[must_use]
+    pub unsafe fn assume_init(self) -> KpBox<T> {
+        let b = KpBox {
+            virt: self.virt,
+            bytes: self.bytes,
+            len: self.len,
+            _marker: PhantomData,
+        };
+        mem::forget(self);
+        b
+    }
+}
 impl<T> Deref for KpBox<T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
@@ -76,7 +106,7 @@ where
 impl<T> From<T> for KpBox<T> {
     fn from(x: T) -> Self {
         let bytes = Bytes::new(mem::size_of::<T>());
-        let mut page_box = Self::from_bytes(bytes);
+        let mut page_box = Self::from_bytes(bytes, 1);
         page_box.write_initial_value(x);
         page_box
     }
@@ -87,24 +117,36 @@ where
     T: Clone,
 {
     pub fn new_slice(x: T, num_of_elements: usize) -> Self {
-        let bytes = Bytes::new(mem::size_of::<T>() * num_of_elements);
-        let mut page_box = Self::from_bytes(bytes);
-        page_box.write_all_elements_with_same_value(x);
-        page_box
+        Self::from_fn(num_of_elements, |_| x.clone())
     }
 
-    fn write_all_elements_with_same_value(&mut self, x: T) {
-        for i in 0..self.len() {
-            let ptr: usize = usize::try_from(self.virt.as_u64()).unwrap() + mem::size_of::<T>() * i;
+    /// A slice of `num_of_elements` elements, the element at `i` is `f(i)`. The slice may
+    /// be empty
+    fn from_fn(num_of_elements: usize, mut f: impl FnMut(usize) -> T) -> Self {
+        let page_box = Self::from_bytes(Self::slice_bytes(num_of_elements), num_of_elements);
+        for i in 0..num_of_elements {
+            let ptr: usize =
+                usize::try_from(page_box.virt.as_u64()).unwrap() + mem::size_of::<T>() * i;
 
             // SAFETY: This operation is safe. The memory ptr points is allocated and is aligned
             // because the first elements is page-aligned.
-            unsafe { ptr::write(ptr as *mut T, x.clone()) }
+            unsafe { ptr::write(ptr as *mut T, f(i)) }
         }
+        page_box
+    }
+
+    /// # Panics
+    ///
+    /// This method panics if `num_of_elements` elements do not fit into the address space.
+    fn slice_bytes(num_of_elements: usize) -> Bytes {
+        let bytes = mem::size_of::<T>()
+            .checked_mul(num_of_elements)
+            .expect("The slice is too large.");
+        Bytes::new(bytes)
     }
 
     fn num_of_elements(&self) -> usize {
-        self.bytes.as_usize() / mem::size_of::<T>()
+        self.len
     }
 }
 impl<T> Deref for KpBox<[T]>
@@ -147,19 +189,15 @@ where
     T: Clone,
 {
     fn clone(&self) -> Self {
-        let mut b = Self::new_slice(self[0].clone(), self.len());
-
-        for (dst, src) in b.iter_mut().zip(self.iter()) {
-            *dst = src.clone();
-        }
-
-        b
+        Self::from_fn(self.len(), |i| self[i].clone())
     }
 }
 impl<T: Copy> From<&[T]> for KpBox<[T]> {
     fn from(s: &[T]) -> Self {
-        let b = Self::new_slice(s[0], s.len());
+        let b = Self::from_bytes(Self::slice_bytes(s.len()), s.len());
 
+        // SAFETY: This operation is safe because the memory of `b` holds `s.len()` elements
+        // and can not overlap `s`, an empty `s` copies nothing.
         unsafe {
             ptr::copy_nonoverlapping(s.as_ptr(), b.virt_addr().as_mut_ptr(), s.len());
         }
@@ -190,19 +228,51 @@ impl<T: ?Sized> KpBox<T> {
         self.bytes
     }
 
-    fn from_bytes(bytes: Bytes) -> Self {
+    /// Map `bytes` zeroed bytes for `len` elements, an empty box still gets a page-aligned
+    /// address of its own
+    fn from_bytes(bytes: Bytes, len: usize) -> Self {
         let virt = allocate_new(bytes.as_num_of_pages()).expect("Failed to allocate pages.");
 
         Self {
             virt,
             bytes,
+            len,
             _marker: PhantomData,
         }
     }
+
+    /// Map `bytes` zeroed bytes for `len` elements with the cache mode `mode` on frames that
+    /// follow each other in physical memory and end at or below `phys_limit`, returns the box
+    /// and the physical address it starts at
+    pub(crate) fn from_contiguous(
+        bytes: Bytes,
+        len: usize,
+        phys_limit: PhysAddr,
+        mode: CacheMode,
+    ) -> Option<(Self, PhysAddr)> {
+        let (virt, phys) = allocate_contiguous_new(bytes.as_num_of_pages(), phys_limit, mode)?;
+        let b = Self {
+            virt,
+            bytes,
+            len,
+            _marker: PhantomData,
+        };
+        Some((b, phys))
+    }
+}
+impl KpBox<[u8]> {
+    /// Allocate `bytes` zeroed bytes on physically contiguous frames that end at or below
+    /// `phys_limit`, for devices that read and write the memory by its physical address.
+    /// Returns the box and the physical address it starts at, or `None` if there is no
+    /// such block of frames left.
+    #[must_use]
+    pub fn new_contiguous(bytes: Bytes, phys_limit: PhysAddr) -> Option<(Self, PhysAddr)> {
+        Self::from_contiguous(bytes, bytes.as_usize(), phys_limit, CacheMode::WriteBack)
+    }
 }
 impl<T: ?Sized> Drop for KpBox<T> {
     fn drop(&mut self) {
         let num_of_pages = self.bytes.as_num_of_pages::<Size4KiB>();
-        super::deallocate_pages(self.virt, num_of_pages);
+        deallocate_new(self.virt, num_of_pages);
     }
 }
@@ -1,36 +1,41 @@
 //! OS Memory functionality and Structures
 #![no_std]
 #![feature(asm)]
-#![feature(const_mut_refs, lang_items, alloc_error_handler)]
+#![feature(const_mut_refs, const_btree_new, lang_items, alloc_error_handler)]
 
+use address_space::AddressSpace;
 use bootloader::boot_info::Optional;
-use core::ops::Index;
 use spin::{Mutex, Once};
-use virt::deallocate_pages;
-use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{PageTable, RecursivePageTable},
-};
+use x86_64::structures::paging::{PageTable, RecursivePageTable};
 
 extern crate alloc;
 
+pub mod address_space;
 pub mod allocator;
+pub mod dma;
 pub mod kpbox;
+pub mod meminfo;
+pub mod pat;
 pub mod phys;
+pub mod range;
+pub mod tlb;
 pub mod virt;
 
-pub static RECURSIVE_INDEX: Once<Mutex<u16>> = Once::new();
+/// Index of the level 4 entry every address space maps its own level 4 table at
+pub static RECURSIVE_INDEX: Once<u16> = Once::new();
 
 pub static KERNEL_PAGE_TABLE: Once<Mutex<RecursivePageTable>> = Once::new();
 
 ///Using the recursive index find the level 4 table address and
-///create a page table
+///create a page table, and program the [PAT](pat) for the cache modes of mappings
 ///
 /// # Safety
 /// This function is unsafe because if the recursive index is not a valid index this
 /// can result in undefined behavior
 pub unsafe fn init(recursive_index: Optional<u16>) {
-    RECURSIVE_INDEX.call_once(|| Mutex::new(recursive_index.into_option().unwrap()));
+    RECURSIVE_INDEX.call_once(|| recursive_index.into_option().unwrap());
+    pat::init();
+    AddressSpace::init_kernel();
     let level_4_table = active_level_4_table();
     //mark_pages_unused();
     let kernel_page_table = RecursivePageTable::new(level_4_table).unwrap();
@@ -39,7 +44,7 @@ pub unsafe fn init(recursive_index: Optional<u16>) {
 
 ///Find the base address of the active level page table with the recursive index
 pub fn active_level_4_table() -> &'static mut PageTable {
-    let r = *RECURSIVE_INDEX.wait().unwrap().lock() as u64;
+    let r = *RECURSIVE_INDEX.wait().unwrap() as u64;
     let sign: u64;
 
     if r > 255 {
@@ -54,23 +59,9 @@ pub fn active_level_4_table() -> &'static mut PageTable {
     unsafe { &mut *level_4_table }
 }
 
-///TODO
+/// Load the address space the bootloader handed over, see [AddressSpace::kernel]
 pub fn swap_to_kernel_table() {
-    *RECURSIVE_INDEX.wait().unwrap().lock() = 508;
-    unsafe {
-        Cr3::write(
-            KERNEL_PAGE_TABLE
-                .wait()
-                .unwrap()
-                .lock()
-                .level_4_table()
-                .index(508)
-                .frame()
-                .unwrap(),
-            Cr3::read().1,
-        )
-    };
-    x86_64::instructions::tlb::flush_all();
+    AddressSpace::kernel().activate();
 }
 
 #[alloc_error_handler]
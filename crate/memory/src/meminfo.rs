@@ -0,0 +1,81 @@
+//! Counters of how much memory is in use, read together with [meminfo]
+use core::fmt;
+
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    allocator,
+    phys::{PhysFrameAllocator, FRAME_ALLOCATOR},
+};
+
+/// The memory counters at one point in time, laid out for user space
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemInfo {
+    /// Usable frames of RAM
+    pub total_frames: u64,
+    pub free_frames: u64,
+    /// Frames in use, including the page table frames
+    pub used_frames: u64,
+    pub page_table_frames: u64,
+    /// Bytes of the kernel heap that are mapped
+    pub heap_size: u64,
+    /// Bytes allocated from the kernel heap and not freed yet
+    pub heap_used: u64,
+    /// Most bytes allocated from the kernel heap at once since boot
+    pub heap_peak: u64,
+}
+
+/// Read every memory counter
+pub fn meminfo() -> MemInfo {
+    let allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    counters(&allocator)
+}
+
+/// [meminfo] unless the frame allocator is in use, for interrupt handlers that could
+/// have interrupted the code holding it
+pub fn try_meminfo() -> Option<MemInfo> {
+    let allocator = FRAME_ALLOCATOR.wait()?.inner.try_lock()?;
+    Some(counters(&allocator))
+}
+
+/// Frames owned by each of the processes with the ids `owners`, which has to be sorted.
+/// None while the frame allocator is in use like [try_meminfo]
+pub fn try_owned_frames(owners: &[u32]) -> Option<Vec<u64>> {
+    // Allocated up front, growing the heap with the frame allocator locked would deadlock
+    let mut counts = vec![0; owners.len()];
+    let allocator = FRAME_ALLOCATOR.wait()?.inner.try_lock()?;
+    allocator.owned_frames(owners, &mut counts);
+    Some(counts)
+}
+
+fn counters(allocator: &PhysFrameAllocator) -> MemInfo {
+    let total_frames = allocator.total_frames();
+    let free_frames = allocator.free_frames();
+
+    MemInfo {
+        total_frames,
+        free_frames,
+        used_frames: total_frames - free_frames,
+        page_table_frames: allocator.table_frames(),
+        heap_size: allocator::heap_size() as u64,
+        heap_used: allocator::heap_used() as u64,
+        heap_peak: allocator::heap_peak() as u64,
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "frames: {} used, {} free, {} total, {} page tables",
+            self.used_frames, self.free_frames, self.total_frames, self.page_table_frames
+        )?;
+        write!(
+            f,
+            "heap: {} bytes used, {} peak, {} mapped",
+            self.heap_used, self.heap_peak, self.heap_size
+        )
+    }
+}
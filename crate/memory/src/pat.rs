@@ -0,0 +1,110 @@
+//! Cache modes of mappings, selected through the page attribute table (PAT)
+//!
+//! The PWT, PCD and PAT bits of a page table entry pick one of the eight entries of the
+//! PAT MSR, which holds the memory type of the mapped page. [init] keeps the power on
+//! types of the entries except for entry 1, which becomes write-combining. Mappings use
+//! these entries
+//!
+//! | PAT | PCD | PWT | Type                  |
+//! |-----|-----|-----|-----------------------|
+//! | 0   | 0   | 0   | Write-back            |
+//! | 0   | 0   | 1   | Write-combining       |
+//! | 0   | 1   | 0   | Uncached, overridable |
+//! | 0   | 1   | 1   | Uncached              |
+//! | 1   | 0   | 1   | Write-through         |
+//!
+//! Entries without PWT and PCD, all the bootloader makes, stay write-back. The PAT bit
+//! of a 4 KiB entry is the bit that marks huge pages in the tables above, huge pages
+//! have it in their address field instead, so [CacheMode::WriteThrough] mappings are
+//! only made of 4 KiB pages. A processor without PAT keeps the power on types and maps
+//! [CacheMode::WriteCombining] uncached
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::tlb, registers::model_specific::Msr, structures::paging::PageTableFlags,
+};
+
+/// The PAT MSR
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types of the PAT entries
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+const UNCACHED: u64 = 0x07;
+
+/// The table [init] programs, entry 0 in the lowest byte
+const TABLE: [u64; 8] = [
+    WRITE_BACK,
+    WRITE_COMBINING,
+    UNCACHED,
+    UNCACHEABLE,
+    WRITE_BACK,
+    WRITE_THROUGH,
+    UNCACHED,
+    UNCACHEABLE,
+];
+
+/// The PAT bit of a 4 KiB page table entry
+pub const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static PROGRAMMED: AtomicBool = AtomicBool::new(false);
+
+/// How the processor caches the memory of a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Ordinary RAM
+    WriteBack,
+    /// Reads are cached, writes go to memory right away
+    WriteThrough,
+    /// Writes are collected and written in bursts, for framebuffers
+    WriteCombining,
+    /// Every access goes to the device, for registers
+    Uncached,
+}
+
+impl CacheMode {
+    /// The PWT and PCD flags of the entries of a mapping with this mode and whether
+    /// they need the PAT bit too
+    pub fn flags(self) -> (PageTableFlags, bool) {
+        let pwt = PageTableFlags::WRITE_THROUGH;
+        let pcd = PageTableFlags::NO_CACHE;
+        match (self, PROGRAMMED.load(Ordering::Acquire)) {
+            (CacheMode::WriteBack, _) => (PageTableFlags::empty(), false),
+            (CacheMode::WriteThrough, true) => (pwt, true),
+            (CacheMode::WriteThrough, false) => (pwt, false),
+            (CacheMode::WriteCombining, true) => (pwt, false),
+            (CacheMode::WriteCombining, false) => (pcd, false),
+            (CacheMode::Uncached, _) => (pwt | pcd, false),
+        }
+    }
+}
+
+/// Whether the processor has a PAT
+pub fn supported() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 16) != 0 }
+}
+
+/// Program the PAT as described in the [module](self) documentation, has to run before
+/// any mapping with a [CacheMode] other than write-back is made
+pub fn init() {
+    if !supported() {
+        return;
+    }
+
+    let table = TABLE
+        .iter()
+        .enumerate()
+        .fold(0, |table, (i, kind)| table | kind << (8 * i));
+    unsafe {
+        // Nothing may be cached under the old types of the entries that change
+        asm!("wbinvd", options(nostack));
+        Msr::new(IA32_PAT).write(table);
+        asm!("wbinvd", options(nostack));
+    }
+    tlb::flush_all();
+    PROGRAMMED.store(true, Ordering::Release);
+}
@@ -1,6 +1,8 @@
 //! Physical Frame structures and functionality
 
+use bitflags::bitflags;
 use bootloader::boot_info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
+use core::{mem, ops::Range, slice};
 use spin::{Mutex, Once};
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::{PhysAddr, VirtAddr};
@@ -9,95 +11,706 @@ use x86_64::{PhysAddr, VirtAddr};
 pub static FRAME_ALLOCATOR: Once<PhysFrameAllocatorWrapper> = Once::new();
 pub static BYTES_AVAILABLE_RAM: Once<u64> = Once::new();
 
-const BITMAP_START: usize = 0xFFFF_FF00_0000_0000;
+bitflags! {
+    /// What a frame is used for, kept in its [FrameDescriptor]
+    pub struct FrameFlags: u8 {
+        /// Has to stay at its physical address, a device may be using it
+        const PINNED = 1 << 0;
+        /// Written to since it was last written back to its backing store
+        const DIRTY = 1 << 1;
+        /// Shared by pages that copy it on the first write
+        const COPY_ON_WRITE = 1 << 2;
+        /// Holds memory of the kernel itself rather than of a process
+        const KERNEL = 1 << 3;
+        /// Holds a page table, the allocator counts these frames
+        const PAGE_TABLE = 1 << 4;
+    }
+}
+
+/// Owner hint of a frame nobody claimed
+pub const NO_OWNER: u32 = 0;
+
+/// What is known about an allocated frame. Every frame taken from the allocator starts
+/// with a single reference and goes back to it once the last one is dropped with
+/// [put_frame]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameDescriptor {
+    count: u32,
+    pub flags: FrameFlags,
+    /// Id of the process the frame was allocated for, [NO_OWNER] if unknown
+    pub owner: u32,
+}
+
+impl FrameDescriptor {
+    const FREE: FrameDescriptor = FrameDescriptor {
+        count: 0,
+        flags: FrameFlags::empty(),
+        owner: NO_OWNER,
+    };
+
+    /// Number of references to the frame, 0 while it is free
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+/// Copy of the descriptor of `frame`, None for frames the allocator does not track like
+/// device memory
+pub fn frame_descriptor(frame: PhysFrame) -> Option<FrameDescriptor> {
+    FRAME_ALLOCATOR
+        .wait()
+        .unwrap()
+        .inner
+        .lock()
+        .descriptor_mut(frame)
+        .map(|descriptor| *descriptor)
+}
+
+/// Change the flags or the owner of `frame`, returns false for frames that are not
+/// tracked. [FrameFlags::PAGE_TABLE] is kept as it was, it is only set through
+/// [PageTableFrames] and [mark](PhysFrameAllocator::mark)
+pub fn update_frame(frame: PhysFrame, f: impl FnOnce(&mut FrameDescriptor)) -> bool {
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    allocator
+        .descriptor_mut(frame)
+        .map(|descriptor| {
+            let table = descriptor.flags & FrameFlags::PAGE_TABLE;
+            f(descriptor);
+            descriptor.flags = (descriptor.flags - FrameFlags::PAGE_TABLE) | table;
+        })
+        .is_some()
+}
+
+/// Add a reference to a frame that is about to be mapped by another page, returns the
+/// number of references it has now. Untracked frames have none
+pub fn get_frame(frame: PhysFrame) -> u32 {
+    FRAME_ALLOCATOR.wait().unwrap().inner.lock().get(frame)
+}
+
+/// Number of pages that map a frame
+pub fn frame_references(frame: PhysFrame) -> u32 {
+    frame_descriptor(frame).map_or(0, |descriptor| descriptor.count)
+}
+
+/// Drop a reference to a frame that has been unmapped from a page, the frame is
+/// given back to the frame allocator when it was the last reference
+///
+/// Returns true if the frame was deallocated
+///
+/// # Safety
+/// The caller must have unmapped the frame from the page it held the reference for
+pub unsafe fn put_frame(frame: PhysFrame) -> bool {
+    FRAME_ALLOCATOR.wait().unwrap().inner.lock().put(frame, 0)
+}
+
+/// [put_frame] for every frame of the 2^`order` frames from `frame`, like the frames of
+/// a huge page. Returns true if every frame was deallocated
+///
+/// # Safety
+/// The caller must have unmapped the frames from the page it held the references for
+pub unsafe fn put_frames(frame: PhysFrame, order: usize) -> bool {
+    FRAME_ALLOCATOR
+        .wait()
+        .unwrap()
+        .inner
+        .lock()
+        .put(frame, order)
+}
+
+/// Whether the frame allocator is locked, by code an interrupt handler may have
+/// interrupted
+pub fn is_locked() -> bool {
+    FRAME_ALLOCATOR
+        .wait()
+        .map_or(false, |allocator| allocator.inner.try_lock().is_none())
+}
+
+/// Where the entries of every tracked frame are mapped
+const FRAME_ENTRIES_START: u64 = 0xFFFF_FF10_0000_0000;
+
+/// Largest block the buddy allocator hands out is 2^MAX_ORDER frames, 1 GiB
+pub const MAX_ORDER: usize = 18;
+
+/// Frames below 16 MiB, where legacy ISA DMA can reach
+const DMA_FRAMES: u64 = 0x100_0000 / Size4KiB::SIZE;
+
+/// Frames below 4 GiB, where devices with 32 bit addressing can reach
+const DMA32_FRAMES: u64 = 0x1_0000_0000 / Size4KiB::SIZE;
+
+/// End of a free list
+const NONE: u32 = u32::MAX;
+
+/// Free frames only the kernel may take, 1 MiB. Memory of processes is allocated with
+/// [allocate_user](PhysFrameAllocator::allocate_user), which fails before it would dip
+/// into them, so the kernel can still allocate while it deals with running out of memory
+pub const EMERGENCY_FRAMES: u64 = 256;
+
+/// Frame number of the frame `addr` is in
+fn frame_index(addr: u64) -> u64 {
+    addr / Size4KiB::SIZE
+}
+
+fn frame_at(index: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index * Size4KiB::SIZE))
+}
+
+/// Physical memory an allocation may come from. Allocations take memory from the
+/// highest zone they allow first so the low zones are left for those that need them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, for legacy ISA DMA
+    Dma = 0,
+    /// Below 4 GiB, for devices that only address 32 bits
+    Dma32 = 1,
+    /// Anywhere
+    Normal = 2,
+}
+
+impl Zone {
+    /// The zone the frame numbered `index` is in
+    fn of(index: u64) -> Zone {
+        if index < DMA_FRAMES {
+            Zone::Dma
+        } else if index < DMA32_FRAMES {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+}
+
+/// Smallest order of a block that holds `bytes` and starts at a multiple of `align`
+pub fn order_of(bytes: u64, align: u64) -> usize {
+    let frames = ((bytes + Size4KiB::SIZE - 1) / Size4KiB::SIZE).max(1);
+    let align_frames = (align / Size4KiB::SIZE).max(1);
+    let order = |n: u64| (64 - (n - 1).leading_zeros()) as usize;
+    order(frames).max(order(align_frames))
+}
+
+/// What the allocator keeps about every tracked frame, the free list links are only
+/// meaningful for the first frame of a free block
+#[derive(Debug, Clone, Copy)]
+struct FrameEntry {
+    descriptor: FrameDescriptor,
+    next: u32,
+    prev: u32,
+    /// Order of the free block the frame starts
+    order: u8,
+    /// The frame is usable memory
+    usable: bool,
+    /// The frame starts a free block
+    free: bool,
+}
+
+impl FrameEntry {
+    const UNUSABLE: FrameEntry = FrameEntry {
+        descriptor: FrameDescriptor::FREE,
+        next: NONE,
+        prev: NONE,
+        order: 0,
+        usable: false,
+        free: false,
+    };
+}
 
-/// A structure that holds the usable memory region from BIOS and a corresponding
-/// bitmap to track allocated frames with associated functions
+/// A buddy allocator over the usable regions the bootloader reported. Free memory is
+/// kept in blocks of 2^order frames aligned to their size, with a free list for every
+/// order in each [Zone]. A block is split in halves until it has the order asked for
+/// and a freed block is merged with its buddy, the other half of the block both came
+/// from, whenever that is free as well
+///
+/// The free lists are linked through an entry for every frame from physical address 0
+/// up to the end of the highest usable region, the entries are stored in frames taken
+/// from the first usable region large enough and mapped at `FRAME_ENTRIES_START`
+///
+/// The entries also hold the [FrameDescriptor] of every frame, frames are handed out with
+/// a single reference and [put](PhysFrameAllocator::put) only frees them after the last
+/// one is dropped
 pub struct PhysFrameAllocator {
-    /// The region of memory marked as usable for OS and User use
-    pub usable_memory_region: MemoryRegion,
+    /// Memory map from the bootloader
+    memory_regions: &'static MemoryRegions,
+
+    /// One entry for each tracked frame
+    entries: &'static mut [FrameEntry],
+
+    /// Frame numbers of the frames the entries and their page tables are stored in
+    reserved: Range<u64>,
+
+    /// First frame of the first block of each order in each zone
+    free_lists: [[u32; MAX_ORDER + 1]; 3],
+
+    /// Usable frames that are not in use
+    free: u64,
+
+    /// Usable frames, the ones holding the entries not included
+    total: u64,
 
-    /// The region of memory reserved for bit map manipulation for
-    /// preserving which frames have been allocated or deallocated
-    pub bit_map_region: MemoryRegion,
+    /// Allocated frames marked as [FrameFlags::PAGE_TABLE]
+    tables: u64,
 }
 
 impl PhysFrameAllocator {
     /// This function initializes the physical frame allocator for the system
     ///
-    /// We take the usable memory region from the bootloader and divide it into two new regions,
-    /// the first being a bit map region that will be able to indicate wether a frame is free or used
-    /// in the new usable region. And a new usable region minus the consumed bit map region frames.
+    /// The frame entries are placed at the start of the first usable region large
+    /// enough to hold them, then every frame of every usable region except those and
+    /// the frame at address 0 is given to the free lists. Regions the bootloader
+    /// reserved for itself, the kernel, its page tables and the boot info stay in use.
     /// This function will not assign a new global frame allocator again once initialized
     pub fn init(memory_regions: &'static MemoryRegions) {
-        let mut usable_memory_region = MemoryRegion::empty();
-        let mut bit_map_region = MemoryRegion::empty();
+        let usable_end = usable_regions(memory_regions)
+            .map(|region| region.end)
+            .max()
+            .expect("No usable memory");
+        let frames = frame_index(usable_end).min(u64::from(NONE));
+
+        let entry_bytes = frames * mem::size_of::<FrameEntry>() as u64;
+        let entry_pages = (entry_bytes + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        // Level 1 and level 2 tables to map them, the level 3 table exists already
+        let table_frames = entry_pages / 512 + entry_pages / (512 * 512) + 2;
+        let num_reserved = entry_pages + table_frames;
+
+        // Frame 0 is never used, a null physical address is almost always a mistake
+        let reserved_start = usable_regions(memory_regions)
+            .map(|region| (align_up(region.start.max(1), Size4KiB::SIZE), region.end))
+            .find(|&(start, end)| start + num_reserved * Size4KiB::SIZE <= end)
+            .map(|(start, _)| frame_index(start))
+            .expect("No usable region can hold the frame entries");
+        let reserved = reserved_start..reserved_start + num_reserved;
+
+        map_entries(entry_pages, reserved.clone()).unwrap();
+        let entries = unsafe {
+            slice::from_raw_parts_mut(FRAME_ENTRIES_START as *mut FrameEntry, frames as usize)
+        };
 
-        usable_memory_region.kind = MemoryRegionKind::Usable;
+        let mut allocator = PhysFrameAllocator {
+            memory_regions,
+            entries,
+            reserved,
+            free_lists: [[NONE; MAX_ORDER + 1]; 3],
+            free: 0,
+            total: 0,
+            tables: 0,
+        };
+        allocator.reset();
+        let total = allocator.total;
 
-        let mut num_bit_map_frames: u64 = 0;
+        // Init global frame allocator
+        FRAME_ALLOCATOR.call_once(|| PhysFrameAllocatorWrapper::new(Mutex::new(allocator)));
 
-        for memory_region in memory_regions.iter() {
-            if memory_region.kind == MemoryRegionKind::Usable {
-                // Calculate the number of frames required for the bitmap + 1
-                num_bit_map_frames = ((memory_region.end - memory_region.start) >> 24) + 1;
+        BYTES_AVAILABLE_RAM.call_once(|| total * Size4KiB::SIZE);
+    }
 
-                // Assign the bitmap region
-                bit_map_region.start = memory_region.start;
-                bit_map_region.end = memory_region.start + (num_bit_map_frames << 12) - 1;
+    /// Give every usable frame to the free lists and mark every other frame used
+    fn reset(&mut self) {
+        self.entries.fill(FrameEntry::UNUSABLE);
+        self.free_lists = [[NONE; MAX_ORDER + 1]; 3];
+        self.free = 0;
+        self.tables = 0;
+
+        let frames = self.entries.len() as u64;
+        for region in usable_regions(self.memory_regions) {
+            let start = frame_index(align_up(region.start, Size4KiB::SIZE)).max(1);
+            let end = frame_index(region.end).min(frames);
+            for index in start..end {
+                if !self.reserved.contains(&index) {
+                    self.entries[index as usize].usable = true;
+                }
+            }
+        }
 
-                // Assign the new usable memory region
-                usable_memory_region.start = memory_region.start + (num_bit_map_frames << 12);
-                usable_memory_region.end = memory_region.end;
+        // Cut every run of usable frames into the largest aligned blocks that fit
+        let mut index = 0;
+        while index < frames {
+            if !self.entries[index as usize].usable {
+                index += 1;
+                continue;
+            }
+            let mut end = index;
+            while end < frames && self.entries[end as usize].usable {
+                end += 1;
+            }
+            for (start, end) in split_at_zones(index, end) {
+                self.add_range(start, end);
             }
+            index = end;
         }
+        self.total = self.free;
+    }
 
-        // Identity Map the physical bit map frames to pages
-        map_bit_frames(bit_map_region, num_bit_map_frames).unwrap();
+    /// Free `start..end` as blocks that are as large as their alignment allows
+    fn add_range(&mut self, mut start: u64, end: u64) {
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.push(start, order);
+            self.free += 1 << order;
+            start += 1 << order;
+        }
+    }
 
-        // Init global frame allocator
-        FRAME_ALLOCATOR.call_once(|| {
-            PhysFrameAllocatorWrapper::new(Mutex::new(PhysFrameAllocator {
-                usable_memory_region,
-                bit_map_region,
-            }))
-        });
+    /// Usable frames that are not in use
+    pub fn free_frames(&self) -> u64 {
+        self.free
+    }
 
-        BYTES_AVAILABLE_RAM.call_once(|| usable_memory_region.end - usable_memory_region.start);
+    /// Usable frames, whether they are in use or not
+    pub fn total_frames(&self) -> u64 {
+        self.total
     }
 
-    /// Get the physical frame from the bitmap index
-    fn frame_from_bit_index(&self, index: u64) -> PhysFrame {
-        let frame_addr = PhysAddr::new(self.usable_memory_region.start + (index << 12));
-        PhysFrame::<Size4KiB>::containing_address(frame_addr)
+    /// Allocated frames holding page tables
+    pub fn table_frames(&self) -> u64 {
+        self.tables
     }
 
-    /// Allocate a specific frame from an addr alligned to the nearest 4KiB frame, will return none if
-    /// frame is in use
-    pub fn allocate_frame_nth(&self, start: PhysAddr) -> Option<PhysFrame> {
-        let start_frame_addr = start.align_down(Size4KiB::SIZE);
-        let usable_addr = start_frame_addr.as_u64() - self.usable_memory_region.start;
+    /// Free frames in blocks of each order
+    pub fn free_blocks(&self) -> [u64; MAX_ORDER + 1] {
+        let mut blocks = [0; MAX_ORDER + 1];
+        for zone in &self.free_lists {
+            for (order, &head) in zone.iter().enumerate() {
+                let mut index = head;
+                while index != NONE {
+                    blocks[order] += 1;
+                    index = self.entries[index as usize].next;
+                }
+            }
+        }
+        blocks
+    }
 
-        // Get the index of the frame in the bitmap
-        let index = usable_addr >> 12;
+    /// Allocate 2^`order` contiguous frames aligned to their size from `zone` or a zone
+    /// below it, returns the first frame
+    pub fn allocate_contiguous(&mut self, order: usize, zone: Zone) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
 
-        let u64_byte = index >> 6;
-        let bit_index = index % 64;
+        for zone in (0..=zone as usize).rev() {
+            let found = (order..=MAX_ORDER).find(|&k| self.free_lists[zone][k] != NONE);
+            let k = match found {
+                Some(k) => k,
+                None => continue,
+            };
 
-        let u64_byte_ptr = (self.bit_map_region.start + (u64_byte << 6)) as *mut u64;
+            let index = u64::from(self.free_lists[zone][k]);
+            return Some(self.take(index, k, order));
+        }
+        None
+    }
 
-        unsafe {
-            if *u64_byte_ptr >> bit_index == 1 {
-                *u64_byte_ptr &= !(0 << bit_index);
-                Some(PhysFrame::from_start_address(start_frame_addr).unwrap())
+    /// [allocate_contiguous](PhysFrameAllocator::allocate_contiguous) for frames that
+    /// end at or below `limit`. The zones are tried from the highest one that starts
+    /// below `limit` down and their free lists are searched for a block below it
+    pub fn allocate_below(&mut self, order: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let end = limit.as_u64() / Size4KiB::SIZE;
+        let zone_starts = [0, DMA_FRAMES, DMA32_FRAMES];
+        for zone in (0..3).rev().filter(|&zone| zone_starts[zone] < end) {
+            for k in order..=MAX_ORDER {
+                let mut index = self.free_lists[zone][k];
+                while index != NONE {
+                    // Only the first 2^`order` frames of the block are taken
+                    if u64::from(index) + (1 << order) <= end {
+                        return Some(self.take(u64::from(index), k, order));
+                    }
+                    index = self.entries[index as usize].next;
+                }
+            }
+        }
+        None
+    }
+
+    /// Take the first 2^`order` frames of the free block of order `k` starting at the
+    /// frame numbered `index`, the upper halves of the block go back to the free lists
+    fn take(&mut self, index: u64, mut k: usize, order: usize) -> PhysFrame {
+        self.remove(index, k);
+        while k > order {
+            k -= 1;
+            self.push(index + (1 << k), k);
+        }
+        self.free -= 1 << order;
+        self.claim(index, order);
+        frame_at(index)
+    }
+
+    /// [allocate_contiguous](PhysFrameAllocator::allocate_contiguous) for memory of the
+    /// process with the id `owner`, None rather than leaving fewer than
+    /// [EMERGENCY_FRAMES] frames free
+    pub fn allocate_user(&mut self, order: usize, zone: Zone, owner: u32) -> Option<PhysFrame> {
+        if order > MAX_ORDER || self.free < EMERGENCY_FRAMES + (1 << order) {
+            return None;
+        }
+        let frame = self.allocate_contiguous(order, zone)?;
+        let index = frame_index(frame.start_address().as_u64()) as usize;
+        for entry in &mut self.entries[index..index + (1 << order)] {
+            entry.descriptor.owner = owner;
+        }
+        Some(frame)
+    }
+
+    /// Number of frames marked with each of `owners` as their owner, `owners` has to
+    /// be sorted
+    pub fn owned_frames(&self, owners: &[u32], counts: &mut [u64]) {
+        for entry in self.entries.iter() {
+            if entry.descriptor.count > 0 {
+                if let Ok(i) = owners.binary_search(&entry.descriptor.owner) {
+                    counts[i] += 1;
+                }
+            }
+        }
+    }
+
+    /// Give the 2^`order` frames from the frame numbered `index` a single reference
+    fn claim(&mut self, index: u64, order: usize) {
+        let block = &mut self.entries[index as usize..(index + (1 << order)) as usize];
+        for entry in block {
+            entry.descriptor = FrameDescriptor {
+                count: 1,
+                ..FrameDescriptor::FREE
+            };
+        }
+    }
+
+    /// Descriptor of `frame`, None if it is not usable memory
+    pub fn descriptor_mut(&mut self, frame: PhysFrame) -> Option<&mut FrameDescriptor> {
+        let index = frame_index(frame.start_address().as_u64());
+        self.entries
+            .get_mut(index as usize)
+            .filter(|entry| entry.usable)
+            .map(|entry| &mut entry.descriptor)
+    }
+
+    /// Add `flags` to each of the 2^`order` frames from `frame`
+    pub fn mark(&mut self, frame: PhysFrame, order: usize, flags: FrameFlags) {
+        for n in 0..1 << order {
+            if let Some(descriptor) = self.descriptor_mut(frame + n) {
+                let added = flags - descriptor.flags;
+                descriptor.flags.insert(flags);
+                if added.contains(FrameFlags::PAGE_TABLE) {
+                    self.tables += 1;
+                }
+            }
+        }
+    }
+
+    /// Add a reference to an allocated frame, returns the number of references it has
+    /// now. Free and untracked frames are left alone and have none
+    pub fn get(&mut self, frame: PhysFrame) -> u32 {
+        match self.descriptor_mut(frame) {
+            Some(descriptor) if descriptor.count > 0 => {
+                descriptor.count += 1;
+                descriptor.count
+            }
+            _ => 0,
+        }
+    }
+
+    /// Drop a reference to each of the 2^`order` frames from `frame`, the frames left
+    /// without a reference are deallocated. Returns true if all of them were
+    ///
+    /// # Safety
+    /// The references dropped must not be used anymore
+    pub unsafe fn put(&mut self, frame: PhysFrame, order: usize) -> bool {
+        let last = (0..1 << order).all(|n| {
+            self.descriptor_mut(frame + n)
+                .map_or(false, |descriptor| descriptor.count == 1)
+        });
+        if last {
+            self.deallocate_contiguous(frame, order);
+            return true;
+        }
+
+        for n in 0..1 << order {
+            match self.descriptor_mut(frame + n) {
+                Some(descriptor) if descriptor.count == 1 => {
+                    self.deallocate_contiguous(frame + n, 0)
+                }
+                Some(descriptor) if descriptor.count > 1 => descriptor.count -= 1,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Give back 2^`order` frames allocated with [allocate_contiguous](PhysFrameAllocator::allocate_contiguous)
+    ///
+    /// # Safety
+    /// None of the frames may be in use anymore
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, order: usize) {
+        let mut index = frame_index(frame.start_address().as_u64());
+        let block = index as usize..index as usize + (1 << order);
+        // Device memory and frames freed twice are ignored, a free frame has no
+        // references whether it starts a free block or is inside one
+        let allocated = self.entries.get(block.clone()).map_or(false, |entries| {
+            entries
+                .iter()
+                .all(|entry| entry.usable && entry.descriptor.count > 0)
+        });
+        if !allocated {
+            return;
+        }
+        self.free += 1 << order;
+        for entry in &mut self.entries[block] {
+            if entry.descriptor.flags.contains(FrameFlags::PAGE_TABLE) {
+                self.tables -= 1;
+            }
+            entry.descriptor = FrameDescriptor::FREE;
+        }
+
+        let zone = Zone::of(index);
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            let merge = self.entries.get(buddy as usize).map_or(false, |entry| {
+                entry.free && usize::from(entry.order) == order && Zone::of(buddy) == zone
+            });
+            if !merge {
+                break;
+            }
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    /// Allocate a specific frame from an addr alligned to the nearest 4KiB frame, will return none if
+    /// frame is in use or not usable memory
+    pub fn allocate_frame_nth(&mut self, start: PhysAddr) -> Option<PhysFrame> {
+        let index = frame_index(start.as_u64());
+        // The free block the frame is in starts at the frame rounded down to its order
+        let (mut head, mut order) = (0..=MAX_ORDER)
+            .map(|order| (index & !((1 << order) - 1), order))
+            .find(|&(head, order)| {
+                self.entries.get(head as usize).map_or(false, |entry| {
+                    entry.free && usize::from(entry.order) == order
+                })
+            })?;
+
+        self.remove(head, order);
+        // Give back the halves the frame is not in until only the frame is left
+        while order > 0 {
+            order -= 1;
+            let half = 1 << order;
+            if index >= head + half {
+                self.push(head, order);
+                head += half;
             } else {
-                None
+                self.push(head + half, order);
             }
         }
+        self.free -= 1;
+        self.claim(index, 0);
+        Some(frame_at(index))
+    }
+
+    /// Add the block starting at the frame numbered `index` to the front of its free list
+    fn push(&mut self, index: u64, order: usize) {
+        let list = &mut self.free_lists[Zone::of(index) as usize][order];
+        let next = *list;
+        *list = index as u32;
+
+        let entry = &mut self.entries[index as usize];
+        entry.next = next;
+        entry.prev = NONE;
+        entry.order = order as u8;
+        entry.free = true;
+        if next != NONE {
+            self.entries[next as usize].prev = index as u32;
+        }
+    }
+
+    /// Take the free block starting at the frame numbered `index` out of its free list
+    fn remove(&mut self, index: u64, order: usize) {
+        let FrameEntry { next, prev, .. } = self.entries[index as usize];
+        if prev == NONE {
+            self.free_lists[Zone::of(index) as usize][order] = next;
+        } else {
+            self.entries[prev as usize].next = next;
+        }
+        if next != NONE {
+            self.entries[next as usize].prev = prev;
+        }
+        self.entries[index as usize].free = false;
+    }
+
+    /// Exhaustive check of the allocator that has to run right after
+    /// [init](PhysFrameAllocator::init), before any frame has been allocated
+    ///
+    /// Every free frame is allocated, each has to be usable memory and none may be
+    /// handed out twice. The allocator is left as `init` built it afterwards
+    pub fn self_test(&mut self) {
+        let free = self.free;
+        assert_eq!(
+            free, self.total,
+            "Frames were allocated before the self test"
+        );
+        let listed: u64 = self
+            .free_blocks()
+            .iter()
+            .enumerate()
+            .map(|(order, &blocks)| blocks << order)
+            .sum();
+        assert_eq!(listed, free);
+
+        let mut allocated = 0;
+        let mut last = None;
+        while let Some(frame) = self.allocate_frame() {
+            let index = frame_index(frame.start_address().as_u64());
+            assert!(
+                self.entries[index as usize].usable,
+                "Allocated a reserved frame {:?}",
+                frame
+            );
+            assert_eq!(self.allocate_frame_nth(frame.start_address()), None);
+            allocated += 1;
+            last = Some(frame);
+        }
+        // Every allocation took a frame no other allocation took
+        assert_eq!(allocated, free);
+        assert_eq!(self.free, 0);
+
+        // A freed frame is the only one left to allocate
+        let last = last.expect("No usable frames");
+        unsafe { self.deallocate_frame(last) };
+        assert_eq!(self.allocate_frame(), Some(last));
+        unsafe { self.deallocate_frame(last) };
+        assert_eq!(self.allocate_frame_nth(last.start_address()), Some(last));
+
+        // Frames that are not usable memory are never taken in
+        unsafe {
+            self.deallocate_frame(frame_at(0));
+            self.deallocate_frame(frame_at(self.reserved.start));
+        }
+        assert_eq!(self.allocate_frame(), None);
+
+        // Freeing a frame again is ignored, also when it is not the head of the free
+        // block it went back into
+        self.reset();
+        let block = self
+            .allocate_contiguous(1, Zone::Normal)
+            .expect("No two contiguous frames");
+        let inner = block + 1;
+        unsafe {
+            self.deallocate_contiguous(block, 1);
+            self.deallocate_frame(block);
+            self.deallocate_frame(inner);
+        }
+        assert_eq!(self.free, free);
+        assert_eq!(self.allocate_frame_nth(inner.start_address()), Some(inner));
+        assert_eq!(self.allocate_frame_nth(inner.start_address()), None);
+
+        self.reset();
+        assert_eq!(self.free, free);
     }
 }
 
-// TODO: CONSIDER TURNING BITMAP HANDLING ROUTINES INTO A STRUCT
 use x86_64::structures::paging::{
     FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
 };
@@ -105,108 +718,78 @@ use x86_64::structures::paging::{
 use crate::KERNEL_PAGE_TABLE;
 
 unsafe impl FrameAllocator<Size4KiB> for PhysFrameAllocator {
-    /// Allocate the next available frame in the usable memory region.
-    /// We navigate the bitmap for a empty bit and return the Physical Frame
-    /// if there, else no frames are available and return None
+    /// Allocate a single frame from the free lists, splitting the smallest free block
+    /// if there is no free single frame
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let mut bm_ptr = BITMAP_START as *mut u64;
-        while bm_ptr
-            < (BITMAP_START as u64 + (self.bit_map_region.end - self.bit_map_region.start))
-                as *mut u64
-        {
-            let mut quadword = unsafe { *bm_ptr } as u64;
-            let qw_clone = quadword;
-            let mut index = 0;
-
-            if quadword != 0xFFFFFFFFFFFFFFFF {
-                while index < 64 {
-                    if quadword & 1 == 0 {
-                        // Usable PhysFrame
-                        unsafe { *bm_ptr = qw_clone | (0x01 << index) };
-                        // Return the frame containing the physical address of the bit in the bitmap
-                        return Some(
-                            self.frame_from_bit_index(bm_ptr as u64 - BITMAP_START as u64 + index),
-                        );
-                    }
-                    index += 1;
-                    quadword >>= 1;
-                }
-            }
-            // Point to next quadword in the map
-            unsafe { bm_ptr = bm_ptr.add(8) };
-        }
-        None
+        self.allocate_contiguous(0, Zone::Normal)
     }
 }
 
 impl FrameDeallocator<Size4KiB> for PhysFrameAllocator {
     /// Deallocate a frame in no longer in use
     ///
-    /// This is done by clearing the bit in the bit_map to indicate that this
-    /// frame is no longer in use
+    /// The frame goes back to the free lists merged with its free buddies. Frames
+    /// that are not usable memory, like device memory, are ignored
     ///
     /// # Safety
     /// The user must validate that the frame is no longer in use before
     /// deallocation
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        // Get addr of frame along usable_mem_region
-        let usable_addr = frame.start_address().as_u64() - self.usable_memory_region.start;
-
-        // Get the index of the frame in the bitmap
-        let index = usable_addr >> 12;
+        self.deallocate_contiguous(frame, 0)
+    }
+}
 
-        let u64_byte = index >> 6;
-        let bit_index = index % 64;
+/// The regions of the memory map the kernel may use
+fn usable_regions(memory_regions: &MemoryRegions) -> impl Iterator<Item = &MemoryRegion> {
+    memory_regions
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+}
 
-        let u64_byte_ptr = (BITMAP_START as u64 + (u64_byte << 6)) as *mut u64;
-        *u64_byte_ptr &= !(1 << bit_index);
-    }
+/// Cut `start..end` where the zones meet
+fn split_at_zones(start: u64, end: u64) -> impl Iterator<Item = (u64, u64)> {
+    let bounds = [start, DMA_FRAMES, DMA32_FRAMES, end];
+    (0..3)
+        .map(move |i| (bounds[i].max(start), bounds[i + 1].min(end)))
+        .filter(|(start, end)| start < end)
 }
 
-/// This function is called during the initialization of the frame allocator to identity map the bit map frames
-fn map_bit_frames(
-    bit_map_region: MemoryRegion,
-    num_bit_map_frames: u64,
-) -> Result<(), MapToError<Size4KiB>> {
-    // Create pages in the bit map region
-    let page_range = {
-        let bit_map_start = VirtAddr::new(BITMAP_START as u64);
-        let bit_map_end =
-            VirtAddr::new(BITMAP_START as u64 + (bit_map_region.end - bit_map_region.start));
-        let bit_map_start_page = Page::<Size4KiB>::containing_address(bit_map_start);
-        let bit_map_end_page = Page::<Size4KiB>::containing_address(bit_map_end);
-        Page::range_inclusive(bit_map_start_page, bit_map_end_page)
-    };
+/// Align `addr` upwards to `align`, which must be a power of two
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
 
-    let bm_range = bit_map_region.start..bit_map_region.end;
-    let frame_addresses = bm_range.step_by(4096);
-    let mut frames =
-        frame_addresses.map(|addr| PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr)));
-    let mut empty_allocator = EmptyFrameAllocator;
+/// Hands out the frames reserved for the frame entries while they are mapped
+struct ReservedFrames {
+    frames: Range<u64>,
+}
 
-    assert_eq!(page_range.count() as u64, num_bit_map_frames);
+unsafe impl FrameAllocator<Size4KiB> for ReservedFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.frames.next().map(frame_at)
+    }
+}
 
-    for page in page_range {
-        let frame = frames.next().ok_or(MapToError::FrameAllocationFailed)?;
+/// This function is called during the initialization of the frame allocator to map
+/// `entry_pages` pages for the frame entries, the frames and the page tables needed
+/// come from `reserved`
+fn map_entries(entry_pages: u64, reserved: Range<u64>) -> Result<(), MapToError<Size4KiB>> {
+    let mut reserved = ReservedFrames { frames: reserved };
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(FRAME_ENTRIES_START));
+
+    for i in 0..entry_pages {
+        let frame = reserved
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         unsafe {
             KERNEL_PAGE_TABLE
                 .wait()
                 .unwrap()
                 .lock()
-                .map_to(page, frame, flags, &mut empty_allocator)
-                .unwrap()
+                .map_to(first_page + i, frame, flags, &mut reserved)?
                 .flush()
         };
-        assert_eq!(
-            KERNEL_PAGE_TABLE
-                .wait()
-                .unwrap()
-                .lock()
-                .translate_page(page)
-                .unwrap(),
-            frame
-        );
     }
     Ok(())
 }
@@ -239,6 +822,19 @@ impl FrameDeallocator<Size4KiB> for &PhysFrameAllocatorWrapper {
     }
 }
 
+/// Allocates the frames of new page tables and marks them as [FrameFlags::PAGE_TABLE],
+/// to be handed to the [Mapper] functions that create missing tables
+pub struct PageTableFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for PageTableFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+        let frame = allocator.allocate_frame()?;
+        allocator.mark(frame, 0, FrameFlags::PAGE_TABLE);
+        Some(frame)
+    }
+}
+
 /// Used once for allocating the bitmap frames because the map_to function requires an allocator
 /// encase more frame allocations are required for more table entries which is impossible
 /// at the early stage of execution
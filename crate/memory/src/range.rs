@@ -0,0 +1,146 @@
+//! Allocator for ranges of an address space
+//!
+//! Free ranges are kept twice, ordered by their start to merge a freed range with its
+//! neighbours and ordered by their length to find the smallest range an allocation
+//! fits into. Allocating, reserving and freeing are O(log n) in the number of free
+//! ranges
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::ops::Range;
+
+#[derive(Debug, Clone)]
+pub struct RangeAllocator {
+    /// Free ranges, the end of each keyed by its start
+    by_start: BTreeMap<u64, u64>,
+    /// The same free ranges as (length, start)
+    by_length: BTreeSet<(u64, u64)>,
+}
+
+impl RangeAllocator {
+    /// An allocator without any free range
+    pub const fn new() -> Self {
+        Self {
+            by_start: BTreeMap::new(),
+            by_length: BTreeSet::new(),
+        }
+    }
+
+    /// An allocator that hands out `range`
+    pub fn with_range(range: Range<u64>) -> Self {
+        let mut allocator = Self::new();
+        allocator.free(range.start, range.end - range.start);
+        allocator
+    }
+
+    /// Total length of the free ranges
+    pub fn free_length(&self) -> u64 {
+        self.by_length.iter().map(|(length, _)| length).sum()
+    }
+
+    /// Start of the range [allocate](RangeAllocator::allocate) would hand out, without
+    /// taking it
+    pub fn find(&self, length: u64, align: u64) -> Option<u64> {
+        self.by_length
+            .range((length, 0)..)
+            .find_map(|&(free, start)| {
+                let aligned = align_up(start, align)?;
+                (aligned.checked_add(length)? <= start + free).then(|| aligned)
+            })
+    }
+
+    /// Take the smallest free range `length` fits into at an `align` aligned start,
+    /// `align` has to be a power of two. Returns the start
+    pub fn allocate(&mut self, length: u64, align: u64) -> Option<u64> {
+        if length == 0 {
+            return None;
+        }
+        let start = self.find(length, align)?;
+        self.take(start, length);
+        Some(start)
+    }
+
+    /// Take `length` bytes from `start` if all of them are free
+    pub fn reserve(&mut self, start: u64, length: u64) -> bool {
+        let end = match start.checked_add(length) {
+            Some(end) if length > 0 => end,
+            _ => return false,
+        };
+        let free = self
+            .by_start
+            .range(..=start)
+            .next_back()
+            .map_or(false, |(_, &free_end)| end <= free_end);
+        if free {
+            self.take(start, length);
+        }
+        free
+    }
+
+    /// Give back `length` bytes from `start`, they must not be free already
+    pub fn free(&mut self, start: u64, length: u64) {
+        if length == 0 {
+            return;
+        }
+        let mut start = start;
+        let mut end = start + length;
+
+        let before = self
+            .by_start
+            .range(..start)
+            .next_back()
+            .map(|(&s, &e)| (s, e));
+        if let Some((previous_start, previous_end)) = before {
+            debug_assert!(previous_end <= start, "range freed twice");
+            if previous_end == start {
+                self.remove(previous_start, previous_end);
+                start = previous_start;
+            }
+        }
+
+        if let Some(next_end) = self.by_start.get(&end).copied() {
+            self.remove(end, next_end);
+            end = next_end;
+        }
+
+        self.insert(start, end);
+    }
+
+    /// Cut `start..start + length` out of the free range that contains it
+    fn take(&mut self, start: u64, length: u64) {
+        let (&free_start, &free_end) = self
+            .by_start
+            .range(..=start)
+            .next_back()
+            .expect("taken range is not free");
+        let end = start + length;
+        debug_assert!(end <= free_end, "taken range is not free");
+
+        self.remove(free_start, free_end);
+        if free_start < start {
+            self.insert(free_start, start);
+        }
+        if end < free_end {
+            self.insert(end, free_end);
+        }
+    }
+
+    fn insert(&mut self, start: u64, end: u64) {
+        self.by_start.insert(start, end);
+        self.by_length.insert((end - start, start));
+    }
+
+    fn remove(&mut self, start: u64, end: u64) {
+        self.by_start.remove(&start);
+        self.by_length.remove(&(end - start, start));
+    }
+}
+
+impl Default for RangeAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Align `addr` upwards to `align`, None if that overflows
+fn align_up(addr: u64, align: u64) -> Option<u64> {
+    Some(addr.checked_add(align - 1)? & !(align - 1))
+}
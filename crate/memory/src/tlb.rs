@@ -0,0 +1,31 @@
+//! Invalidating translations cached in the TLB after page table entries change
+//!
+//! Every change to a mapping of an address space that may be cached has to be followed
+//! by a shootdown of the pages it touched. The kernel runs on a single CPU, so the
+//! translations to drop are the ones of the local TLB. Once other CPUs run threads of
+//! the same address space they have to be sent an IPI from [shootdown] as well
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{page::PageRange, Page, Size4KiB},
+};
+
+/// Number of pages above which dropping the whole TLB is cheaper than `invlpg` on
+/// each of them
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// Drop the cached translations of `pages` in the active address space
+pub fn shootdown(pages: PageRange<Size4KiB>) {
+    let count = pages.end - pages.start;
+    if count > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for page in pages {
+            tlb::flush(page.start_address());
+        }
+    }
+}
+
+/// Drop the cached translation of a single page in the active address space
+pub fn shootdown_page(page: Page<Size4KiB>) {
+    shootdown(Page::range(page, page + 1));
+}
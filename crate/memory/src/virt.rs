@@ -1,48 +1,287 @@
 use crate::{
     active_level_4_table,
-    phys::{BYTES_AVAILABLE_RAM, FRAME_ALLOCATOR},
+    address_space::{active_owner, Tables},
+    pat::{CacheMode, PAT_4KIB},
+    phys::{
+        frame_references, get_frame, order_of, put_frame, put_frames, update_frame, FrameFlags,
+        PageTableFrames, Zone, EMERGENCY_FRAMES, FRAME_ALLOCATOR,
+    },
+    range::RangeAllocator,
+    tlb,
 };
 use accessor::single::ReadWrite;
+use alloc::vec::Vec;
 use core::{
+    arch::x86_64::__cpuid,
     convert::{TryFrom, TryInto},
     num::NonZeroUsize,
+    ops::Range,
 };
 use os_units::{self, Bytes, NumOfPages};
+use spin::{Mutex, MutexGuard, Once};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags,
-        RecursivePageTable, Size4KiB, Translate,
+        mapper::MapToError, page::PageRange, page_table::PageTableEntry, FrameAllocator, Mapper,
+        Page, PageSize, PageTableFlags, PhysFrame, RecursivePageTable, Size1GiB, Size2MiB,
+        Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
-/// Search for any free address space that has a consistent number of pages in the
-/// active level 4 page table
-/// O(n)
-pub fn search_free_addr(num_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
-    let mut cnt = 0;
-    let mut start = None;
-    for addr in (0..(*BYTES_AVAILABLE_RAM.wait().unwrap()) as usize)
-        .step_by(usize::try_from(Size4KiB::SIZE).unwrap())
-    {
-        let addr = VirtAddr::new(addr as _);
-        if available(addr) {
-            if start.is_none() {
-                start = Some(addr);
-            }
+/// Part of level 4 entry 0, which every address space shares with the kernel, that
+/// [KpBox](crate::kpbox::KpBox) pages are placed in
+pub const KPBOX_AREA: Range<u64> = 0x10_0000_0000..0x20_0000_0000;
 
-            cnt += 1;
+/// Part of level 4 entry 0 physical memory mapped by [MemoryMapper] is placed in
+pub const MMIO_AREA: Range<u64> = 0x20_0000_0000..0x30_0000_0000;
 
-            if cnt >= num_pages.as_usize() {
-                return start;
-            }
+/// Part of level 4 entry 0 the stacks of kernel threads are placed in
+pub const STACK_AREA: Range<u64> = 0x30_0000_0000..0x40_0000_0000;
+
+/// Allocators of the address ranges in the kernel areas, in the order of [KernelArea]
+static KERNEL_AREAS: Once<[Mutex<RangeAllocator>; 3]> = Once::new();
+
+/// The parts of the address space shared by every address space that the kernel
+/// places its own mappings in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelArea {
+    KpBox,
+    Mmio,
+    Stack,
+}
+
+impl KernelArea {
+    pub fn range(self) -> Range<u64> {
+        match self {
+            KernelArea::KpBox => KPBOX_AREA,
+            KernelArea::Mmio => MMIO_AREA,
+            KernelArea::Stack => STACK_AREA,
+        }
+    }
+
+    fn allocator(self) -> MutexGuard<'static, RangeAllocator> {
+        let areas = KERNEL_AREAS.call_once(|| {
+            [
+                Mutex::new(RangeAllocator::with_range(KPBOX_AREA)),
+                Mutex::new(RangeAllocator::with_range(MMIO_AREA)),
+                Mutex::new(RangeAllocator::with_range(STACK_AREA)),
+            ]
+        });
+        areas[self as usize].lock()
+    }
+
+    /// Reserve `num_of_pages` pages of address space in the area without mapping them,
+    /// None once the area is full. Reservations of 2 MiB or more start on a 2 MiB
+    /// boundary so they can be mapped with huge pages
+    /// O(log n)
+    pub fn reserve(self, num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+        let length = Size4KiB::SIZE * num_of_pages.as_usize() as u64;
+        let align = if length >= Size2MiB::SIZE {
+            Size2MiB::SIZE
         } else {
-            cnt = 0;
-            start = None;
+            Size4KiB::SIZE
+        };
+        self.reserve_aligned(length, align)
+    }
+
+    fn reserve_aligned(self, length: u64, align: u64) -> Option<VirtAddr> {
+        self.allocator().allocate(length, align).map(VirtAddr::new)
+    }
+
+    /// Give back pages reserved with [reserve](KernelArea::reserve), they have to be
+    /// unmapped already
+    pub fn release(self, virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+        let length = Size4KiB::SIZE * num_of_pages.as_usize() as u64;
+        self.allocator().free(virt.as_u64(), length);
+    }
+}
+
+/// Software defined page table bit marking a read only page whose frame is shared
+/// after a fork. Writing to it gives the page a private copy of the frame
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Software defined page table bit marking a page of a shared mapping. Its frame stays
+/// shared with every process that maps it and is never copied on write after a fork
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Walk every 4 KiB page mapped below the level 4 entries in `p4_range` of the active
+/// address space, `f` is called with the page and its level 1 entry which may be modified.
+/// Huge pages are skipped. The caller is responsible for flushing the TLB after changing
+/// entries
+pub fn for_each_mapping(p4_range: Range<u16>, f: impl FnMut(Page, &mut PageTableEntry)) {
+    Tables::active().for_each_mapping(p4_range, f)
+}
+
+/// Get the level 1 entry of a page in the active address space, None if any of the
+/// tables above it are missing
+fn page_entry(page: Page) -> Option<&'static mut PageTableEntry> {
+    Tables::active().entry(page)
+}
+
+/// The flags of the 4 KiB or huge page `addr` is on in the active address space, None if
+/// no page maps it
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let page = Page::containing_address(addr);
+    let tables = Tables::active();
+    match tables.huge_entry(page) {
+        Some((entry, _)) => Some(entry.flags()),
+        None => tables.entry(page).map(|entry| entry.flags()),
+    }
+}
+
+/// Resolve a write to a [COPY_ON_WRITE] page of the active address space. The page
+/// gets a private copy of its frame, or if no other page maps the frame anymore it
+/// simply becomes writable again
+///
+/// Returns false if the address is not on a copy on write page, an error if there is no
+/// frame left for the copy
+pub fn handle_copy_on_write(addr: VirtAddr) -> Result<bool, MapToError<Size4KiB>> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let entry = match page_entry(page) {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return Ok(false),
+    };
+
+    let frame = entry.frame().unwrap();
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    if frame_references(frame) > 1 {
+        let copy = FRAME_ALLOCATOR
+            .wait()
+            .unwrap()
+            .inner
+            .lock()
+            .allocate_user(0, Zone::Normal, active_owner())
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let mut contents = [0u8; Size4KiB::SIZE as usize];
+        let page_ptr = page.start_address().as_mut_ptr::<u8>();
+        unsafe {
+            contents
+                .as_mut_ptr()
+                .copy_from_nonoverlapping(page_ptr, contents.len());
+        }
+
+        entry.set_frame(copy, flags);
+        tlb::shootdown_page(page);
+
+        unsafe {
+            page_ptr.copy_from_nonoverlapping(contents.as_ptr(), contents.len());
+            put_frame(frame);
+        }
+    } else {
+        update_frame(frame, |descriptor| {
+            descriptor.flags.remove(FrameFlags::COPY_ON_WRITE)
+        });
+        entry.set_flags(flags);
+        tlb::shootdown_page(page);
+    }
+
+    Ok(true)
+}
+
+/// Sizes of the pages a mapping can be made of, largest first
+const PAGE_SIZES: [u64; 3] = [Size1GiB::SIZE, Size2MiB::SIZE, Size4KiB::SIZE];
+
+/// Whether the processor can map 1 GiB pages, 2 MiB pages are always available in long
+/// mode
+pub fn gigabyte_pages() -> bool {
+    static SUPPORTED: Once<bool> = Once::new();
+    *SUPPORTED.call_once(|| unsafe { __cpuid(0x8000_0001).edx & (1 << 26) != 0 })
+}
+
+/// Size of the largest page that can map `virt` to `phys` without reaching past `end`,
+/// both addresses have to be aligned to it
+pub fn page_size_at(virt: u64, phys: u64, end: u64) -> u64 {
+    PAGE_SIZES
+        .iter()
+        .copied()
+        .filter(|&size| size != Size1GiB::SIZE || gigabyte_pages())
+        .find(|&size| virt % size == 0 && phys % size == 0 && virt + size <= end)
+        .unwrap_or(Size4KiB::SIZE)
+}
+
+/// Size of the page `addr` is mapped with in the active address space, None if it is not
+/// mapped
+pub fn mapped_page_size(addr: VirtAddr) -> Option<u64> {
+    let page = Page::containing_address(addr);
+    let tables = Tables::active();
+    match tables.huge_entry(page) {
+        Some((_, size)) => Some(size),
+        None => tables.entry(page).map(|_| Size4KiB::SIZE),
+    }
+}
+
+/// Map a page of `size` bytes at `virt` to the frames from `phys`
+///
+/// # Safety
+/// The frames must not be mapped anywhere they are used for something else
+unsafe fn map_page(
+    page_table: &mut RecursivePageTable,
+    virt: u64,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let table_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut allocator = PageTableFrames;
+    let virt = VirtAddr::new(virt);
+
+    let result = match size {
+        Size1GiB::SIZE => page_table
+            .map_to_with_table_flags(
+                Page::<Size1GiB>::containing_address(virt),
+                PhysFrame::containing_address(phys),
+                flags,
+                table_flags,
+                &mut allocator,
+            )
+            .map(|flush| flush.ignore())
+            .map_err(small_error),
+        Size2MiB::SIZE => page_table
+            .map_to_with_table_flags(
+                Page::<Size2MiB>::containing_address(virt),
+                PhysFrame::containing_address(phys),
+                flags,
+                table_flags,
+                &mut allocator,
+            )
+            .map(|flush| flush.ignore())
+            .map_err(small_error),
+        _ => page_table
+            .map_to_with_table_flags(
+                Page::<Size4KiB>::containing_address(virt),
+                PhysFrame::containing_address(phys),
+                flags,
+                table_flags,
+                &mut allocator,
+            )
+            .map(|flush| flush.ignore()),
+    };
+    tlb::shootdown_page(Page::containing_address(virt));
+    result
+}
+
+/// The error of mapping a huge page as the error of mapping a 4 KiB page
+fn small_error<S: PageSize>(err: MapToError<S>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
         }
     }
+}
 
-    None
+/// Give the page `addr` is in the flags `flags`, a huge page keeps its huge page bit
+fn set_page_flags(tables: Tables, addr: u64, flags: PageTableFlags) {
+    let page = Page::containing_address(VirtAddr::new(addr));
+    if let Some((entry, _)) = tables.huge_entry(page) {
+        entry.set_flags(flags | PageTableFlags::HUGE_PAGE);
+    } else if let Some(entry) = tables.entry(page) {
+        entry.set_flags(flags);
+    }
 }
 
 /// Check to see if an address is free from the current active level page table
@@ -51,53 +290,417 @@ pub fn available(addr: VirtAddr) -> bool {
     pml4.translate_addr(addr).is_none() && !addr.is_null()
 }
 
-/// Deallocate # of pages in a linear space starting from the Virtual Address
+/// Deallocate # of pages in a linear space starting from the Virtual Address, frames
+/// still shared with another address space are only freed by their last user. The pages
+/// have to cover what [allocate_pages] mapped as a whole
 pub fn deallocate_pages(virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+    let start = Page::from_start_address(virt).unwrap();
+    unmap_whole(Page::range(start, start + num_of_pages.as_usize() as u64));
+}
+
+/// Allocate # of pages starting at a virtual address to new zeroed frames, huge pages
+/// are used where the address and the number of pages allow. Nothing stays mapped when
+/// there are not enough frames left
+pub fn allocate_pages(
+    virt: VirtAddr,
+    num_of_pages: NumOfPages<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = Page::containing_address(virt);
+    let pages = Page::range(start, start + num_of_pages.as_usize() as u64);
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    map_zeroed(pages, flags)
+}
+
+/// Map `pages` of the active address space to new zeroed frames with `flags`. Every part
+/// of the range aligned to 2 MiB or 1 GiB is mapped with a huge page while contiguous
+/// frames for it are left, the rest with 4 KiB pages. When no frame is left the pages
+/// mapped so far are unmapped again
+pub fn map_zeroed(pages: PageRange, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
     let mut page_table = RecursivePageTable::new(active_level_4_table()).unwrap();
-    for i in 0..u64::try_from(num_of_pages.as_usize()).unwrap() {
-        let page = Page::<Size4KiB>::from_start_address(virt + Size4KiB::SIZE * i).unwrap();
+    // Writable until the frame has been cleared
+    let initial_flags = flags | PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let end = pages.end.start_address().as_u64();
+    let mut addr = pages.start.start_address().as_u64();
 
-        let (frame, flush) = page_table.unmap(page).unwrap();
-        unsafe {
-            FRAME_ALLOCATOR
-                .wait()
-                .unwrap()
-                .inner
-                .lock()
-                .deallocate_frame(frame)
+    while addr < end {
+        match map_zeroed_page(&mut page_table, addr, end, initial_flags) {
+            Ok(size) => {
+                if flags != initial_flags {
+                    set_page_flags(Tables::active(), addr, flags);
+                }
+                addr += size;
+            }
+            Err(err) => {
+                unmap_whole(Page::range(
+                    pages.start,
+                    Page::containing_address(VirtAddr::new(addr)),
+                ));
+                return Err(err);
+            }
+        }
+    }
+
+    tlb::shootdown(pages);
+    Ok(())
+}
+
+/// Map the largest page that fits at `addr` below `end` to new zeroed frames, falling
+/// back to smaller pages when there are no contiguous frames for it or a table is in
+/// the way. Returns the size of the page
+///
+/// Pages of processes never take frames of the [EMERGENCY_FRAMES] pool
+fn map_zeroed_page(
+    page_table: &mut RecursivePageTable,
+    addr: u64,
+    end: u64,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let mut size = page_size_at(addr, 0, end);
+    loop {
+        let order = order_of(size, size);
+        let block = {
+            let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+            if kernel_address(addr) {
+                let block = allocator.allocate_contiguous(order, Zone::Normal);
+                if let Some(frame) = block {
+                    allocator.mark(frame, order, FrameFlags::KERNEL);
+                }
+                block
+            } else {
+                allocator.allocate_user(order, Zone::Normal, active_owner())
+            }
         };
-        flush.flush();
+        let mapped = block
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| unsafe {
+                map_page(page_table, addr, frame.start_address(), size, flags).map_err(|err| {
+                    FRAME_ALLOCATOR
+                        .wait()
+                        .unwrap()
+                        .inner
+                        .lock()
+                        .deallocate_contiguous(frame, order);
+                    err
+                })
+            });
+
+        match mapped {
+            Ok(()) => {
+                unsafe { core::ptr::write_bytes(addr as *mut u8, 0, size as usize) };
+                return Ok(size);
+            }
+            // The next page size is 512 times smaller
+            Err(_) if size > Size4KiB::SIZE => size /= 512,
+            Err(err) => return Err(err),
+        }
     }
 }
 
-/// Allocate # of pages starting at a virtual address to random frames
-pub fn allocate_pages(virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+/// Map `pages` of the active address space to `frames`, the first page to the first
+/// frame and so on, and add a reference to every frame mapped. When a page table can
+/// not be allocated the pages mapped so far are unmapped again
+pub fn map_frames(
+    pages: PageRange,
+    frames: &[PhysFrame],
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
     let mut page_table = RecursivePageTable::new(active_level_4_table()).unwrap();
+    for (page, &frame) in pages.zip(frames) {
+        let addr = page.start_address().as_u64();
+        let mapped = unsafe {
+            map_page(
+                &mut page_table,
+                addr,
+                frame.start_address(),
+                Size4KiB::SIZE,
+                flags,
+            )
+        };
+        if let Err(err) = mapped {
+            unmap_whole(Page::range(pages.start, page));
+            return Err(err);
+        }
+        get_frame(frame);
+    }
+    Ok(())
+}
 
-    for i in 0..num_of_pages.as_usize() {
-        let page = Page::<Size4KiB>::containing_address(virt + Size4KiB::SIZE * i as u64);
-        let frame = (*FRAME_ALLOCATOR.wait().unwrap())
-            .inner
-            .lock()
-            .allocate_frame()
-            .expect("Phys Memory not avialable");
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-        unsafe {
-            page_table
-                .map_to(page, frame, flags, FRAME_ALLOCATOR.wait().as_mut().unwrap())
-                .unwrap()
-                .flush();
+/// Take `count` zeroed frames for memory of processes that are not mapped anywhere, the
+/// caller holds the only reference to each and gives them back with [put_frame]. None if
+/// there are not enough frames left outside of the [EMERGENCY_FRAMES] pool
+pub fn allocate_frames(count: usize) -> Option<Vec<PhysFrame>> {
+    let free = FRAME_ALLOCATOR.wait().unwrap().inner.lock().free_frames();
+    if count as u64 > free.saturating_sub(EMERGENCY_FRAMES) {
+        return None;
+    }
+
+    // Allocated up front, growing the heap with the frame allocator locked would deadlock
+    let mut frames = Vec::with_capacity(count);
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    while frames.len() < count {
+        match allocator.allocate_user(0, Zone::Normal, active_owner()) {
+            Some(frame) => frames.push(frame),
+            None => break,
+        }
+    }
+    drop(allocator);
+
+    // The frames are cleared through a mapping in the KpBox area
+    let reserved = NumOfPages::new(count.max(1));
+    let cleared = frames.len() == count
+        && KernelArea::KpBox.reserve(reserved).map_or(false, |virt| {
+            let start = Page::containing_address(virt);
+            let pages = Page::range(start, start + count as u64);
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            let mapped = map_frames(pages, &frames, flags).is_ok();
+            if mapped {
+                let bytes = count * Size4KiB::SIZE as usize;
+                unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, bytes) };
+                unmap_whole(pages);
+            }
+            KernelArea::KpBox.release(virt, reserved);
+            mapped
+        });
+
+    if !cleared {
+        for frame in frames {
+            unsafe { put_frame(frame) };
         }
+        return None;
     }
+    Some(frames)
 }
-/// TODO
+
+/// Whether `addr` is in the part of the address space holding memory of the kernel
+/// rather than of a process
+fn kernel_address(addr: u64) -> bool {
+    addr >= 0xFFFF_8000_0000_0000 || KPBOX_AREA.contains(&addr) || STACK_AREA.contains(&addr)
+}
+
+/// Split the huge pages that are only partly in `pages`, which can only be the ones at
+/// either end of the range. Every huge page left in the range lies in it as a whole
+fn split_ends(tables: Tables, pages: PageRange) -> Result<(), MapToError<Size4KiB>> {
+    if pages.is_empty() {
+        return Ok(());
+    }
+
+    let start = pages.start.start_address().as_u64();
+    let end = pages.end.start_address().as_u64();
+    for &page in &[pages.start, pages.end - 1] {
+        if let Some((_, size)) = tables.huge_entry(page) {
+            let huge_start = page.start_address().align_down(size).as_u64();
+            if huge_start < start || huge_start + size > end {
+                tables.split(page)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// [unmap_range] for `pages` that cover whole mappings, like the part of a range mapped
+/// before running out of frames. Huge pages only ever lie inside a mapping, so none
+/// has to be split
+fn unmap_whole(pages: PageRange) {
+    unmap_range(pages).expect("Unmapping whole mappings split a huge page");
+}
+
+/// Unmap `pages` from the active address space and drop their references to their
+/// frames, a frame is freed once no page maps it anymore. Pages that are not mapped are
+/// skipped. A huge page that is only partly in `pages` is split first
+///
+/// Fails if there is no frame left to split a huge page, nothing is unmapped then
+pub fn unmap_range(pages: PageRange) -> Result<(), MapToError<Size4KiB>> {
+    let tables = Tables::active();
+    split_ends(tables, pages)?;
+    let end = pages.end.start_address().as_u64();
+    let mut addr = pages.start.start_address().as_u64();
+
+    while addr < end {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        if let Some((entry, size)) = tables.huge_entry(page) {
+            let frame = PhysFrame::containing_address(entry.addr());
+            entry.set_unused();
+            unsafe { put_frames(frame, order_of(size, size)) };
+            addr += size;
+            continue;
+        }
+
+        if let Some(entry) = tables.entry(page) {
+            let frame = entry.frame().unwrap();
+            entry.set_unused();
+            unsafe { put_frame(frame) };
+        }
+        addr += Size4KiB::SIZE;
+    }
+    tlb::shootdown(pages);
+    Ok(())
+}
+
+/// Give the mapped pages in `pages` of the active address space the flags `flags`,
+/// pages that are not mapped are skipped. A huge page that is only partly in `pages` is
+/// split first
+///
+/// A page made writable whose frame is still shared after a fork becomes a
+/// [COPY_ON_WRITE] page instead, unless it is [SHARED]
+///
+/// Fails if there is no frame left to split a huge page, no flags are changed then
+pub fn protect_range(pages: PageRange, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let tables = Tables::active();
+    split_ends(tables, pages)?;
+    let end = pages.end.start_address().as_u64();
+    let mut addr = pages.start.start_address().as_u64();
+
+    while addr < end {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        if let Some((_, size)) = tables.huge_entry(page) {
+            set_page_flags(tables, addr, flags);
+            addr += size;
+            continue;
+        }
+
+        if let Some(entry) = tables.entry(page) {
+            let copy = flags.contains(PageTableFlags::WRITABLE)
+                && !flags.contains(SHARED)
+                && frame_references(entry.frame().unwrap()) > 1;
+            if copy {
+                entry.set_flags((flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE);
+            } else {
+                entry.set_flags(flags);
+            }
+        }
+        addr += Size4KiB::SIZE;
+    }
+    tlb::shootdown(pages);
+    Ok(())
+}
+
+/// Map `num_of_pages` pages to new frames in the [KernelArea::KpBox] area, an empty
+/// allocation still gets an address of its own
+/// O(log n)
 pub fn allocate_new(num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
-    let virt = search_free_addr(num_of_pages)?;
-    allocate_pages(virt, num_of_pages);
+    let reserved = NumOfPages::new(num_of_pages.as_usize().max(1));
+    let virt = KernelArea::KpBox.reserve(reserved)?;
+    if allocate_pages(virt, num_of_pages).is_err() {
+        KernelArea::KpBox.release(virt, reserved);
+        return None;
+    }
     Some(virt)
 }
 
+/// Unmap pages mapped by [allocate_new] or [allocate_contiguous_new] and give back their
+/// address space
+pub fn deallocate_new(virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+    // An empty allocation of allocate_contiguous_new maps the page it reserves
+    let reserved = NumOfPages::new(num_of_pages.as_usize().max(1));
+    deallocate_pages(virt, reserved);
+    KernelArea::KpBox.release(virt, reserved);
+}
+
+/// Map `num_of_pages` pages in the [KernelArea::KpBox] area to zeroed frames that follow
+/// each other in physical memory and end at or below `phys_limit`, with the cache mode
+/// `mode`. Returns the address of the mapping and of its first frame, None if there is
+/// no such block of frames left. An empty allocation gets a page of its own
+///
+/// The frames are taken from the highest zone that has a block below `phys_limit`, so
+/// the low zones are left for allocations that need them, see [Zone]
+pub fn allocate_contiguous_new(
+    num_of_pages: NumOfPages<Size4KiB>,
+    phys_limit: PhysAddr,
+    mode: CacheMode,
+) -> Option<(VirtAddr, PhysAddr)> {
+    let count = num_of_pages.as_usize().max(1) as u64;
+    let order = order_of(count * Size4KiB::SIZE, Size4KiB::SIZE);
+    let frame = allocate_below(order, phys_limit)?;
+    // The frames of the block past the allocation are given back right away, every
+    // frame has a reference of its own
+    for n in count..1 << order {
+        unsafe { put_frame(frame + n) };
+    }
+    let put_from = |first: u64| {
+        for n in first..count {
+            unsafe { put_frame(frame + n) };
+        }
+    };
+
+    let reserved = NumOfPages::new(count as usize);
+    let virt = match KernelArea::KpBox.reserve(reserved) {
+        Some(virt) => virt,
+        None => {
+            put_from(0);
+            return None;
+        }
+    };
+    let start = virt.as_u64();
+    let end = start + count * Size4KiB::SIZE;
+    let phys = frame.start_address();
+
+    if let Err((mapped, _)) = unsafe { map_contiguous(start, end, phys.as_u64(), mode) } {
+        unmap_whole(Page::range(
+            Page::containing_address(virt),
+            Page::containing_address(VirtAddr::new(mapped)),
+        ));
+        put_from((mapped - start) / Size4KiB::SIZE);
+        KernelArea::KpBox.release(virt, reserved);
+        return None;
+    }
+    let bytes = (end - start) as usize;
+    unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, bytes) };
+    Some((virt, phys))
+}
+
+/// Allocate 2^`order` contiguous frames for the kernel that end at or below `limit`,
+/// see [allocate_below](crate::phys::PhysFrameAllocator::allocate_below)
+fn allocate_below(order: usize, limit: PhysAddr) -> Option<PhysFrame> {
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    let frame = allocator.allocate_below(order, limit)?;
+    allocator.mark(frame, order, FrameFlags::KERNEL);
+    Some(frame)
+}
+
+/// Stack of a kernel thread in the [KernelArea::Stack] area. The page below it stays
+/// unmapped, so overflowing the stack faults instead of overwriting other memory
+#[derive(Debug)]
+pub struct KernelStack {
+    /// Start of the guard page
+    guard: VirtAddr,
+    num_of_pages: NumOfPages<Size4KiB>,
+}
+
+impl KernelStack {
+    /// Map a zeroed stack of at least `bytes`, None once there is no memory left for it
+    pub fn new(bytes: Bytes) -> Option<Self> {
+        let num_of_pages = bytes.as_num_of_pages::<Size4KiB>();
+        let guard = KernelArea::Stack.reserve(NumOfPages::new(num_of_pages.as_usize() + 1))?;
+        let stack = Self {
+            guard,
+            num_of_pages,
+        };
+
+        // On failure dropping the stack gives back its address space
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        map_zeroed(stack.pages(), flags).ok().map(|_| stack)
+    }
+
+    /// The address the stack grows down from
+    pub fn top(&self) -> VirtAddr {
+        self.pages().end.start_address()
+    }
+
+    fn pages(&self) -> PageRange {
+        let bottom = Page::containing_address(self.guard) + 1;
+        Page::range(bottom, bottom + self.num_of_pages.as_usize() as u64)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unmap_whole(self.pages());
+        let reserved = NumOfPages::new(self.num_of_pages.as_usize() + 1);
+        KernelArea::Stack.release(self.guard, reserved);
+    }
+}
+
 /// TODO
 pub type Single<T> = ReadWrite<T, MemoryMapper>;
 
@@ -110,7 +713,7 @@ where
     ReadWrite::new(phys_base.as_u64().try_into().unwrap(), MemoryMapper)
 }
 
-/// TODO
+/// Maps the registers of devices for [accessor], uncached with [map_mmio]
 pub struct MemoryMapper;
 
 impl accessor::Mapper for MemoryMapper {
@@ -118,53 +721,138 @@ impl accessor::Mapper for MemoryMapper {
     /// the size of an object
     unsafe fn map(&mut self, phys_start: usize, bytes: usize) -> core::num::NonZeroUsize {
         let phys_start = PhysAddr::new(phys_start.try_into().unwrap());
-        let bytes = Bytes::new(bytes);
-
-        let start_frame_addr = phys_start.align_down(Size4KiB::SIZE);
-        let end_frame_addr = (phys_start + bytes.as_usize()).align_down(Size4KiB::SIZE);
+        let virt = map_mmio(phys_start, bytes.try_into().unwrap(), CacheMode::Uncached)
+            .expect("Failed to map pages.");
+        let v: usize = virt.as_u64().try_into().unwrap();
 
-        let num_pages = Bytes::new(usize::try_from(end_frame_addr - start_frame_addr).unwrap() + 1)
-            .as_num_of_pages::<Size4KiB>();
+        NonZeroUsize::new(v).unwrap()
+    }
 
-        let virt = search_free_addr(num_pages).expect("OOM Virtual");
-        let mut page_table = RecursivePageTable::new(active_level_4_table()).unwrap();
+    /// Unmap a number of pages based on the object size from a virtual address from there
+    /// mapped physical frames
+    fn unmap(&mut self, virt_start: usize, bytes: usize) {
+        let virt_start = VirtAddr::new(virt_start.try_into().unwrap());
+        unmap_mmio(virt_start, bytes.try_into().unwrap());
+    }
+}
 
-        for i in 0..num_pages.as_usize() {
-            let page = Page::<Size4KiB>::containing_address(virt + Size4KiB::SIZE * i as u64);
-            let frame = (*FRAME_ALLOCATOR.wait().unwrap())
-                .inner
-                .lock()
-                .allocate_frame_nth(start_frame_addr + Size4KiB::SIZE * i as u64)
-                .expect("Phys Memory not avialable");
-            let flags = PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::USER_ACCESSIBLE;
+/// The part of the [KernelArea::Mmio] area [map_mmio] reserves to map `bytes` from an
+/// address `offset` bytes into a 2 MiB page, as the offset of the reservation start to
+/// the mapping start and the length of the reservation
+fn physical_reservation(offset: u64, bytes: u64) -> (u64, u64) {
+    let start = offset - offset % Size4KiB::SIZE;
+    let end = align_up(offset + bytes.max(1), Size4KiB::SIZE);
+    if end - start >= Size2MiB::SIZE {
+        (start, end)
+    } else {
+        (0, end - start)
+    }
+}
 
-            page_table
-                .map_to(page, frame, flags, FRAME_ALLOCATOR.wait().as_mut().unwrap())
-                .unwrap()
-                .flush();
-        }
+/// Map `bytes` of device memory from `phys` into the [KernelArea::Mmio] area with the
+/// cache mode `mode`, returns the address `phys` is mapped at. Only the kernel can
+/// access the mapping. Large mappings keep the offset of `phys` into its 2 MiB page, so
+/// the parts covering whole 2 MiB pages are mapped with huge pages unless `mode` needs
+/// the PAT bit, see [pat](crate::pat). The frames are never taken from the frame
+/// allocator
+///
+/// None if the area has no room left or no frame is left for a page table, nothing
+/// stays mapped then
+///
+/// # Safety
+/// The physical memory must not be memory the frame allocator hands out, and must not
+/// be mapped with another cache mode anywhere else
+pub unsafe fn map_mmio(phys: PhysAddr, bytes: u64, mode: CacheMode) -> Option<VirtAddr> {
+    let (offset, length) = physical_reservation(phys.as_u64() % Size2MiB::SIZE, bytes);
+    let align = if length >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    };
+    let base = KernelArea::Mmio.reserve_aligned(length, align)?;
 
-        let page_offset = phys_start.as_u64() % Size4KiB::SIZE;
+    let phys_start = phys.align_down(Size4KiB::SIZE).as_u64();
+    let start = base.as_u64() + offset;
+    let end = base.as_u64() + length;
+    let virt = VirtAddr::new(start + phys.as_u64() % Size4KiB::SIZE);
+    if map_contiguous(start, end, phys_start, mode).is_err() {
+        // Unmaps the pages mapped so far and gives back the reservation
+        unmap_mmio(virt, bytes);
+        return None;
+    }
+    Some(virt)
+}
 
-        let v = virt + page_offset;
-        let v: usize = v.as_u64().try_into().unwrap();
+/// Map `start..end` of the active address space for the kernel to the frames from
+/// `phys` with the cache mode `mode`, with the largest pages the addresses allow unless
+/// the mode needs the PAT bit. No reference is added to the frames. On failure returns
+/// the address the pages from `start` are mapped up to
+///
+/// # Safety
+/// The frames must not be mapped anywhere they are used for something else
+unsafe fn map_contiguous(
+    start: u64,
+    end: u64,
+    phys: u64,
+    mode: CacheMode,
+) -> Result<(), (u64, MapToError<Size4KiB>)> {
+    let mut page_table = RecursivePageTable::new(active_level_4_table()).unwrap();
+    let (cache_flags, pat) = mode.flags();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cache_flags;
+    let tables = Tables::active();
 
-        NonZeroUsize::new(v).expect("Failed to map pages.")
+    let mut addr = start;
+    while addr < end {
+        let frame = phys + (addr - start);
+        let size = if pat {
+            Size4KiB::SIZE
+        } else {
+            page_size_at(addr, frame, end)
+        };
+        map_page(&mut page_table, addr, PhysAddr::new(frame), size, flags)
+            .map_err(|err| (addr, err))?;
+        if pat {
+            // The mapper refuses the bit on 4 KiB pages, it reads it as the huge page bit
+            let page = Page::containing_address(VirtAddr::new(addr));
+            let entry = tables.entry(page).unwrap();
+            entry.set_flags(entry.flags() | PAT_4KIB);
+            tlb::shootdown_page(page);
+        }
+        addr += size;
     }
+    Ok(())
+}
 
-    /// Unmap a number of pages based on the object size from a virtual address from there
-    /// mapped physical frames
-    fn unmap(&mut self, virt_start: usize, bytes: usize) {
-        let virt_start = VirtAddr::new(virt_start.try_into().unwrap());
-        let bytes = Bytes::new(bytes);
-
-        let start_frame_addr = virt_start.align_down(Size4KiB::SIZE);
-        let end_frame_addr = (virt_start + bytes.as_usize()).align_down(Size4KiB::SIZE);
+/// Unmap `bytes` mapped from `virt` by [map_mmio] and give back their address space
+pub fn unmap_mmio(virt: VirtAddr, bytes: u64) {
+    let (offset, length) = physical_reservation(virt.as_u64() % Size2MiB::SIZE, bytes);
+    let start = virt.align_down(Size4KiB::SIZE).as_u64();
+    let base = start - offset;
+    let end = base + length;
+    let tables = Tables::active();
 
-        let num_pages = Bytes::new(usize::try_from(end_frame_addr - start_frame_addr).unwrap())
-            .as_num_of_pages::<Size4KiB>();
-        deallocate_pages(start_frame_addr, num_pages);
+    let mut addr = start;
+    while addr < end {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        // The frames are left alone, they were never taken from the frame allocator
+        if let Some((entry, size)) = tables.huge_entry(page) {
+            entry.set_unused();
+            addr += size;
+        } else {
+            if let Some(entry) = tables.entry(page) {
+                entry.set_unused();
+            }
+            addr += Size4KiB::SIZE;
+        }
     }
+    tlb::shootdown(Page::range(
+        Page::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(end)),
+    ));
+    KernelArea::Mmio.allocator().free(base, length);
+}
+
+/// Align `addr` upwards to `align`, which must be a power of two
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
 }
@@ -5,7 +5,6 @@ use conquer_once::spin::OnceCell;
 use spin::Mutex;
 use core::{
     fmt::{self},
-    ptr,
 };
 use font8x8::UnicodeFonts;
 
@@ -40,6 +39,12 @@ impl Writer {
         writer
     }
 
+    /// Draw to `framebuffer` from now on, it has to be a mapping of the same pixels as
+    /// the current framebuffer. Returns the current framebuffer
+    pub fn replace_framebuffer(&mut self, framebuffer: &'static mut [u8]) -> &'static mut [u8] {
+        core::mem::replace(&mut self.framebuffer, framebuffer)
+    }
+
     fn newline(&mut self) {
         self.y_pos += 8 + LINE_SPACING;
         self.carriage_return()
@@ -49,6 +54,15 @@ impl Writer {
         self.x_pos = 0;
     }
 
+    /// Erases the character before the cursor on the current line
+    fn backspace(&mut self) {
+        if self.x_pos >= 8 {
+            self.x_pos -= 8;
+            self.write_rendered_char([0; 8]);
+            self.x_pos -= 8;
+        }
+    }
+
     /// Erases all text on the screen.
     pub fn clear(&mut self) {
         self.x_pos = 0;
@@ -68,6 +82,7 @@ impl Writer {
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            '\u{8}' => self.backspace(),
             c => {
                 if self.x_pos >= self.width() {
                     self.newline();
@@ -105,7 +120,6 @@ impl Writer {
         let byte_offset = pixel_offset * bytes_per_pixel;
         self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
             .copy_from_slice(&color[..bytes_per_pixel]);
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
     }
 }
 
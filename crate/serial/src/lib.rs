@@ -24,6 +24,19 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// A byte the host sent through the serial interface, None if nothing arrived
+pub fn try_receive() -> Option<u8> {
+    use x86_64::instructions::{interrupts, port::Port};
+
+    interrupts::without_interrupts(|| {
+        let _port = SERIAL1.lock();
+        let mut line_status = Port::<u8>::new(0x3F8 + 5);
+        let mut data = Port::<u8>::new(0x3F8);
+        // Bit 0 of the line status is set while received data is waiting
+        unsafe { (line_status.read() & 1 != 0).then(|| data.read()) }
+    })
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -0,0 +1,195 @@
+//! Dynamic linking of executables against the shared objects they list
+//! as `DT_NEEDED`. Libraries are read from [LIBRARY_PATH] on the ram
+//! filesystem and loaded into the currently active address space
+use crate::elf::{
+    dynamic_symbols, image_span, needed_libraries, program_header_address, tls_template,
+    ElfMemory, SharedObject, TlsTemplate,
+};
+use elfloader::{ElfBinary, ElfLoaderErr};
+use x86_64::{align_up, VirtAddr};
+use xmas_elf::ElfFile;
+
+extern crate alloc;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+/// Directory shared objects are loaded from
+pub const LIBRARY_PATH: &str = "/lib";
+
+/// Virtual address the first shared object of a task is loaded at, this is
+/// the start of level 4 entry 2 which belongs to the task alone
+pub const LIBRARY_BASE: u64 = 0x100_0000_0000;
+
+/// Shared objects are placed at 2 MiB boundaries from each other
+const LIBRARY_ALIGN: u64 = 0x20_0000;
+
+/// Errors that can happen while loading and linking an executable
+#[derive(Debug)]
+pub enum LinkError {
+    /// The object could not be parsed, loaded or relocated
+    Elf(ElfLoaderErr),
+    /// A library named in `DT_NEEDED` could not be found in [LIBRARY_PATH]
+    LibraryNotFound(String),
+    /// There were no frames left for the segments or the first thread
+    NoMemory,
+    /// The arguments and environment do not fit onto the stack of the first thread
+    TooBig,
+}
+
+impl From<ElfLoaderErr> for LinkError {
+    fn from(err: ElfLoaderErr) -> Self {
+        match err {
+            ElfLoaderErr::OutOfMemory => LinkError::NoMemory,
+            err => LinkError::Elf(err),
+        }
+    }
+}
+
+/// An executable that has been loaded and linked together with its libraries
+#[derive(Clone)]
+pub struct LoadedImage {
+    /// Entry point of the executable
+    pub entry: VirtAddr,
+    /// Load base of the executable
+    pub vbase: u64,
+    /// Address of the executables program headers
+    pub phdr: u64,
+    /// Number of program headers of the executable
+    pub phnum: u64,
+    /// Every loaded object, libraries first and the executable last
+    pub objects: Vec<Arc<SharedObject>>,
+    /// Total size of the static TLS area below the thread pointer
+    pub tls_size: u64,
+    /// End of the last loadable segment of the executable, the heap starts above it
+    pub end: u64,
+}
+
+impl LoadedImage {
+    /// An image without any objects, used by processes that only run kernel code
+    pub fn empty() -> Self {
+        Self {
+            entry: VirtAddr::zero(),
+            vbase: 0,
+            phdr: 0,
+            phnum: 0,
+            objects: Vec::new(),
+            tls_size: 0,
+            end: 0,
+        }
+    }
+
+    /// TLS templates of all loaded objects that have thread local storage
+    pub fn tls_templates(&self) -> impl Iterator<Item = TlsTemplate> + '_ {
+        self.objects.iter().filter_map(|object| object.tls)
+    }
+}
+
+/// Loads an executable and the shared objects it depends on into the active
+/// address space, libraries are loaded depth first so each object can resolve
+/// its symbols against everything loaded before it
+pub struct DynamicLinker {
+    objects: Vec<Arc<SharedObject>>,
+    next_base: u64,
+    next_module: u64,
+    tls_size: u64,
+}
+
+impl DynamicLinker {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            next_base: LIBRARY_BASE,
+            next_module: 1,
+            tls_size: 0,
+        }
+    }
+
+    /// Load the executable `bin` at `vbase` along with all of its `DT_NEEDED`
+    /// libraries and apply the relocations of every object
+    pub fn load_executable(
+        mut self,
+        name: &str,
+        bin: &[u8],
+        vbase: u64,
+    ) -> Result<LoadedImage, LinkError> {
+        let file = ElfFile::new(bin).map_err(|_| ElfLoaderErr::UnsupportedElfFormat)?;
+
+        // The executable is always module 1 and its TLS block sits directly
+        // below the thread pointer, local-exec code depends on that layout
+        let tls = self.reserve_tls(&file, vbase);
+
+        for library in needed_libraries(&file) {
+            self.load_library(&library)?;
+        }
+
+        let elf = ElfBinary::new(bin)?;
+        let object = self.load_object(name, &elf, &file, vbase, tls)?;
+        self.objects.push(object);
+
+        Ok(LoadedImage {
+            entry: VirtAddr::new(elf.entry_point() + vbase),
+            vbase,
+            phdr: program_header_address(&file, vbase),
+            phnum: u64::from(file.header.pt2.ph_count()),
+            objects: self.objects,
+            tls_size: self.tls_size,
+            end: vbase + image_span(&file),
+        })
+    }
+
+    /// Read a shared object from the filesystem and load it, unless an object
+    /// with that name has already been loaded
+    fn load_library(&mut self, name: &str) -> Result<(), LinkError> {
+        if self.objects.iter().any(|object| object.name == name) {
+            return Ok(());
+        }
+
+        let bin = fs::read_file(&format!("{}/{}", LIBRARY_PATH, name))
+            .map_err(|_| LinkError::LibraryNotFound(String::from(name)))?;
+        let file = ElfFile::new(&bin).map_err(|_| ElfLoaderErr::UnsupportedElfFormat)?;
+
+        for library in needed_libraries(&file) {
+            self.load_library(&library)?;
+        }
+
+        let vbase = self.next_base;
+        self.next_base = align_up(vbase + image_span(&file), LIBRARY_ALIGN);
+
+        let tls = self.reserve_tls(&file, vbase);
+        let elf = ElfBinary::new(&bin)?;
+        let object = self.load_object(name, &elf, &file, vbase, tls)?;
+        self.objects.push(object);
+
+        Ok(())
+    }
+
+    /// Load a parsed object at `vbase` with the already loaded objects as its scope
+    fn load_object(
+        &self,
+        name: &str,
+        elf: &ElfBinary,
+        file: &ElfFile,
+        vbase: u64,
+        tls: Option<TlsTemplate>,
+    ) -> Result<Arc<SharedObject>, LinkError> {
+        let mut loader =
+            ElfMemory::with_scope(vbase, dynamic_symbols(file), self.objects.clone(), tls);
+        elf.load(&mut loader)?;
+        loader.protect_segments()?;
+
+        Ok(Arc::new(loader.into_shared_object(String::from(name))))
+    }
+
+    /// Hand out the next module ID and a static TLS block to an object with a `PT_TLS` header
+    fn reserve_tls(&mut self, file: &ElfFile, vbase: u64) -> Option<TlsTemplate> {
+        let tls = tls_template(file, vbase, self.next_module, self.tls_size)?;
+        self.next_module += 1;
+        self.tls_size = tls.offset;
+        Some(tls)
+    }
+}
+
+impl Default for DynamicLinker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -7,18 +7,25 @@
 // given there size and there starting address, so when a page fault occurs we can reference
 // the tasks segment size to see if it is out of bounds or more memory can be allocated to it.
 // Also the way that this is set up, is meant for processes that are position independent executables
-use elfloader::{ElfLoader, TypeRela64};
-use memory::{active_level_4_table, phys::FRAME_ALLOCATOR};
+use crate::task::USER_P4_INDICES;
+use elfloader::{ElfLoader, ElfLoaderErr, TypeRela64};
+use memory::virt;
 
 use x86_64::{
     align_up,
-    structures::paging::{
-        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, RecursivePageTable, Size4KiB,
-    },
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{page::PageRange, Page, PageSize, PageTableFlags, Size4KiB},
     VirtAddr,
 };
+use xmas_elf::{
+    dynamic::Tag,
+    program::{self, Type},
+    sections::SectionData,
+    symbol_table::{Binding, Entry},
+    ElfFile,
+};
 extern crate alloc;
-use alloc::vec::Vec;
+use alloc::{string::String, sync::Arc, vec::Vec};
 
 pub fn align_bin(bin: &[u8]) -> Vec<u8> {
     let mut vec = Vec::<u8>::new();
@@ -27,6 +34,218 @@ pub fn align_bin(bin: &[u8]) -> Vec<u8> {
     vec
 }
 
+/// Section index of symbols whose value is an absolute address (`SHN_ABS`)
+const SHN_ABS: u16 = 0xfff1;
+
+/// Segments have to lie in the level 4 entries that belong to the task alone
+const USER_START: u64 = (USER_P4_INDICES.start as u64) << 39;
+const USER_END: u64 = (USER_P4_INDICES.end as u64) << 39;
+
+/// An entry of an objects dynamic symbol table `.dynsym`
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    /// Value of the symbol relative to the objects load base, or to the
+    /// start of its TLS block for thread local symbols
+    pub value: u64,
+    /// False if the symbol is only referenced here and has to be
+    /// resolved from another loaded object
+    pub defined: bool,
+    /// The value is an address of its own, not relative to the load base
+    pub absolute: bool,
+    /// An undefined weak symbol that is not found anywhere resolves to 0
+    pub weak: bool,
+}
+
+/// Read the dynamic symbol table of an ELF file, statically linked
+/// executables don't have one and return an empty table
+pub fn dynamic_symbols(file: &ElfFile) -> Vec<Symbol> {
+    let section = match file.find_section_by_name(".dynsym") {
+        Some(section) => section,
+        None => return Vec::new(),
+    };
+
+    match section.get_data(file) {
+        Ok(SectionData::DynSymbolTable64(entries)) => entries
+            .iter()
+            .map(|entry| Symbol {
+                name: String::from(entry.get_name(file).unwrap_or("")),
+                value: entry.value(),
+                defined: entry.shndx() != 0,
+                absolute: entry.shndx() == SHN_ABS,
+                weak: matches!(entry.get_binding(), Ok(Binding::Weak)),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Names of the shared objects an ELF file depends on (`DT_NEEDED`)
+pub fn needed_libraries(file: &ElfFile) -> Vec<String> {
+    let section = match file.find_section_by_name(".dynamic") {
+        Some(section) => section,
+        None => return Vec::new(),
+    };
+
+    match section.get_data(file) {
+        Ok(SectionData::Dynamic64(entries)) => entries
+            .iter()
+            .filter(|entry| matches!(entry.get_tag(), Ok(Tag::Needed)))
+            .filter_map(|entry| entry.get_val().ok())
+            .filter_map(|offset| file.get_dyn_string(offset as u32).ok())
+            .map(String::from)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Number of bytes from the load base to the end of the last loadable segment
+pub fn image_span(file: &ElfFile) -> u64 {
+    file.program_iter()
+        .filter(|header| matches!(header.get_type(), Ok(Type::Load)))
+        .map(|header| header.virtual_addr() + header.mem_size())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Virtual address the program headers end up at, taken from `PT_PHDR` if there is
+/// one and otherwise from the loadable segment that covers them in the file
+pub fn program_header_address(file: &ElfFile, vbase: u64) -> u64 {
+    if let Some(header) = file
+        .program_iter()
+        .find(|header| matches!(header.get_type(), Ok(Type::Phdr)))
+    {
+        return vbase + header.virtual_addr();
+    }
+
+    let ph_offset = file.header.pt2.ph_offset();
+    file.program_iter()
+        .filter(|header| matches!(header.get_type(), Ok(Type::Load)))
+        .find(|header| {
+            header.offset() <= ph_offset && ph_offset < header.offset() + header.file_size()
+        })
+        .map(|header| vbase + header.virtual_addr() + (ph_offset - header.offset()))
+        .unwrap_or(0)
+}
+
+/// The initialization image and layout of an objects thread local storage
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate {
+    /// Module ID handed out for `R_X86_64_DTPMOD64`
+    pub module_id: u64,
+    /// Virtual address of the `.tdata` initialization image
+    pub tdata_start: u64,
+    /// Length of the `.tdata` initialization image
+    pub tdata_length: u64,
+    /// Size of `.tdata` and `.tbss` together
+    pub total_size: u64,
+    pub align: u64,
+    /// Distance from the thread pointer down to the start of this block,
+    /// blocks are laid out below the thread pointer (TLS variant II)
+    pub offset: u64,
+}
+
+/// Build the TLS template of an object from its `PT_TLS` header, `offset_before` is the
+/// amount of static TLS already handed to previously loaded objects
+pub fn tls_template(
+    file: &ElfFile,
+    vbase: u64,
+    module_id: u64,
+    offset_before: u64,
+) -> Option<TlsTemplate> {
+    let header = file
+        .program_iter()
+        .find(|header| matches!(header.get_type(), Ok(Type::Tls)))?;
+    let align = header.align().max(1);
+
+    Some(TlsTemplate {
+        module_id,
+        tdata_start: vbase + header.virtual_addr(),
+        tdata_length: header.file_size(),
+        total_size: header.mem_size(),
+        align,
+        offset: align_up(offset_before + header.mem_size(), align),
+    })
+}
+
+/// An ELF object that has been loaded into an address space. Objects are kept
+/// around so objects loaded after them can resolve their undefined symbols
+pub struct SharedObject {
+    pub name: String,
+    pub vbase: u64,
+    pub symbols: Vec<Symbol>,
+    pub tls: Option<TlsTemplate>,
+}
+
+impl SharedObject {
+    /// Find a symbol defined by this object
+    fn find(&self, name: &str) -> Option<Resolved> {
+        let symbol = self
+            .symbols
+            .iter()
+            .find(|symbol| symbol.defined && symbol.name == name)?;
+
+        Some(Resolved {
+            base: if symbol.absolute { 0 } else { self.vbase },
+            value: symbol.value,
+            tls: self.tls,
+        })
+    }
+
+    /// Absolute address of a symbol defined by this object
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.find(name).map(|resolved| resolved.address())
+    }
+}
+
+/// A relocation symbol after it has been located in one of the loaded objects
+struct Resolved {
+    base: u64,
+    value: u64,
+    tls: Option<TlsTemplate>,
+}
+
+impl Resolved {
+    fn address(&self) -> u64 {
+        self.base.wrapping_add(self.value)
+    }
+}
+
+/// A loadable segment of the object being loaded, `start..end` are the addresses its
+/// memory covers
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: u64,
+    /// The permissions the segment ends up with once it is loaded and relocated
+    flags: PageTableFlags,
+}
+
+impl Segment {
+    fn pages(&self) -> PageRange {
+        let start = Page::containing_address(VirtAddr::new(self.start));
+        let end = Page::containing_address(VirtAddr::new(align_up(self.end, Size4KiB::SIZE)));
+        Page::range(start, end)
+    }
+
+    fn contains_page(&self, page: Page) -> bool {
+        let pages = self.pages();
+        pages.start <= page && page < pages.end
+    }
+}
+
+/// Page table flags for a segment with the ELF flags `flags`, code and data of user
+/// space are never writable and executable both unless the segment asks for it
+fn segment_flags(flags: program::Flags) -> PageTableFlags {
+    let mut ptf = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if flags.is_write() {
+        ptf |= PageTableFlags::WRITABLE;
+    }
+    if !flags.is_execute() && Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        ptf |= PageTableFlags::NO_EXECUTE;
+    }
+    ptf
+}
 
 /// This struct represents a loaded ELF executable in memory starting at an
 /// offset. Using the [elfloader] crate we allocate memory for the elf
@@ -34,18 +253,157 @@ pub fn align_bin(bin: &[u8]) -> Vec<u8> {
 pub struct ElfMemory {
     /// Virtual base where the elf mapping starts at
     vbase: u64,
+
+    /// Dynamic symbol table of the object being loaded
+    symbols: Vec<Symbol>,
+
+    /// Previously loaded objects that undefined symbols are resolved against
+    scope: Vec<Arc<SharedObject>>,
+
+    /// Thread local storage of the object being loaded
+    tls: Option<TlsTemplate>,
+
+    /// Loadable segments mapped by [ElfLoader::allocate], nothing outside of them is
+    /// written while loading
+    segments: Vec<Segment>,
 }
 
 impl ElfMemory {
     /// Create a new ElfMemory at an offset in virtual memory
     pub fn new(vbase: u64) -> Self {
-        Self { vbase }
+        Self {
+            vbase,
+            symbols: Vec::new(),
+            scope: Vec::new(),
+            tls: None,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Create a new ElfMemory for a dynamically linked object, undefined symbols
+    /// are looked up in the objects of `scope` in order
+    pub fn with_scope(
+        vbase: u64,
+        symbols: Vec<Symbol>,
+        scope: Vec<Arc<SharedObject>>,
+        tls: Option<TlsTemplate>,
+    ) -> Self {
+        Self {
+            vbase,
+            symbols,
+            scope,
+            tls,
+            segments: Vec::new(),
+        }
     }
 
     /// Get a reference to loaded elf base memory address.
     pub fn vbase(&self) -> &u64 {
         &self.vbase
     }
+
+    /// Turn the loaded elf into a [SharedObject] other objects can link against
+    pub fn into_shared_object(self, name: String) -> SharedObject {
+        SharedObject {
+            name,
+            vbase: self.vbase,
+            symbols: self.symbols,
+            tls: self.tls,
+        }
+    }
+
+    /// Locate the symbol at `index` of the dynamic symbol table, first in this
+    /// object and then in the objects of the scope. Index 0 refers to the
+    /// object itself
+    fn resolve(&self, index: u32) -> Result<Resolved, ElfLoaderErr> {
+        let local = Resolved {
+            base: self.vbase,
+            value: 0,
+            tls: self.tls,
+        };
+        if index == 0 {
+            return Ok(local);
+        }
+
+        let symbol = self
+            .symbols
+            .get(index as usize)
+            .ok_or(ElfLoaderErr::SymbolTableNotFound)?;
+
+        if symbol.defined {
+            return Ok(Resolved {
+                base: if symbol.absolute { 0 } else { self.vbase },
+                value: symbol.value,
+                ..local
+            });
+        }
+
+        let found = self
+            .scope
+            .iter()
+            .find_map(|object| object.find(&symbol.name));
+        match found {
+            Some(resolved) => Ok(resolved),
+            None if symbol.weak => Ok(Resolved {
+                base: 0,
+                value: 0,
+                tls: None,
+            }),
+            None => Err(ElfLoaderErr::SymbolTableNotFound),
+        }
+    }
+
+    /// The address `offset` bytes from the load base, if the `len` bytes from there lie
+    /// in one of the loaded segments
+    fn target(&self, offset: u64, len: u64) -> Result<u64, ElfLoaderErr> {
+        let start = self
+            .vbase
+            .checked_add(offset)
+            .ok_or(ElfLoaderErr::UnsupportedElfFormat)?;
+        let end = start
+            .checked_add(len)
+            .ok_or(ElfLoaderErr::UnsupportedElfFormat)?;
+        self.segments
+            .iter()
+            .any(|segment| segment.start <= start && end <= segment.end)
+            .then(|| start)
+            .ok_or(ElfLoaderErr::UnsupportedElfFormat)
+    }
+
+    /// Give the pages of the loaded segments the permissions their flags ask for, a page
+    /// shared by two segments gets what both need. Segments are mapped writable until
+    /// they are loaded and relocated, so this runs once the object is loaded
+    pub fn protect_segments(&self) -> Result<(), ElfLoaderErr> {
+        // A shared page is executable if either segment is
+        let page_flags = |page: Page| {
+            self.segments
+                .iter()
+                .filter(|segment| segment.contains_page(page))
+                .fold(PageTableFlags::NO_EXECUTE, |flags, segment| {
+                    let both = flags & segment.flags & PageTableFlags::NO_EXECUTE;
+                    ((flags | segment.flags) - PageTableFlags::NO_EXECUTE) | both
+                })
+        };
+
+        for segment in &self.segments {
+            let pages = segment.pages();
+            if pages.is_empty() {
+                continue;
+            }
+            virt::protect_range(pages, segment.flags).map_err(|_| ElfLoaderErr::OutOfMemory)?;
+            for &page in &[pages.start, pages.end - 1] {
+                virt::protect_range(Page::range(page, page + 1), page_flags(page))
+                    .map_err(|_| ElfLoaderErr::OutOfMemory)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Map the pages of a segment to new frames, .bss and the GOT are expected to start out
+/// zeroed
+fn map_segment(pages: PageRange, flags: PageTableFlags) -> Result<(), ElfLoaderErr> {
+    virt::map_zeroed(pages, flags).map_err(|_| ElfLoaderErr::OutOfMemory)
 }
 
 impl ElfLoader for ElfMemory {
@@ -55,36 +413,52 @@ impl ElfLoader for ElfMemory {
         &mut self,
         load_headers: elfloader::LoadableHeaders,
     ) -> Result<(), elfloader::ElfLoaderErr> {
-        let mut current_pt = RecursivePageTable::new(active_level_4_table()).unwrap();
         for header in load_headers {
-            let _flags = header.flags();
-            let ptf = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            // Writable until the segment is loaded and relocated, see [ElfMemory::protect_segments]
+            let ptf = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE;
 
-            let start = self.vbase + header.virtual_addr();
-            let end = align_up(start + header.mem_size(), Size4KiB::SIZE);
-            let start_virt = VirtAddr::new(start);
-            let end_virt = VirtAddr::new(end - 1);
+            let start = self
+                .vbase
+                .checked_add(header.virtual_addr())
+                .ok_or(ElfLoaderErr::UnsupportedElfFormat)?;
+            let segment_end = start
+                .checked_add(header.mem_size())
+                .ok_or(ElfLoaderErr::UnsupportedElfFormat)?;
+            if start < USER_START || segment_end > USER_END {
+                return Err(ElfLoaderErr::UnsupportedElfFormat);
+            }
+            if header.mem_size() == 0 {
+                continue;
+            }
+            self.segments.push(Segment {
+                start,
+                end: segment_end,
+                flags: segment_flags(header.flags()),
+            });
+
+            let end = align_up(segment_end, Size4KiB::SIZE);
+            let start_virt =
+                VirtAddr::try_new(start).map_err(|_| ElfLoaderErr::UnsupportedElfFormat)?;
+            let end_virt =
+                VirtAddr::try_new(end - 1).map_err(|_| ElfLoaderErr::UnsupportedElfFormat)?;
             let start_page = Page::<Size4KiB>::containing_address(start_virt);
             let end_page = Page::<Size4KiB>::containing_address(end_virt);
-            let page_range = Page::range_inclusive(start_page, end_page);
-            for page in page_range {
-                let frame = FRAME_ALLOCATOR.wait().unwrap().allocate_frame().unwrap();
-
-                unsafe {
-                    let result = current_pt.map_to_with_table_flags(
-                        page,
-                        frame,
-                        ptf,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        FRAME_ALLOCATOR.wait().as_mut().unwrap(),
-                    );
-
-                    match result {
-                        Ok(mapper_flush) => mapper_flush.flush(),
-                        Err(err) => panic!("{:#?} => {:#?}", page, err),
-                    }
+
+            // Segments of different objects may share a page, each run of pages that are
+            // not mapped yet is mapped at once so it can use huge pages
+            let mut unmapped = None;
+            for page in Page::range_inclusive(start_page, end_page) {
+                if virt::mapped_page_size(page.start_address()).is_none() {
+                    unmapped.get_or_insert(page);
+                } else if let Some(first) = unmapped.take() {
+                    map_segment(Page::range(first, page), ptf)?;
                 }
             }
+            if let Some(first) = unmapped {
+                map_segment(Page::range(first, end_page + 1), ptf)?;
+            }
         }
 
         Ok(())
@@ -94,14 +468,15 @@ impl ElfLoader for ElfMemory {
     /// into the buffer obtained from allocate
     fn load(
         &mut self,
-        flags: elfloader::Flags,
+        _flags: elfloader::Flags,
         base: elfloader::VAddr,
         region: &[u8],
     ) -> Result<(), elfloader::ElfLoaderErr> {
-        let start = self.vbase + base;
+        let start = self.target(base, region.len() as u64)?;
 
         let start_ptr = start as *mut u8;
 
+        // SAFETY: The region lies in a segment mapped writable by `allocate`
         for (offset, entry) in region.iter().enumerate() {
             unsafe {
                 *(start_ptr.add(offset)) = *entry;
@@ -111,22 +486,44 @@ impl ElfLoader for ElfMemory {
         Ok(())
     }
 
-    /// TODO
+    /// Apply a relocation entry to the loaded elf
+    ///
+    /// Symbol based relocations are resolved through the dynamic symbol table
+    /// of this object and then the objects in its scope. Thread local relocations
+    /// use the static TLS layout where each block sits below the thread pointer
     fn relocate(
         &mut self,
         entry: &elfloader::Rela<elfloader::P64>,
     ) -> Result<(), elfloader::ElfLoaderErr> {
         let typ = TypeRela64::from(entry.get_type());
-        let addr: *mut u64 = (self.vbase + entry.get_offset()) as *mut u64;
+        let addr = self.target(entry.get_offset(), core::mem::size_of::<u64>() as u64)? as *mut u64;
+        let addend = entry.get_addend();
+        let index = entry.get_symbol_table_index();
 
-        match typ {
-            TypeRela64::R_RELATIVE => {
-                unsafe { *addr = self.vbase() + entry.get_addend() };
-                Ok(())
+        let value = match typ {
+            TypeRela64::R_NONE => return Ok(()),
+            TypeRela64::R_RELATIVE => self.vbase().wrapping_add(addend),
+            TypeRela64::R_64 => self.resolve(index)?.address().wrapping_add(addend),
+            TypeRela64::R_GLOB_DAT | TypeRela64::R_JMP_SLOT => self.resolve(index)?.address(),
+            TypeRela64::R_DTPMOD64 => {
+                self.resolve(index)?
+                    .tls
+                    .ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?
+                    .module_id
             }
-            _ => todo!("{:#?} else not yet implemented", typ)
-        }
-        
+            TypeRela64::R_DTPOFF64 => self.resolve(index)?.value.wrapping_add(addend),
+            TypeRela64::R_TPOFF64 => {
+                let symbol = self.resolve(index)?;
+                let tls = symbol.tls.ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?;
+                symbol.value.wrapping_add(addend).wrapping_sub(tls.offset)
+            }
+            _ => return Err(ElfLoaderErr::UnsupportedRelocationEntry),
+        };
+
+        // SAFETY: The target lies in a segment mapped writable by `allocate`, relocations
+        // do not have to be aligned
+        unsafe { addr.write_unaligned(value) };
+        Ok(())
     }
 }
 
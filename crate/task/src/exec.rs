@@ -0,0 +1,93 @@
+//! Starting programs stored on the filesystem, either as a new task or in
+//! place of the program a task is currently running
+use crate::{
+    dynamic::LinkError,
+    process::Process,
+    rlimit::Limits,
+    scheduler::Scheduler,
+    task::{Context, Ring, Task, TaskID},
+};
+use fs::inode::FileSystemError;
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+
+/// Errors that can happen while starting a program from the filesystem
+#[derive(Debug)]
+pub enum ExecError {
+    /// The path could not be read from the filesystem
+    FileSystem(FileSystemError),
+    /// The file is not an executable that could be loaded and linked
+    Link(LinkError),
+    /// The scheduler has no room for another task
+    QueueFull,
+}
+
+impl From<FileSystemError> for ExecError {
+    fn from(err: FileSystemError) -> Self {
+        ExecError::FileSystem(err)
+    }
+}
+
+impl From<LinkError> for ExecError {
+    fn from(err: LinkError) -> Self {
+        ExecError::Link(err)
+    }
+}
+
+/// Load the executable at `path` into a new ring 3 task and hand it to the scheduler,
+/// the new process becomes a child of `parent` in its group and session and starts with
+/// its resource limits if given
+///
+/// Must not be called with the scheduler locked
+pub fn spawn(
+    path: &str,
+    args: Vec<String>,
+    env: Vec<String>,
+    parent: Option<&Process>,
+) -> Result<TaskID, ExecError> {
+    let bin = fs::read_file(path)?;
+    let limits = parent.map_or_else(Limits::default, Process::limits);
+    let task = Task::load(
+        String::from(path),
+        &bin,
+        Ring::Ring3,
+        None,
+        args,
+        env,
+        limits,
+    )?;
+    let task_id = task.task_id();
+    let process = task.process().clone();
+    if let Some(parent) = parent {
+        parent.adopt(&process);
+    }
+
+    // A child that never runs must not be left for the parent to wait for
+    if Scheduler::add_task(task).is_err() {
+        if let Some(parent) = parent {
+            parent.disown(&process);
+        }
+        return Err(ExecError::QueueFull);
+    }
+    Ok(task_id)
+}
+
+/// Replace the program `task` is running with the executable at `path`
+///
+/// `context` is the saved register frame the task returns to, on success it is
+/// replaced so that returning from the interrupt starts the new program at its
+/// entry point. On failure the task keeps running its current program
+pub fn exec(
+    task: &mut Task,
+    context: &mut Context,
+    path: &str,
+    args: Vec<String>,
+    env: Vec<String>,
+) -> Result<(), ExecError> {
+    let bin = fs::read_file(path)?;
+    task.exec(String::from(path), &bin, args, env)?;
+
+    *context = *task.context();
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Process groups, sessions and waiting for child processes
+//!
+//! Every process belongs to a process group and every group to a session, both are
+//! named after the ID of the process that created them and are inherited by children.
+//! The terminal sends the signals typed at it to its foreground group, see
+//! [terminal](crate::terminal). A parent learns about its children exiting or
+//! stopping through the [ChildStatus] it keeps for each of them
+use crate::process::Process;
+use crate::task::TaskID;
+
+/// `waitpid` returns 0 instead of blocking when no child has changed its state
+pub const WNOHANG: u64 = 1;
+/// `waitpid` also reports children that have been stopped
+pub const WUNTRACED: u64 = 2;
+
+/// What a parent has not been told about a child yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildStatus {
+    /// Nothing to report
+    Running,
+    /// The child has been stopped by the signal
+    Stopped(u32),
+    /// The child has ended with the wait status, it is forgotten once waited for
+    Exited(u32),
+}
+
+/// Wait status of a process that exited with `code`
+pub fn exit_status(code: u64) -> u32 {
+    ((code & 0xff) as u32) << 8
+}
+
+/// Wait status of a process ended by `signal`
+pub fn signal_status(signal: u32, core_dumped: bool) -> u32 {
+    signal | if core_dumped { 0x80 } else { 0 }
+}
+
+/// Wait status of a process stopped by `signal`
+pub fn stop_status(signal: u32) -> u32 {
+    signal << 8 | 0x7f
+}
+
+/// Which children `waitpid` waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitTarget {
+    Any,
+    Child(TaskID),
+    Group(TaskID),
+}
+
+impl WaitTarget {
+    /// Decode the pid parameter of `waitpid`, -1 is any child, 0 any child in the
+    /// callers group `own_group` and -pgid any child in the group pgid
+    pub fn from_pid(pid: i64, own_group: TaskID) -> Self {
+        match pid {
+            -1 => WaitTarget::Any,
+            0 => WaitTarget::Group(own_group),
+            pid if pid < 0 => WaitTarget::Group(TaskID::new((-pid) as usize)),
+            pid => WaitTarget::Child(TaskID::new(pid as usize)),
+        }
+    }
+
+    /// Whether the child `child` in the group `group` is waited for
+    pub fn matches(self, child: TaskID, group: TaskID) -> bool {
+        match self {
+            WaitTarget::Any => true,
+            WaitTarget::Child(id) => id == child,
+            WaitTarget::Group(id) => id == group,
+        }
+    }
+}
+
+/// Errors from changing process groups, sessions and the foreground group or from
+/// waiting for children
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobError {
+    /// The process does not exist or may not be changed by the caller
+    NoSuchProcess,
+    /// The change would move a process out of its session or the group is not
+    /// one of the callers session
+    NotPermitted,
+    /// The caller has no child it could wait for
+    NoChild,
+    /// The terminal is not the controlling terminal of the callers session
+    NotTerminal,
+}
+
+/// Move the process `pid` into the group `pgid`, like `setpgid`. A process can move
+/// itself and its children, but only within its session and into a new group named
+/// after the moved process or a group that already exists in the session
+pub fn set_process_group(caller: &Process, pid: TaskID, pgid: TaskID) -> Result<(), JobError> {
+    let process = Process::find(pid).ok_or(JobError::NoSuchProcess)?;
+    if pid != caller.process_id() && process.parent() != Some(caller.process_id()) {
+        return Err(JobError::NoSuchProcess);
+    }
+    if process.session() != caller.session() || process.session() == pid {
+        return Err(JobError::NotPermitted);
+    }
+
+    let group_exists = Process::all()
+        .iter()
+        .any(|p| p.process_group() == pgid && p.session() == caller.session());
+    if pgid != pid && !group_exists {
+        return Err(JobError::NotPermitted);
+    }
+
+    process.set_process_group(pgid);
+    if let Some(parent) = process.parent().and_then(Process::find) {
+        parent.update_child(&process, None);
+    }
+    Ok(())
+}
+
+/// Make `process` the leader of a new session and of a new group in it, like
+/// `setsid`. Returns the ID of the session, which has no controlling terminal.
+/// A process that already leads a group can not start a session
+pub fn create_session(process: &Process) -> Result<TaskID, JobError> {
+    let pid = process.process_id();
+    if Process::all().iter().any(|p| p.process_group() == pid) {
+        return Err(JobError::NotPermitted);
+    }
+
+    process.set_session(pid);
+    process.set_process_group(pid);
+    if let Some(parent) = process.parent().and_then(Process::find) {
+        parent.update_child(process, None);
+    }
+    Ok(pid)
+}
@@ -3,7 +3,20 @@
 #![feature(naked_functions)]
 #![feature(ptr_internals)]
 #![feature(thread_local)]
+#![feature(const_btree_new)]
 
+pub mod dynamic;
 pub mod elf;
+pub mod exec;
+pub mod job;
+pub mod process;
+pub mod region;
+pub mod rlimit;
 pub mod scheduler;
+pub mod shm;
+pub mod signal;
+pub mod stack;
+pub mod table;
 pub mod task;
+pub mod terminal;
+pub mod thread;
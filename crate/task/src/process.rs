@@ -0,0 +1,643 @@
+//! Processes own what the threads of a program share, the address space, the open
+//! files, the shared memory handles, the credentials and the resource limits. Every
+//! [Task](crate::task::Task) is a thread of exactly one process and keeps it alive
+//! through an [Arc]
+use core::{
+    iter, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use fs::file_table::FileTable;
+use memory::{
+    address_space::AddressSpace,
+    phys::{put_frame, FrameFlags},
+    virt::{COPY_ON_WRITE, SHARED},
+};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::dynamic::{DynamicLinker, LinkError, LoadedImage};
+use crate::job::{stop_status, ChildStatus, JobError, WaitTarget};
+use crate::region::Regions;
+use crate::rlimit::{self, LimitError, Limits, RLimit, RLIMIT_NOFILE};
+use crate::shm::Handles;
+use crate::signal::{SigAction, SignalSet, NSIG, SIG_IGN};
+use crate::stack::{build_initial_stack, initial_stack_size, AuxiliaryValues};
+use crate::task::{Ring, TaskID, USER_P4_INDICES};
+use crate::thread;
+
+extern crate alloc;
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+/// Process every kernel thread belongs to, task IDs are handed out from 1 so
+/// this never collides with a user process
+pub const KERNEL_PROCESS_ID: TaskID = TaskID::new(0);
+
+static KERNEL_PROCESS: Once<Arc<Process>> = Once::new();
+
+/// Every user process that is alive, keyed by its process ID
+static PROCESSES: Mutex<BTreeMap<TaskID, Weak<Process>>> = Mutex::new(BTreeMap::new());
+
+/// User and group a process acts as
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What a process knows about one of its children
+#[derive(Debug, Clone, Copy)]
+struct Child {
+    /// Group the child was in when it last changed, `waitpid` can select by it
+    /// after the child has exited
+    group: TaskID,
+    status: ChildStatus,
+}
+
+/// Registers the first thread of a newly loaded program starts with
+#[derive(Debug, Clone, Copy)]
+pub struct InitialThread {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    /// Value of the FS base, points at the thread control block
+    pub thread_pointer: VirtAddr,
+}
+
+pub struct Process {
+    process_id: TaskID,
+    pub name: String,
+    pub ring: Ring,
+    credentials: Credentials,
+    address_space: AddressSpace,
+    image: LoadedImage,
+    /// The heap and the mappings made with `mmap`
+    regions: Mutex<Regions>,
+    args: Vec<String>,
+    env: Vec<String>,
+    files: FileTable,
+    /// Handles to shared memory objects
+    shared_memory: Mutex<Handles>,
+    /// Threads of the process, an exited thread keeps its exit value here until
+    /// another thread joins it
+    threads: Mutex<BTreeMap<TaskID, Option<u64>>>,
+    /// Next free thread slot, see [thread](crate::thread)
+    next_slot: AtomicUsize,
+    exited: AtomicBool,
+    /// Set by a stop signal, threads of a stopped process are not scheduled
+    stopped: AtomicBool,
+    /// Signals sent to the process that no thread has taken yet
+    pending_signals: Mutex<SignalSet>,
+    signal_actions: Mutex<[SigAction; NSIG]>,
+    /// ID of the process that started this one, 0 if the kernel did
+    parent: AtomicUsize,
+    process_group: AtomicUsize,
+    session: AtomicUsize,
+    children: Mutex<BTreeMap<TaskID, Child>>,
+    limits: Mutex<Limits>,
+    /// Timer ticks all threads of the process have run for, checked against
+    /// [RLIMIT_CPU](crate::rlimit::RLIMIT_CPU)
+    cpu_ticks: AtomicU64,
+}
+
+impl Process {
+    /// Load an executable and its shared libraries into a new address space and set
+    /// up the stack and TLS of its first thread in slot 0. The stack holds argc, argv,
+    /// envp and the auxiliary vector as described in [stack](crate::stack), its size
+    /// and the number of files the process can open are taken from `limits`
+    ///
+    /// The address space that was active before is active again when this returns
+    #[allow(clippy::too_many_arguments)]
+    pub fn load(
+        process_id: TaskID,
+        name: String,
+        bin: &[u8],
+        ring: Ring,
+        offset: u64,
+        args: Vec<String>,
+        env: Vec<String>,
+        limits: Limits,
+    ) -> Result<(Process, InitialThread), LinkError> {
+        let address_space = AddressSpace::with_owner(process_id.get_id() as u32);
+
+        let loaded = address_space.enter(|| {
+            let image = DynamicLinker::new().load_executable(&name, bin, offset)?;
+
+            let aux = AuxiliaryValues {
+                phdr: image.phdr,
+                phnum: image.phnum,
+                entry: image.entry.as_u64(),
+                base: 0,
+            };
+            let stack_pages = rlimit::stack_pages(&limits);
+            if initial_stack_size(&args, &env) > stack_pages as u64 * Size4KiB::SIZE {
+                return Err(LinkError::TooBig);
+            }
+            let stack_top = thread::map_stack(0, stack_pages).map_err(|_| LinkError::NoMemory)?;
+
+            let initial = unsafe {
+                InitialThread {
+                    entry: image.entry,
+                    stack_pointer: build_initial_stack(stack_top, &args, &env, aux),
+                    thread_pointer: thread::map_tls(0, &image).map_err(|_| LinkError::NoMemory)?,
+                }
+            };
+            Ok::<_, LinkError>((image, initial))
+        });
+        let (image, initial) = loaded?;
+
+        let process = Process {
+            process_id,
+            name,
+            ring,
+            credentials: Credentials::default(),
+            address_space,
+            regions: Mutex::new(Regions::new(image.end)),
+            image,
+            args,
+            env,
+            files: FileTable::with_limit(limits.soft(RLIMIT_NOFILE) as usize),
+            shared_memory: Mutex::new(Handles::default()),
+            threads: Mutex::new(BTreeMap::new()),
+            next_slot: AtomicUsize::new(1),
+            exited: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            pending_signals: Mutex::new(SignalSet::empty()),
+            signal_actions: Mutex::new([SigAction::default(); NSIG]),
+            parent: AtomicUsize::new(KERNEL_PROCESS_ID.get_id()),
+            process_group: AtomicUsize::new(process_id.get_id()),
+            session: AtomicUsize::new(process_id.get_id()),
+            children: Mutex::new(BTreeMap::new()),
+            limits: Mutex::new(limits),
+            cpu_ticks: AtomicU64::new(0),
+        };
+
+        Ok((process, initial))
+    }
+
+    /// Load a new program that replaces this one, the new process keeps the ID, ring,
+    /// open files, credentials, parent, children, group, session, resource limits and
+    /// CPU time of this process but none of its threads
+    pub fn exec(
+        &self,
+        name: String,
+        bin: &[u8],
+        args: Vec<String>,
+        env: Vec<String>,
+    ) -> Result<(Process, InitialThread), LinkError> {
+        let (mut process, initial) = Process::load(
+            self.process_id,
+            name,
+            bin,
+            self.ring,
+            crate::task::DEFAULT_OFFSET,
+            args,
+            env,
+            self.limits(),
+        )?;
+
+        process.cpu_ticks = AtomicU64::new(self.cpu_ticks.load(Ordering::Acquire));
+        process.files = self.files.fork();
+        process.shared_memory = Mutex::new(self.shared_memory.lock().clone());
+        process.credentials = self.credentials;
+        process.parent = AtomicUsize::new(self.parent.load(Ordering::Acquire));
+        process.process_group = AtomicUsize::new(self.process_group().get_id());
+        process.session = AtomicUsize::new(self.session().get_id());
+        process.children = Mutex::new(core::mem::take(&mut *self.children.lock()));
+
+        // Handlers are gone with the old program but ignored signals stay ignored
+        let mut actions = process.signal_actions.lock();
+        for (action, old) in actions.iter_mut().zip(self.signal_actions.lock().iter()) {
+            if old.handler == SIG_IGN {
+                *action = *old;
+            }
+        }
+        drop(actions);
+
+        Ok((process, initial))
+    }
+
+    /// Make a user process findable by its ID with [find](Process::find), a process
+    /// that replaces another one through exec takes its place
+    pub fn register(process: Process) -> Arc<Process> {
+        let process = Arc::new(process);
+        PROCESSES
+            .lock()
+            .insert(process.process_id, Arc::downgrade(&process));
+        process
+    }
+
+    /// Find the user process with the ID `process_id` if it has not exited
+    pub fn find(process_id: TaskID) -> Option<Arc<Process>> {
+        let process = PROCESSES.lock().get(&process_id)?.upgrade()?;
+        (!process.has_exited()).then(|| process)
+    }
+
+    /// Every user process that has not exited
+    pub fn all() -> Vec<Arc<Process>> {
+        // Collected before filtering, dropping a process takes the registry lock
+        let processes: Vec<_> = PROCESSES
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        processes
+            .into_iter()
+            .filter(|process| !process.has_exited())
+            .collect()
+    }
+
+    /// Create a child process with a copy of this processes address space, files and
+    /// resource limits in the same group and session, the child starts without any
+    /// threads and without any CPU time used
+    ///
+    /// Pages are not copied but shared, writable pages become read only [COPY_ON_WRITE]
+    /// pages in both processes and get copied by the page fault handler on the first
+    /// write. Pages of [SHARED] mappings stay writable and keep being shared. Huge
+    /// pages are split, only 4 KiB pages are shared
+    ///
+    /// Fails if there are no frames left for the page tables, the pages of this process
+    /// stay copy on write
+    pub fn fork(&self, process_id: TaskID) -> Result<Process, MapToError<Size4KiB>> {
+        let mut mappings = Vec::new();
+        let address_space = &self.address_space;
+        address_space.split_huge_pages(USER_P4_INDICES)?;
+        address_space.for_each_mapping(USER_P4_INDICES, |page, entry| {
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+
+            let frame = entry.frame().unwrap();
+            memory::phys::get_frame(frame);
+            if flags.contains(COPY_ON_WRITE) {
+                memory::phys::update_frame(frame, |descriptor| {
+                    descriptor.flags.insert(FrameFlags::COPY_ON_WRITE)
+                });
+            }
+            mappings.push((page, frame, flags));
+        });
+        if address_space.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+
+        // The child is filled in without switching to it
+        let child_space = AddressSpace::with_owner(process_id.get_id() as u32);
+        let mut mappings = mappings.into_iter();
+        while let Some((page, frame, flags)) = mappings.next() {
+            if let Err(err) = child_space.map(page, frame, flags) {
+                // Dropping the child space only drops the references of the pages it maps
+                for (_, frame, _) in iter::once((page, frame, flags)).chain(mappings) {
+                    unsafe { put_frame(frame) };
+                }
+                return Err(err);
+            }
+        }
+
+        let child = Process {
+            process_id,
+            name: self.name.clone(),
+            ring: self.ring,
+            credentials: self.credentials,
+            address_space: child_space,
+            image: self.image.clone(),
+            regions: Mutex::new(self.regions.lock().clone()),
+            args: self.args.clone(),
+            env: self.env.clone(),
+            files: self.files.fork(),
+            shared_memory: Mutex::new(self.shared_memory.lock().clone()),
+            threads: Mutex::new(BTreeMap::new()),
+            next_slot: AtomicUsize::new(self.next_slot.load(Ordering::Acquire)),
+            exited: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            pending_signals: Mutex::new(SignalSet::empty()),
+            signal_actions: Mutex::new(*self.signal_actions.lock()),
+            parent: AtomicUsize::new(KERNEL_PROCESS_ID.get_id()),
+            process_group: AtomicUsize::new(process_id.get_id()),
+            session: AtomicUsize::new(process_id.get_id()),
+            children: Mutex::new(BTreeMap::new()),
+            limits: Mutex::new(self.limits()),
+            cpu_ticks: AtomicU64::new(0),
+        };
+        self.adopt(&child);
+        Ok(child)
+    }
+
+    /// The process kernel threads run in, its address space only holds the kernel
+    pub fn kernel() -> Arc<Process> {
+        KERNEL_PROCESS
+            .call_once(|| {
+                Arc::new(Process {
+                    process_id: KERNEL_PROCESS_ID,
+                    name: String::from("kernel"),
+                    ring: Ring::Ring0,
+                    credentials: Credentials::default(),
+                    address_space: AddressSpace::with_owner(KERNEL_PROCESS_ID.get_id() as u32),
+                    image: LoadedImage::empty(),
+                    regions: Mutex::new(Regions::new(0)),
+                    args: Vec::new(),
+                    env: Vec::new(),
+                    files: FileTable::new(),
+                    shared_memory: Mutex::new(Handles::default()),
+                    threads: Mutex::new(BTreeMap::new()),
+                    next_slot: AtomicUsize::new(0),
+                    exited: AtomicBool::new(false),
+                    stopped: AtomicBool::new(false),
+                    pending_signals: Mutex::new(SignalSet::empty()),
+                    signal_actions: Mutex::new([SigAction::default(); NSIG]),
+                    parent: AtomicUsize::new(KERNEL_PROCESS_ID.get_id()),
+                    process_group: AtomicUsize::new(KERNEL_PROCESS_ID.get_id()),
+                    session: AtomicUsize::new(KERNEL_PROCESS_ID.get_id()),
+                    children: Mutex::new(BTreeMap::new()),
+                    limits: Mutex::new(Limits::default()),
+                    cpu_ticks: AtomicU64::new(0),
+                })
+            })
+            .clone()
+    }
+
+    /// Bytes of user memory mapped into the processes address space
+    pub fn memory_footprint(&self) -> usize {
+        self.address_space.mapped_bytes(USER_P4_INDICES) as usize
+    }
+
+    /// The resource limits of the process, see [rlimit](crate::rlimit)
+    pub fn limits(&self) -> Limits {
+        *self.limits.lock()
+    }
+
+    /// Change the limit of `resource`, only a process running as root can raise a
+    /// hard limit
+    pub fn set_limit(&self, resource: u32, limit: RLimit) -> Result<(), LimitError> {
+        let mut limits = self.limits.lock();
+        limits.set(resource, limit, self.credentials.uid == 0)?;
+        if resource == RLIMIT_NOFILE {
+            self.files.set_limit(limit.cur as usize);
+        }
+        Ok(())
+    }
+
+    /// Charge a timer tick to the process and return the ticks it has run for
+    pub fn charge_tick(&self) -> u64 {
+        self.cpu_ticks.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Reserve the part of the address space for the stack and TLS of a new thread
+    pub fn allocate_slot(&self) -> usize {
+        self.next_slot.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Register a new thread of this process
+    pub fn add_thread(&self, thread: TaskID) {
+        self.threads.lock().insert(thread, None);
+    }
+
+    /// Remove a thread that exited and has been joined
+    pub fn remove_thread(&self, thread: TaskID) {
+        self.threads.lock().remove(&thread);
+    }
+
+    /// Keep the exit value of a thread until another thread joins it
+    pub fn thread_exited(&self, thread: TaskID, value: u64) {
+        if let Some(status) = self.threads.lock().get_mut(&thread) {
+            *status = Some(value);
+        }
+    }
+
+    /// None if `thread` is not a thread of this process, otherwise its exit value
+    /// if it has exited already
+    pub fn thread_status(&self, thread: TaskID) -> Option<Option<u64>> {
+        self.threads.lock().get(&thread).copied()
+    }
+
+    /// Whether any thread of this process has not exited yet
+    pub fn has_live_threads(&self) -> bool {
+        self.threads.lock().values().any(Option::is_none)
+    }
+
+    /// Mark the process as exited, the scheduler drops its remaining threads
+    pub fn exit(&self) {
+        self.exited.store(true, Ordering::Release);
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::Acquire)
+    }
+
+    /// Stop the threads of the process from being scheduled
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+
+    /// Let the threads of a stopped process be scheduled again
+    pub fn resume(&self) {
+        self.stopped.store(false, Ordering::Release);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+
+    /// Make a signal pending on the process
+    pub fn queue_signal(&self, signal: u32) {
+        self.pending_signals.lock().insert(signal);
+    }
+
+    /// Remove the lowest pending signal that is in `allowed`
+    pub fn take_signal(&self, allowed: SignalSet) -> Option<u32> {
+        let mut pending = self.pending_signals.lock();
+        let signal = SignalSet::from_bits(pending.bits() & allowed.bits()).first()?;
+        pending.remove(signal);
+        Some(signal)
+    }
+
+    /// Drop the pending signals that are in `signals`
+    pub fn discard_signals(&self, signals: SignalSet) {
+        let mut pending = self.pending_signals.lock();
+        *pending = pending.difference(signals);
+    }
+
+    /// How the process handles `signal`
+    pub fn action(&self, signal: u32) -> SigAction {
+        self.signal_actions.lock()[signal as usize]
+    }
+
+    /// Change how the process handles `signal` and return the previous action
+    pub fn set_action(&self, signal: u32, action: SigAction) -> SigAction {
+        let mut actions = self.signal_actions.lock();
+        core::mem::replace(&mut actions[signal as usize], action)
+    }
+
+    /// Make `child` a child of this process and move it into this processes group
+    /// and session
+    pub fn adopt(&self, child: &Process) {
+        child
+            .parent
+            .store(self.process_id.get_id(), Ordering::Release);
+        child.set_process_group(self.process_group());
+        child.set_session(self.session());
+
+        self.children.lock().insert(
+            child.process_id,
+            Child {
+                group: child.process_group(),
+                status: ChildStatus::Running,
+            },
+        );
+    }
+
+    /// Forget `child` again after [adopt](Process::adopt), for a child that never got to
+    /// run
+    pub fn disown(&self, child: &Process) {
+        self.children.lock().remove(&child.process_id);
+    }
+
+    /// Record that `child` changed its group or, if `status` is given, its state
+    pub fn update_child(&self, child: &Process, status: Option<ChildStatus>) {
+        if let Some(entry) = self.children.lock().get_mut(&child.process_id) {
+            entry.group = child.process_group();
+            if let Some(status) = status {
+                entry.status = status;
+            }
+        }
+    }
+
+    /// Forget every child, they are left to the kernel when this process ends
+    pub fn release_children(&self) -> Vec<TaskID> {
+        let children = core::mem::take(&mut *self.children.lock());
+        children.keys().copied().collect()
+    }
+
+    /// Take the wait status of a child selected by `target` that has exited or, with
+    /// `untraced`, has been stopped. An exited child is forgotten afterwards
+    pub fn take_child_status(
+        &self,
+        target: WaitTarget,
+        untraced: bool,
+    ) -> Result<Option<(TaskID, u32)>, JobError> {
+        let mut children = self.children.lock();
+        let mut selected = children
+            .iter_mut()
+            .filter(|(id, child)| target.matches(**id, child.group))
+            .peekable();
+        if selected.peek().is_none() {
+            return Err(JobError::NoChild);
+        }
+
+        let found = selected.find_map(|(&id, child)| match child.status {
+            ChildStatus::Exited(status) => Some((id, status)),
+            ChildStatus::Stopped(signal) if untraced => {
+                child.status = ChildStatus::Running;
+                Some((id, stop_status(signal)))
+            }
+            _ => None,
+        });
+
+        if let Some((id, _)) = found {
+            if let ChildStatus::Exited(_) = children[&id].status {
+                children.remove(&id);
+            }
+        }
+        Ok(found)
+    }
+
+    /// The process that started this one, None if the kernel did or it has ended
+    pub fn parent(&self) -> Option<TaskID> {
+        match self.parent.load(Ordering::Acquire) {
+            id if id == KERNEL_PROCESS_ID.get_id() => None,
+            id => Some(TaskID::new(id)),
+        }
+    }
+
+    /// Hand the process to the kernel after its parent ended
+    pub fn orphan(&self) {
+        self.parent
+            .store(KERNEL_PROCESS_ID.get_id(), Ordering::Release);
+    }
+
+    pub fn process_group(&self) -> TaskID {
+        TaskID::new(self.process_group.load(Ordering::Acquire))
+    }
+
+    /// Move the process into another group, see [set_process_group](crate::job::set_process_group)
+    pub fn set_process_group(&self, group: TaskID) {
+        self.process_group.store(group.get_id(), Ordering::Release);
+    }
+
+    pub fn session(&self) -> TaskID {
+        TaskID::new(self.session.load(Ordering::Acquire))
+    }
+
+    /// Move the process into another session, see [create_session](crate::job::create_session)
+    pub fn set_session(&self, session: TaskID) {
+        self.session.store(session.get_id(), Ordering::Release);
+    }
+
+    /// Whether this is the process kernel threads run in
+    pub fn is_kernel(&self) -> bool {
+        self.process_id == KERNEL_PROCESS_ID
+    }
+
+    pub fn process_id(&self) -> TaskID {
+        self.process_id
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        self.credentials
+    }
+
+    /// Get a reference to the process' address space.
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /// Get a reference to the executable and shared objects loaded for this process.
+    pub fn image(&self) -> &LoadedImage {
+        &self.image
+    }
+
+    /// Lock the heap and mappings of the process, see [region](crate::region)
+    pub fn regions(&self) -> MutexGuard<Regions> {
+        self.regions.lock()
+    }
+
+    /// Get a reference to the arguments the program was started with.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Get a reference to the environment the program was started with.
+    pub fn env(&self) -> &[String] {
+        &self.env
+    }
+
+    /// Get a reference to the process' shared memory handles.
+    pub fn shared_memory(&self) -> MutexGuard<Handles> {
+        self.shared_memory.lock()
+    }
+
+    /// Get a reference to the process' open files.
+    pub fn files(&self) -> &FileTable {
+        &self.files
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let mut processes = PROCESSES.lock();
+        let registered = processes.get(&self.process_id).map_or(false, |process| {
+            ptr::eq(process.as_ptr(), self as *const Process)
+        });
+        if registered {
+            processes.remove(&self.process_id);
+        }
+    }
+}
@@ -0,0 +1,512 @@
+//! Memory a user process asks for at run time, the heap moved with `brk` and the
+//! anonymous mappings made with `mmap`
+//!
+//! Every process keeps its [Regions]. The heap starts on the page after the end of the
+//! executable and grows up towards the shared libraries at
+//! [LIBRARY_BASE](crate::dynamic::LIBRARY_BASE). Mappings are placed in the smallest
+//! free range they fit into between [MMAP_BASE] and [MMAP_END], below the thread slots
+//! at the top of the address space.
+//! Pages are mapped when a region is created, zeroed, and only `munmap` and `mprotect`
+//! on regions made by `mmap` are allowed, the executable, libraries and stacks can not
+//! be changed through them. Nothing is mapped that would take the process over its
+//! [RLIMIT_AS](crate::rlimit::RLIMIT_AS) or [RLIMIT_RSS](crate::rlimit::RLIMIT_RSS)
+//!
+//! [Shared memory objects](crate::shm) are mapped with [map_shared], their mappings can
+//! not be given more protection than the handle they were mapped through allows
+//!
+//! The kernel does not enable no-execute pages, so [PROT_EXEC] is accepted but every
+//! readable page can be executed
+use memory::{
+    range::RangeAllocator,
+    virt::{self, SHARED},
+};
+use x86_64::{
+    align_up,
+    structures::paging::{page::PageRange, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::dynamic::LIBRARY_BASE;
+use crate::process::Process;
+use crate::rlimit;
+
+extern crate alloc;
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// The pages can not be accessed
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Changes to the mapping are seen by every process that maps it, forked children too
+pub const MAP_SHARED: u64 = 0x01;
+/// Changes to the mapping are private, a forked child gets a copy on write
+pub const MAP_PRIVATE: u64 = 0x02;
+/// Place the mapping exactly at the address, replacing the mappings there
+pub const MAP_FIXED: u64 = 0x10;
+/// The mapping is not backed by a file, only anonymous mappings are supported
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Lowest address of the area mappings are placed in
+pub const MMAP_BASE: u64 = 0x200_0000_0000;
+/// End of the area mappings are placed in, the thread slots are above it
+pub const MMAP_END: u64 = 0x7000_0000_0000;
+/// The heap can not grow into the shared libraries
+pub const HEAP_END: u64 = LIBRARY_BASE;
+
+/// Errors from changing the heap or mappings of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// An address is not page aligned, a length is 0 or the flags are not supported
+    Invalid,
+    /// There are no frames or no free part of the address space left, the process
+    /// would go over its memory limits, or the range of an `mprotect` is not mapped
+    NoMemory,
+    /// The protection asked for is more than a mapping allows
+    AccessDenied,
+}
+
+/// A mapping made with `mmap`, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    /// [PROT_READ], [PROT_WRITE] and [PROT_EXEC] or [PROT_NONE]
+    pub protection: u64,
+    /// Protection `mprotect` may give the mapping at most, anonymous mappings allow any
+    pub max_protection: u64,
+    pub shared: bool,
+}
+
+impl Region {
+    /// Flags of the page table entries of the region
+    pub fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.protection != PROT_NONE {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.protection & PROT_WRITE != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.shared {
+            flags |= SHARED;
+        }
+        flags
+    }
+
+    fn pages(&self) -> PageRange {
+        pages(self.start, self.end)
+    }
+}
+
+/// The heap and the mappings of a process
+#[derive(Debug, Clone)]
+pub struct Regions {
+    /// Mappings keyed by their start address, they never overlap
+    regions: BTreeMap<u64, Region>,
+    /// Parts of the mapping area no mapping covers
+    free: RangeAllocator,
+    heap_start: u64,
+    /// Current end of the heap, pages are mapped up to the page it is on
+    brk: u64,
+}
+
+impl Regions {
+    /// Regions of a process whose executable ends at `image_end`, without any mappings
+    pub fn new(image_end: u64) -> Self {
+        let heap_start = align_up(image_end, Size4KiB::SIZE);
+        Self {
+            regions: BTreeMap::new(),
+            free: RangeAllocator::with_range(MMAP_BASE..MMAP_END),
+            heap_start,
+            brk: heap_start,
+        }
+    }
+
+    pub fn heap_start(&self) -> u64 {
+        self.heap_start
+    }
+
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// The mapping that contains `addr`
+    pub fn find(&self, addr: u64) -> Option<&Region> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| addr < region.end)
+    }
+
+    /// Every mapping ordered by address
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
+    /// Whether `start..end` lies in the mapping area and overlaps no mapping
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        MMAP_BASE <= start
+            && start < end
+            && end <= MMAP_END
+            && self.overlapping(start, end).next().is_none()
+    }
+
+    /// Start of the smallest free part of the mapping area that `length` bytes fit into
+    /// O(log n)
+    pub fn find_free(&self, length: u64) -> Option<u64> {
+        self.free.find(length, Size4KiB::SIZE)
+    }
+
+    /// Add a mapping to a part of the address space that [is free](Regions::is_free)
+    pub fn insert(&mut self, region: Region) {
+        debug_assert!(self.is_free(region.start, region.end));
+        self.free.reserve(region.start, region.end - region.start);
+        self.regions.insert(region.start, region);
+    }
+
+    /// Remove every part of a mapping that lies in `start..end`, mappings reaching out
+    /// of the range are cut. Returns the removed parts
+    pub fn remove(&mut self, start: u64, end: u64) -> Vec<Region> {
+        let removed: Vec<_> = self.overlapping(start, end).copied().collect();
+        for region in &removed {
+            self.regions.remove(&region.start);
+            if region.start < start {
+                self.regions.insert(
+                    region.start,
+                    Region {
+                        end: start,
+                        ..*region
+                    },
+                );
+            }
+            if end < region.end {
+                self.regions.insert(
+                    end,
+                    Region {
+                        start: end,
+                        ..*region
+                    },
+                );
+            }
+        }
+
+        let removed: Vec<_> = removed
+            .into_iter()
+            .map(|region| Region {
+                start: region.start.max(start),
+                end: region.end.min(end),
+                ..region
+            })
+            .collect();
+        for region in &removed {
+            self.free.free(region.start, region.end - region.start);
+        }
+        removed
+    }
+
+    /// Change the protection of `start..end`, which has to be covered by mappings
+    /// without gaps. Returns the changed parts
+    pub fn protect(
+        &mut self,
+        start: u64,
+        end: u64,
+        protection: u64,
+    ) -> Result<Vec<Region>, RegionError> {
+        let mut covered = start;
+        for region in self.overlapping(start, end) {
+            if region.start > covered {
+                return Err(RegionError::NoMemory);
+            }
+            if protection & !region.max_protection != 0 {
+                return Err(RegionError::AccessDenied);
+            }
+            covered = region.end;
+        }
+        if covered < end {
+            return Err(RegionError::NoMemory);
+        }
+
+        let changed: Vec<_> = self
+            .remove(start, end)
+            .into_iter()
+            .map(|region| Region {
+                protection,
+                ..region
+            })
+            .collect();
+        for region in &changed {
+            self.insert(*region);
+        }
+        Ok(changed)
+    }
+
+    /// Mappings that overlap `start..end` ordered by address
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &Region> {
+        let first = self.find(start).map_or(start, |region| region.start);
+        self.regions
+            .range(first..end)
+            .map(|(_, region)| region)
+            .filter(move |region| region.end > start)
+    }
+}
+
+/// Pages from `start` up to `end`, both page aligned
+fn pages(start: u64, end: u64) -> PageRange {
+    Page::range(
+        Page::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(end)),
+    )
+}
+
+fn is_page_aligned(addr: u64) -> bool {
+    addr % Size4KiB::SIZE == 0
+}
+
+/// Length of a range of `length` bytes rounded up to whole pages
+fn page_length(length: u64) -> Result<u64, RegionError> {
+    match length {
+        0 => Err(RegionError::Invalid),
+        length => length
+            .checked_add(Size4KiB::SIZE - 1)
+            .map(|length| length & !(Size4KiB::SIZE - 1))
+            .ok_or(RegionError::NoMemory),
+    }
+}
+
+/// Move the end of the heap of `process` to `addr`, like `brk`. Returns the new end, or
+/// the current one if the heap can not end there. An `addr` of 0 only returns the end
+///
+/// The address space of `process` must be active
+pub fn brk(process: &Process, addr: u64) -> u64 {
+    let mut regions = process.regions();
+    let current = regions.brk;
+    if addr == 0 || addr < regions.heap_start || addr > HEAP_END {
+        return current;
+    }
+
+    let mapped_end = align_up(current, Size4KiB::SIZE);
+    let new_end = align_up(addr, Size4KiB::SIZE);
+    if new_end > mapped_end {
+        if !rlimit::may_grow(process, new_end - mapped_end) {
+            return current;
+        }
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        if virt::map_zeroed(pages(mapped_end, new_end), flags).is_err() {
+            return current;
+        }
+    } else if new_end < mapped_end && virt::unmap_range(pages(new_end, mapped_end)).is_err() {
+        return current;
+    }
+
+    regions.brk = addr;
+    addr
+}
+
+/// Grow or shrink the heap of `process` by `increment` bytes, like `sbrk`. Returns the
+/// previous end of the heap
+///
+/// The address space of `process` must be active
+pub fn sbrk(process: &Process, increment: i64) -> Result<u64, RegionError> {
+    let current = process.regions().brk;
+    if increment == 0 {
+        return Ok(current);
+    }
+
+    let requested = if increment > 0 {
+        current.checked_add(increment as u64)
+    } else {
+        current.checked_sub(increment.unsigned_abs())
+    }
+    .ok_or(RegionError::NoMemory)?;
+
+    match brk(process, requested) {
+        end if end == requested => Ok(current),
+        _ => Err(RegionError::NoMemory),
+    }
+}
+
+/// Map `length` bytes of zeroed anonymous memory into `process`, like `mmap`. Without
+/// [MAP_FIXED] `addr` is used if it is free and otherwise the lowest free part of the
+/// mapping area. Returns the start of the mapping
+///
+/// With [MAP_FIXED] whatever was mapped in the range is unmapped before the new memory
+/// is mapped. Like on Linux the old mappings are gone even if there are no frames left
+/// for the new one, the range is left unmapped then
+///
+/// The address space of `process` must be active
+pub fn mmap(
+    process: &Process,
+    addr: u64,
+    length: u64,
+    protection: u64,
+    flags: u64,
+) -> Result<VirtAddr, RegionError> {
+    let length = page_length(length)?;
+    if protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        || flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0
+        || flags & MAP_ANONYMOUS == 0
+    {
+        return Err(RegionError::Invalid);
+    }
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(RegionError::Invalid),
+    };
+    if !rlimit::may_grow(process, length) {
+        return Err(RegionError::NoMemory);
+    }
+
+    let mut regions = process.regions();
+    let start = if flags & MAP_FIXED != 0 {
+        let end = addr.checked_add(length).ok_or(RegionError::Invalid)?;
+        if !is_page_aligned(addr) || addr < MMAP_BASE || end > MMAP_END {
+            return Err(RegionError::Invalid);
+        }
+        let removed = regions.remove(addr, end);
+        unmap_removed(&mut regions, removed)?;
+        addr
+    } else {
+        match addr.checked_add(length) {
+            Some(end) if is_page_aligned(addr) && regions.is_free(addr, end) => addr,
+            _ => regions.find_free(length).ok_or(RegionError::NoMemory)?,
+        }
+    };
+
+    let region = Region {
+        start,
+        end: start + length,
+        protection,
+        max_protection: PROT_READ | PROT_WRITE | PROT_EXEC,
+        shared,
+    };
+    virt::map_zeroed(region.pages(), region.flags()).map_err(|_| RegionError::NoMemory)?;
+    regions.insert(region);
+
+    Ok(VirtAddr::new(start))
+}
+
+/// Map `frames` into `process` as a shared mapping with the PROT_* flags `protection`,
+/// which `mprotect` can not raise above `max_protection`. `addr` is used if it is free
+/// and otherwise the lowest free part of the mapping area. Every page holds a reference
+/// to its frame, so the frames stay alive until the mapping is gone. Returns the start
+/// of the mapping
+///
+/// The address space of `process` must be active
+pub fn map_shared(
+    process: &Process,
+    addr: u64,
+    frames: &[PhysFrame],
+    protection: u64,
+    max_protection: u64,
+) -> Result<VirtAddr, RegionError> {
+    let length = (frames.len() as u64)
+        .checked_mul(Size4KiB::SIZE)
+        .ok_or(RegionError::NoMemory)
+        .and_then(page_length)?;
+    if protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(RegionError::Invalid);
+    }
+    if protection & !max_protection != 0 {
+        return Err(RegionError::AccessDenied);
+    }
+    if !rlimit::may_grow(process, length) {
+        return Err(RegionError::NoMemory);
+    }
+
+    let mut regions = process.regions();
+    let start = match addr.checked_add(length) {
+        Some(end) if is_page_aligned(addr) && regions.is_free(addr, end) => addr,
+        _ => regions.find_free(length).ok_or(RegionError::NoMemory)?,
+    };
+
+    let region = Region {
+        start,
+        end: start + length,
+        protection,
+        max_protection,
+        shared: true,
+    };
+    virt::map_frames(region.pages(), frames, region.flags()).map_err(|_| RegionError::NoMemory)?;
+    regions.insert(region);
+
+    Ok(VirtAddr::new(start))
+}
+
+/// Unmap the parts of mappings of `process` in `length` bytes from `addr` and release
+/// their frames, like `munmap`. Parts of the range that are not mapped are skipped
+///
+/// The address space of `process` must be active
+pub fn munmap(process: &Process, addr: u64, length: u64) -> Result<(), RegionError> {
+    let length = page_length(length)?;
+    let end = addr.checked_add(length).ok_or(RegionError::Invalid)?;
+    if !is_page_aligned(addr) {
+        return Err(RegionError::Invalid);
+    }
+
+    let mut regions = process.regions();
+    let removed = regions.remove(addr, end);
+    unmap_removed(&mut regions, removed)
+}
+
+/// Change the protection of `length` bytes of mappings from `addr`, like `mprotect`
+///
+/// The address space of `process` must be active
+pub fn mprotect(
+    process: &Process,
+    addr: u64,
+    length: u64,
+    protection: u64,
+) -> Result<(), RegionError> {
+    let length = page_length(length)?;
+    let end = addr.checked_add(length).ok_or(RegionError::Invalid)?;
+    if !is_page_aligned(addr) || protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(RegionError::Invalid);
+    }
+
+    let mut regions = process.regions();
+    let before: Vec<Region> = regions
+        .iter()
+        .filter(|region| region.start < end && addr < region.end)
+        .copied()
+        .collect();
+    let changed = regions.protect(addr, end, protection)?;
+
+    for (i, region) in changed.iter().enumerate() {
+        if virt::protect_range(region.pages(), region.flags()).is_ok() {
+            continue;
+        }
+        // The pages of this part and the ones after it were left alone, so are they
+        for region in &changed[i..] {
+            let old = before
+                .iter()
+                .find(|old| old.start <= region.start && region.start < old.end)
+                .expect("A changed part is in a mapping from before");
+            regions.remove(region.start, region.end);
+            regions.insert(Region {
+                protection: old.protection,
+                ..*region
+            });
+        }
+        return Err(RegionError::NoMemory);
+    }
+    Ok(())
+}
+
+/// Unmap the parts of mappings `removed` from `regions`. When no memory is left to split
+/// a huge page the part and the ones after it stay mapped and go back into `regions`
+fn unmap_removed(regions: &mut Regions, removed: Vec<Region>) -> Result<(), RegionError> {
+    for (i, region) in removed.iter().enumerate() {
+        if virt::unmap_range(region.pages()).is_err() {
+            for region in &removed[i..] {
+                regions.insert(*region);
+            }
+            return Err(RegionError::NoMemory);
+        }
+    }
+    Ok(())
+}
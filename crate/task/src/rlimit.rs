@@ -0,0 +1,158 @@
+//! Resource limits of processes, read with `getrlimit` and changed with `setrlimit`
+//!
+//! Every resource has a soft limit that is enforced and a hard limit the soft limit can
+//! be raised up to. A process can lower both, only a process running as root can raise
+//! its hard limits. Forked and spawned children start with the limits of their parent
+//! and exec keeps them
+//!
+//! - [RLIMIT_CPU]: seconds of CPU time used by all threads. The scheduler sends
+//!   [SIGXCPU] once a second past the soft limit and kills the process at the hard limit
+//! - [RLIMIT_STACK]: bytes of the stack of every thread started afterwards, at most
+//!   [MAX_STACK_SIZE]
+//! - [RLIMIT_RSS]: bytes of memory mapped into the process
+//! - [RLIMIT_NOFILE]: one more than the highest file descriptor that can be opened
+//! - [RLIMIT_AS]: bytes of address space used
+//!
+//! Pages are mapped as soon as they are allocated, so the address space and the resident
+//! memory of a process are the same and both limits are checked by [may_grow] wherever
+//! pages are added, `brk`, `mmap`, shared memory mappings and new threads
+use fs::file_table;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+use crate::process::Process;
+use crate::signal::{SIGKILL, SIGXCPU};
+use crate::task::USER_STACK_PAGES;
+use crate::thread::THREAD_SLOT_SIZE;
+
+pub const RLIMIT_CPU: u32 = 0;
+pub const RLIMIT_STACK: u32 = 3;
+pub const RLIMIT_RSS: u32 = 5;
+pub const RLIMIT_NOFILE: u32 = 7;
+pub const RLIMIT_AS: u32 = 9;
+
+/// Resource numbers are below this value, the ones between the supported resources
+/// are the unsupported ones of Linux
+pub const RLIM_NLIMITS: usize = 10;
+
+/// No limit
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Largest stack a thread can have, the other half of its thread slot is left for TLS
+pub const MAX_STACK_SIZE: u64 = THREAD_SLOT_SIZE / 2;
+
+/// Hard limit of [RLIMIT_NOFILE] processes start with
+const MAX_FILES: u64 = 4096;
+
+/// The timer runs at the rate the PIT starts with, 1193182 Hz / 65536
+const TICKS_PER_SECOND: u64 = 18;
+
+/// Errors from reading or changing a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// The resource is not supported or the soft limit is above the hard limit
+    Invalid,
+    /// A hard limit would be raised by a process not running as root
+    NotPermitted,
+}
+
+/// A soft and a hard limit, laid out like the `rlimit` structure of the syscalls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RLimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl RLimit {
+    pub const INFINITY: RLimit = RLimit {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+}
+
+/// The limits of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits([RLimit; RLIM_NLIMITS]);
+
+impl Limits {
+    /// The limit of `resource`
+    pub fn get(&self, resource: u32) -> Result<RLimit, LimitError> {
+        if !is_supported(resource) {
+            return Err(LimitError::Invalid);
+        }
+        Ok(self.0[resource as usize])
+    }
+
+    /// Change the limit of `resource`, raising the hard limit needs `privileged`
+    pub fn set(
+        &mut self,
+        resource: u32,
+        limit: RLimit,
+        privileged: bool,
+    ) -> Result<(), LimitError> {
+        let current = self.get(resource)?;
+        if limit.cur > limit.max {
+            return Err(LimitError::Invalid);
+        }
+        if limit.max > current.max && !privileged {
+            return Err(LimitError::NotPermitted);
+        }
+        self.0[resource as usize] = limit;
+        Ok(())
+    }
+
+    /// The soft limit of `resource`, which has to be supported
+    pub fn soft(&self, resource: u32) -> u64 {
+        self.0[resource as usize].cur
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        let mut limits = [RLimit::INFINITY; RLIM_NLIMITS];
+        limits[RLIMIT_STACK as usize] = RLimit {
+            cur: Size4KiB::SIZE * USER_STACK_PAGES as u64,
+            max: MAX_STACK_SIZE,
+        };
+        limits[RLIMIT_NOFILE as usize] = RLimit {
+            cur: file_table::DEFAULT_LIMIT as u64,
+            max: MAX_FILES,
+        };
+        Self(limits)
+    }
+}
+
+fn is_supported(resource: u32) -> bool {
+    matches!(
+        resource,
+        RLIMIT_CPU | RLIMIT_STACK | RLIMIT_RSS | RLIMIT_NOFILE | RLIMIT_AS
+    )
+}
+
+/// Whether `bytes` more can be mapped into `process` without going over the soft limit
+/// of [RLIMIT_AS] or [RLIMIT_RSS]
+pub fn may_grow(process: &Process, bytes: u64) -> bool {
+    let limits = process.limits();
+    let limit = limits.soft(RLIMIT_AS).min(limits.soft(RLIMIT_RSS));
+    limit == RLIM_INFINITY || (process.memory_footprint() as u64).saturating_add(bytes) <= limit
+}
+
+/// Pages of the stack of a new thread of a process with the limits `limits`
+pub fn stack_pages(limits: &Limits) -> usize {
+    let bytes = limits.soft(RLIMIT_STACK).min(MAX_STACK_SIZE);
+    (bytes / Size4KiB::SIZE).max(1) as usize
+}
+
+/// The signal a process with the CPU limit `limit` gets after running for `ticks`
+/// timer ticks, checked on every tick
+pub fn cpu_signal(limit: RLimit, ticks: u64) -> Option<u32> {
+    let hard = limit.max.saturating_mul(TICKS_PER_SECOND);
+    let soft = limit.cur.saturating_mul(TICKS_PER_SECOND);
+    if ticks >= hard {
+        Some(SIGKILL)
+    } else if ticks >= soft && (ticks - soft) % TICKS_PER_SECOND == 0 {
+        Some(SIGXCPU)
+    } else {
+        None
+    }
+}
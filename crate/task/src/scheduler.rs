@@ -1,8 +1,20 @@
 use crossbeam_queue::{ArrayQueue, PushError};
-use printer::{print, println};
+use memory::swap_to_kernel_table;
 use spin::{Mutex, MutexGuard, Once};
 
-use crate::task::Task;
+use crate::job::{
+    exit_status, signal_status, ChildStatus, JobError, WaitTarget, WNOHANG, WUNTRACED,
+};
+use crate::process::Process;
+use crate::rlimit::{self, RLIMIT_CPU};
+use crate::signal::{
+    self, SigAction, SignalSet, EINTR, SIGCHLD, SIGCONT, SIGKILL, SIGSTOP, SIG_IGN, STOP_SIGNALS,
+};
+use crate::table;
+use crate::task::{Context, Task, TaskID, TaskState, WaitReason};
+
+extern crate alloc;
+use alloc::{sync::Arc, vec::Vec};
 
 /// Only 1000 processes are allowed in the ready queue
 static PROCESS_CAPACITY: usize = 1000;
@@ -19,7 +31,12 @@ pub static SCHEDULER: Once<Mutex<Scheduler>> = Once::new();
 pub struct Scheduler {
     ready_queue: ArrayQueue<Task>,
     new_queue: ArrayQueue<Task>,
-    running_task : Option<Task>,
+    running_task: Option<Task>,
+    /// Tasks waiting in a syscall, see [WaitReason], or for their process to be continued
+    blocked: Vec<Task>,
+    /// Registers of the kernel loop that was interrupted by the first
+    /// schedule, returned to when there are no tasks left to run
+    idle: Option<Context>,
 }
 
 impl Scheduler {
@@ -28,151 +45,421 @@ impl Scheduler {
             Mutex::new(Self {
                 ready_queue: ArrayQueue::<Task>::new(PROCESS_CAPACITY),
                 new_queue: ArrayQueue::<Task>::new(NEW_CAPACITY),
-                running_task : None,
+                running_task: None,
+                blocked: Vec::new(),
+                idle: None,
             })
         });
     }
 
     /// Add a new task to the new queue so the scheduler will enter it given
-    /// the next schedulers time slice
+    /// the next schedulers time slice, the task is listed in the [task table](table)
+    ///
+    /// Fails once the scheduler holds [PROCESS_CAPACITY] tasks, so every task it holds
+    /// always fits into the ready queue
     pub fn add_task(task: Task) -> Result<(), PushError<Task>> {
         let scheduler = Scheduler::get_scheduler();
-        scheduler.new_queue.push(task)
-    }
-
-    /// Transfer control from the kernels main to the scheduler
-    /// this will only be called once because it will handle control to the first
-    /// task in the created queue and then the scheduler will only be invoked from
-    /// the timer interrupt from here on out
-    pub fn run() {
-        use memory::swap_to_kernel_table;
-        use crate::task::TaskState;
-        use core::mem::replace;
-
-        unsafe {
-            asm!("
-                push rbp
-                mov  rbp, rsp
-                push r15
-                push r14
-                push r13
-                push r12
-                push r11
-                push r10
-                push r9
-                push r8
-                push rdi
-                push rsi
-                push rdx
-                push rcx
-                push rbx
-                push rax
-            ");
-        }
-
-        let mut scheduler = Scheduler::get_scheduler();
-        
-        //TODO: ISOLATE STATE CHANGE SOMEHOW
-
-        if !scheduler.new_queue.is_empty() {
-        // Might be a problem since were not returning interrupt
-        // Also were not saving an old tasks state, consider using a get context method
-            swap_to_kernel_table();
-            
-            let task = scheduler.new_queue.pop().unwrap();
-
-            //////////////////////// TO ISOLATE //////////////////////////
-            let running_task = scheduler.running_task();
-
-            // Move the old task to the ready queue
-            if let Some(r_task) = running_task {
-                match r_task.state() {
-                    TaskState::Running => r_task.set_state(TaskState::Ready),
-                    TaskState::Blocked => todo!("implement a blocked queue for io req"),
-                    TaskState::Finished => todo!("implement a finished list for ended tasks"),
-                    _ => ()
+        if scheduler.task_count() >= PROCESS_CAPACITY {
+            return Err(PushError(task));
+        }
+        table::record(&task);
+        scheduler.new_queue.push(task).map_err(|err| {
+            table::remove(err.0.task_id());
+            err
+        })
+    }
+
+    /// Charge the current timer tick to the running task and its process, a process
+    /// that goes over its [RLIMIT_CPU] is sent SIGXCPU or SIGKILL, see
+    /// [cpu_signal](rlimit::cpu_signal)
+    pub fn charge_tick(&mut self) {
+        let process = match self.running_task.as_ref() {
+            Some(task) => {
+                table::charge(task.task_id());
+                task.process().clone()
+            }
+            None => return,
+        };
+
+        let ticks = process.charge_tick();
+        if let Ok(limit) = process.limits().get(RLIMIT_CPU) {
+            if let Some(signal) = rlimit::cpu_signal(limit, ticks) {
+                self.send_signal(&process, signal);
+            }
+        }
+    }
+
+    /// Switch to the next task in the ready queue
+    ///
+    /// `context` holds the registers of whatever was interrupted by the timer or by a
+    /// syscall. They are saved into the running task and overwritten with the registers
+    /// of the next task, so returning from the interrupt resumes the next task. If the
+    /// running task can not continue and nothing else is ready, the kernel loop that was
+    /// interrupted by the first schedule is resumed instead
+    ///
+    /// Threads of processes that have exited are dropped instead of being run, after
+    /// the scheduler is unlocked as freeing their address spaces takes other locks
+    pub fn schedule(context: &mut Context) {
+        let reaped = Scheduler::get_scheduler().switch(context);
+
+        // The tables of finished tasks are freed only after we switched away from them
+        for task in &reaped {
+            table::remove(task.task_id());
+        }
+        drop(reaped);
+    }
+
+    /// Save `context` into the running task and load the next one for [schedule],
+    /// returns the tasks to drop
+    ///
+    /// [schedule]: Scheduler::schedule
+    fn switch(&mut self, context: &mut Context) -> Vec<Task> {
+        // New tasks start from their entry point the first time they are run
+        while let Some(task) = self.new_queue.pop() {
+            self.ready_queue.push(task).expect("Ready queue is full");
+        }
+
+        let mut reaped = self.reap_blocked();
+        let next = loop {
+            match self.ready_queue.pop() {
+                Some(task) if task.process().has_exited() => reaped.push(task),
+                Some(mut task) if task.process().is_stopped() => {
+                    task.set_state(TaskState::Stopped);
+                    self.blocked.push(task);
                 }
-                let old_task = replace::<Task>(r_task, task);
-                scheduler.ready_queue.push(old_task).expect("Ready queue is full");
-            } else {
-                scheduler.set_running_task(Some(task));
-            }
-            //^^^^^^^^^^^^^^^^^^^^^ TO ISOLATE ^^^^^^^^^^^^^^^^^^^^^^^^^//
-
-            let task = scheduler.running_task().unwrap();
-
-            println!("New Swapping to {}", task.name);
-            task.set_state(TaskState::Running);
-            
-            Scheduler::swap_to_task_table(task);
-            
-            unsafe {
-                x86_64::registers::control::Efer::write_raw(
-                    x86_64::registers::control::Efer::read_raw() ^ 2 ^ 11,
-                );
-                
-                asm!(
-                    "jmp {}",
-                    in(reg) task.entry_point()
-                );
-            }
-        } 
-        if !scheduler.ready_queue.is_empty() {
-            // First prepare old task
-            // Then get ready to execute new task
-
-            let task = scheduler.ready_queue.pop().unwrap();
-
-            //////////////////////// TO ISOLATE //////////////////////////
-            let running_task = scheduler.running_task();
-
-            // Move the old task to the ready queue
-            if let Some(r_task) = running_task {
-                match r_task.state() {
-                    TaskState::Running => r_task.set_state(TaskState::Ready),
-                    TaskState::Blocked => todo!("implement a blocked queue for io req"),
-                    TaskState::Finished => todo!("implement a finished list for ended tasks"),
-                    _ => ()
+                next => break next,
+            }
+        };
+
+        let keeps_running = matches!(
+            self.running_task.as_ref().map(Task::state),
+            Some(TaskState::Running)
+        );
+
+        // Nothing else to run so the running task keeps its time slice
+        if next.is_none() && keeps_running {
+            return reaped;
+        }
+
+        match self.running_task.take() {
+            Some(mut task) => {
+                task.save_context(context);
+                match task.state() {
+                    TaskState::Finished => reaped.push(task),
+                    TaskState::Blocked | TaskState::Stopped => self.blocked.push(task),
+                    _ => {
+                        task.set_state(TaskState::Ready);
+                        self.ready_queue.push(task).expect("Ready queue is full");
+                    }
                 }
-                let old_task = replace::<Task>(r_task, task);
-                scheduler.ready_queue.push(old_task).expect("Ready queue is full");
+            }
+            None => self.idle = Some(*context),
+        }
+
+        match next {
+            Some(mut task) => {
+                task.set_state(TaskState::Running);
+
+                *context = *task.context();
+                task.activate();
+                self.set_running_task(Some(task));
+            }
+            None => {
+                *context = self
+                    .idle
+                    .take()
+                    .expect("No task or idle context to return to");
+                swap_to_kernel_table();
+            }
+        }
+        reaped
+    }
+
+    /// Number of tasks in the queues, the blocked list and running
+    fn task_count(&self) -> usize {
+        self.ready_queue.len()
+            + self.new_queue.len()
+            + self.blocked.len()
+            + usize::from(self.running_task.is_some())
+    }
+
+    /// Take the blocked tasks whose process has exited out of the blocked list
+    fn reap_blocked(&mut self) -> Vec<Task> {
+        let mut reaped = Vec::new();
+        let mut i = 0;
+        while i < self.blocked.len() {
+            if self.blocked[i].process().has_exited() {
+                reaped.push(self.blocked.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        reaped
+    }
+
+    /// End the running thread with the exit value `value`. A thread blocked joining it
+    /// is woken with the value, otherwise the value is kept until the thread is joined.
+    /// The process exits with its last thread
+    ///
+    /// Must be called while the address space of the running task is active
+    pub fn exit_thread(&mut self, value: u64) {
+        let task = match self.running_task.as_mut() {
+            Some(task) => task,
+            None => return,
+        };
+        task.set_state(TaskState::Finished);
+        task.release_stack();
+
+        let (task_id, process) = (task.task_id(), task.process().clone());
+        let joiner = self
+            .blocked
+            .iter()
+            .position(|t| t.waiting() == Some(WaitReason::Thread(task_id)));
+        match joiner {
+            Some(index) => {
+                let mut joiner = self.blocked.swap_remove(index);
+                joiner.wake(value);
+                self.ready_queue.push(joiner).expect("Ready queue is full");
+                process.remove_thread(task_id);
+            }
+            None => process.thread_exited(task_id, value),
+        }
+
+        if !process.has_live_threads() && !process.is_kernel() {
+            self.end_process(&process, exit_status(value));
+        }
+    }
+
+    /// End `process` together with all of its threads, its parent can collect the wait
+    /// `status` with `waitpid` and its children are left to the kernel
+    pub fn end_process(&mut self, process: &Arc<Process>, status: u32) {
+        if process.has_exited() {
+            return;
+        }
+        process.exit();
+        if let Some(task) = self.running_of(process) {
+            task.set_state(TaskState::Finished);
+        }
+
+        for child in process.release_children() {
+            if let Some(child) = Process::find(child) {
+                child.orphan();
+            }
+        }
+        self.notify_parent(process, ChildStatus::Exited(status));
+    }
+
+    /// Tell the parent of `process` that it changed its state, threads of the parent
+    /// waiting in `waitpid` look for the child again
+    fn notify_parent(&mut self, process: &Arc<Process>, status: ChildStatus) {
+        let parent = match process.parent().and_then(Process::find) {
+            Some(parent) => parent,
+            None => return,
+        };
+        parent.update_child(process, Some(status));
+
+        self.restart_blocked(|task| {
+            Arc::ptr_eq(task.process(), &parent) && task.waiting() == Some(WaitReason::Child)
+        });
+        if status != ChildStatus::Running {
+            self.send_signal(&parent, SIGCHLD);
+        }
+    }
+
+    /// Restart the syscalls of the blocked tasks `filter` selects
+    pub fn restart_blocked(&mut self, filter: impl Fn(&Task) -> bool) {
+        let mut i = 0;
+        while i < self.blocked.len() {
+            let task = &self.blocked[i];
+            if task.state() == TaskState::Blocked && filter(task) {
+                let mut task = self.blocked.swap_remove(i);
+                task.restart();
+                self.ready_queue.push(task).expect("Ready queue is full");
             } else {
-                scheduler.set_running_task(Some(task));
+                i += 1;
             }
-            //^^^^^^^^^^^^^^^^^^^^^ TO ISOLATE ^^^^^^^^^^^^^^^^^^^^^^^^^//
+        }
+    }
+
+    /// Wait for a child of the running tasks process selected by the `waitpid` style
+    /// `pid` to exit, or to stop with [WUNTRACED]. Returns the ID and wait status of
+    /// the child if one already has, otherwise the running task is blocked until a
+    /// child changes and makes its syscall again, unless [WNOHANG] is set
+    pub fn wait_child(
+        &mut self,
+        pid: i64,
+        options: u64,
+    ) -> Result<Option<(TaskID, u32)>, JobError> {
+        let task = self.running_task.as_mut().ok_or(JobError::NoChild)?;
+        let process = task.process().clone();
+
+        let target = WaitTarget::from_pid(pid, process.process_group());
+        let found = process.take_child_status(target, options & WUNTRACED != 0)?;
+        if found.is_none() && options & WNOHANG == 0 {
+            task.block(WaitReason::Child);
+        }
+        Ok(found)
+    }
+
+    /// Wait for `thread` of the running tasks process to exit. Returns its exit value
+    /// if it already has, otherwise the running task is blocked and receives the
+    /// value as the result of its syscall once the thread exits
+    pub fn join(&mut self, thread: TaskID) -> Result<Option<u64>, JoinError> {
+        let task = self.running_task.as_mut().ok_or(JoinError::NoSuchThread)?;
+        if task.task_id() == thread {
+            return Err(JoinError::Deadlock);
+        }
+
+        let process = task.process().clone();
+        match process.thread_status(thread) {
+            None => Err(JoinError::NoSuchThread),
+            Some(Some(value)) => {
+                process.remove_thread(thread);
+                Ok(Some(value))
+            }
+            Some(None) => {
+                task.block(WaitReason::Thread(thread));
+                Ok(None)
+            }
+        }
+    }
 
-            let task = scheduler.running_task().unwrap();
+    /// Send `signal` to the process with the ID `process_id`, a signal of 0 only checks
+    /// that the process exists
+    pub fn signal_process(&mut self, process_id: TaskID, signal: u32) -> Result<(), SignalError> {
+        let process = Process::find(process_id).ok_or(SignalError::NoSuchProcess)?;
+        if signal != 0 && !signal::is_valid(signal) {
+            return Err(SignalError::InvalidSignal);
+        }
+
+        self.send_signal(&process, signal);
+        Ok(())
+    }
+
+    /// Send `signal` to every process in the group `group`, a signal of 0 only checks
+    /// that the group exists
+    pub fn signal_group(&mut self, group: TaskID, signal: u32) -> Result<(), SignalError> {
+        if signal != 0 && !signal::is_valid(signal) {
+            return Err(SignalError::InvalidSignal);
+        }
 
-            println!("Ready Swapping to {}", task.name);
-            task.set_state(TaskState::Running);
+        let members: Vec<_> = Process::all()
+            .into_iter()
+            .filter(|process| process.process_group() == group)
+            .collect();
+        if members.is_empty() {
+            return Err(SignalError::NoSuchProcess);
+        }
 
-            Scheduler::swap_to_task_table(task);
+        for process in members {
+            self.send_signal(&process, signal);
+        }
+        Ok(())
+    }
 
-            unsafe {
-                asm!(
-                    "
-                    mov  rsp, rax
-                    pop rax
-                    pop rbx
-                    pop rcx
-                    pop rdx
-                    pop rsi
-                    pop rdi
-                    pop r8
-                    pop r9
-                    pop r10
-                    pop r11
-                    pop r12
-                    pop r13
-                    pop r14
-                    pop r15
-                    pop rbp
-                    ",
-                );
+    /// SIGKILL, SIGSTOP and SIGCONT take effect right away. Other signals are made
+    /// pending unless the process ignores them, threads blocked in a syscall that do
+    /// not block the signal are woken with EINTR so they can take it
+    fn send_signal(&mut self, process: &Arc<Process>, signal: u32) {
+        match signal {
+            0 => return,
+            SIGKILL => {
+                self.end_process(process, signal_status(SIGKILL, false));
+                return;
             }
-            
+            SIGSTOP => {
+                self.stop_process(process, SIGSTOP);
+                return;
+            }
+            SIGCONT => self.continue_process(process),
+            _ => {}
+        }
+
+        if process.action(signal).ignores(signal) {
+            return;
         }
+        process.queue_signal(signal);
+
+        let mut i = 0;
+        while i < self.blocked.len() {
+            let task = &self.blocked[i];
+            let interrupted = task.state() == TaskState::Blocked
+                && Arc::ptr_eq(task.process(), process)
+                && !task.blocked_signals().contains(signal);
 
+            if interrupted {
+                let mut task = self.blocked.swap_remove(i);
+                task.wake(EINTR.wrapping_neg());
+                self.ready_queue.push(task).expect("Ready queue is full");
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Send a signal raised by a fault to the running thread. A fault can not be
+    /// skipped, so a blocked or ignored signal is unblocked and its default action used
+    pub fn force_signal(&mut self, signal: u32) {
+        if let Some(task) = self.running_task.as_mut() {
+            let mut blocked = task.blocked_signals();
+            if blocked.contains(signal) || task.process().action(signal).handler == SIG_IGN {
+                blocked.remove(signal);
+                task.set_blocked_signals(blocked);
+                task.process().set_action(signal, SigAction::default());
+            }
+            task.queue_signal(signal);
+        }
+    }
+
+    /// Stop every thread of `process` because of `signal`, a running thread is stopped
+    /// when it gives up the CPU and the others when the scheduler comes across them
+    pub fn stop_process(&mut self, process: &Arc<Process>, signal: u32) {
+        if process.is_stopped() {
+            return;
+        }
+        process.stop();
+
+        let mut cont = SignalSet::empty();
+        cont.insert(SIGCONT);
+        process.discard_signals(cont);
+        if let Some(task) = self.running_of(process) {
+            task.set_state(TaskState::Stopped);
+        }
+        self.notify_parent(process, ChildStatus::Stopped(signal));
+    }
+
+    /// Continue a stopped process, pending stop signals are discarded
+    pub fn continue_process(&mut self, process: &Arc<Process>) {
+        if process.is_stopped() {
+            self.notify_parent(process, ChildStatus::Running);
+        }
+        process.resume();
+        process.discard_signals(STOP_SIGNALS);
+
+        let mut i = 0;
+        while i < self.blocked.len() {
+            let task = &self.blocked[i];
+            if task.state() == TaskState::Stopped && Arc::ptr_eq(task.process(), process) {
+                let mut task = self.blocked.swap_remove(i);
+                task.set_state(TaskState::Ready);
+                self.ready_queue.push(task).expect("Ready queue is full");
+            } else {
+                i += 1;
+            }
+        }
+
+        if let Some(task) = self.running_of(process) {
+            if task.state() == TaskState::Stopped {
+                task.set_state(TaskState::Running);
+            }
+        }
+    }
+
+    /// The running task if it is a thread of `process`
+    fn running_of(&mut self, process: &Arc<Process>) -> Option<&mut Task> {
+        self.running_task
+            .as_mut()
+            .filter(|task| Arc::ptr_eq(task.process(), process))
     }
 
     /// Get the current scheduler from the static lock
@@ -185,25 +472,26 @@ impl Scheduler {
         self.running_task.as_mut()
     }
 
-    fn swap_to_task_table(task : &Task) {
-        use memory::RECURSIVE_INDEX;
-        use core::ops::Index;
-        use x86_64::registers::control::Cr3;
-        use x86_64::structures::paging::PhysFrame;
-        let page_table = task.page_table();
-
-        unsafe {
-            Cr3::write(
-                PhysFrame::containing_address(page_table.index(511).addr()),
-                Cr3::read().1,
-            )
-        };
-        *RECURSIVE_INDEX.wait().unwrap().lock() = 508;
-        x86_64::instructions::tlb::flush_all();
-    }
-
     /// Set the scheduler's running task.
     pub fn set_running_task(&mut self, running_task: Option<Task>) {
         self.running_task = running_task;
     }
 }
+
+/// Errors from joining a thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The thread does not exist or belongs to another process
+    NoSuchThread,
+    /// A thread tried to join itself
+    Deadlock,
+}
+
+/// Errors from sending a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    /// There is no process with the ID or it has exited
+    NoSuchProcess,
+    /// The signal number is out of range
+    InvalidSignal,
+}
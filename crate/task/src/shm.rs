@@ -0,0 +1,255 @@
+//! Shared memory objects, named blocks of memory processes map into their address
+//! spaces to exchange data without copying it through the kernel
+//!
+//! [open] creates or finds an object by its name and gives the process a handle to it.
+//! [map] maps the whole object into the process, every mapping may be at a different
+//! address and can be read only or writable as far as the handle allows. The pages of a
+//! mapping hold their own references to the frames of the object, so a mapping stays
+//! valid after its handle is closed. The object and its name are gone once the last
+//! handle to it is closed, its frames are freed when the last mapping is gone as well
+//!
+//! Handles are copied into a forked child and kept across an exec like open files
+use memory::{phys::put_frame, virt};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::process::Process;
+use crate::region::{self, RegionError, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+extern crate alloc;
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+/// Create the object if there is none with the name
+pub const SHM_CREATE: u64 = 1;
+/// Fail if an object with the name exists, only with [SHM_CREATE]
+pub const SHM_EXCLUSIVE: u64 = 2;
+/// Open the object for writing, without it mappings can only be read
+pub const SHM_WRITE: u64 = 4;
+
+/// Longest name an object can have
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Every object that has a handle open to it, keyed by its name
+static OBJECTS: Mutex<BTreeMap<String, Weak<SharedMemory>>> = Mutex::new(BTreeMap::new());
+
+/// Errors from opening, mapping or closing shared memory objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// The name is empty or too long, the flags are not supported or a new object would
+    /// be empty
+    Invalid,
+    /// No object has the name and [SHM_CREATE] was not given
+    NotFound,
+    /// An object has the name and [SHM_EXCLUSIVE] was given
+    Exists,
+    /// The process has no such handle, or the mapping at an address is not of the object
+    BadHandle,
+    /// A writable mapping was asked for through a read only handle
+    AccessDenied,
+    /// There are no frames or no free part of the address space left
+    NoMemory,
+}
+
+impl From<RegionError> for ShmError {
+    fn from(err: RegionError) -> Self {
+        match err {
+            RegionError::Invalid => ShmError::Invalid,
+            RegionError::NoMemory => ShmError::NoMemory,
+            RegionError::AccessDenied => ShmError::AccessDenied,
+        }
+    }
+}
+
+/// A named block of zeroed memory, the object holds a reference to each of its frames
+#[derive(Debug)]
+pub struct SharedMemory {
+    name: String,
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size in bytes, a multiple of the page size
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        let mut objects = OBJECTS.lock();
+        let registered = objects.get(&self.name).map_or(false, |object| {
+            object.as_ptr() == self as *const SharedMemory
+        });
+        if registered {
+            objects.remove(&self.name);
+        }
+        drop(objects);
+
+        for &frame in &self.frames {
+            unsafe { put_frame(frame) };
+        }
+    }
+}
+
+/// An object opened by a process
+#[derive(Debug, Clone)]
+pub struct Handle {
+    object: Arc<SharedMemory>,
+    writable: bool,
+}
+
+impl Handle {
+    pub fn object(&self) -> &Arc<SharedMemory> {
+        &self.object
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+}
+
+/// The shared memory handles of a process, numbered from 0
+#[derive(Debug, Clone, Default)]
+pub struct Handles {
+    handles: BTreeMap<u64, Handle>,
+}
+
+impl Handles {
+    pub fn get(&self, handle: u64) -> Option<&Handle> {
+        self.handles.get(&handle)
+    }
+
+    /// Add a handle with the lowest free number and return the number
+    fn insert(&mut self, handle: Handle) -> u64 {
+        let number = (0..)
+            .find(|number| !self.handles.contains_key(number))
+            .unwrap();
+        self.handles.insert(number, handle);
+        number
+    }
+
+    fn remove(&mut self, handle: u64) -> Option<Handle> {
+        self.handles.remove(&handle)
+    }
+}
+
+/// Open the object called `name` for `process`, creating it with `size` bytes rounded
+/// up to whole pages if there is none and `flags` has [SHM_CREATE]. The size of an
+/// existing object is kept. Returns the number of the new handle
+pub fn open(process: &Process, name: &str, size: u64, flags: u64) -> Result<u64, ShmError> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_LENGTH
+        || flags & !(SHM_CREATE | SHM_EXCLUSIVE | SHM_WRITE) != 0
+    {
+        return Err(ShmError::Invalid);
+    }
+
+    let mut objects = OBJECTS.lock();
+    let existing = objects.get(name).and_then(Weak::upgrade);
+    let object = match existing {
+        Some(object) if flags & SHM_CREATE != 0 && flags & SHM_EXCLUSIVE != 0 => {
+            // The object may have to be dropped, which takes the lock again
+            drop(objects);
+            drop(object);
+            return Err(ShmError::Exists);
+        }
+        Some(object) => object,
+        None if flags & SHM_CREATE == 0 => return Err(ShmError::NotFound),
+        None => {
+            let pages = size
+                .checked_add(Size4KiB::SIZE - 1)
+                .ok_or(ShmError::NoMemory)?
+                / Size4KiB::SIZE;
+            if pages == 0 {
+                return Err(ShmError::Invalid);
+            }
+            let frames = virt::allocate_frames(pages as usize).ok_or(ShmError::NoMemory)?;
+            let object = Arc::new(SharedMemory {
+                name: String::from(name),
+                frames,
+            });
+            objects.insert(String::from(name), Arc::downgrade(&object));
+            object
+        }
+    };
+    drop(objects);
+
+    let handle = Handle {
+        object,
+        writable: flags & SHM_WRITE != 0,
+    };
+    Ok(process.shared_memory().insert(handle))
+}
+
+/// Map the whole object of `handle` into `process` with the PROT_* flags `protection`,
+/// at `addr` if that part of the address space is free. Returns the start of the
+/// mapping
+///
+/// The address space of `process` must be active
+pub fn map(
+    process: &Process,
+    handle: u64,
+    addr: u64,
+    protection: u64,
+) -> Result<VirtAddr, ShmError> {
+    let handle = process
+        .shared_memory()
+        .get(handle)
+        .cloned()
+        .ok_or(ShmError::BadHandle)?;
+
+    let mut max_protection = PROT_READ | PROT_EXEC;
+    if handle.writable {
+        max_protection |= PROT_WRITE;
+    }
+    let frames = &handle.object.frames;
+    Ok(region::map_shared(
+        process,
+        addr,
+        frames,
+        protection,
+        max_protection,
+    )?)
+}
+
+/// Unmap the mapping of the object of `handle` that starts at `addr` from `process`
+///
+/// The address space of `process` must be active
+pub fn unmap(process: &Process, handle: u64, addr: u64) -> Result<(), ShmError> {
+    let object = process
+        .shared_memory()
+        .get(handle)
+        .map(|handle| handle.object.clone())
+        .ok_or(ShmError::BadHandle)?;
+
+    let start = VirtAddr::try_new(addr).map_err(|_| ShmError::Invalid)?;
+    let mapped = process
+        .address_space()
+        .translate(start)
+        .map(PhysFrame::containing_address);
+    if mapped != object.frames.first().copied() {
+        return Err(ShmError::BadHandle);
+    }
+
+    Ok(region::munmap(process, addr, object.size())?)
+}
+
+/// Close `handle` of `process`, the mappings made through it stay
+pub fn close(process: &Process, handle: u64) -> Result<(), ShmError> {
+    match process.shared_memory().remove(handle) {
+        Some(_) => Ok(()),
+        None => Err(ShmError::BadHandle),
+    }
+}
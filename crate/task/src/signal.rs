@@ -0,0 +1,396 @@
+//! POSIX style signals
+//!
+//! Signals are sent to a process and stay pending on it until one of its threads
+//! that does not block them returns to ring 3, signals raised by a fault are sent
+//! to the faulting thread alone. What happens on delivery is decided by the
+//! [SigAction] the process installed with `sigaction`, a handler is called by
+//! pushing a [SignalFrame] onto the user stack and `sigreturn` restores the
+//! registers saved in it. Without a handler the [DefaultAction] of the signal
+//! terminates, stops or continues the process through the scheduler
+use core::{mem, ptr};
+
+use memory::virt;
+use x86_64::{
+    align_down,
+    registers::rflags::RFlags,
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::job::signal_status;
+use crate::scheduler::Scheduler;
+use crate::task::{Context, USER_STACK_TOP};
+
+use printer::{print, println};
+
+/// Signal numbers are below this value
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGXCPU: u32 = 24;
+pub const SIGWINCH: u32 = 28;
+
+/// Handler value that selects the [DefaultAction] of a signal
+pub const SIG_DFL: u64 = 0;
+/// Handler value that discards a signal
+pub const SIG_IGN: u64 = 1;
+
+/// The restorer of the [SigAction] is set, handlers return into it
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// Do not block the signal while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// Go back to the default action once the handler has been called
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `sigprocmask` adds the set to the blocked signals
+pub const SIG_BLOCK: u64 = 0;
+/// `sigprocmask` removes the set from the blocked signals
+pub const SIG_UNBLOCK: u64 = 1;
+/// `sigprocmask` replaces the blocked signals with the set
+pub const SIG_SETMASK: u64 = 2;
+
+/// Error number a blocked syscall returns when a signal interrupts it
+pub const EINTR: u64 = 4;
+
+/// Size of the area below the stack pointer that leaf functions may use without
+/// moving the stack pointer, signal frames are pushed below it
+const RED_ZONE: u64 = 128;
+
+/// Addresses at or above this are in the kernel half or not canonical
+const USER_HALF_END: u64 = 0x8000_0000_0000;
+
+/// A set of signals, signal `n` is bit `n - 1` like the sigset of the System V ABI
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SignalSet(u64);
+
+impl SignalSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, signal: u32) -> bool {
+        self.0 & bit(signal) != 0
+    }
+
+    pub fn insert(&mut self, signal: u32) {
+        self.0 |= bit(signal);
+    }
+
+    pub fn remove(&mut self, signal: u32) {
+        self.0 &= !bit(signal);
+    }
+
+    pub const fn union(self, other: SignalSet) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn difference(self, other: SignalSet) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Lowest numbered signal of the set
+    pub fn first(self) -> Option<u32> {
+        match self.0 {
+            0 => None,
+            bits => Some(bits.trailing_zeros() + 1),
+        }
+    }
+}
+
+const fn bit(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+/// Signals that can not be caught, blocked or ignored
+pub const UNBLOCKABLE: SignalSet = SignalSet(bit(SIGKILL) | bit(SIGSTOP));
+
+/// Signals whose default action stops the process
+pub const STOP_SIGNALS: SignalSet =
+    SignalSet(bit(SIGSTOP) | bit(SIGTSTP) | bit(SIGTTIN) | bit(SIGTTOU));
+
+/// Whether `signal` is a signal number, 0 is only valid for `kill`
+pub fn is_valid(signal: u32) -> bool {
+    signal > 0 && (signal as usize) < NSIG
+}
+
+/// What happens when a signal without a handler is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    /// End the process
+    Terminate,
+    /// End the process, which would write a core dump
+    Core,
+    /// Discard the signal
+    Ignore,
+    /// Stop the process until it receives [SIGCONT]
+    Stop,
+    /// Continue a stopped process
+    Continue,
+}
+
+/// The [DefaultAction] of a signal
+pub fn default_action(signal: u32) -> DefaultAction {
+    match signal {
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU => {
+            DefaultAction::Core
+        }
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// How a process handles a signal, laid out like the `sigaction` structure user
+/// space passes to the syscall
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct SigAction {
+    /// [SIG_DFL], [SIG_IGN] or the address of the handler
+    pub handler: u64,
+    pub flags: u64,
+    /// Address the handler returns to, it has to call `sigreturn`
+    pub restorer: u64,
+    /// Signals blocked in addition while the handler runs
+    pub mask: SignalSet,
+}
+
+impl SigAction {
+    /// Whether a signal with this action would be discarded when delivered
+    pub fn ignores(&self, signal: u32) -> bool {
+        match self.handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(signal) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+}
+
+/// Pushed onto the user stack when a handler is called, the handler is entered with
+/// the stack pointer at `restorer` as if it had been called from there. When the
+/// handler returns the stack pointer points at `signal`, which is where `sigreturn`
+/// expects it
+#[repr(C)]
+pub struct SignalFrame {
+    restorer: u64,
+    signal: u64,
+    context: Context,
+    blocked: SignalSet,
+}
+
+/// What delivering the next pending signal does to the running task
+enum Delivery {
+    /// Call a handler in user space
+    Handler(u32, SigAction),
+    /// End the process, with a core dump for [DefaultAction::Core]
+    Terminate(u32, bool),
+    /// Stop the process
+    Stop(u32),
+}
+
+/// Deliver the pending signals of the running task before the kernel returns to
+/// ring 3 with `context`, which is updated to call a handler if there is one
+///
+/// If a signal ends or stops the running task the scheduler switches to the next
+/// task and its signals are delivered in turn. Nothing happens when `context`
+/// returns to ring 0
+pub fn deliver(context: &mut Context) {
+    while context.is_user() {
+        let mut scheduler = Scheduler::get_scheduler();
+        let task = match scheduler.running_task() {
+            Some(task) => task,
+            None => return,
+        };
+        let process = task.process().clone();
+
+        let delivery = loop {
+            let signal = match task.take_signal() {
+                Some(signal) => signal,
+                None => return,
+            };
+
+            let action = process.action(signal);
+            match action.handler {
+                SIG_IGN => continue,
+                SIG_DFL => match default_action(signal) {
+                    DefaultAction::Ignore | DefaultAction::Continue => continue,
+                    DefaultAction::Terminate => break Delivery::Terminate(signal, false),
+                    DefaultAction::Core => break Delivery::Terminate(signal, true),
+                    DefaultAction::Stop => break Delivery::Stop(signal),
+                },
+                _ => break Delivery::Handler(signal, action),
+            }
+        };
+
+        match delivery {
+            Delivery::Handler(signal, action) => {
+                let blocked = task.blocked_signals();
+                if unsafe { push_frame(context, signal, &action, blocked) } {
+                    let mut mask = blocked.union(action.mask);
+                    if action.flags & SA_NODEFER == 0 {
+                        mask.insert(signal);
+                    }
+                    task.set_blocked_signals(mask);
+
+                    if action.flags & SA_RESETHAND != 0 {
+                        process.set_action(signal, SigAction::default());
+                    }
+                    return;
+                }
+
+                // There is no room for the frame, so the process can not handle anything
+                println!("{}: bad signal frame, killed", process.name);
+                scheduler.end_process(&process, signal_status(SIGSEGV, false));
+            }
+            Delivery::Terminate(signal, core) => {
+                if core {
+                    println!("{}: signal {} (core dumped)", process.name, signal);
+                }
+                scheduler.end_process(&process, signal_status(signal, core));
+            }
+            Delivery::Stop(signal) => scheduler.stop_process(&process, signal),
+        }
+
+        drop(scheduler);
+        Scheduler::schedule(context);
+    }
+}
+
+/// Push a [SignalFrame] below the red zone of the user stack and point `context`
+/// at the handler, returns false if the frame does not fit onto the stack
+///
+/// # Safety
+/// The address space of the running task has to be active
+unsafe fn push_frame(
+    context: &mut Context,
+    signal: u32,
+    action: &SigAction,
+    blocked: SignalSet,
+) -> bool {
+    let size = mem::size_of::<SignalFrame>() as u64;
+    let frame = match context.rsp.checked_sub(RED_ZONE + size) {
+        // Handlers are entered with a stack pointer 8 bytes off 16 byte alignment
+        Some(addr) => align_down(addr, 16) - 8,
+        None => return false,
+    };
+
+    if !frame_mapped(frame, true) {
+        return false;
+    }
+
+    ptr::write_unaligned(
+        frame as *mut SignalFrame,
+        SignalFrame {
+            restorer: action.restorer,
+            signal: u64::from(signal),
+            context: *context,
+            blocked,
+        },
+    );
+
+    context.rip = action.handler;
+    context.rsp = frame;
+    context.rdi = u64::from(signal);
+    true
+}
+
+/// Whether a [SignalFrame] at `frame` lies below the user stack top on pages the task
+/// may access, and may write if `write` is set. Copy on write pages get their own frame
+/// first so the frame does not end up in the memory of another process
+fn frame_mapped(frame: u64, write: bool) -> bool {
+    let end = match frame.checked_add(mem::size_of::<SignalFrame>() as u64) {
+        Some(end) if frame >= 0x1000 && end <= USER_STACK_TOP => end,
+        _ => return false,
+    };
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(frame));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        let addr = page.start_address();
+        let flags = match virt::page_flags(addr) {
+            Some(flags) => flags,
+            None => return false,
+        };
+        flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+            && (!write
+                || flags.contains(PageTableFlags::WRITABLE)
+                || virt::handle_copy_on_write(addr).unwrap_or(false))
+    })
+}
+
+/// Restore the registers and blocked signals saved in the [SignalFrame] a handler
+/// returned from. The stack pointer of `context` is the one `sigreturn` was called
+/// with, it points 8 bytes into the frame
+///
+/// Only the registers user space can change itself are restored, the segments and
+/// the privileged flags of `context` are kept. Returns the restored rax
+///
+/// # Safety
+/// The address space of the running task has to be active
+pub unsafe fn restore_frame(context: &mut Context) -> Option<(u64, SignalSet)> {
+    let frame = context.rsp.checked_sub(8)?;
+    if !frame_mapped(frame, false) {
+        return None;
+    }
+    let frame = ptr::read_unaligned(frame as *const SignalFrame);
+
+    // Returning to the kernel half or to a non-canonical address would fault in the kernel
+    if frame.context.rip >= USER_HALF_END || frame.context.rsp >= USER_HALF_END {
+        return None;
+    }
+
+    let user_flags = RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::TRAP_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG;
+    let rflags = (frame.context.rflags & user_flags.bits())
+        | (context.rflags & !user_flags.bits())
+        | RFlags::INTERRUPT_FLAG.bits();
+
+    *context = Context {
+        cs: context.cs,
+        ss: context.ss,
+        rflags,
+        ..frame.context
+    };
+
+    Some((context.rax, frame.blocked.difference(UNBLOCKABLE)))
+}
@@ -0,0 +1,136 @@
+//! Builds the initial stack a program starts with as laid out by the
+//! System V x86_64 ABI
+//!
+//! From the stack pointer upwards the stack holds
+//! 1. argc
+//! 2. argv pointers followed by a null pointer
+//! 3. envp pointers followed by a null pointer
+//! 4. auxiliary vector entries (type, value) ending with [AT_NULL]
+//! 5. padding, then the argument and environment strings and [AT_RANDOM] bytes
+use core::ptr;
+
+use x86_64::{align_down, VirtAddr};
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+
+/// End of the auxiliary vector
+pub const AT_NULL: u64 = 0;
+/// Address of the program headers of the executable
+pub const AT_PHDR: u64 = 3;
+/// Size of one program header entry
+pub const AT_PHENT: u64 = 4;
+/// Number of program headers
+pub const AT_PHNUM: u64 = 5;
+/// Size of a page in bytes
+pub const AT_PAGESZ: u64 = 6;
+/// Base address of the program interpreter
+pub const AT_BASE: u64 = 7;
+/// Entry point of the executable
+pub const AT_ENTRY: u64 = 9;
+/// Address of 16 random bytes
+pub const AT_RANDOM: u64 = 25;
+
+/// Size of an ELF64 program header
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+/// Number of entries of the auxiliary vector, [AT_NULL] included
+const AUXV_LENGTH: usize = 8;
+
+/// Length of the [AT_RANDOM] bytes
+const RANDOM_LENGTH: usize = 16;
+
+/// Values the loader reports to the program through the auxiliary vector
+#[derive(Debug, Clone, Copy)]
+pub struct AuxiliaryValues {
+    pub phdr: u64,
+    pub phnum: u64,
+    pub entry: u64,
+    /// Shared objects are linked by the kernel so there is no interpreter and this is 0
+    pub base: u64,
+}
+
+/// Bytes [build_initial_stack] takes below the top of the stack for `args` and `env`,
+/// the padding that aligns the stack pointer included
+pub fn initial_stack_size(args: &[String], env: &[String]) -> u64 {
+    let strings: u64 = args.iter().chain(env).map(|s| s.len() as u64 + 1).sum();
+    // argc, the null pointers ending argv and envp and every auxiliary entry
+    let words = (args.len() + env.len() + 3 + AUXV_LENGTH * 2) as u64;
+    RANDOM_LENGTH as u64 + strings + words * 8 + 15
+}
+
+/// Write argc, argv, envp and the auxiliary vector onto the stack that ends at `top`
+/// and return the 16 byte aligned stack pointer the program starts with
+///
+/// # Safety
+/// The stack below `top` must be mapped writable in the active address space and
+/// be at least [initial_stack_size] bytes large
+pub unsafe fn build_initial_stack(
+    top: VirtAddr,
+    args: &[String],
+    env: &[String],
+    aux: AuxiliaryValues,
+) -> VirtAddr {
+    let mut cursor = top.as_u64();
+
+    let random = push_bytes(&mut cursor, &random_bytes());
+    let env_ptrs: Vec<u64> = env.iter().map(|s| push_c_str(&mut cursor, s)).collect();
+    let arg_ptrs: Vec<u64> = args.iter().map(|s| push_c_str(&mut cursor, s)).collect();
+
+    let auxv: [(u64, u64); AUXV_LENGTH] = [
+        (AT_PHDR, aux.phdr),
+        (AT_PHENT, PROGRAM_HEADER_SIZE),
+        (AT_PHNUM, aux.phnum),
+        (AT_PAGESZ, 4096),
+        (AT_BASE, aux.base),
+        (AT_ENTRY, aux.entry),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = Vec::with_capacity(arg_ptrs.len() + env_ptrs.len() + auxv.len() * 2 + 3);
+    words.push(args.len() as u64);
+    words.extend_from_slice(&arg_ptrs);
+    words.push(0);
+    words.extend_from_slice(&env_ptrs);
+    words.push(0);
+    for (key, value) in auxv.iter() {
+        words.push(*key);
+        words.push(*value);
+    }
+
+    // The ABI requires the stack pointer to be 16 byte aligned, pointing at argc
+    let rsp = align_down(cursor - (words.len() * 8) as u64, 16);
+    ptr::copy_nonoverlapping(words.as_ptr(), rsp as *mut u64, words.len());
+
+    VirtAddr::new(rsp)
+}
+
+/// Copy bytes below the cursor and return their address
+unsafe fn push_bytes(cursor: &mut u64, bytes: &[u8]) -> u64 {
+    *cursor -= bytes.len() as u64;
+    ptr::copy_nonoverlapping(bytes.as_ptr(), *cursor as *mut u8, bytes.len());
+    *cursor
+}
+
+/// Copy a string with a null terminator below the cursor and return its address
+unsafe fn push_c_str(cursor: &mut u64, string: &str) -> u64 {
+    push_bytes(cursor, &[0]);
+    push_bytes(cursor, string.as_bytes())
+}
+
+/// 16 bytes for [AT_RANDOM], taken from RDRAND when the CPU has it and from
+/// the time stamp counter otherwise
+fn random_bytes() -> [u8; RANDOM_LENGTH] {
+    use x86_64::instructions::random::RdRand;
+
+    let mut next = || match RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        Some(value) => value,
+        None => unsafe { core::arch::x86_64::_rdtsc() },
+    };
+
+    let mut bytes = [0; RANDOM_LENGTH];
+    bytes[..8].copy_from_slice(&next().to_ne_bytes());
+    bytes[8..].copy_from_slice(&next().to_ne_bytes());
+    bytes
+}
@@ -0,0 +1,248 @@
+//! Global table of every task the scheduler knows about, keyed by [TaskID]
+//!
+//! A task is listed from the moment it is handed to the scheduler until the scheduler
+//! drops it. The table keeps what can not be read off the task at any time, like the
+//! timer ticks it ran for, and is what the `task_info` and `list_tasks` syscalls copy
+//! [TaskStat] records from
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::process::Process;
+use crate::task::{Ring, Task, TaskID, TaskState, KERNEL_STACK_SIZE};
+
+extern crate alloc;
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+/// Timer ticks since the timer was started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static TASK_TABLE: Mutex<BTreeMap<TaskID, TaskRecord>> = Mutex::new(BTreeMap::new());
+
+/// Longest name copied into a [TaskStat], including the null terminator
+pub const TASK_NAME_LENGTH: usize = 32;
+
+struct TaskRecord {
+    name: String,
+    ring: Ring,
+    state: TaskState,
+    process: Weak<Process>,
+    /// Timer ticks the task was running for
+    cpu_ticks: u64,
+    /// Tick the task was handed to the scheduler at
+    start_tick: u64,
+}
+
+/// What the table knows about a task
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub task_id: TaskID,
+    pub process_id: TaskID,
+    /// Parent of the process, None for kernel threads and processes the kernel started
+    pub parent: Option<TaskID>,
+    pub process_group: TaskID,
+    pub name: String,
+    pub state: TaskState,
+    pub ring: Ring,
+    pub cpu_ticks: u64,
+    /// Bytes of memory mapped for the process, or the stack of a kernel thread
+    pub memory: usize,
+    pub start_tick: u64,
+}
+
+/// A [TaskInfo] laid out for user space, IDs of 0 stand for none
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TaskStat {
+    pub task_id: u64,
+    pub process_id: u64,
+    pub parent: u64,
+    pub process_group: u64,
+    /// 0 new, 1 ready, 2 running, 3 blocked, 4 stopped, 5 finished
+    pub state: u64,
+    /// 0 or 3
+    pub ring: u64,
+    pub cpu_ticks: u64,
+    pub memory: u64,
+    pub start_tick: u64,
+    /// Null terminated, cut off if it is too long
+    pub name: [u8; TASK_NAME_LENGTH],
+}
+
+impl From<&TaskInfo> for TaskStat {
+    fn from(info: &TaskInfo) -> Self {
+        let mut name = [0; TASK_NAME_LENGTH];
+        let length = info.name.len().min(TASK_NAME_LENGTH - 1);
+        name[..length].copy_from_slice(&info.name.as_bytes()[..length]);
+
+        Self {
+            task_id: info.task_id.get_id() as u64,
+            process_id: info.process_id.get_id() as u64,
+            parent: info.parent.map_or(0, |parent| parent.get_id() as u64),
+            process_group: info.process_group.get_id() as u64,
+            state: info.state as u64,
+            ring: info.ring as u64,
+            cpu_ticks: info.cpu_ticks,
+            memory: info.memory as u64,
+            start_tick: info.start_tick,
+            name,
+        }
+    }
+}
+
+/// Run `f` on the table with interrupts disabled, the timer updates it too
+fn with_table<R>(f: impl FnOnce(&mut BTreeMap<TaskID, TaskRecord>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TASK_TABLE.lock()))
+}
+
+/// Count a timer tick, called on every timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer ticks since the timer was started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// List `task` or update its entry after it started a new program, an updated entry
+/// keeps its start time and CPU time
+pub fn record(task: &Task) {
+    with_table(|table| {
+        let (cpu_ticks, start_tick) = table
+            .get(&task.task_id())
+            .map_or((0, ticks()), |record| (record.cpu_ticks, record.start_tick));
+
+        table.insert(
+            task.task_id(),
+            TaskRecord {
+                name: task.name.clone(),
+                ring: task.ring,
+                state: task.state(),
+                process: Arc::downgrade(task.process()),
+                cpu_ticks,
+                start_tick,
+            },
+        );
+    });
+}
+
+/// Remove a task the scheduler dropped
+pub fn remove(task_id: TaskID) {
+    with_table(|table| table.remove(&task_id));
+}
+
+/// Record the state a task changed to, tasks that are not listed are skipped
+pub fn set_state(task_id: TaskID, state: TaskState) {
+    with_table(|table| {
+        if let Some(record) = table.get_mut(&task_id) {
+            record.state = state;
+        }
+    });
+}
+
+/// Charge a timer tick to the running task
+pub fn charge(task_id: TaskID) {
+    with_table(|table| {
+        if let Some(record) = table.get_mut(&task_id) {
+            record.cpu_ticks += 1;
+        }
+    });
+}
+
+/// What the table knows about the task `task_id`
+///
+/// Measuring the memory of a process walks its page tables, so this must not be
+/// called while the scheduler is switching address spaces
+pub fn info(task_id: TaskID) -> Option<TaskInfo> {
+    let (task_id, record) = with_table(|table| {
+        let record = table.get(&task_id)?;
+        Some((task_id, snapshot(record)))
+    })?;
+    Some(complete(task_id, record, &mut BTreeMap::new()))
+}
+
+/// Every listed task ordered by ID, see [info]
+pub fn list() -> Vec<TaskInfo> {
+    let records: Vec<_> = with_table(|table| {
+        table
+            .iter()
+            .map(|(&task_id, record)| (task_id, snapshot(record)))
+            .collect()
+    });
+
+    // Threads of a process share its memory, which is measured once
+    let mut footprints = BTreeMap::new();
+    records
+        .into_iter()
+        .map(|(task_id, record)| complete(task_id, record, &mut footprints))
+        .collect()
+}
+
+/// The fields of a record copied out of the table
+struct Snapshot {
+    name: String,
+    ring: Ring,
+    state: TaskState,
+    process: Option<Arc<Process>>,
+    cpu_ticks: u64,
+    start_tick: u64,
+}
+
+fn snapshot(record: &TaskRecord) -> Snapshot {
+    Snapshot {
+        name: record.name.clone(),
+        ring: record.ring,
+        state: record.state,
+        process: record.process.upgrade(),
+        cpu_ticks: record.cpu_ticks,
+        start_tick: record.start_tick,
+    }
+}
+
+/// Fill in what has to be read from the process of a task, outside of the table lock
+fn complete(
+    task_id: TaskID,
+    snapshot: Snapshot,
+    footprints: &mut BTreeMap<TaskID, usize>,
+) -> TaskInfo {
+    let (process_id, parent, process_group, memory) = match &snapshot.process {
+        Some(process) if process.is_kernel() => (
+            process.process_id(),
+            None,
+            process.process_group(),
+            KERNEL_STACK_SIZE,
+        ),
+        Some(process) => {
+            let memory = *footprints
+                .entry(process.process_id())
+                .or_insert_with(|| process.memory_footprint());
+            (
+                process.process_id(),
+                process.parent(),
+                process.process_group(),
+                memory,
+            )
+        }
+        None => (task_id, None, task_id, 0),
+    };
+
+    TaskInfo {
+        task_id,
+        process_id,
+        parent,
+        process_group,
+        name: snapshot.name,
+        state: snapshot.state,
+        ring: snapshot.ring,
+        cpu_ticks: snapshot.cpu_ticks,
+        memory,
+        start_tick: snapshot.start_tick,
+    }
+}
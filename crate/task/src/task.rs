@@ -1,32 +1,83 @@
 use core::{
-    ops::Index,
+    mem,
+    ops::Range,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-use elfloader::ElfBinary;
-use memory::{
-    kpbox::KpBox, swap_to_kernel_table, KERNEL_PAGE_TABLE, RECURSIVE_INDEX,
-};
+use fs::file_table::FileTable;
+use memory::{address_space::AddressSpace, virt::KernelStack};
+use os_units::Bytes;
 
 use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{
-        PageTable, PageTableFlags, PhysFrame,
-    },
+    align_down,
+    instructions::interrupts,
+    registers::{model_specific::FsBase, rflags::RFlags},
+    structures::paging::{mapper::MapToError, PageSize, Size4KiB},
     VirtAddr,
 };
 
-
-use crate::elf::ElfMemory;
+use crate::dynamic::{LinkError, LoadedImage};
+use crate::process::{InitialThread, Process};
+use crate::rlimit::{self, Limits};
+use crate::scheduler::Scheduler;
+use crate::signal::{SignalSet, UNBLOCKABLE};
+use crate::table;
+use crate::thread;
 
 extern crate alloc;
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+
+/// Offset in virtual memory executables are loaded at when none is given
+pub const DEFAULT_OFFSET: u64 = 0x81_FF00_0000;
+
+/// Top of the stack every task starts with, the stack grows down from here
+pub const USER_STACK_TOP: u64 = 0x7FFF_FFFF_F000;
+
+/// Number of pages mapped for a tasks stack before it starts running, unless
+/// [RLIMIT_STACK](crate::rlimit::RLIMIT_STACK) asks for another size
+pub const USER_STACK_PAGES: usize = 16;
+
+/// Level 4 entries that belong to a task alone, every other entry is shared with
+/// the kernel address space by [AddressSpace]
+pub const USER_P4_INDICES: Range<u16> = 1..256;
+
+/// Length of the `int 0x80` instruction that enters a syscall
+pub const SYSCALL_INSTRUCTION_LENGTH: u64 = 2;
+
+/// Size of the stack of a kernel thread
+pub(crate) const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// Closure a kernel thread runs, boxed twice so it can be passed in a single register
+type KernelThreadFn = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread of execution, the unit the scheduler switches between. The address space,
+/// open files and credentials belong to the [Process] the task is a thread of, the
+/// registers, stack, thread pointer and state belong to the task alone
 pub struct Task {
     task_id: TaskID,
     pub entry: VirtAddr,
-    page_table : KpBox<PageTable>,
     state: TaskState,
-    pub name: &'static str,
+    pub name: String,
     pub ring: Ring,
+    process: Arc<Process>,
+    context: Context,
+    stack: ThreadStack,
+    /// Thread pointer loaded into the FS base while the task runs
+    fs_base: VirtAddr,
+    /// What the task waits for while it is blocked in a syscall
+    waiting: Option<WaitReason>,
+    /// Signals sent to this thread alone, like the ones raised by its faults
+    pending_signals: SignalSet,
+    /// Signals that stay pending instead of being delivered to this thread
+    blocked_signals: SignalSet,
+}
+
+/// Where the stack of a task lives
+enum ThreadStack {
+    /// Stack and TLS area in a thread slot of the user address space, see [thread]
+    User(usize),
+    /// Guarded stack in the kernel stack area
+    Kernel(KernelStack),
 }
 
 impl Task {
@@ -38,47 +89,266 @@ impl Task {
     /// ring   : Ring that this executable will be in (TODO consider making default ring 3)
     /// offset : Offset in virtual memory that the program will be loaded too (TODO consider making a default offset)
     pub fn binary(
-        name: Option<&'static str>,
+        name: Option<&str>,
         bin: &[u8],
         ring: Option<Ring>,
         offset: Option<u64>,
     ) -> Task {
-        let offset = offset.unwrap_or(0x81_FF00_0000);
-
         let ring = ring.unwrap_or(Ring::Ring3);
 
-        let name = name.unwrap_or("");
+        let name = String::from(name.unwrap_or(""));
 
-        let page_table = Pml4Creator::default().create();
+        Self::load(
+            name,
+            bin,
+            ring,
+            offset,
+            Vec::new(),
+            Vec::new(),
+            Limits::default(),
+        )
+        .expect("Failed to load and link the executable")
+    }
 
-        unsafe {
-            Cr3::write(
-                PhysFrame::containing_address(page_table.index(511).addr()),
-                Cr3::read().1,
-            )
-        };
-        *RECURSIVE_INDEX.wait().unwrap().lock() = 511;
-        x86_64::instructions::tlb::flush_all();
+    /// Create a process from an executable and return its first thread, the executable
+    /// and its shared libraries are loaded into a new address space with a stack mapped
+    /// below [USER_STACK_TOP]. See [Process::load]
+    ///
+    /// args   : Arguments the program is started with
+    /// env    : Environment strings of the program in `KEY=VALUE` form
+    /// limits : Resource limits of the new process
+    pub fn load(
+        name: String,
+        bin: &[u8],
+        ring: Ring,
+        offset: Option<u64>,
+        args: Vec<String>,
+        env: Vec<String>,
+        limits: Limits,
+    ) -> Result<Task, LinkError> {
+        let offset = offset.unwrap_or(DEFAULT_OFFSET);
+
+        let task_id = TaskID::allocate();
+        let (process, initial) =
+            Process::load(task_id, name.clone(), bin, ring, offset, args, env, limits)?;
+        process.add_thread(task_id);
+
+        Ok(Self::user(task_id, name, Process::register(process), initial, 0))
+    }
+
+    /// Create a thread that starts in a user address space
+    fn user(
+        task_id: TaskID,
+        name: String,
+        process: Arc<Process>,
+        initial: InitialThread,
+        slot: usize,
+    ) -> Task {
+        let ring = process.ring;
+
+        Self {
+            task_id,
+            entry: initial.entry,
+            state: TaskState::New,
+            name,
+            ring,
+            process,
+            context: Context::new(initial.entry, initial.stack_pointer, ring),
+            stack: ThreadStack::User(slot),
+            fs_base: initial.thread_pointer,
+            waiting: None,
+            pending_signals: SignalSet::empty(),
+            blocked_signals: SignalSet::empty(),
+        }
+    }
 
-        let elf = ElfBinary::new(bin).unwrap();
-        let mut loader = ElfMemory::new(offset);
-        elf.load(&mut loader).unwrap();
+    /// Create a kernel thread that runs `f` in ring 0, the thread exits when `f` returns
+    pub fn kernel_thread<F>(name: &str, f: F) -> Task
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let stack = KernelStack::new(Bytes::new(KERNEL_STACK_SIZE))
+            .expect("No memory left for a kernel stack");
+        // Functions expect the stack to be misaligned by the return address of their call
+        let stack_top = align_down(stack.top().as_u64(), 16) - 8;
 
-        let entry = VirtAddr::new(elf.entry_point() + offset);
+        let entry = VirtAddr::new(kernel_thread_start as usize as u64);
+        let f: Box<KernelThreadFn> = Box::new(Box::new(f));
 
-        
-        swap_to_kernel_table();
+        let mut context = Context::new(entry, VirtAddr::new(stack_top), Ring::Ring0);
+        context.rdi = Box::into_raw(f) as u64;
 
         Self {
             task_id: TaskID::allocate(),
             entry,
             state: TaskState::New,
-            ring,
-            name,
-            page_table,
+            name: String::from(name),
+            ring: Ring::Ring0,
+            process: Process::kernel(),
+            context,
+            stack: ThreadStack::Kernel(stack),
+            fs_base: VirtAddr::zero(),
+            waiting: None,
+            pending_signals: SignalSet::empty(),
+            blocked_signals: SignalSet::empty(),
+        }
+    }
+
+    /// Create another thread of this tasks process that calls `entry` with `arg` in rdi
+    ///
+    /// Must be called while the address space of the process is active. The thread gets
+    /// its own stack and a TLS area initialized from the templates of the loaded objects.
+    /// Returning from `entry` jumps to address 0, threads end with `thread_exit`.
+    /// Fails if there are no frames left for the stack or the TLS area or if they would
+    /// take the process over its memory limits
+    pub fn thread(&self, entry: VirtAddr, arg: u64) -> Result<Task, MapToError<Size4KiB>> {
+        let stack_pages = rlimit::stack_pages(&self.process.limits());
+        let pages = stack_pages + thread::tls_pages(self.process.image());
+        if !rlimit::may_grow(&self.process, Size4KiB::SIZE * pages as u64) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        let slot = self.process.allocate_slot();
+        let stack_top = thread::map_stack(slot, stack_pages)?;
+        let thread_pointer = match unsafe { thread::map_tls(slot, self.process.image()) } {
+            Ok(thread_pointer) => thread_pointer,
+            Err(err) => {
+                thread::unmap(slot, self.process.image());
+                return Err(err);
+            }
+        };
+
+        // Push a null return address so the stack is aligned like after a call
+        let stack_pointer = stack_top - 8u64;
+        unsafe { *stack_pointer.as_mut_ptr::<u64>() = 0 };
+
+        let task_id = TaskID::allocate();
+        self.process.add_thread(task_id);
+
+        let initial = InitialThread {
+            entry,
+            stack_pointer,
+            thread_pointer,
+        };
+        let mut task = Self::user(
+            task_id,
+            self.name.clone(),
+            self.process.clone(),
+            initial,
+            slot,
+        );
+        task.context.rdi = arg;
+        task.blocked_signals = self.blocked_signals;
+        Ok(task)
+    }
+
+    /// Replace the program this task is running with a new executable, the task keeps
+    /// its ID and ring but moves to a new process with a new address space and starts
+    /// again from the entry point. Every other thread of the old process is ended.
+    /// On failure the task is left untouched
+    ///
+    /// The new address space is active when this returns successfully
+    pub fn exec(
+        &mut self,
+        name: String,
+        bin: &[u8],
+        args: Vec<String>,
+        env: Vec<String>,
+    ) -> Result<(), LinkError> {
+        let (process, initial) = self.process.exec(name.clone(), bin, args, env)?;
+        process.add_thread(self.task_id);
+
+        let new_task = Self::user(self.task_id, name, Process::register(process), initial, 0);
+        let old_task = mem::replace(
+            self,
+            Task {
+                state: self.state,
+                pending_signals: self.pending_signals,
+                blocked_signals: self.blocked_signals,
+                ..new_task
+            },
+        );
+        old_task.process.exit();
+        table::record(self);
+        self.activate();
+        // The old tables can only be freed once they are no longer loaded in CR3
+        drop(old_task);
+
+        Ok(())
+    }
+
+    /// Create a child process that continues from the same registers in a copy of this
+    /// tasks address space, the child sees 0 in rax as the result of the syscall. Only
+    /// the calling thread is copied into the child. See [Process::fork]
+    pub fn fork(&self, context: &Context) -> Result<Task, MapToError<Size4KiB>> {
+        let slot = match self.stack {
+            ThreadStack::User(slot) => slot,
+            ThreadStack::Kernel(_) => panic!("Kernel threads can not be forked"),
+        };
+
+        let task_id = TaskID::allocate();
+        let process = self.process.fork(task_id)?;
+        process.add_thread(task_id);
+
+        let mut context = *context;
+        context.rax = 0;
+
+        Ok(Task {
+            task_id,
+            entry: self.entry,
+            state: TaskState::New,
+            name: self.name.clone(),
+            ring: self.ring,
+            process: Process::register(process),
+            context,
+            stack: ThreadStack::User(slot),
+            fs_base: self.fs_base,
+            waiting: None,
+            pending_signals: SignalSet::empty(),
+            blocked_signals: self.blocked_signals,
+        })
+    }
+
+    /// Load the address space and thread pointer of this task
+    pub fn activate(&self) {
+        self.process.address_space().activate();
+        FsBase::write(self.fs_base);
+    }
+
+    /// Unmap the stack and TLS area of a thread that has exited, the stack of the
+    /// first thread also holds the arguments and environment so it stays mapped
+    ///
+    /// Must be called while the address space of the process is active
+    pub fn release_stack(&mut self) {
+        if let ThreadStack::User(slot) = self.stack {
+            if slot != 0 {
+                thread::unmap(slot, self.process.image());
+            }
         }
     }
 
+    /// Block this task in its syscall until `reason` is resolved
+    pub fn block(&mut self, reason: WaitReason) {
+        self.waiting = Some(reason);
+        self.set_state(TaskState::Blocked);
+    }
+
+    /// Wake a blocked task, `value` is the result of the syscall it blocked in
+    pub fn wake(&mut self, value: u64) {
+        self.waiting = None;
+        self.context.rax = value;
+        self.set_state(TaskState::Ready);
+    }
+
+    /// Wake a blocked task so it makes its syscall again, for syscalls like `read`
+    /// that can only find their result once they run. A syscall that blocks returns
+    /// its own number so rax still holds it
+    pub fn restart(&mut self) {
+        self.waiting = None;
+        self.context.repeat_syscall();
+        self.set_state(TaskState::Ready);
+    }
+
     pub fn task_id(&self) -> TaskID {
         self.task_id
     }
@@ -100,14 +370,101 @@ impl Task {
     //     self.stack_frame_top_addr() + b.as_usize()
     // }
 
-    /// Set the task's state.
+    /// Set the task's state, the [task table](crate::table) is kept up to date.
     pub fn set_state(&mut self, state: TaskState) {
         self.state = state;
+        table::set_state(self.task_id, state);
+    }
+
+    /// Get a reference to the process the task is a thread of.
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    /// Make a signal pending on this thread alone.
+    pub fn queue_signal(&mut self, signal: u32) {
+        self.pending_signals.insert(signal);
+    }
+
+    /// Take the next signal to deliver to this thread, signals sent to the thread
+    /// come before the ones sent to its process
+    pub fn take_signal(&mut self) -> Option<u32> {
+        let allowed = SignalSet::from_bits(!self.blocked_signals.bits());
+
+        match SignalSet::from_bits(self.pending_signals.bits() & allowed.bits()).first() {
+            Some(signal) => {
+                self.pending_signals.remove(signal);
+                Some(signal)
+            }
+            None => self.process.take_signal(allowed),
+        }
+    }
+
+    /// Get the signals the task blocks.
+    pub fn blocked_signals(&self) -> SignalSet {
+        self.blocked_signals
+    }
+
+    /// Set the signals the task blocks, [UNBLOCKABLE] signals are never blocked.
+    pub fn set_blocked_signals(&mut self, signals: SignalSet) {
+        self.blocked_signals = signals.difference(UNBLOCKABLE);
+    }
+
+    /// Get what the task is blocked on.
+    pub fn waiting(&self) -> Option<WaitReason> {
+        self.waiting
+    }
+
+    /// Get the thread pointer of the task.
+    pub fn fs_base(&self) -> VirtAddr {
+        self.fs_base
+    }
+
+    /// Get a reference to the task's address space.
+    pub fn address_space(&self) -> &AddressSpace {
+        self.process.address_space()
+    }
+
+    /// Get a reference to the executable and shared objects loaded for this task.
+    pub fn image(&self) -> &LoadedImage {
+        self.process.image()
+    }
+
+    /// Get a reference to the registers the task resumes with.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Save the registers of the task after it has been interrupted.
+    pub fn save_context(&mut self, context: &Context) {
+        self.context = *context;
+    }
+
+    /// Get a reference to the arguments the task was started with.
+    pub fn args(&self) -> &[String] {
+        self.process.args()
     }
 
-    /// Get a reference to the task's page table.
-    pub fn page_table(&self) -> &KpBox<PageTable> {
-        &self.page_table
+    /// Get a reference to the environment the task was started with.
+    pub fn env(&self) -> &[String] {
+        self.process.env()
+    }
+
+    /// Get a reference to the task's open files.
+    pub fn files(&self) -> &FileTable {
+        self.process.files()
+    }
+}
+
+/// First code a kernel thread runs, calls its closure and ends the thread
+extern "C" fn kernel_thread_start(f: *mut KernelThreadFn) -> ! {
+    let f = unsafe { Box::from_raw(f) };
+    f();
+
+    interrupts::without_interrupts(|| Scheduler::get_scheduler().exit_thread(0));
+    // The next timer interrupt switches away and the thread is never resumed
+    loop {
+        x86_64::instructions::hlt();
     }
 }
 
@@ -118,7 +475,7 @@ pub enum Ring {
     Ring3 = 0b11,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 /// TaskID struct used for atomically getting new task ID's
 pub struct TaskID(usize);
 
@@ -157,48 +514,85 @@ pub enum TaskState {
     /// Process is blocked from IO
     Blocked,
 
+    /// Process has been stopped by a signal and waits for SIGCONT
+    Stopped,
+
     /// Process has finished execution
     Finished,
 }
 
+/// What a task blocked in a syscall waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitReason {
+    /// Another thread of its process to exit, see `thread_join`
+    Thread(TaskID),
+    /// A child process to exit or stop, see `waitpid`
+    Child,
+    /// A line typed at the terminal, see [terminal](crate::terminal)
+    TerminalInput,
+}
+
 /// Context of registers used for task switching
-#[derive(Default)]
-#[repr(C, packed)]
+///
+/// The general purpose registers are pushed by the `syscall_entry` and `timer_entry`
+/// stubs of the interrupts crate below the frame the CPU pushes on an interrupt, so the
+/// field order has to match the order they are pushed in. Returning from the interrupt
+/// resumes whatever context is written into that frame
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
 pub struct Context {
-    cr3: u64,
-    rbp: u64,
-    r12: u64,
-    r13: u64,
-    r14: u64,
-    r15: u64,
-    rbx: u64,
-    rflags: u64,
-    rip: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
-#[derive(Default)]
-pub struct Pml4Creator {
-    pml4: KpBox<PageTable>,
-}
-impl Pml4Creator {
-    pub fn create(mut self) -> KpBox<PageTable> {
-        self.map_kernel_area();
-        self.enable_recursive_paging();
-        self.pml4
+impl Context {
+    /// Whether returning to this context enters ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == Ring::Ring3 as u64
     }
 
-    fn enable_recursive_paging(&mut self) {
-        let a = PhysFrame::containing_address(self.pml4.phys_addr());
-        let f =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-        self.pml4[511].set_frame(a, f);
+    /// Return to the `int 0x80` this context entered the kernel with, so the syscall
+    /// in rax is made again
+    pub fn repeat_syscall(&mut self) {
+        self.rip -= SYSCALL_INSTRUCTION_LENGTH;
     }
 
-    fn map_kernel_area(&mut self) {
-        self.pml4[0] = KERNEL_PAGE_TABLE.wait().unwrap().lock().level_4_table()[0].clone();
-        self.pml4[256] = KERNEL_PAGE_TABLE.wait().unwrap().lock().level_4_table()[256].clone();
-        for i in 507..512 {
-            self.pml4[i] = KERNEL_PAGE_TABLE.wait().unwrap().lock().level_4_table()[i].clone();
+    /// Create a context that starts executing at `entry` with interrupts enabled
+    /// and the stack pointer at `stack` in the code and data segments of `ring`
+    pub fn new(entry: VirtAddr, stack: VirtAddr, ring: Ring) -> Self {
+        let selectors = &gdt::GDT.1;
+        let (code, data) = match ring {
+            Ring::Ring0 => (selectors.kernel_code_selector, selectors.kernel_data_selector),
+            Ring::Ring3 => (selectors.user_code_selector, selectors.user_data_selector),
+        };
+
+        Self {
+            rip: entry.as_u64(),
+            cs: u64::from(code.0),
+            rflags: RFlags::INTERRUPT_FLAG.bits(),
+            rsp: stack.as_u64(),
+            ss: u64::from(data.0),
+            ..Self::default()
         }
     }
 }
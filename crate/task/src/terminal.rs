@@ -0,0 +1,280 @@
+//! The console terminal, it turns the scancodes of the keyboard into lines of input
+//! for `read` and sends SIGINT for Ctrl+C and SIGTSTP for Ctrl+Z to its foreground
+//! process group
+//!
+//! The terminal is the controlling terminal of one session, only processes in the
+//! foreground group of that session may read from it. A background process that
+//! tries to gets SIGTTIN instead, which stops it until the shell moves it into the
+//! foreground with `tcsetpgrp` and continues it
+//!
+//! The keyboard interrupt only queues its scancodes. They are decoded, and the signals
+//! typed and the tasks waiting for input are handed to the scheduler, by [dispatch] on
+//! the next timer tick, so the keyboard interrupt neither allocates nor waits for the
+//! terminal, the printer or the scheduler
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use printer::{print, println};
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::job::JobError;
+use crate::process::Process;
+use crate::scheduler::Scheduler;
+use crate::signal::{SignalSet, SIGINT, SIGTSTP};
+use crate::task::{TaskID, WaitReason};
+
+extern crate alloc;
+use alloc::{collections::VecDeque, vec::Vec};
+
+static TERMINAL: Once<Mutex<Terminal>> = Once::new();
+
+/// Scancodes received since the last [dispatch]
+static SCANCODES: Mutex<ScancodeRing> = Mutex::new(ScancodeRing::new());
+
+/// Scancodes kept until the next [dispatch], more are dropped
+const SCANCODE_CAPACITY: usize = 64;
+
+/// Characters Ctrl and a letter are decoded to
+const CTRL_C: char = '\u{3}';
+const CTRL_Z: char = '\u{1a}';
+const BACKSPACE: char = '\u{8}';
+
+/// Fixed size queue of scancodes, filled by the keyboard interrupt
+struct ScancodeRing {
+    scancodes: [u8; SCANCODE_CAPACITY],
+    start: usize,
+    len: usize,
+}
+
+impl ScancodeRing {
+    const fn new() -> Self {
+        Self {
+            scancodes: [0; SCANCODE_CAPACITY],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a scancode at the end, it is dropped when the ring is full
+    fn push(&mut self, scancode: u8) {
+        if self.len < SCANCODE_CAPACITY {
+            self.scancodes[(self.start + self.len) % SCANCODE_CAPACITY] = scancode;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let scancode = self.scancodes[self.start];
+        self.start = (self.start + 1) % SCANCODE_CAPACITY;
+        self.len -= 1;
+        Some(scancode)
+    }
+}
+
+pub struct Terminal {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    /// Line that is being typed, readers get it once enter is pressed
+    line: Vec<u8>,
+    /// Typed lines that have not been read yet
+    input: VecDeque<u8>,
+    /// Session the terminal is the controlling terminal of
+    session: Option<TaskID>,
+    foreground: Option<TaskID>,
+    /// Signals typed since the last [dispatch]
+    signals: SignalSet,
+    /// A line was finished since the last [dispatch]
+    input_ready: bool,
+}
+
+/// What a `read` from the terminal found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadResult {
+    /// Bytes were copied into the buffer
+    Data(usize),
+    /// No line has been typed yet
+    Empty,
+    /// The reader is not in the foreground group, it has to be stopped with SIGTTIN
+    Background,
+    /// The reader is not in the session of the terminal
+    NotInSession,
+}
+
+impl Terminal {
+    fn new() -> Self {
+        Self {
+            keyboard: Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::MapLettersToUnicode,
+            ),
+            line: Vec::new(),
+            input: VecDeque::new(),
+            session: None,
+            foreground: None,
+            signals: SignalSet::empty(),
+            input_ready: false,
+        }
+    }
+
+    /// Decode a scancode and type the character of the key that was pressed
+    fn receive(&mut self, scancode: u8) {
+        if let Ok(Some(event)) = self.keyboard.add_byte(scancode) {
+            if let Some(DecodedKey::Unicode(character)) = self.keyboard.process_keyevent(event) {
+                self.type_char(character);
+            }
+        }
+    }
+
+    /// Echo a typed character and add it to the line being edited
+    fn type_char(&mut self, character: char) {
+        match character {
+            CTRL_C | CTRL_Z => {
+                let (signal, echo) = match character {
+                    CTRL_C => (SIGINT, "^C"),
+                    _ => (SIGTSTP, "^Z"),
+                };
+                println!("{}", echo);
+                self.line.clear();
+                self.signals.insert(signal);
+            }
+            BACKSPACE => {
+                if let Some(byte) = self.line.pop() {
+                    // Remove a whole multi byte character
+                    let mut byte = byte;
+                    while byte & 0xC0 == 0x80 {
+                        byte = match self.line.pop() {
+                            Some(byte) => byte,
+                            None => break,
+                        };
+                    }
+                    print!("{}", BACKSPACE);
+                }
+            }
+            '\n' => {
+                println!();
+                self.input.extend(self.line.drain(..));
+                self.input.push_back(b'\n');
+                self.input_ready = true;
+            }
+            character if !character.is_control() => {
+                print!("{}", character);
+                let mut bytes = [0; 4];
+                self.line
+                    .extend_from_slice(character.encode_utf8(&mut bytes).as_bytes());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn terminal() -> MutexGuard<'static, Terminal> {
+    TERMINAL.call_once(|| Mutex::new(Terminal::new())).lock()
+}
+
+/// Queue a scancode read from the keyboard for the next [dispatch], called from the
+/// keyboard interrupt
+pub fn receive_scancode(scancode: u8) {
+    SCANCODES.lock().push(scancode);
+}
+
+/// Make the terminal the controlling terminal of `session`, the group of the session
+/// leader is in the foreground
+pub fn attach(session: TaskID) {
+    let mut terminal = terminal();
+    terminal.session = Some(session);
+    terminal.foreground = Some(session);
+}
+
+/// The foreground group if `process` is in the session of the terminal, like `tcgetpgrp`
+pub fn foreground_group(process: &Process) -> Result<TaskID, JobError> {
+    let terminal = terminal();
+    match terminal.session {
+        Some(session) if session == process.session() => {
+            terminal.foreground.ok_or(JobError::NotTerminal)
+        }
+        _ => Err(JobError::NotTerminal),
+    }
+}
+
+/// Move the group `group` into the foreground, like `tcsetpgrp`. `process` has to be in
+/// the session of the terminal and `group` has to be a group of that session
+pub fn set_foreground_group(process: &Process, group: TaskID) -> Result<(), JobError> {
+    let session = process.session();
+    if terminal().session != Some(session) {
+        return Err(JobError::NotTerminal);
+    }
+
+    let group_exists = Process::all()
+        .iter()
+        .any(|p| p.process_group() == group && p.session() == session);
+    if !group_exists {
+        return Err(JobError::NotPermitted);
+    }
+
+    terminal().foreground = Some(group);
+    Ok(())
+}
+
+/// Copy the next typed line, or as much of it as fits, into `buf` for `process`
+pub fn read(process: &Process, buf: &mut [u8]) -> ReadResult {
+    let mut terminal = terminal();
+    if terminal.session != Some(process.session()) {
+        return ReadResult::NotInSession;
+    }
+    if terminal.foreground != Some(process.process_group()) {
+        return ReadResult::Background;
+    }
+    if terminal.input.is_empty() {
+        return ReadResult::Empty;
+    }
+
+    let mut length = 0;
+    while length < buf.len() {
+        match terminal.input.pop_front() {
+            Some(byte) => {
+                buf[length] = byte;
+                length += 1;
+                if byte == b'\n' {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    ReadResult::Data(length)
+}
+
+/// Decode the scancodes received since the last call, send the signals typed to the
+/// foreground group and let the tasks waiting for input look for it again, called on
+/// every timer tick
+pub fn dispatch() {
+    let (signals, input_ready, foreground) = {
+        let mut terminal = terminal();
+        let mut scancodes = SCANCODES.lock();
+        while let Some(scancode) = scancodes.pop() {
+            terminal.receive(scancode);
+        }
+        drop(scancodes);
+
+        let signals = core::mem::take(&mut terminal.signals);
+        let input_ready = core::mem::take(&mut terminal.input_ready);
+        (signals, input_ready, terminal.foreground)
+    };
+    if signals.is_empty() && !input_ready {
+        return;
+    }
+
+    let mut scheduler = Scheduler::get_scheduler();
+    if let Some(group) = foreground {
+        let mut signals = signals;
+        while let Some(signal) = signals.first() {
+            signals.remove(signal);
+            // The group may have ended since it was moved into the foreground
+            let _ = scheduler.signal_group(group, signal);
+        }
+    }
+    if input_ready {
+        scheduler.restart_blocked(|task| task.waiting() == Some(WaitReason::TerminalInput));
+    }
+}
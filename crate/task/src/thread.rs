@@ -0,0 +1,116 @@
+//! Stacks and thread local storage of the threads of a user process
+//!
+//! The top of the user address space is split into fixed size thread slots
+//! growing down from [USER_STACK_TOP]. A slot holds the stack of one thread
+//! at its top and the threads static TLS area at its bottom, slot 0 belongs
+//! to the thread the process was started with. Stacks are as large as the
+//! [RLIMIT_STACK](crate::rlimit::RLIMIT_STACK) of the process when the thread
+//! is created allows
+//!
+//! TLS is laid out as TLS variant II, the thread pointer loaded into the FS
+//! base points at a thread control block whose first word points to itself
+//! and the TLS blocks of all loaded objects sit directly below it
+use core::ptr;
+
+use os_units::NumOfPages;
+use x86_64::{
+    align_up,
+    structures::paging::{mapper::MapToError, PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use crate::dynamic::LoadedImage;
+use crate::rlimit::MAX_STACK_SIZE;
+use crate::task::USER_STACK_TOP;
+
+/// Size of the part of the address space reserved for each thread
+pub const THREAD_SLOT_SIZE: u64 = 0x10_0000;
+
+/// Size of the thread control block above the thread pointer, only the self
+/// pointer is used but the ABI reserves space for values like the stack guard
+const TCB_SIZE: u64 = 0x40;
+
+/// Address the stack of the thread in `slot` grows down from
+pub fn slot_top(slot: usize) -> VirtAddr {
+    VirtAddr::new(USER_STACK_TOP - THREAD_SLOT_SIZE * slot as u64)
+}
+
+/// Lowest address of `slot`, the TLS area of its thread starts here
+fn slot_bottom(slot: usize) -> VirtAddr {
+    slot_top(slot + 1)
+}
+
+/// Map a stack of `pages` pages, see [stack_pages](crate::rlimit::stack_pages), for
+/// the thread in `slot` into the active address space and return the address it
+/// starts at
+pub fn map_stack(slot: usize, pages: usize) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let top = slot_top(slot);
+    memory::virt::allocate_pages(
+        top - Size4KiB::SIZE * pages as u64,
+        NumOfPages::<Size4KiB>::new(pages),
+    )?;
+    Ok(top)
+}
+
+/// Number of pages the TLS area of a thread of `image` takes
+pub fn tls_pages(image: &LoadedImage) -> usize {
+    let size = thread_pointer_offset(image) + TCB_SIZE;
+    (align_up(size, Size4KiB::SIZE) / Size4KiB::SIZE) as usize
+}
+
+/// Distance from the bottom of the TLS area to the thread pointer, the thread
+/// pointer has to be aligned to the largest alignment of any TLS block
+fn thread_pointer_offset(image: &LoadedImage) -> u64 {
+    let align = image
+        .tls_templates()
+        .map(|tls| tls.align)
+        .fold(16, u64::max);
+    align_up(image.tls_size, align)
+}
+
+/// Map the TLS area of the thread in `slot`, initialize every TLS block from the
+/// templates of `image` and return the thread pointer to load into the FS base
+///
+/// # Safety
+/// The address space `image` was loaded into must be active
+pub unsafe fn map_tls(slot: usize, image: &LoadedImage) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let bottom = slot_bottom(slot);
+    let pages = tls_pages(image);
+    assert!(
+        Size4KiB::SIZE * pages as u64 + MAX_STACK_SIZE <= THREAD_SLOT_SIZE,
+        "TLS area does not fit into a thread slot"
+    );
+
+    memory::virt::allocate_pages(bottom, NumOfPages::<Size4KiB>::new(pages))?;
+    ptr::write_bytes(
+        bottom.as_mut_ptr::<u8>(),
+        0,
+        Size4KiB::SIZE as usize * pages,
+    );
+
+    let thread_pointer = bottom + thread_pointer_offset(image);
+    for tls in image.tls_templates() {
+        ptr::copy_nonoverlapping(
+            tls.tdata_start as *const u8,
+            (thread_pointer - tls.offset).as_mut_ptr::<u8>(),
+            tls.tdata_length as usize,
+        );
+    }
+    *thread_pointer.as_mut_ptr::<u64>() = thread_pointer.as_u64();
+
+    Ok(thread_pointer)
+}
+
+/// Unmap the stack and TLS area of the thread in `slot` from the active address space
+pub fn unmap(slot: usize, image: &LoadedImage) {
+    // The stack may have any size up to the largest one
+    let top = slot_top(slot);
+    memory::virt::deallocate_pages(
+        top - MAX_STACK_SIZE,
+        NumOfPages::<Size4KiB>::new((MAX_STACK_SIZE / Size4KiB::SIZE) as usize),
+    );
+    memory::virt::deallocate_pages(
+        slot_bottom(slot),
+        NumOfPages::<Size4KiB>::new(tls_pages(image)),
+    );
+}
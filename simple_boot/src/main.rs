@@ -48,6 +48,11 @@ fn main() {
         .arg("-D")
         .arg("./log.txt");
 
+    // Lets tests run against memory maps of different sizes
+    if let Ok(memory) = std::env::var("QEMU_MEMORY") {
+        run_cmd.arg("-m").arg(memory);
+    }
+
     let binary_kind = runner_utils::binary_kind(&kernel_binary_path);
     if binary_kind.is_test() {
         run_cmd.args(TEST_ARGS);
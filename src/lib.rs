@@ -51,6 +51,33 @@ pub fn init_logger(framebuffer: &'static mut [u8], info: FrameBufferInfo) {
     WRITER.init_once(|| mutex_writer);
 }
 
+/// Map the framebuffer of the logger again with the largest pages its physical address
+/// allows and write-combining, the bootloader maps it with 4 KiB write-back pages. Has
+/// to run once the frame allocator is initialized
+pub fn remap_framebuffer() {
+    use memory::{address_space::AddressSpace, pat::CacheMode, virt};
+    use x86_64::VirtAddr;
+
+    let mut writer = match WRITER.get() {
+        Some(writer) => writer.lock(),
+        None => return,
+    };
+    let framebuffer = writer.replace_framebuffer(&mut []);
+    let addr = VirtAddr::new(framebuffer.as_ptr() as u64);
+    let len = framebuffer.len();
+
+    let phys = AddressSpace::kernel()
+        .translate(addr)
+        .expect("The framebuffer is not mapped");
+    let mapped = unsafe { virt::map_mmio(phys, len as u64, CacheMode::WriteCombining) };
+    let framebuffer = match mapped {
+        Some(virt) => unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), len) },
+        // The framebuffer stays on the mapping of the bootloader
+        None => framebuffer,
+    };
+    writer.replace_framebuffer(framebuffer);
+}
+
 ////////////////////////////////////////////////////////////////////////////////////
 //                                  Tests
 ////////////////////////////////////////////////////////////////////////////////////
@@ -90,52 +117,294 @@ fn test_empty_load_elf2() {
 fn test_switch_to_elf() {}
 #[test_case]
 fn test_change_virtual_address_space() {
-    use core::ops::Index;
-    use memory::{swap_to_kernel_table, RECURSIVE_INDEX};
-    use task::task::Pml4Creator;
+    use memory::address_space::AddressSpace;
+    use memory::swap_to_kernel_table;
     use x86_64::registers::control::Cr3;
-    use x86_64::structures::paging::PhysFrame;
 
-    let page_table = Pml4Creator::default().create();
+    let address_space = AddressSpace::new();
     let pt1 = Cr3::read_raw();
-    unsafe {
-        Cr3::write(
-            PhysFrame::containing_address(page_table.index(511).addr()),
-            Cr3::read().1,
-        )
-    };
-    *RECURSIVE_INDEX.wait().unwrap().lock() = 511;
+    address_space.activate();
     let pt2 = Cr3::read_raw();
+    assert!(address_space.is_active());
     swap_to_kernel_table();
-    assert_ne!(pt1, pt2)
+    assert_ne!(pt1, pt2);
+    assert!(AddressSpace::kernel().is_active());
+}
+
+#[test_case]
+fn test_initial_stack_layout() {
+    extern crate alloc;
+    use alloc::string::String;
+    use task::stack::{build_initial_stack, AuxiliaryValues, AT_ENTRY};
+    use x86_64::VirtAddr;
+
+    let mut stack = [0u64; 512];
+    let top = VirtAddr::from_ptr(stack.as_mut_ptr()) + 4096u64 - 8u64;
+    let args = [String::from("hello_world"), String::from("-v")];
+    let env = [String::from("PATH=/bin")];
+    let aux = AuxiliaryValues {
+        phdr: 0x1040,
+        phnum: 4,
+        entry: 0x1234,
+        base: 0,
+    };
+
+    let rsp = unsafe { build_initial_stack(top, &args, &env, aux) };
+    assert_eq!(rsp.as_u64() % 16, 0);
+
+    let words = rsp.as_ptr::<u64>();
+    unsafe {
+        // argc, 2 argv pointers, null, 1 envp pointer, null
+        assert_eq!(*words, 2);
+        assert_eq!(*words.add(3), 0);
+        assert_eq!(*words.add(5), 0);
+        let argv0 = *words.add(1) as *const u8;
+        assert_eq!(core::slice::from_raw_parts(argv0, 12), b"hello_world\0");
+
+        let mut auxv = words.add(6);
+        while *auxv != AT_ENTRY {
+            auxv = auxv.add(2);
+        }
+        assert_eq!(*auxv.add(1), 0x1234);
+    }
+}
+
+#[test_case]
+fn test_kernel_threads_share_process() {
+    extern crate alloc;
+    use alloc::sync::Arc;
+    use task::task::{Ring, Task};
+
+    let one = Task::kernel_thread("one", || {});
+    let two = Task::kernel_thread("two", || {});
+
+    assert!(Arc::ptr_eq(one.process(), two.process()));
+    assert!(one.process().is_kernel());
+    assert_ne!(one.task_id(), two.task_id());
+    assert_eq!(one.context().rip, two.context().rip);
+    assert_eq!(one.ring, Ring::Ring0);
+}
+
+#[test_case]
+fn test_signal_sets_and_default_actions() {
+    use task::signal::*;
+
+    let mut set = SignalSet::empty();
+    set.insert(SIGTERM);
+    set.insert(SIGINT);
+    assert_eq!(set.bits(), 1 << (SIGINT - 1) | 1 << (SIGTERM - 1));
+    assert_eq!(set.first(), Some(SIGINT));
+
+    set.remove(SIGINT);
+    assert_eq!(set.first(), Some(SIGTERM));
+    assert!(set.union(UNBLOCKABLE).difference(UNBLOCKABLE) == set);
+
+    assert_eq!(default_action(SIGSEGV), DefaultAction::Core);
+    assert_eq!(default_action(SIGCHLD), DefaultAction::Ignore);
+    assert_eq!(default_action(SIGTSTP), DefaultAction::Stop);
+    assert_eq!(default_action(SIGUSR1), DefaultAction::Terminate);
+}
+
+#[test_case]
+fn test_wait_status_and_targets() {
+    use task::job::*;
+    use task::signal::{SIGKILL, SIGTSTP};
+    use task::task::TaskID;
+
+    assert_eq!(exit_status(3), 0x300);
+    assert_eq!(signal_status(SIGKILL, false), 9);
+    assert_eq!(stop_status(SIGTSTP) & 0xff, 0x7f);
+
+    let own_group = TaskID::new(4);
+    assert_eq!(WaitTarget::from_pid(-1, own_group), WaitTarget::Any);
+    assert_eq!(WaitTarget::from_pid(0, own_group), WaitTarget::Group(own_group));
+    assert!(WaitTarget::from_pid(-7, own_group).matches(TaskID::new(9), TaskID::new(7)));
+    assert!(!WaitTarget::from_pid(9, own_group).matches(TaskID::new(8), own_group));
+}
+
+#[test_case]
+fn test_task_stat_from_info() {
+    extern crate alloc;
+    use alloc::string::String;
+    use task::table::{TaskInfo, TaskStat, TASK_NAME_LENGTH};
+    use task::task::{Ring, TaskID, TaskState};
+
+    let info = TaskInfo {
+        task_id: TaskID::new(7),
+        process_id: TaskID::new(5),
+        parent: None,
+        process_group: TaskID::new(5),
+        name: String::from("/bin/a_program_with_a_name_longer_than_the_record"),
+        state: TaskState::Blocked,
+        ring: Ring::Ring3,
+        cpu_ticks: 12,
+        memory: 0x4000,
+        start_tick: 3,
+    };
+
+    let stat = TaskStat::from(&info);
+    assert_eq!(stat.task_id, 7);
+    assert_eq!(stat.parent, 0);
+    assert_eq!(stat.state, 3);
+    assert_eq!(stat.ring, 3);
+    assert_eq!(stat.name[TASK_NAME_LENGTH - 1], 0);
+    assert_eq!(&stat.name[..5], b"/bin/");
+}
+
+#[test_case]
+fn test_region_placement_and_splitting() {
+    use task::region::{Region, RegionError, Regions, MMAP_BASE, PROT_READ, PROT_WRITE};
+    use x86_64::structures::paging::{PageSize, Size4KiB};
+
+    let page = Size4KiB::SIZE;
+    let mut regions = Regions::new(0x81_FF00_1234);
+    assert_eq!(regions.heap_start(), 0x81_FF00_2000);
+    assert_eq!(regions.brk(), regions.heap_start());
+
+    let region = |start: u64, pages: u64| Region {
+        start,
+        end: start + pages * page,
+        protection: PROT_READ | PROT_WRITE,
+        max_protection: PROT_READ | PROT_WRITE,
+        shared: false,
+    };
+    assert_eq!(regions.find_free(page), Some(MMAP_BASE));
+    regions.insert(region(MMAP_BASE, 4));
+    regions.insert(region(MMAP_BASE + 6 * page, 2));
+    assert_eq!(regions.find_free(2 * page), Some(MMAP_BASE + 4 * page));
+    assert_eq!(regions.find_free(3 * page), Some(MMAP_BASE + 8 * page));
+    assert!(!regions.is_free(MMAP_BASE + 3 * page, MMAP_BASE + 5 * page));
+
+    // Unmapping the middle of a region leaves both ends mapped
+    let removed = regions.remove(MMAP_BASE + page, MMAP_BASE + 2 * page);
+    assert_eq!(removed, [region(MMAP_BASE + page, 1)]);
+    assert_eq!(regions.find(MMAP_BASE).unwrap().end, MMAP_BASE + page);
+    assert_eq!(
+        regions.find(MMAP_BASE + 3 * page).unwrap().start,
+        MMAP_BASE + 2 * page
+    );
+    assert!(regions.find(MMAP_BASE + page).is_none());
+
+    // Protecting a range with a hole in it fails and changes nothing
+    assert_eq!(
+        regions.protect(MMAP_BASE, MMAP_BASE + 3 * page, PROT_READ),
+        Err(RegionError::NoMemory)
+    );
+    assert_eq!(
+        regions.protect(MMAP_BASE + 3 * page, MMAP_BASE + 7 * page, PROT_READ),
+        Err(RegionError::NoMemory)
+    );
+    let changed = regions
+        .protect(MMAP_BASE + 6 * page, MMAP_BASE + 7 * page, PROT_READ)
+        .unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        regions.iter().filter(|r| r.protection == PROT_READ).count(),
+        1
+    );
+    assert_eq!(regions.iter().count(), 4);
+}
+
+#[test_case]
+fn test_shared_memory_between_mappings() {
+    use task::process::Process;
+    use task::region::{self, RegionError, PROT_READ, PROT_WRITE};
+    use task::shm::{self, ShmError, SHM_CREATE, SHM_EXCLUSIVE, SHM_WRITE};
+    use x86_64::VirtAddr;
+
+    // Second page of a mapping
+    let word = |start: VirtAddr| (start + 0x1000u64).as_mut_ptr::<u64>();
+    let process = Process::kernel();
+    let writer = shm::open(&process, "test-pixels", 5000, SHM_CREATE | SHM_WRITE).unwrap();
+    assert_eq!(
+        shm::open(&process, "test-pixels", 0, SHM_CREATE | SHM_EXCLUSIVE),
+        Err(ShmError::Exists)
+    );
+    let reader = shm::open(&process, "test-pixels", 0, 0).unwrap();
+    let size = process.shared_memory().get(reader).unwrap().object().size();
+    assert_eq!(size, 0x2000);
+
+    process.address_space().enter(|| {
+        let write = shm::map(&process, writer, 0, PROT_READ | PROT_WRITE).unwrap();
+        let read = shm::map(&process, reader, 0, PROT_READ).unwrap();
+        assert_ne!(write, read);
+        unsafe { word(write).write(0xC0FFEE) };
+        assert_eq!(unsafe { word(read).read() }, 0xC0FFEE);
+
+        // A read only handle can not give a writable mapping, not even through mprotect
+        assert_eq!(
+            shm::map(&process, reader, 0, PROT_WRITE),
+            Err(ShmError::AccessDenied)
+        );
+        assert_eq!(
+            region::mprotect(&process, read.as_u64(), size, PROT_WRITE),
+            Err(RegionError::AccessDenied)
+        );
+        assert_eq!(
+            shm::unmap(&process, reader, write.as_u64() + 0x1000),
+            Err(ShmError::BadHandle)
+        );
+        shm::unmap(&process, reader, read.as_u64()).unwrap();
+
+        // The mapping keeps the memory after the last handle is closed
+        shm::close(&process, reader).unwrap();
+        shm::close(&process, writer).unwrap();
+        assert_eq!(shm::close(&process, writer), Err(ShmError::BadHandle));
+        let reopened = shm::open(&process, "test-pixels", 0, 0);
+        assert_eq!(reopened, Err(ShmError::NotFound));
+        assert_eq!(unsafe { word(write).read() }, 0xC0FFEE);
+        region::munmap(&process, write.as_u64(), size).unwrap();
+    });
+}
+
+#[test_case]
+fn test_range_allocator_best_fit_and_coalescing() {
+    use memory::range::RangeAllocator;
+    use memory::virt::{KernelStack, STACK_AREA};
+    use os_units::Bytes;
+
+    let mut ranges = RangeAllocator::with_range(0x1000..0x10000);
+    assert_eq!(ranges.allocate(0x2000, 0x1000), Some(0x1000));
+    assert!(ranges.reserve(0x5000, 0x1000));
+    assert!(!ranges.reserve(0x5000, 0x1000));
+
+    // The hole of 0x2000 bytes at 0x3000 fits better than the rest above 0x6000
+    assert_eq!(ranges.allocate(0x1000, 0x1000), Some(0x3000));
+    assert_eq!(ranges.allocate(0x1000, 0x2000), Some(0x6000));
+
+    // Freeing everything merges the ranges back into one
+    ranges.free(0x3000, 0x1000);
+    ranges.free(0x1000, 0x2000);
+    ranges.free(0x6000, 0x1000);
+    ranges.free(0x5000, 0x1000);
+    assert_eq!(ranges.free_length(), 0xF000);
+    assert_eq!(ranges.allocate(0xF000, 0x1000), Some(0x1000));
+
+    // Kernel stacks keep an unmapped guard page below them
+    let stack = KernelStack::new(Bytes::new(0x4000)).unwrap();
+    assert!(STACK_AREA.contains(&stack.top().as_u64()));
+    assert!(!memory::virt::available(stack.top() - 8u64));
+    assert!(memory::virt::available(stack.top() - 0x4000u64 - 8u64));
+    let top = stack.top();
+    drop(stack);
+    assert!(memory::virt::available(top - 8u64));
 }
 
 #[test_case]
 fn test_create_empty_page_tables() {
-    use task::task::Pml4Creator;
-    let _one = Pml4Creator::default().create();
-    let _two = Pml4Creator::default().create();
+    use memory::address_space::AddressSpace;
+    let _one = AddressSpace::new();
+    let _two = AddressSpace::new();
 }
 
 #[test_case]
 fn test_construct_page_table() {
-    use core::ops::Index;
-    use memory::{active_level_4_table, phys::FRAME_ALLOCATOR, RECURSIVE_INDEX};
-    use task::task::Pml4Creator;
-    use x86_64::registers::control::Cr3;
-    use x86_64::structures::paging::{
-        FrameAllocator, Page, PageTableFlags, PhysFrame, RecursivePageTable, Size4KiB,
-    };
+    use memory::{address_space::AddressSpace, phys::FRAME_ALLOCATOR};
+    use x86_64::structures::paging::{FrameAllocator, Page, PageSize, PageTableFlags, Size4KiB};
     use x86_64::VirtAddr;
-    let page_table = Pml4Creator::default().create();
-    unsafe {
-        Cr3::write(
-            PhysFrame::containing_address(page_table.index(511).addr()),
-            Cr3::read().1,
-        )
-    };
-    *RECURSIVE_INDEX.wait().unwrap().lock() = 511;
-    let mut rpt = RecursivePageTable::new(active_level_4_table()).unwrap();
+
+    // The address space is edited without ever being loaded
+    let address_space = AddressSpace::new();
     let frame = FRAME_ALLOCATOR
         .wait()
         .unwrap()
@@ -145,19 +414,316 @@ fn test_construct_page_table() {
 
     for i in 0..10 {
         let addr = VirtAddr::new(0xDEAD_BEEF + (i * Size4KiB::SIZE));
-        unsafe {
-            rpt.map_to(
-                Page::<Size4KiB>::containing_address(addr),
-                frame,
-                flags,
-                FRAME_ALLOCATOR.wait().as_mut().unwrap(),
-            )
-            .unwrap()
-            .ignore();
+        let page = Page::<Size4KiB>::containing_address(addr);
+        address_space.map(page, frame, flags).unwrap();
+    }
+    assert!(!address_space.is_active());
+
+    let addr = VirtAddr::new(0xDEAD_BEEF);
+    assert_eq!(
+        address_space.translate(addr),
+        Some(frame.start_address() + 0xEEFu64)
+    );
+    assert!(memory::virt::available(addr));
+
+    let page = Page::containing_address(addr);
+    address_space
+        .protect(page, PageTableFlags::PRESENT)
+        .unwrap();
+    assert_eq!(address_space.unmap(page).unwrap(), frame);
+    assert_eq!(address_space.translate(addr), None);
+}
+
+#[test_case]
+fn test_huge_pages_map_and_split() {
+    use memory::pat::CacheMode;
+    use memory::virt::{self, KernelArea};
+    use os_units::NumOfPages;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::PhysAddr;
+
+    const HUGE: u64 = 0x20_0000;
+
+    // Two 2 MiB pages of anonymous memory
+    let start = KernelArea::KpBox.reserve(NumOfPages::new(1024)).unwrap();
+    assert_eq!(start.as_u64() % HUGE, 0);
+    let first = Page::containing_address(start);
+    let pages = Page::range(first, first + 1024);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    virt::map_zeroed(pages, flags).unwrap();
+    assert_eq!(virt::mapped_page_size(start), Some(HUGE));
+    unsafe { start.as_mut_ptr::<u64>().write(0xB1A4C) };
+
+    // Changing a single page splits the huge page it is in
+    virt::protect_range(Page::range(first + 3, first + 4), PageTableFlags::PRESENT).unwrap();
+    assert_eq!(virt::mapped_page_size(start), Some(0x1000));
+    assert_eq!(virt::mapped_page_size(start + HUGE), Some(HUGE));
+    assert_eq!(unsafe { start.as_ptr::<u64>().read() }, 0xB1A4C);
+
+    virt::unmap_range(pages).unwrap();
+    assert_eq!(virt::mapped_page_size(start), None);
+    assert_eq!(virt::mapped_page_size(start + HUGE), None);
+    KernelArea::KpBox.release(start, NumOfPages::new(1024));
+
+    // Device memory keeps its offset into a 2 MiB page, no RAM is at this address
+    let phys = PhysAddr::new(0x40_0010_0123);
+    let virt = unsafe { virt::map_mmio(phys, 2 * HUGE, CacheMode::Uncached) }.unwrap();
+    assert_eq!(virt.as_u64() % HUGE, phys.as_u64() % HUGE);
+    assert_eq!(virt::mapped_page_size(virt), Some(0x1000));
+    let aligned = virt.align_up(HUGE);
+    assert_eq!(virt::mapped_page_size(aligned), Some(HUGE));
+    virt::unmap_mmio(virt, 2 * HUGE);
+    assert_eq!(virt::mapped_page_size(aligned), None);
+}
+
+#[test_case]
+fn test_mmio_cache_modes() {
+    use memory::{pat::CacheMode, virt, KERNEL_PAGE_TABLE};
+    use x86_64::structures::paging::{mapper::TranslateResult, PageTableFlags, Translate};
+    use x86_64::PhysAddr;
+
+    const HUGE: u64 = 0x20_0000;
+    let phys = PhysAddr::new(0x40_0000_0000);
+    let flags = |virt| match KERNEL_PAGE_TABLE.wait().unwrap().lock().translate(virt) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("MMIO page is not mapped"),
+    };
+
+    // Write-combining keeps huge pages, nothing is mapped for user space
+    let virt = unsafe { virt::map_mmio(phys, HUGE, CacheMode::WriteCombining) }.unwrap();
+    assert_eq!(virt::mapped_page_size(virt), Some(HUGE));
+    let wc = flags(virt);
+    assert!(!wc.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(wc.contains(CacheMode::WriteCombining.flags().0));
+    virt::unmap_mmio(virt, HUGE);
+
+    // The PAT bit of write-through is only found in 4 KiB entries
+    let virt = unsafe { virt::map_mmio(phys, HUGE, CacheMode::WriteThrough) }.unwrap();
+    assert_eq!(virt::mapped_page_size(virt), Some(0x1000));
+    let (cache, pat) = CacheMode::WriteThrough.flags();
+    assert!(flags(virt).contains(cache));
+    assert_eq!(flags(virt).contains(PageTableFlags::HUGE_PAGE), pat);
+    virt::unmap_mmio(virt, HUGE);
+
+    let virt = unsafe { virt::map_mmio(phys, 8, CacheMode::Uncached) }.unwrap();
+    assert!(flags(virt).contains(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE));
+    virt::unmap_mmio(virt, 8);
+}
+
+#[test_case]
+fn test_contiguous_and_dma_boxes() {
+    use memory::{address_space::AddressSpace, dma::DmaBuffer, kpbox::KpBox, KERNEL_PAGE_TABLE};
+    use os_units::Bytes;
+    use x86_64::structures::paging::{mapper::TranslateResult, PageTableFlags, Translate};
+    use x86_64::PhysAddr;
+
+    // Eight pages below 16 MiB, each one right after the other in physical memory
+    let limit = PhysAddr::new(0x100_0000);
+    let (contiguous, phys) = KpBox::new_contiguous(Bytes::new(8 * 0x1000), limit).unwrap();
+    assert!(phys + 8 * 0x1000u64 <= limit);
+    let translate = |addr| AddressSpace::kernel().translate(addr);
+    let last = contiguous.virt_addr() + 7 * 0x1000u64;
+    assert_eq!(translate(last), Some(phys + 7 * 0x1000u64));
+    assert!(contiguous.iter().all(|&byte| byte == 0));
+    drop(contiguous);
+
+    let zeroed = unsafe { KpBox::<[u64; 64]>::new_zeroed().assume_init() };
+    assert_eq!(*zeroed, [0; 64]);
+
+    // Empty slices no longer index their first element
+    let empty = KpBox::<[u8]>::from(&[][..]);
+    assert!(empty.is_empty());
+    assert!(empty.clone().is_empty());
+
+    // Slices of zero sized elements keep their length
+    let units = KpBox::new_slice((), 3);
+    assert_eq!(units.len(), 3);
+    assert_eq!(units.clone().len(), 3);
+
+    let ring = DmaBuffer::new_slice(7u32, 256, limit).unwrap();
+    assert_eq!(ring[255], 7);
+    assert!(DmaBuffer::new_slice(7u32, usize::MAX / 2, limit).is_none());
+    assert_eq!(translate(ring.virt_addr()), Some(ring.phys_addr()));
+    let kernel_table = KERNEL_PAGE_TABLE.wait().unwrap().lock();
+    match kernel_table.translate(ring.virt_addr()) {
+        TranslateResult::Mapped { flags, .. } => {
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH))
         }
+        _ => panic!("DMA buffer is not mapped"),
     }
 }
 
+#[test_case]
+fn test_meminfo_follows_allocations() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+    use memory::{address_space::AddressSpace, meminfo::meminfo};
+
+    let before = meminfo();
+    assert_eq!(before.used_frames + before.free_frames, before.total_frames);
+
+    // A new address space takes a frame for its level 4 table, mapping it may have
+    // taken more for the tables of the kernel
+    let address_space = AddressSpace::new();
+    let with_table = meminfo();
+    assert!(with_table.page_table_frames > before.page_table_frames);
+    assert!(with_table.used_frames > before.used_frames);
+
+    let buffer: Vec<u8> = Vec::with_capacity(10_000);
+    let allocated = meminfo();
+    assert!(allocated.heap_used >= with_table.heap_used + 10_000);
+    assert!(allocated.heap_peak >= allocated.heap_used);
+
+    drop(buffer);
+    assert_eq!(meminfo().heap_used, with_table.heap_used);
+    drop(address_space);
+    assert_eq!(
+        meminfo().page_table_frames,
+        with_table.page_table_frames - 1
+    );
+}
+
+#[test_case]
+fn test_heap_survives_allocation_churn() {
+    extern crate alloc;
+    #[cfg(not(feature = "linked-list-heap"))]
+    use alloc::boxed::Box;
+    use alloc::{collections::BTreeMap, vec::Vec};
+
+    // Far more than the heap holds is allocated over all rounds
+    for round in 0..64 {
+        let mut map = BTreeMap::new();
+        for i in 0..128 {
+            map.insert(i, Vec::<u8>::with_capacity((i * 7 + round) % 200));
+        }
+        map.retain(|key, _| key % 3 == 0);
+    }
+    let large = Vec::<u8>::with_capacity(16 * 1024);
+    assert_eq!(large.capacity(), 16 * 1024);
+
+    // A freed block is the next one handed out for its size class
+    #[cfg(not(feature = "linked-list-heap"))]
+    {
+        let first = Box::new([0u8; 24]);
+        let addr = &*first as *const [u8; 24] as usize;
+        drop(first);
+        let second = Box::new([1u8; 24]);
+        assert_eq!(&*second as *const [u8; 24] as usize, addr);
+    }
+}
+
+#[test_case]
+fn test_linked_list_merges_free_blocks() {
+    extern crate alloc;
+    use alloc::{vec, vec::Vec};
+    use core::alloc::Layout;
+    use memory::allocator::HeapAllocator;
+
+    let mut arena = vec![0u64; 512];
+    let start = arena.as_mut_ptr() as usize;
+    let mut heap = LinkedListAllocator::new();
+    unsafe { heap.init(start, 4096) };
+
+    let quarter = Layout::from_size_align(1024, 8).unwrap();
+    let blocks: Vec<*mut u8> = (0..4).map(|_| heap.allocate(quarter)).collect();
+    assert!(blocks.iter().all(|block| !block.is_null()));
+
+    // Freed out of order the quarters only hold the whole arena again once merged
+    for &i in &[2, 0, 3, 1] {
+        unsafe { heap.deallocate(blocks[i], quarter) };
+    }
+    let whole = Layout::from_size_align(4096, 8).unwrap();
+    assert_eq!(heap.allocate(whole) as usize, start);
+}
+
+#[test_case]
+fn test_heap_grows_and_gives_back_pages() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+    use memory::allocator::{heap_size, trim_heap, HEAP_SIZE};
+
+    let before = heap_size();
+    // Twice as large as the heap the kernel starts with
+    let large = Vec::<u8>::with_capacity(2 * HEAP_SIZE);
+    assert!(heap_size() >= before + 2 * HEAP_SIZE);
+
+    drop(large);
+    assert!(trim_heap() >= 2 * HEAP_SIZE);
+    assert!(heap_size() < before + 2 * HEAP_SIZE);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn test_heap_debug_red_zones() {
+    extern crate alloc;
+    use alloc::boxed::Box;
+    use memory::allocator::{debug::GUARD, ALLOCATOR};
+
+    let live = ALLOCATOR.lock().live_allocations();
+    let boxed = Box::new([7u8; 40]);
+    assert_eq!(ALLOCATOR.lock().live_allocations(), live + 1);
+    assert!(ALLOCATOR.lock().check().is_ok());
+
+    // One byte past the end is caught and put back before the free checks it
+    let end = unsafe { (&*boxed as *const [u8; 40] as *mut u8).add(40) };
+    unsafe { end.write(0) };
+    let corruption = ALLOCATOR.lock().check().unwrap_err();
+    assert_eq!(corruption.what, "Overflow");
+    assert_eq!(corruption.size, 40);
+    unsafe { end.write(GUARD) };
+
+    drop(boxed);
+    assert_eq!(ALLOCATOR.lock().live_allocations(), live);
+}
+
+#[test_case]
+fn test_resource_limits() {
+    use task::rlimit::{
+        cpu_signal, stack_pages, LimitError, Limits, RLimit, MAX_STACK_SIZE, RLIMIT_CPU,
+        RLIMIT_NOFILE, RLIMIT_STACK,
+    };
+    use task::signal::{SIGKILL, SIGXCPU};
+
+    let mut limits = Limits::default();
+    let files = limits.get(RLIMIT_NOFILE).unwrap();
+    assert_eq!(limits.get(2), Err(LimitError::Invalid));
+
+    // Only root raises a hard limit, anyone can lower one
+    let raised = RLimit {
+        cur: files.cur,
+        max: files.max + 1,
+    };
+    assert_eq!(
+        limits.set(RLIMIT_NOFILE, raised, false),
+        Err(LimitError::NotPermitted)
+    );
+    let lowered = RLimit { cur: 8, max: 16 };
+    assert_eq!(limits.set(RLIMIT_NOFILE, lowered, false), Ok(()));
+    assert_eq!(
+        limits.set(RLIMIT_NOFILE, files, false),
+        Err(LimitError::NotPermitted)
+    );
+    assert_eq!(limits.set(RLIMIT_NOFILE, files, true), Ok(()));
+    let inverted = RLimit { cur: 16, max: 8 };
+    assert_eq!(
+        limits.set(RLIMIT_NOFILE, inverted, true),
+        Err(LimitError::Invalid)
+    );
+
+    let stack = RLimit {
+        cur: 2 * MAX_STACK_SIZE,
+        max: RLimit::INFINITY.max,
+    };
+    limits.set(RLIMIT_STACK, stack, true).unwrap();
+    assert_eq!(stack_pages(&limits) as u64 * 4096, MAX_STACK_SIZE);
+
+    // One SIGXCPU a second past the soft limit of 1 second, then SIGKILL at 3 seconds
+    let cpu = RLimit { cur: 1, max: 3 };
+    assert!(limits.set(RLIMIT_CPU, cpu, false).is_ok());
+    let signals = (1..=54).filter_map(|tick| cpu_signal(cpu, tick));
+    assert!(signals.eq([SIGXCPU, SIGXCPU, SIGKILL].iter().copied()));
+}
+
 ////////////////////////////////////////////////////////////////////////////////////
 //                                  Testing
 ////////////////////////////////////////////////////////////////////////////////////
@@ -185,6 +751,8 @@ fn test_entry_main(boot_info: &'static mut BootInfo) -> ! {
 
     memory::allocator::init_heap().expect("Heap did not properly map");
 
+    remap_framebuffer();
+
     test_main();
 
     halt_loop();
@@ -6,7 +6,9 @@
 #![reexport_test_harness_main = "test_main"]
 
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec;
+use alloc::vec::Vec;
 #[allow(unused_imports)]
 use blanc_os::test_runner;
 
@@ -45,7 +47,6 @@ use serial::serial_print;
 use serial::serial_println;
 
 use task::scheduler::Scheduler;
-use task::task::Pml4Creator;
 use task::task::Ring;
 use x86_64::registers::control::Cr3;
 use x86_64::registers::control::Cr4;
@@ -57,7 +58,7 @@ use x86_64::structures::paging::Size4KiB;
 #[rustfmt::skip]
 static HELLO_WORLD: &[u8] = include_bytes!("../applications/hello_world/target/hello_world/debug/hello_world");
 static SHELL: &[u8] = include_bytes!("../applications/shell/target/shell/debug/shell");
-static DO_NOTHING: &[u8] = include_bytes!("../applications/shell/target/shell/debug/shell");
+static DO_NOTHING: &[u8] = include_bytes!("../applications/do_nothing/target/do_nothing/debug/do_nothing");
 
 /// The kernels main after being handed off from the bootloader
 ///
@@ -76,16 +77,26 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
 
     allocator::init_heap().expect("Heap did not properly map");
 
+    blanc_os::remap_framebuffer();
+
     #[cfg(test)]
     test_main();
 
-    let nothing1 = task::task::Task::binary(Some("nothing1"), DO_NOTHING, Some(Ring::Ring0), None);
-    let nothing2 = task::task::Task::binary(Some("nothing2"), DO_NOTHING, Some(Ring::Ring0), None);
+    fs::write_file("/bin/hello_world", HELLO_WORLD).expect("Failed to install hello_world");
+    fs::write_file("/bin/shell", SHELL).expect("Failed to install shell");
+    fs::write_file("/bin/do_nothing", DO_NOTHING).expect("Failed to install do_nothing");
 
     Scheduler::init();
 
-    Scheduler::add_task(nothing1).unwrap();
-    Scheduler::add_task(nothing2).unwrap();
+    let shell = task::exec::spawn(
+        "/bin/shell",
+        vec![String::from("/bin/shell")],
+        Vec::new(),
+        None,
+    )
+    .expect("Failed to start the shell");
+    // The shell leads the session the console terminal belongs to
+    task::terminal::attach(shell);
 
     use interrupts::READY;
     *READY.lock() = true;
@@ -0,0 +1,172 @@
+//! Boot time self test of the physical frame allocator and tests of its buddy blocks
+//!
+//! Every usable frame is allocated once before anything else runs, so the test is
+//! only meaningful across different memory maps. Run it with several memory sizes:
+//!
+//! `QEMU_MEMORY=64M cargo ktest --test frame_allocator`
+//! `QEMU_MEMORY=1G cargo ktest --test frame_allocator`
+//! `QEMU_MEMORY=5G cargo ktest --test frame_allocator`
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blanc_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use memory::{
+    allocator,
+    phys::{PhysFrameAllocator, FRAME_ALLOCATOR},
+};
+use serial::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    blanc_os::init();
+
+    unsafe { memory::init(boot_info.recursive_index) };
+
+    PhysFrameAllocator::init(&boot_info.memory_regions);
+
+    serial_print!("frame_allocator::self_test...\t");
+    FRAME_ALLOCATOR.wait().unwrap().inner.lock().self_test();
+    serial_println!("[ok]");
+
+    allocator::init_heap().expect("Heap did not properly map");
+
+    test_main();
+
+    blanc_os::halt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blanc_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_counts_follow_allocations() {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    let free = allocator.free_frames();
+    // The heap took some frames
+    assert!(free < allocator.total_frames());
+
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.free_frames(), free - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn test_reserved_frames_are_not_handed_out() {
+    use x86_64::{
+        structures::paging::{FrameDeallocator, PhysFrame},
+        PhysAddr,
+    };
+
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    let free = allocator.free_frames();
+
+    // Frame 0 and the legacy VGA memory are never usable
+    for addr in [0, 0xA_0000] {
+        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+        assert_eq!(allocator.allocate_frame_nth(frame.start_address()), None);
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn test_contiguous_blocks_are_aligned() {
+    use memory::phys::{order_of, Zone};
+
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    let free = allocator.free_frames();
+
+    // 2 MiB aligned to 2 MiB, as a huge page needs
+    let order = order_of(0x20_0000, 0x20_0000);
+    assert_eq!(order, 9);
+    let block = allocator.allocate_contiguous(order, Zone::Normal).unwrap();
+    assert_eq!(block.start_address().as_u64() % 0x20_0000, 0);
+    assert_eq!(allocator.free_frames(), free - 512);
+    assert_eq!(allocator.allocate_frame_nth(block.start_address()), None);
+
+    unsafe { allocator.deallocate_contiguous(block, order) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn test_zones_limit_physical_addresses() {
+    use memory::phys::Zone;
+
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    let free = allocator.free_frames();
+
+    let dma = allocator.allocate_contiguous(4, Zone::Dma).unwrap();
+    assert!(dma.start_address().as_u64() + 16 * 4096 <= 0x100_0000);
+    let dma32 = allocator.allocate_contiguous(4, Zone::Dma32).unwrap();
+    assert!(dma32.start_address().as_u64() + 16 * 4096 <= 0x1_0000_0000);
+
+    unsafe {
+        allocator.deallocate_contiguous(dma, 4);
+        allocator.deallocate_contiguous(dma32, 4);
+    }
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn test_frames_are_freed_with_the_last_reference() {
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    let free = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.descriptor_mut(frame).unwrap().count(), 1);
+    assert_eq!(allocator.get(frame), 2);
+
+    unsafe {
+        assert!(!allocator.put(frame, 0));
+        assert_eq!(allocator.free_frames(), free - 1);
+        assert!(allocator.put(frame, 0));
+    }
+    assert_eq!(allocator.free_frames(), free);
+    // A free frame has no references to add to
+    assert_eq!(allocator.get(frame), 0);
+}
+
+#[test_case]
+fn test_processes_leave_the_emergency_pool() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+    use memory::phys::{Zone, EMERGENCY_FRAMES};
+    use x86_64::structures::paging::FrameAllocator;
+
+    // Growing the heap while the frame allocator is locked would deadlock. Reserving the
+    // room may take frames itself, so the free frames are counted after it
+    let before = FRAME_ALLOCATOR.wait().unwrap().inner.lock().free_frames();
+    let mut frames = Vec::with_capacity(before as usize);
+    let free = FRAME_ALLOCATOR.wait().unwrap().inner.lock().free_frames();
+
+    let mut allocator = FRAME_ALLOCATOR.wait().unwrap().inner.lock();
+    while let Some(frame) = allocator.allocate_user(0, Zone::Normal, u32::MAX) {
+        frames.push(frame);
+    }
+    assert_eq!(allocator.free_frames(), EMERGENCY_FRAMES);
+    // Every frame is marked with the process it was allocated for
+    let mut owned = [0];
+    allocator.owned_frames(&[u32::MAX], &mut owned);
+    assert_eq!(owned[0], frames.len() as u64);
+
+    // The kernel still gets frames from the pool
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe {
+        allocator.put(frame, 0);
+        for &frame in &frames {
+            allocator.put(frame, 0);
+        }
+    }
+    assert_eq!(allocator.free_frames(), free);
+}